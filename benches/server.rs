@@ -20,9 +20,11 @@ use condure::connection::testutil::{
 };
 use condure::executor::Executor;
 use condure::future::{AsyncReadExt, AsyncTcpStream, AsyncWriteExt};
+use condure::http1::{BodySize, ServerProtocol};
 use condure::reactor::Reactor;
+use condure::server::testutil::BenchBatchAdd;
 use condure::server::TestServer;
-use condure::websocket::testutil::{BenchRecvMessage, BenchSendMessage};
+use condure::websocket::testutil::{BenchApplyMask, BenchRecvMessage, BenchSendMessage};
 use criterion::{criterion_group, criterion_main, Criterion};
 use std::io::{self, Write};
 use std::net::SocketAddr;
@@ -30,6 +32,8 @@ use std::str;
 
 const REQS_PER_ITER: usize = 10;
 
+const HEADERS_MAX: usize = 64;
+
 fn req(addr: SocketAddr) {
     let reactor = Reactor::new(REQS_PER_ITER * 10);
     let executor = Executor::new(REQS_PER_ITER);
@@ -69,6 +73,58 @@ fn req(addr: SocketAddr) {
     executor.run(|timeout| reactor.poll(timeout)).unwrap();
 }
 
+struct BenchServerRecvRequest {
+    data: Vec<u8>,
+}
+
+impl BenchServerRecvRequest {
+    // a request with a typical number of headers for api traffic
+    fn new(headers: usize) -> Self {
+        assert!(headers <= HEADERS_MAX - 3);
+
+        let mut data = Vec::new();
+
+        write!(
+            data,
+            "POST /api/v1/items?page=2 HTTP/1.1\r\n\
+             Host: example.com\r\n\
+             Connection: keep-alive\r\n\
+             Content-Length: 0\r\n"
+        )
+        .unwrap();
+
+        for i in 0..headers {
+            write!(
+                data,
+                "X-Header-{}: some value, with params; a=1; b=\"two\"\r\n",
+                i
+            )
+            .unwrap();
+        }
+
+        write!(data, "\r\n").unwrap();
+
+        Self { data }
+    }
+
+    fn init(&self) -> ServerProtocol {
+        ServerProtocol::new()
+    }
+
+    fn run(&self, protocol: &mut ServerProtocol) {
+        let mut headers = [httparse::EMPTY_HEADER; HEADERS_MAX];
+        let mut rbuf = io::Cursor::new(&self.data[..]);
+
+        let req = protocol
+            .recv_request(&mut rbuf, &mut headers)
+            .unwrap()
+            .unwrap();
+
+        assert_eq!(req.body_size, BodySize::Known(0));
+        assert_eq!(rbuf.position() as usize, self.data.len());
+    }
+}
+
 fn criterion_benchmark(c: &mut Criterion) {
     {
         let t = BenchServerReqHandler::new();
@@ -102,6 +158,14 @@ fn criterion_benchmark(c: &mut Criterion) {
         });
     }
 
+    for headers in [4, 40] {
+        let t = BenchServerRecvRequest::new(headers);
+
+        c.bench_function(&format!("http1_recv_request headers={}", headers), |b| {
+            b.iter_batched_ref(|| t.init(), |i| t.run(i), criterion::BatchSize::SmallInput)
+        });
+    }
+
     {
         let t = BenchSendMessage::new(false);
 
@@ -134,6 +198,22 @@ fn criterion_benchmark(c: &mut Criterion) {
         });
     }
 
+    for size in [125, 16_384] {
+        let t = BenchApplyMask::new(size);
+
+        c.bench_function(&format!("ws_apply_mask size={}", size), |b| {
+            b.iter_batched_ref(|| t.init(), |i| t.run(i), criterion::BatchSize::SmallInput)
+        });
+    }
+
+    for addrs in [10, 1000] {
+        let t = BenchBatchAdd::new(addrs);
+
+        c.bench_function(&format!("batch_add addrs={}", addrs), |b| {
+            b.iter_batched_ref(|| t.init(), |i| t.run(i), criterion::BatchSize::SmallInput)
+        });
+    }
+
     {
         let server = TestServer::new(1);
         let req_addr = server.req_addr();
@@ -0,0 +1,321 @@
+/*
+ * Copyright (C) 2024 Fanout, Inc.
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+// one line per completed request, in common or combined log format, written
+// to a file or stdout independently of the debug log. worker threads hand
+// lines to a writer thread so that they never block on the destination
+
+use crate::spawn_thread;
+use log::debug;
+use std::fmt::Write as _;
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufWriter, Write};
+use std::net::IpAddr;
+use std::str::FromStr;
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, SystemTime};
+use time::macros::format_description;
+use time::{OffsetDateTime, UtcOffset};
+
+// lines waiting to be written. if the destination can't keep up, further
+// lines are dropped rather than queued without bound
+const QUEUE_MAX: usize = 10_000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AccessLogFormat {
+    // host, time, request line, status, and bytes
+    Common,
+
+    // common, plus referer, user agent, and request duration in
+    // microseconds
+    Combined,
+}
+
+impl AccessLogFormat {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Common => "common",
+            Self::Combined => "combined",
+        }
+    }
+}
+
+impl FromStr for AccessLogFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "common" => Ok(Self::Common),
+            "combined" => Ok(Self::Combined),
+            _ => Err(format!("unknown access log format: {}", s)),
+        }
+    }
+}
+
+pub struct AccessEntry {
+    // unset for clients on unix sockets
+    pub peer: Option<IpAddr>,
+
+    // when the request was received
+    pub time: SystemTime,
+
+    // method, uri, and version, as sent by the client
+    pub request_line: String,
+
+    pub referer: Option<String>,
+    pub user_agent: Option<String>,
+    pub status: u16,
+
+    // sent to the client for the response, including the header
+    pub bytes: u64,
+
+    pub duration: Duration,
+}
+
+// quote-safe copy of client-provided text, escaped the way apache does
+fn write_escaped(dest: &mut String, s: &str) {
+    for c in s.chars() {
+        match c {
+            '"' => dest.push_str("\\\""),
+            '\\' => dest.push_str("\\\\"),
+            c if c.is_ascii_graphic() || c == ' ' => dest.push(c),
+            c => {
+                let mut buf = [0; 4];
+
+                for b in c.encode_utf8(&mut buf).bytes() {
+                    write!(dest, "\\x{:02x}", b).unwrap();
+                }
+            }
+        }
+    }
+}
+
+impl AccessEntry {
+    pub fn format(&self, format: AccessLogFormat, offset: UtcOffset, dest: &mut String) {
+        match self.peer {
+            Some(ip) => write!(dest, "{}", ip).unwrap(),
+            None => dest.push('-'),
+        }
+
+        let time = OffsetDateTime::from(self.time).to_offset(offset);
+
+        let time_format = format_description!(
+            "[day]/[month repr:short]/[year]:[hour]:[minute]:[second] [offset_hour sign:mandatory][offset_minute]"
+        );
+
+        dest.push_str(" - - [");
+        dest.push_str(
+            &time
+                .format(&time_format)
+                .expect("failed to format timestamp"),
+        );
+        dest.push_str("] \"");
+        write_escaped(dest, &self.request_line);
+        dest.push_str("\" ");
+
+        write!(dest, "{} ", self.status).unwrap();
+
+        if self.bytes > 0 {
+            write!(dest, "{}", self.bytes).unwrap();
+        } else {
+            dest.push('-');
+        }
+
+        if format == AccessLogFormat::Combined {
+            for v in [&self.referer, &self.user_agent] {
+                dest.push_str(" \"");
+
+                match v {
+                    Some(v) => write_escaped(dest, v),
+                    None => dest.push('-'),
+                }
+
+                dest.push('"');
+            }
+
+            write!(dest, " {}", self.duration.as_micros()).unwrap();
+        }
+
+        dest.push('\n');
+    }
+}
+
+enum Destination {
+    Stdout,
+    File(File),
+}
+
+pub struct AccessLog {
+    format: AccessLogFormat,
+    offset: UtcOffset,
+    sender: Option<mpsc::SyncSender<String>>,
+    thread: Option<thread::JoinHandle<()>>,
+}
+
+impl AccessLog {
+    // path "-" means stdout. other paths are opened for appending
+    pub fn new(path: &str, format: AccessLogFormat) -> Result<Self, String> {
+        let dest = if path == "-" {
+            Destination::Stdout
+        } else {
+            match OpenOptions::new().create(true).append(true).open(path) {
+                Ok(f) => Destination::File(f),
+                Err(e) => return Err(format!("failed to open {}: {}", path, e)),
+            }
+        };
+
+        // fall back to utc if the local offset can't be determined safely
+        let offset = UtcOffset::current_local_offset().unwrap_or(UtcOffset::UTC);
+
+        let (sender, receiver) = mpsc::sync_channel::<String>(QUEUE_MAX);
+
+        let thread = spawn_thread("access-log".to_string(), move || {
+            let w: Box<dyn Write> = match dest {
+                Destination::Stdout => Box::new(io::stdout()),
+                Destination::File(f) => Box::new(f),
+            };
+
+            let mut w = BufWriter::new(w);
+
+            while let Ok(line) = receiver.recv() {
+                let mut ret = w.write_all(line.as_bytes());
+
+                // write out whatever else is queued before flushing
+                while ret.is_ok() {
+                    match receiver.try_recv() {
+                        Ok(line) => ret = w.write_all(line.as_bytes()),
+                        Err(_) => break,
+                    }
+                }
+
+                if let Err(e) = ret.and_then(|()| w.flush()) {
+                    debug!("access log write: {}", e);
+                }
+            }
+        })?;
+
+        Ok(Self {
+            format,
+            offset,
+            sender: Some(sender),
+            thread: Some(thread),
+        })
+    }
+
+    pub fn write(&self, entry: &AccessEntry) {
+        let mut line = String::new();
+        entry.format(self.format, self.offset, &mut line);
+
+        if let Err(e) = self.sender.as_ref().unwrap().try_send(line) {
+            debug!("access log dropped entry: {}", e);
+        }
+    }
+}
+
+impl Drop for AccessLog {
+    fn drop(&mut self) {
+        // let the thread write out what remains and exit
+        self.sender = None;
+
+        let thread = self.thread.take().unwrap();
+        thread.join().unwrap();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::net::Ipv4Addr;
+    use std::time::UNIX_EPOCH;
+
+    fn entry() -> AccessEntry {
+        AccessEntry {
+            peer: Some(IpAddr::V4(Ipv4Addr::new(192, 168, 1, 2))),
+            time: UNIX_EPOCH + Duration::from_secs(971_211_336),
+            request_line: "GET /apache_pb.gif HTTP/1.0".to_string(),
+            referer: Some("http://www.example.com/start.html".to_string()),
+            user_agent: Some("Mozilla/4.08 [en] \"test\"".to_string()),
+            status: 200,
+            bytes: 2326,
+            duration: Duration::from_micros(1500),
+        }
+    }
+
+    #[test]
+    fn format() {
+        let offset = UtcOffset::from_hms(-7, 0, 0).unwrap();
+
+        let mut s = String::new();
+        entry().format(AccessLogFormat::Common, offset, &mut s);
+
+        assert_eq!(
+            s,
+            "192.168.1.2 - - [10/Oct/2000:13:55:36 -0700] \"GET /apache_pb.gif HTTP/1.0\" 200 2326\n"
+        );
+
+        let mut s = String::new();
+        entry().format(AccessLogFormat::Combined, offset, &mut s);
+
+        assert_eq!(
+            s,
+            concat!(
+                "192.168.1.2 - - [10/Oct/2000:13:55:36 -0700] \"GET /apache_pb.gif HTTP/1.0\" ",
+                "200 2326 \"http://www.example.com/start.html\" ",
+                "\"Mozilla/4.08 [en] \\\"test\\\"\" 1500\n"
+            )
+        );
+
+        let e = AccessEntry {
+            peer: None,
+            request_line: "GET /a\tb HTTP/1.1".to_string(),
+            referer: None,
+            user_agent: None,
+            status: 304,
+            bytes: 0,
+            ..entry()
+        };
+
+        let mut s = String::new();
+        e.format(AccessLogFormat::Combined, UtcOffset::UTC, &mut s);
+
+        assert_eq!(
+            s,
+            "- - - [10/Oct/2000:20:55:36 +0000] \"GET /a\\x09b HTTP/1.1\" 304 - \"-\" \"-\" 1500\n"
+        );
+    }
+
+    #[test]
+    fn write_file() {
+        let path = std::env::temp_dir().join(format!("condure-access-{}.log", std::process::id()));
+        let _ = fs::remove_file(&path);
+
+        {
+            let log = AccessLog::new(path.to_str().unwrap(), AccessLogFormat::Common).unwrap();
+            log.write(&entry());
+            log.write(&entry());
+        }
+
+        let data = fs::read_to_string(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        let lines: Vec<&str> = data.lines().collect();
+        assert_eq!(lines.len(), 2);
+        assert!(lines[0].starts_with("192.168.1.2 - - ["));
+        assert!(lines[0].ends_with("] \"GET /apache_pb.gif HTTP/1.0\" 200 2326"));
+    }
+}
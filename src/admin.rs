@@ -0,0 +1,107 @@
+/*
+ * Copyright (C) 2024 Fanout, Inc.
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+// admin socket for managing a running instance from scripts, over a zmq REP
+// socket. each request is a single message containing a command line, and
+// the reply is a single message, as described in the command module. the
+// commands are the same as those of the control socket, except upgrade
+
+use crate::command::{handle_command, Commands};
+use crate::spawn_thread;
+use crate::zmq::{SpecInfo, ZmqSocket};
+use log::{debug, error};
+use std::str;
+use std::sync::mpsc;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+const COMMAND_SIZE_MAX: usize = 1_024;
+
+// how often to check for stop while waiting for requests
+const RECV_TIMEOUT: Duration = Duration::from_millis(100);
+
+pub struct AdminServer {
+    stop: Option<mpsc::Sender<()>>,
+    thread: Option<thread::JoinHandle<()>>,
+}
+
+impl AdminServer {
+    pub fn new<C>(
+        zmq_context: &Arc<zmq::Context>,
+        spec: SpecInfo,
+        commands: C,
+    ) -> Result<Self, String>
+    where
+        C: Commands + Send + 'static,
+    {
+        let sock = ZmqSocket::new(zmq_context, zmq::REP);
+
+        if let Err(e) = sock.inner().set_rcvtimeo(RECV_TIMEOUT.as_millis() as i32) {
+            return Err(e.to_string());
+        }
+
+        if let Err(e) = sock.apply_specs(&[spec]) {
+            return Err(e.to_string());
+        }
+
+        let (stop, r_stop) = mpsc::channel::<()>();
+
+        let thread = spawn_thread("admin".to_string(), move || loop {
+            if let Err(mpsc::TryRecvError::Disconnected) = r_stop.try_recv() {
+                break;
+            }
+
+            let msg = match sock.recv(0) {
+                Ok(msg) => msg,
+                Err(zmq::Error::EAGAIN) | Err(zmq::Error::EINTR) => continue,
+                Err(e) => {
+                    error!("admin recv: {}", e);
+                    break;
+                }
+            };
+
+            let reply = if msg.len() > COMMAND_SIZE_MAX {
+                "error: command too large".to_string()
+            } else {
+                match str::from_utf8(&msg) {
+                    Ok(line) => handle_command(&commands, line.trim()),
+                    Err(_) => "error: command is not valid utf-8".to_string(),
+                }
+            };
+
+            // a REP socket must reply before it can receive again
+            if let Err(e) = sock.send(zmq::Message::from(reply.as_bytes()), 0) {
+                debug!("admin send: {}", e);
+            }
+        })?;
+
+        Ok(Self {
+            stop: Some(stop),
+            thread: Some(thread),
+        })
+    }
+}
+
+impl Drop for AdminServer {
+    fn drop(&mut self) {
+        // the thread notices within the receive timeout
+        self.stop = None;
+
+        let thread = self.thread.take().unwrap();
+        thread.join().unwrap();
+    }
+}
@@ -0,0 +1,210 @@
+/*
+ * Copyright (C) 2024 Fanout, Inc.
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+// periodic announcements of a running instance, published on a zmq PUB
+// socket so that handlers can discover live instances and notice restarts.
+// each message is the topic "announce", a space, and a tnetstring map
+// prefixed with 'T', similar to zhttp
+
+use crate::spawn_thread;
+use crate::stats::Occupancy;
+use crate::tnetstring;
+use crate::zmq::{SpecInfo, ZmqSocket};
+use log::{debug, error};
+use std::io;
+use std::sync::mpsc;
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+pub const TOPIC: &[u8] = b"announce ";
+
+const MESSAGE_SIZE_MAX: usize = 1_024;
+
+pub struct Announcement<'a> {
+    pub instance_id: &'a str,
+    pub version: &'a str,
+
+    // unix time the instance started, which changes if it restarts
+    pub started: u64,
+
+    pub uptime: Duration,
+
+    // increases with each announcement
+    pub seq: u64,
+
+    pub connections: Occupancy,
+}
+
+impl Announcement<'_> {
+    pub fn serialize(&self, dest: &mut [u8]) -> Result<usize, io::Error> {
+        if dest.len() < TOPIC.len() + 1 {
+            return Err(io::Error::from(io::ErrorKind::WriteZero));
+        }
+
+        dest[..TOPIC.len()].copy_from_slice(TOPIC);
+        dest[TOPIC.len()] = b'T';
+
+        let start = TOPIC.len() + 1;
+
+        let mut cursor = io::Cursor::new(&mut dest[start..]);
+        let mut w = tnetstring::Writer::new(&mut cursor);
+
+        w.start_map()?;
+
+        w.write_string(b"from")?;
+        w.write_string(self.instance_id.as_bytes())?;
+
+        w.write_string(b"version")?;
+        w.write_string(self.version.as_bytes())?;
+
+        w.write_string(b"started")?;
+        w.write_int(self.started as isize)?;
+
+        w.write_string(b"uptime")?;
+        w.write_int(self.uptime.as_secs() as isize)?;
+
+        w.write_string(b"seq")?;
+        w.write_int(self.seq as isize)?;
+
+        w.write_string(b"connections")?;
+        w.write_int(self.connections.used as isize)?;
+
+        w.write_string(b"maxconn")?;
+        w.write_int(self.connections.capacity as isize)?;
+
+        w.end_map()?;
+
+        w.flush()?;
+
+        Ok(start + (cursor.position() as usize))
+    }
+}
+
+pub struct Announcer {
+    stop: Option<mpsc::Sender<()>>,
+    thread: Option<thread::JoinHandle<()>>,
+}
+
+impl Announcer {
+    // connections is called before each announcement to get the current
+    // connection usage
+    pub fn new<F>(
+        zmq_context: &Arc<zmq::Context>,
+        instance_id: &str,
+        spec: SpecInfo,
+        interval: Duration,
+        connections: F,
+    ) -> Result<Self, String>
+    where
+        F: Fn() -> Occupancy + Send + 'static,
+    {
+        let sock = ZmqSocket::new(zmq_context, zmq::PUB);
+
+        if let Err(e) = sock.apply_specs(&[spec]) {
+            return Err(e.to_string());
+        }
+
+        let (stop, r_stop) = mpsc::channel();
+
+        let instance_id = instance_id.to_string();
+
+        let thread = spawn_thread("announce".to_string(), move || {
+            let start = Instant::now();
+
+            let started = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or(0);
+
+            let mut buf = [0; MESSAGE_SIZE_MAX];
+
+            for seq in 0.. {
+                let a = Announcement {
+                    instance_id: &instance_id,
+                    version: env!("CARGO_PKG_VERSION"),
+                    started,
+                    uptime: start.elapsed(),
+                    seq,
+                    connections: connections(),
+                };
+
+                match a.serialize(&mut buf) {
+                    Ok(size) => {
+                        let msg = zmq::Message::from(&buf[..size]);
+
+                        // subscribers that can't keep up miss announcements
+                        if let Err(e) = sock.send(msg, zmq::DONTWAIT) {
+                            debug!("announce send: {}", e);
+                        }
+                    }
+                    Err(e) => error!("announce serialize: {}", e),
+                }
+
+                match r_stop.recv_timeout(interval) {
+                    Err(mpsc::RecvTimeoutError::Timeout) => {}
+                    _ => break,
+                }
+            }
+        })?;
+
+        Ok(Self {
+            stop: Some(stop),
+            thread: Some(thread),
+        })
+    }
+}
+
+impl Drop for Announcer {
+    fn drop(&mut self) {
+        // wake the thread
+        self.stop = None;
+
+        let thread = self.thread.take().unwrap();
+        thread.join().unwrap();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str;
+
+    #[test]
+    fn serialize() {
+        let a = Announcement {
+            instance_id: "condure",
+            version: "1.0.0",
+            started: 1700000000,
+            uptime: Duration::from_millis(65_500),
+            seq: 3,
+            connections: Occupancy::new(2, 100),
+        };
+
+        let mut buf = [0; MESSAGE_SIZE_MAX];
+        let size = a.serialize(&mut buf).unwrap();
+
+        let expected = concat!(
+            "announce T118:4:from,7:condure,7:version,5:1.0.0,7:started,10:170000000",
+            "0#6:uptime,2:65#3:seq,1:3#11:connections,1:2#7:maxconn,3:100#}",
+        );
+
+        assert_eq!(str::from_utf8(&buf[..size]).unwrap(), expected);
+
+        let mut buf = [0; 8];
+        assert!(a.serialize(&mut buf).is_err());
+    }
+}
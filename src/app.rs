@@ -14,24 +14,119 @@
  * limitations under the License.
  */
 
-use crate::client::Client;
-use crate::server::{Server, MSG_RETAINED_PER_CONNECTION_MAX, MSG_RETAINED_PER_WORKER_MAX};
+use crate::accesslog::{AccessLog, AccessLogFormat};
+use crate::admin::AdminServer;
+use crate::announce::Announcer;
+use crate::client::{self, Client};
+use crate::command::ServerCommands;
+use crate::connection::{
+    self, ErrorPage, FixedResponse, ForwardedHeaders, OptionsResponse, PhaseTimeouts,
+};
+use crate::control::{self, ControlServer};
+use crate::curve::{self, ZapHandler};
+use crate::listener::AcceptRateLimits;
+use crate::logfilter::{self, LogFilter};
+use crate::net::{BindOpts, InheritedListeners, SocketAddr};
+use crate::platform::{Signals, SIGINT, SIGTERM};
+#[cfg(unix)]
+use crate::platform::{SIGQUIT, SIGUSR2};
+use crate::ratelimit::RequestRateLimits;
+use crate::report::Reporter;
+use crate::server::{self, LoopPacing, Server, MSG_RETAINED_PER_WORKER_MAX};
+use crate::stats::{MirrorCounters, Occupancy};
 use crate::websocket;
+use crate::zhttppacket;
 use crate::zhttpsocket;
-use crate::zmq::SpecInfo;
+use crate::zmq::{CurveOpts, SpecInfo, SpecOpts};
 use ipnet::IpNet;
-use log::info;
+use log::{info, warn, LevelFilter};
 use signal_hook;
-use signal_hook::consts::TERM_SIGNALS;
-use signal_hook::iterator::Signals;
 use std::cmp;
-use std::path::PathBuf;
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
 use std::sync::atomic::AtomicBool;
 use std::sync::Arc;
 use std::time::Duration;
 
 const INIT_HWM: usize = 128;
 
+// messages retained by connections are preallocated at startup, so refuse
+// bound and maxconn combinations that would need an excessive amount
+const MSG_RETAINED_MAX: usize = 50_000_000;
+
+// a spec may be followed by zmq socket options for its connections, for
+// example "tcp://127.0.0.1:10000?sndhwm=1000&rcvhwm=1000&linger=0"
+fn parse_spec_opts(s: &str) -> Result<(&str, SpecOpts), String> {
+    let (spec, params) = match s.split_once('?') {
+        Some(ret) => ret,
+        None => return Ok((s, SpecOpts::default())),
+    };
+
+    let mut opts = SpecOpts::default();
+
+    for param in params.split('&') {
+        let (name, value) = match param.split_once('=') {
+            Some(ret) => ret,
+            None => return Err(format!("spec option must be name=value: {}", param)),
+        };
+
+        let value: i32 = match value.parse() {
+            Ok(x) => x,
+            Err(e) => return Err(format!("failed to parse spec option {}: {}", name, e)),
+        };
+
+        match name {
+            "sndhwm" | "rcvhwm" | "sndbuf" | "rcvbuf" if value < 0 => {
+                return Err(format!("spec option {} must not be negative", name));
+            }
+            "sndhwm" => opts.sndhwm = Some(value),
+            "rcvhwm" => opts.rcvhwm = Some(value),
+            "sndbuf" => opts.sndbuf = Some(value),
+            "rcvbuf" => opts.rcvbuf = Some(value),
+            "linger" => opts.linger = Some(value),
+            _ => return Err(format!("unknown spec option: {}", name)),
+        }
+    }
+
+    Ok((spec, opts))
+}
+
+// zhttp sockets also get the curve settings
+fn parse_zhttp_spec_opts(s: &str, curve: Option<CurveOpts>) -> Result<(&str, SpecOpts), String> {
+    let (spec, mut opts) = parse_spec_opts(s)?;
+
+    opts.curve = curve;
+
+    Ok((spec, opts))
+}
+
+// the admin socket can manage the instance, so unless it's local, it must
+// be a curve server with the clients limited by the allow list
+fn parse_admin_spec_opts(
+    s: &str,
+    curve: Option<CurveOpts>,
+    clients_limited: bool,
+) -> Result<(&str, SpecOpts), String> {
+    let (spec, mut opts) = parse_spec_opts(s)?;
+
+    if spec.starts_with("ipc://") {
+        return Ok((spec, opts));
+    }
+
+    match curve {
+        Some(curve @ CurveOpts::Server { .. }) if clients_limited => {
+            opts.curve = Some(curve);
+
+            Ok((spec, opts))
+        }
+        _ => Err(format!(
+            "admin spec {} must be ipc, or requires zmq-curve-cert and zmq-curve-allow",
+            spec
+        )),
+    }
+}
+
 fn make_specs(base: &str, is_server: bool) -> Result<(String, String, String), String> {
     if base.starts_with("ipc:") {
         if is_server {
@@ -62,53 +157,1288 @@ fn make_specs(base: &str, is_server: bool) -> Result<(String, String, String), S
     } else {
         Err("base spec must be ipc or tcp".into())
     }
-}
+}
+
+// the implementation handling a tls listener's connections
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum TlsBackend {
+    #[default]
+    OpenSsl,
+
+    #[cfg(feature = "rustls")]
+    Rustls,
+}
+
+impl TlsBackend {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::OpenSsl => "openssl",
+            #[cfg(feature = "rustls")]
+            Self::Rustls => "rustls",
+        }
+    }
+}
+
+// how a tls listener treats clients that don't indicate a server name
+#[derive(Clone, Debug, Default, PartialEq)]
+pub enum NoSniPolicy {
+    // use the default cert, if any, and the default backend
+    #[default]
+    DefaultCert,
+
+    // fail the handshake
+    Reject,
+
+    // use the default cert, if any, and the backend of the given sni
+    // backend domain
+    Backend(String),
+}
+
+pub enum ListenSpec {
+    Tcp {
+        addr: std::net::SocketAddr,
+        tls: bool,
+        default_cert: Option<String>,
+        no_sni: NoSniPolicy,
+
+        // accept tls early data, with the given anti-replay window
+        early_data: Option<Duration>,
+
+        tls_backend: TlsBackend,
+
+        bind_opts: BindOpts,
+
+        // connections start with a PROXY protocol header
+        proxy: bool,
+    },
+    Local {
+        path: PathBuf,
+        mode: Option<u32>,
+        user: Option<String>,
+        group: Option<String>,
+    },
+}
+
+// a request property that selects stream mode on a combined listener
+#[derive(Clone)]
+pub enum StreamRule {
+    Method(String),
+    PathPrefix(String),
+    Upgrade,
+}
+
+pub struct ListenConfig {
+    pub spec: ListenSpec,
+    pub stream: bool,
+
+    // if not empty, the listener serves both modes. connections whose first
+    // request matches any of these rules are handled in stream mode and the
+    // rest in req mode
+    pub stream_rules: Vec<StreamRule>,
+
+    // relay raw tcp bytes as stream data, without http parsing. implies
+    // stream
+    pub raw: bool,
+
+    // overrides messages-max for connections of this listener
+    pub messages_max: Option<usize>,
+
+    // overrides message-size-max for connections of this listener
+    pub message_size_max: Option<usize>,
+
+    // maximum payload size of received websocket frames, or 0 for no limit
+    pub frame_size_max: Option<usize>,
+
+    // key/value pairs included in requests from this listener
+    pub tags: Vec<(String, String)>,
+
+    // if not empty, requests whose Host matches none of these are refused.
+    // exact names, or wildcards such as *.example.com
+    pub allowed_hosts: Vec<String>,
+
+    // requests with larger bodies are refused with 413
+    pub body_size_max: Option<usize>,
+
+    // named backend of the listener's requests, unless a route or the tls
+    // server name selects another
+    pub backend: Option<String>,
+}
+
+// zhttp handlers for tls connections that indicate a particular server name
+pub struct SniBackend {
+    pub domain: String,
+    pub zclient_req: Vec<String>,
+    pub zclient_stream: Vec<String>,
+}
+
+// zhttp handlers that listeners and routes refer to by name
+pub struct Backend {
+    pub name: String,
+    pub zclient_req: Vec<String>,
+    pub zclient_stream: Vec<String>,
+}
+
+// sends requests matching the host and path prefix to a named backend. a
+// host may be a wildcard such as *.example.com, and at least one of host
+// or path prefix is set
+pub struct Route {
+    pub backend: String,
+    pub host: Option<String>,
+    pub path_prefix: Option<String>,
+}
+
+// a response answered locally for requests matching the path. a path
+// ending in * matches as a prefix
+pub struct FixedRoute {
+    pub path: String,
+    pub code: u16,
+    pub headers: Vec<(String, String)>,
+    pub body_file: Option<PathBuf>,
+}
+
+// replaces the body of error responses with the code that are generated
+// locally. if no content type is set, it is based on the file extension
+pub struct ErrorTemplate {
+    pub code: u16,
+    pub content_type: Option<String>,
+    pub body_file: PathBuf,
+}
+
+pub struct Config {
+    pub instance_id: String,
+    pub workers: usize,
+    pub req_maxconn: usize,
+    pub stream_maxconn: usize,
+    pub buffer_size: usize,
+    pub body_buffer_size: usize,
+    pub messages_max: usize,
+    pub message_size_max: usize,
+    pub req_timeout: Duration,
+    pub stream_timeout: Duration,
+    pub req_retries: usize,
+    pub req_retry_timeout: Duration,
+
+    // if nonzero, compressed req mode request bodies are decoded before
+    // forwarding, up to this size
+    pub req_decompress_max: usize,
+    pub handler_timeout: Duration,
+
+    // if nonzero, these phases of receiving a request time out separately
+    // from the rest of the connection
+    pub header_timeout: Duration,
+    pub body_timeout: Duration,
+    pub idle_timeout: Duration,
+
+    // if nonzero, clients reading a request or writing a response more
+    // slowly than this many bytes per second are disconnected
+    pub min_transfer_rate: u32,
+
+    // if nonzero, idle stream mode event streams are sent a comment at
+    // this interval
+    pub sse_keep_alive_interval: Duration,
+    pub listen: Vec<ListenConfig>,
+    pub zclient_req: Vec<String>,
+    pub zclient_stream: Vec<String>,
+    pub sni_backends: Vec<SniBackend>,
+    pub backends: Vec<Backend>,
+
+    // checked in order. requests that don't match any keep the backend of
+    // their connection
+    pub routes: Vec<Route>,
+    pub mirror_req: Vec<String>,
+    pub mirror_percent: u32,
+    pub announce: Option<String>,
+    pub announce_interval: Duration,
+    pub stats: Option<String>,
+    pub stats_interval: Duration,
+
+    // zmq REP spec to bind for admin commands. unless ipc, the socket is a
+    // curve server limited to the zmq_curve_allow clients
+    pub admin: Option<String>,
+    pub zclient_connect: bool,
+    pub zserver_req: Vec<String>,
+    pub zserver_stream: Vec<String>,
+    pub zserver_connect: bool,
+
+    // czmq secret certificate for curve on the zhttp sockets. with a
+    // server cert, this instance is a curve client of the handlers.
+    // otherwise it is the curve server, and clients may be limited to the
+    // public keys in the allow list
+    pub zmq_curve_cert: Option<PathBuf>,
+    pub zmq_curve_server_cert: Option<PathBuf>,
+    pub zmq_curve_allow: Option<PathBuf>,
+    pub ipc_file_mode: u32,
+    pub certs_dir: PathBuf,
+    pub tls_ticket_key_rotation: Duration,
+    pub tls_ticket_key_overlap: Duration,
+    pub allow_compression: bool,
+
+    // headers describing the original request to add to requests sent to
+    // handlers
+    pub forwarded_headers: ForwardedHeaders,
+
+    // requests from these proxies are attributed to the client in their
+    // X-Forwarded-For header
+    pub trusted_proxies: Vec<IpNet>,
+    pub download_rate: u32,
+    pub keep_alive_session_info: bool,
+    pub allow_http09: bool,
+
+    // refuse request targets with control characters, backslashes,
+    // fragments, or dot segments, instead of passing them to handlers
+    pub strict_request_target: bool,
+
+    // requests with longer targets are refused with 414. 0 means the limit
+    // is the buffer size
+    pub uri_max: usize,
+
+    // if empty, server-wide OPTIONS requests are forwarded to the handler
+    pub options_allow: String,
+    pub options_body: Option<PathBuf>,
+
+    // if set, GET requests for these paths are answered locally with the
+    // liveness and readiness of this instance
+    pub health_live_path: Option<String>,
+    pub health_ready_path: Option<String>,
+    pub fixed_responses: Vec<FixedRoute>,
+    pub error_pages: Vec<ErrorTemplate>,
+
+    // if set, completed requests are logged to this file, or "-" for stdout
+    pub access_log: Option<String>,
+    pub access_log_format: AccessLogFormat,
+
+    // if set, handlers may respond with the path of a file within this
+    // directory to use as the body
+    pub file_root: Option<PathBuf>,
+    pub deny: Vec<IpNet>,
+    pub accept_rate: u32,
+    pub accept_rate_per_ip: u32,
+
+    // requests forwarded per second per client, keyed by the value of
+    // request_rate_key_header if a request from a trusted proxy has it,
+    // otherwise by ip
+    pub request_rate_per_ip: u32,
+    pub request_rate_key_header: Option<String>,
+    pub accept_pause_memory: usize,
+
+    // each worker accepts from tcp listeners of its own, bound with
+    // SO_REUSEPORT, instead of a listener thread handing connections out
+    pub reuse_port: bool,
+    pub worker_memory_budget: usize,
+
+    // budget shared by all workers, enforced like the worker budget. when
+    // exceeded, accepting also pauses unless accept_pause_memory is set
+    pub memory_budget: usize,
+
+    // worker event loop tuning. a zero poll timeout means no limit
+    pub accept_per_loop_max: usize,
+    pub poll_timeout_max: Duration,
+    pub reactor_budget: u32,
+
+    // hwm of the zhttp sockets, other than for initial requests. 0 = 5% of
+    // maxconn. spec options override it per spec
+    pub zmq_hwm: usize,
+    pub handle_bound: usize,
+    pub resp_sender_bound: usize,
+    pub handle_accept_bound: usize,
+    pub port_file: Option<PathBuf>,
+    pub control: Option<PathBuf>,
+
+    // take over the listeners of the instance on the control socket, if
+    // any, and have it stop once this instance has started
+    pub upgrade: bool,
+}
+
+impl Config {
+    // the configured hwm, or 5% of maxconn
+    fn other_hwm(&self) -> usize {
+        if self.zmq_hwm > 0 {
+            return self.zmq_hwm;
+        }
+
+        cmp::max((self.req_maxconn + self.stream_maxconn) / 20, 1)
+    }
+
+    // the configured handle bound, or 5% of maxconn spread across workers
+    fn effective_handle_bound(&self) -> usize {
+        if self.handle_bound > 0 {
+            return self.handle_bound;
+        }
+
+        cmp::max(self.other_hwm() / self.workers, 1)
+    }
+
+    fn msg_retained_max(&self) -> Option<usize> {
+        let maxconn = self.req_maxconn.checked_add(self.stream_maxconn)?;
+
+        server::msg_retained_per_connection_max(self.resp_sender_bound)
+            .checked_mul(maxconn)?
+            .checked_add(MSG_RETAINED_PER_WORKER_MAX.checked_mul(self.workers)?)
+    }
+
+    fn validate_bounds(&self) -> Result<(), String> {
+        if self.resp_sender_bound == 0 || self.resp_sender_bound > server::RESP_SENDER_BOUND_MAX {
+            return Err(format!(
+                "resp-sender-bound must be between 1 and {}",
+                server::RESP_SENDER_BOUND_MAX
+            ));
+        }
+
+        if self.handle_accept_bound == 0 {
+            return Err("handle-accept-bound must be at least 1".to_string());
+        }
+
+        if self.zmq_hwm > i32::MAX as usize {
+            return Err(format!("zmq-hwm must be at most {}", i32::MAX));
+        }
+
+        // a handle bound above the zmq hwm only moves queueing from the
+        // sockets into the workers
+        if self.handle_bound > self.other_hwm() {
+            return Err(format!(
+                "handle-bound must be at most the zmq hwm ({})",
+                self.other_hwm()
+            ));
+        }
+
+        match self.msg_retained_max() {
+            Some(n) if n <= MSG_RETAINED_MAX => Ok(()),
+            _ => Err(format!(
+                "resp-sender-bound and maxconn retain too many messages (max {})",
+                MSG_RETAINED_MAX
+            )),
+        }
+    }
+}
+
+// write a listen config in the same format as the listen option. if the
+// bound address is known, it is written instead of the configured one
+fn write_listen<W: Write>(
+    w: &mut W,
+    lc: &ListenConfig,
+    bound: Option<&SocketAddr>,
+) -> Result<(), io::Error> {
+    match bound {
+        Some(SocketAddr::Ip(a)) => write!(w, "{}", a)?,
+        Some(addr @ SocketAddr::Unix(a)) => match a.as_pathname() {
+            Some(path) => write!(w, "{}", path.display())?,
+            None => write!(w, "{}", addr)?,
+        },
+        None => match &lc.spec {
+            ListenSpec::Tcp { addr, .. } => write!(w, "{}", addr)?,
+            ListenSpec::Local { path, .. } => write!(w, "{}", path.display())?,
+        },
+    }
+
+    if lc.raw {
+        write!(w, ",raw")?;
+    } else if !lc.stream_rules.is_empty() {
+        write!(w, ",combined")?;
+
+        for rule in lc.stream_rules.iter() {
+            match rule {
+                StreamRule::Method(method) => write!(w, ",stream-if=method:{}", method)?,
+                StreamRule::PathPrefix(prefix) => write!(w, ",stream-if=path:{}", prefix)?,
+                StreamRule::Upgrade => write!(w, ",stream-if=upgrade")?,
+            }
+        }
+    } else if lc.stream {
+        write!(w, ",stream")?;
+    } else {
+        write!(w, ",req")?;
+    }
+
+    if let Some(n) = lc.messages_max {
+        write!(w, ",messages-max={}", n)?;
+    }
+
+    if let Some(n) = lc.message_size_max {
+        write!(w, ",message-size-max={}", n)?;
+    }
+
+    if let Some(n) = lc.frame_size_max {
+        write!(w, ",frame-size-max={}", n)?;
+    }
+
+    for (name, value) in lc.tags.iter() {
+        write!(w, ",tag={}:{}", name, value)?;
+    }
+
+    for host in lc.allowed_hosts.iter() {
+        write!(w, ",allowed-host={}", host)?;
+    }
+
+    if let Some(n) = lc.body_size_max {
+        write!(w, ",body-size-max={}", n)?;
+    }
+
+    if let Some(name) = &lc.backend {
+        write!(w, ",backend={}", name)?;
+    }
+
+    match &lc.spec {
+        ListenSpec::Tcp {
+            tls,
+            default_cert,
+            no_sni,
+            early_data,
+            tls_backend,
+            bind_opts,
+            proxy,
+            ..
+        } => {
+            if *tls {
+                write!(w, ",tls")?;
+            }
+
+            if let Some(cert) = default_cert {
+                write!(w, ",default-cert={}", cert)?;
+            }
+
+            match no_sni {
+                NoSniPolicy::DefaultCert => {}
+                NoSniPolicy::Reject => write!(w, ",no-sni=reject")?,
+                NoSniPolicy::Backend(domain) => write!(w, ",no-sni={}", domain)?,
+            }
+
+            if let Some(window) = early_data {
+                write!(w, ",early-data={}", window.as_secs())?;
+            }
+
+            if *tls_backend != TlsBackend::OpenSsl {
+                write!(w, ",tls-backend={}", tls_backend.as_str())?;
+            }
+
+            if let Some(device) = &bind_opts.device {
+                write!(w, ",device={}", device)?;
+            }
+
+            if bind_opts.freebind {
+                write!(w, ",freebind")?;
+            }
+
+            if bind_opts.transparent {
+                write!(w, ",transparent")?;
+            }
+
+            if let Some(backlog) = bind_opts.backlog {
+                write!(w, ",backlog={}", backlog)?;
+            }
+
+            if *proxy {
+                write!(w, ",proxy")?;
+            }
+        }
+        ListenSpec::Local {
+            mode, user, group, ..
+        } => {
+            write!(w, ",local")?;
+
+            if let Some(mode) = mode {
+                write!(w, ",mode={:o}", mode)?;
+            }
+
+            if let Some(user) = user {
+                write!(w, ",user={}", user)?;
+            }
+
+            if let Some(group) = group {
+                write!(w, ",group={}", group)?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+// write the bound address of each listener, one per line
+fn write_listen_addrs<W: Write>(
+    w: &mut W,
+    listen: &[ListenConfig],
+    addrs: &[SocketAddr],
+) -> Result<(), io::Error> {
+    for (lc, addr) in listen.iter().zip(addrs) {
+        write_listen(w, lc, Some(addr))?;
+        writeln!(w)?;
+    }
+
+    Ok(())
+}
+
+// the bound address of each listener, in the same format as the listen
+// option
+fn listen_addrs(listen: &[ListenConfig], addrs: &[SocketAddr]) -> Vec<String> {
+    listen
+        .iter()
+        .zip(addrs)
+        .map(|(lc, addr)| {
+            let mut out = Vec::new();
+            write_listen(&mut out, lc, Some(addr)).unwrap();
+
+            String::from_utf8_lossy(&out).into_owned()
+        })
+        .collect()
+}
+
+fn write_toml_str<W: Write>(w: &mut W, s: &str) -> Result<(), io::Error> {
+    write!(w, "\"")?;
+
+    for c in s.chars() {
+        match c {
+            '"' => write!(w, "\\\"")?,
+            '\\' => write!(w, "\\\\")?,
+            c if c.is_control() => write!(w, "\\u{:04X}", c as u32)?,
+            c => write!(w, "{}", c)?,
+        }
+    }
+
+    write!(w, "\"")
+}
+
+fn write_toml_strs<W: Write, T: AsRef<str>>(w: &mut W, l: &[T]) -> Result<(), io::Error> {
+    write!(w, "[")?;
+
+    for (i, s) in l.iter().enumerate() {
+        if i > 0 {
+            write!(w, ", ")?;
+        }
+
+        write_toml_str(w, s.as_ref())?;
+    }
+
+    write!(w, "]")
+}
+
+// write the effective configuration as toml, including built-in limits
+// and values derived from the options. keys match the option names
+pub fn write_config<W: Write>(w: &mut W, config: &Config) -> Result<(), io::Error> {
+    write!(w, "id = ")?;
+    write_toml_str(w, &config.instance_id)?;
+    writeln!(w)?;
+
+    writeln!(w, "workers = {}", config.workers)?;
+    writeln!(w, "req-maxconn = {}", config.req_maxconn)?;
+    writeln!(w, "stream-maxconn = {}", config.stream_maxconn)?;
+    writeln!(w, "buffer-size = {}", config.buffer_size)?;
+    writeln!(w, "body-buffer-size = {}", config.body_buffer_size)?;
+    writeln!(w, "messages-max = {}", config.messages_max)?;
+    writeln!(w, "message-size-max = {}", config.message_size_max)?;
+    writeln!(w, "req-timeout = {}", config.req_timeout.as_secs())?;
+    writeln!(w, "stream-timeout = {}", config.stream_timeout.as_secs())?;
+    writeln!(w, "req-retries = {}", config.req_retries)?;
+    writeln!(
+        w,
+        "req-retry-timeout = {}",
+        config.req_retry_timeout.as_millis()
+    )?;
+    writeln!(w, "req-decompress-max = {}", config.req_decompress_max)?;
+    writeln!(w, "handler-timeout = {}", config.handler_timeout.as_secs())?;
+    writeln!(w, "header-timeout = {}", config.header_timeout.as_secs())?;
+    writeln!(w, "body-timeout = {}", config.body_timeout.as_secs())?;
+    writeln!(w, "idle-timeout = {}", config.idle_timeout.as_secs())?;
+    writeln!(w, "min-transfer-rate = {}", config.min_transfer_rate)?;
+    writeln!(
+        w,
+        "sse-keep-alive-interval = {}",
+        config.sse_keep_alive_interval.as_secs()
+    )?;
+
+    let listen: Vec<String> = config
+        .listen
+        .iter()
+        .map(|lc| {
+            let mut v = Vec::new();
+            write_listen(&mut v, lc, None).unwrap();
+
+            String::from_utf8_lossy(&v).into_owned()
+        })
+        .collect();
+
+    write!(w, "listen = ")?;
+    write_toml_strs(w, &listen)?;
+    writeln!(w)?;
+
+    write!(w, "zclient-req = ")?;
+    write_toml_strs(w, &config.zclient_req)?;
+    writeln!(w)?;
+
+    write!(w, "zclient-stream = ")?;
+    write_toml_strs(w, &config.zclient_stream)?;
+    writeln!(w)?;
+
+    let sni_backends: Vec<String> = config
+        .sni_backends
+        .iter()
+        .map(|b| {
+            let mut s = b.domain.clone();
+
+            for spec in b.zclient_req.iter() {
+                s.push_str(&format!(",req={}", spec));
+            }
+
+            for spec in b.zclient_stream.iter() {
+                s.push_str(&format!(",stream={}", spec));
+            }
+
+            s
+        })
+        .collect();
+
+    write!(w, "sni-backend = ")?;
+    write_toml_strs(w, &sni_backends)?;
+    writeln!(w)?;
+
+    let backends: Vec<String> = config
+        .backends
+        .iter()
+        .map(|b| {
+            let mut s = b.name.clone();
+
+            for spec in b.zclient_req.iter() {
+                s.push_str(&format!(",req={}", spec));
+            }
+
+            for spec in b.zclient_stream.iter() {
+                s.push_str(&format!(",stream={}", spec));
+            }
+
+            s
+        })
+        .collect();
+
+    write!(w, "backend = ")?;
+    write_toml_strs(w, &backends)?;
+    writeln!(w)?;
+
+    let routes: Vec<String> = config
+        .routes
+        .iter()
+        .map(|r| {
+            let mut s = r.backend.clone();
+
+            if let Some(host) = &r.host {
+                s.push_str(&format!(",host={}", host));
+            }
+
+            if let Some(prefix) = &r.path_prefix {
+                s.push_str(&format!(",path={}", prefix));
+            }
+
+            s
+        })
+        .collect();
+
+    write!(w, "route = ")?;
+    write_toml_strs(w, &routes)?;
+    writeln!(w)?;
+
+    let fixed_responses: Vec<String> = config
+        .fixed_responses
+        .iter()
+        .map(|r| {
+            let mut s = format!("{},code={}", r.path, r.code);
+
+            for (name, value) in r.headers.iter() {
+                s.push_str(&format!(",header={}:{}", name, value));
+            }
+
+            if let Some(path) = &r.body_file {
+                s.push_str(&format!(",file={}", path.to_string_lossy()));
+            }
+
+            s
+        })
+        .collect();
+
+    write!(w, "fixed-response = ")?;
+    write_toml_strs(w, &fixed_responses)?;
+    writeln!(w)?;
+
+    let error_pages: Vec<String> = config
+        .error_pages
+        .iter()
+        .map(|t| {
+            let mut s = format!("{},file={}", t.code, t.body_file.to_string_lossy());
+
+            if let Some(content_type) = &t.content_type {
+                s.push_str(&format!(",type={}", content_type));
+            }
+
+            s
+        })
+        .collect();
+
+    write!(w, "error-page = ")?;
+    write_toml_strs(w, &error_pages)?;
+    writeln!(w)?;
+
+    writeln!(
+        w,
+        "strict-request-target = {}",
+        config.strict_request_target
+    )?;
+    writeln!(w, "uri-max = {}", config.uri_max)?;
+
+    writeln!(
+        w,
+        "forwarded-headers = \"{}\"",
+        config.forwarded_headers.as_str()
+    )?;
+
+    let trusted_proxies: Vec<String> = config
+        .trusted_proxies
+        .iter()
+        .map(|net| net.to_string())
+        .collect();
+
+    write!(w, "trusted-proxy = ")?;
+    write_toml_strs(w, &trusted_proxies)?;
+    writeln!(w)?;
+
+    write!(w, "mirror-req = ")?;
+    write_toml_strs(w, &config.mirror_req)?;
+    writeln!(w)?;
+
+    writeln!(w, "mirror-percent = {}", config.mirror_percent)?;
+
+    if let Some(spec) = &config.announce {
+        write!(w, "announce = ")?;
+        write_toml_str(w, spec)?;
+        writeln!(w)?;
+    }
+
+    writeln!(
+        w,
+        "announce-interval = {}",
+        config.announce_interval.as_secs()
+    )?;
+
+    if let Some(spec) = &config.stats {
+        write!(w, "stats = ")?;
+        write_toml_str(w, spec)?;
+        writeln!(w)?;
+    }
+
+    writeln!(w, "stats-interval = {}", config.stats_interval.as_secs())?;
+
+    if let Some(spec) = &config.admin {
+        write!(w, "admin = ")?;
+        write_toml_str(w, spec)?;
+        writeln!(w)?;
+    }
+
+    writeln!(w, "zclient-connect = {}", config.zclient_connect)?;
+
+    write!(w, "zserver-req = ")?;
+    write_toml_strs(w, &config.zserver_req)?;
+    writeln!(w)?;
+
+    write!(w, "zserver-stream = ")?;
+    write_toml_strs(w, &config.zserver_stream)?;
+    writeln!(w)?;
+
+    writeln!(w, "zserver-connect = {}", config.zserver_connect)?;
+
+    if let Some(path) = &config.zmq_curve_cert {
+        write!(w, "zmq-curve-cert = ")?;
+        write_toml_str(w, &path.to_string_lossy())?;
+        writeln!(w)?;
+    }
+
+    if let Some(path) = &config.zmq_curve_server_cert {
+        write!(w, "zmq-curve-server-cert = ")?;
+        write_toml_str(w, &path.to_string_lossy())?;
+        writeln!(w)?;
+    }
+
+    if let Some(path) = &config.zmq_curve_allow {
+        write!(w, "zmq-curve-allow = ")?;
+        write_toml_str(w, &path.to_string_lossy())?;
+        writeln!(w)?;
+    }
+
+    writeln!(w, "ipc-file-mode = \"{:o}\"", config.ipc_file_mode)?;
+
+    write!(w, "tls-identities-dir = ")?;
+    write_toml_str(w, &config.certs_dir.to_string_lossy())?;
+    writeln!(w)?;
+
+    writeln!(
+        w,
+        "tls-ticket-key-rotation = {}",
+        config.tls_ticket_key_rotation.as_secs()
+    )?;
+    writeln!(
+        w,
+        "tls-ticket-key-overlap = {}",
+        config.tls_ticket_key_overlap.as_secs()
+    )?;
+
+    writeln!(w, "compression = {}", config.allow_compression)?;
+    writeln!(w, "download-rate = {}", config.download_rate)?;
+    writeln!(
+        w,
+        "keep-alive-session-info = {}",
+        config.keep_alive_session_info
+    )?;
+    writeln!(w, "allow-http09 = {}", config.allow_http09)?;
+
+    write!(w, "options-allow = ")?;
+    write_toml_str(w, &config.options_allow)?;
+    writeln!(w)?;
+
+    if let Some(path) = &config.options_body {
+        write!(w, "options-body = ")?;
+        write_toml_str(w, &path.to_string_lossy())?;
+        writeln!(w)?;
+    }
+
+    if let Some(path) = &config.health_live_path {
+        write!(w, "health-live-path = ")?;
+        write_toml_str(w, path)?;
+        writeln!(w)?;
+    }
+
+    if let Some(path) = &config.health_ready_path {
+        write!(w, "health-ready-path = ")?;
+        write_toml_str(w, path)?;
+        writeln!(w)?;
+    }
+
+    if let Some(path) = &config.access_log {
+        write!(w, "access-log = ")?;
+        write_toml_str(w, path)?;
+        writeln!(w)?;
+    }
+
+    writeln!(
+        w,
+        "access-log-format = \"{}\"",
+        config.access_log_format.as_str()
+    )?;
+
+    if let Some(path) = &config.file_root {
+        write!(w, "file-root = ")?;
+        write_toml_str(w, &path.to_string_lossy())?;
+        writeln!(w)?;
+    }
+
+    let deny: Vec<String> = config.deny.iter().map(|n| n.to_string()).collect();
+
+    write!(w, "deny = ")?;
+    write_toml_strs(w, &deny)?;
+    writeln!(w)?;
+
+    writeln!(w, "accept-rate = {}", config.accept_rate)?;
+    writeln!(w, "accept-rate-per-ip = {}", config.accept_rate_per_ip)?;
+    writeln!(w, "request-rate-per-ip = {}", config.request_rate_per_ip)?;
+
+    if let Some(name) = &config.request_rate_key_header {
+        write!(w, "request-rate-key-header = ")?;
+        write_toml_str(w, name)?;
+        writeln!(w)?;
+    }
+
+    writeln!(w, "accept-pause-memory = {}", config.accept_pause_memory)?;
+    writeln!(w, "reuse-port = {}", config.reuse_port)?;
+    writeln!(w, "worker-memory-budget = {}", config.worker_memory_budget)?;
+    writeln!(w, "memory-budget = {}", config.memory_budget)?;
+    writeln!(w, "accept-per-loop-max = {}", config.accept_per_loop_max)?;
+    writeln!(
+        w,
+        "poll-timeout-max = {}",
+        config.poll_timeout_max.as_millis()
+    )?;
+    writeln!(w, "reactor-budget = {}", config.reactor_budget)?;
+    writeln!(w, "resp-sender-bound = {}", config.resp_sender_bound)?;
+    writeln!(w, "handle-accept-bound = {}", config.handle_accept_bound)?;
+
+    if let Some(path) = &config.port_file {
+        write!(w, "port-file = ")?;
+        write_toml_str(w, &path.to_string_lossy())?;
+        writeln!(w)?;
+    }
+
+    if let Some(path) = &config.control {
+        write!(w, "control = ")?;
+        write_toml_str(w, &path.to_string_lossy())?;
+        writeln!(w)?;
+    }
+
+    writeln!(w)?;
+    writeln!(w, "[limits]")?;
+    writeln!(w, "headers-max = {}", connection::HEADERS_MAX)?;
+    writeln!(
+        w,
+        "keep-alive-timeout = {}",
+        server::KEEP_ALIVE_TIMEOUT_MS / 1000
+    )?;
+    writeln!(
+        w,
+        "zhttp-session-timeout = {}",
+        connection::ZHTTP_SESSION_TIMEOUT.as_secs()
+    )?;
+    writeln!(
+        w,
+        "shutdown-timeout = {}",
+        server::SHUTDOWN_TIMEOUT.as_secs()
+    )?;
+    writeln!(w, "zmq-hwm = {}", config.other_hwm())?;
+    writeln!(w, "handle-bound = {}", config.effective_handle_bound())?;
+
+    Ok(())
+}
+
+// write to a temporary file and rename it into place, so that a reader
+// never sees a partial file
+fn write_port_file(
+    path: &Path,
+    listen: &[ListenConfig],
+    addrs: &[SocketAddr],
+) -> Result<(), io::Error> {
+    let mut tmp_path = path.as_os_str().to_owned();
+    tmp_path.push(".tmp");
+
+    let mut data = Vec::new();
+    write_listen_addrs(&mut data, listen, addrs)?;
+
+    fs::write(&tmp_path, data)?;
+    fs::rename(&tmp_path, path)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StopMode {
+    // finish or cancel connections and flush pending messages
+    Graceful,
+
+    // exit without cleaning up
+    Immediate,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum SignalAction {
+    Stop(StopMode),
+    Diagnostics,
+    ToggleDebug,
+}
+
+// create a client socket manager for the given zhttp handler specs, using
+// the connect/bind settings from the config
+fn client_socket_manager(
+    zmq_context: &Arc<zmq::Context>,
+    config: &Config,
+    req_specs: &[String],
+    stream_specs: &[String],
+    any_req: bool,
+    any_stream: bool,
+    curve: Option<CurveOpts>,
+) -> Result<zhttpsocket::ClientSocketManager, String> {
+    let mut zsockman = zhttpsocket::ClientSocketManager::new(
+        Arc::clone(zmq_context),
+        &config.instance_id,
+        config.msg_retained_max().unwrap(),
+        INIT_HWM,
+        config.other_hwm(),
+        config.effective_handle_bound(),
+    )?;
+
+    if any_req {
+        let mut specs = Vec::new();
+
+        for spec in req_specs.iter() {
+            let (spec, opts) = parse_zhttp_spec_opts(spec, curve)?;
+
+            if config.zclient_connect {
+                info!("zhttp client connect {}", spec);
+            } else {
+                info!("zhttp client bind {}", spec);
+            }
+
+            specs.push(SpecInfo {
+                spec: spec.to_string(),
+                bind: !config.zclient_connect,
+                ipc_file_mode: config.ipc_file_mode,
+                opts,
+            });
+        }
+
+        if let Err(e) = zsockman.set_client_req_specs(&specs) {
+            return Err(format!("failed to set zhttp client req specs: {}", e));
+        }
+    }
+
+    if any_stream {
+        let mut out_specs = Vec::new();
+        let mut out_stream_specs = Vec::new();
+        let mut in_specs = Vec::new();
+
+        for spec in stream_specs.iter() {
+            let (spec, opts) = parse_zhttp_spec_opts(spec, curve)?;
+            let (out_spec, out_stream_spec, in_spec) = make_specs(spec, false)?;
+
+            if config.zclient_connect {
+                info!(
+                    "zhttp client connect {} {} {}",
+                    out_spec, out_stream_spec, in_spec
+                );
+            } else {
+                info!(
+                    "zhttp client bind {} {} {}",
+                    out_spec, out_stream_spec, in_spec
+                );
+            }
+
+            out_specs.push(SpecInfo {
+                spec: out_spec,
+                bind: !config.zclient_connect,
+                ipc_file_mode: config.ipc_file_mode,
+                opts,
+            });
+
+            out_stream_specs.push(SpecInfo {
+                spec: out_stream_spec,
+                bind: !config.zclient_connect,
+                ipc_file_mode: config.ipc_file_mode,
+                opts,
+            });
+
+            in_specs.push(SpecInfo {
+                spec: in_spec,
+                bind: !config.zclient_connect,
+                ipc_file_mode: config.ipc_file_mode,
+                opts,
+            });
+        }
+
+        if let Err(e) = zsockman.set_client_stream_specs(&out_specs, &out_stream_specs, &in_specs) {
+            return Err(format!("failed to set zhttp client stream specs: {}", e));
+        }
+    }
+
+    Ok(zsockman)
+}
+
+// read the curve keys of the zhttp sockets, and start a ZAP handler if the
+// clients are limited
+fn zhttp_curve(
+    zmq_context: &Arc<zmq::Context>,
+    config: &Config,
+) -> Result<(Option<CurveOpts>, Option<ZapHandler>), String> {
+    let path = match &config.zmq_curve_cert {
+        Some(path) => path,
+        None => return Ok((None, None)),
+    };
+
+    let (public_key, secret_key) = curve::read_cert(path)?;
+
+    let secret_key = match secret_key {
+        Some(key) => key,
+        None => return Err(format!("{}: certificate has no secret-key", path.display())),
+    };
+
+    match (&config.zmq_curve_server_cert, &config.zmq_curve_allow) {
+        (Some(_), Some(_)) => {
+            Err("zmq-curve-allow can't be used with zmq-curve-server-cert".into())
+        }
+        (Some(server_path), None) => {
+            let (server_key, _) = curve::read_cert(server_path)?;
+
+            info!("zhttp curve client");
+
+            Ok((
+                Some(CurveOpts::Client {
+                    public_key,
+                    secret_key,
+                    server_key,
+                }),
+                None,
+            ))
+        }
+        (None, Some(allow_path)) => {
+            let allow = curve::read_allow_list(allow_path)?;
+
+            info!("zhttp curve server, {} clients allowed", allow.len());
+
+            let zap = match ZapHandler::new(zmq_context, allow) {
+                Ok(zap) => zap,
+                Err(e) => return Err(format!("failed to start zap handler: {}", e)),
+            };
+
+            Ok((Some(CurveOpts::Server { secret_key }), Some(zap)))
+        }
+        (None, None) => {
+            info!("zhttp curve server");
+
+            Ok((Some(CurveOpts::Server { secret_key }), None))
+        }
+    }
+}
+
+fn nonzero_duration(d: Duration) -> Option<Duration> {
+    if d > Duration::ZERO {
+        Some(d)
+    } else {
+        None
+    }
+}
+
+fn options_response(config: &Config) -> Result<Option<OptionsResponse>, String> {
+    if config.options_allow.is_empty() {
+        return Ok(None);
+    }
+
+    let body = match &config.options_body {
+        Some(path) => {
+            let data = match fs::read(path) {
+                Ok(data) => data,
+                Err(e) => return Err(format!("failed to read options body {:?}: {}", path, e)),
+            };
+
+            let content_type = if path.extension() == Some("json".as_ref()) {
+                "application/json"
+            } else {
+                "text/plain"
+            };
+
+            Some((content_type.to_string(), data))
+        }
+        None => None,
+    };
+
+    Ok(Some(OptionsResponse {
+        allow: config.options_allow.clone(),
+        body,
+    }))
+}
+
+fn status_reason(code: u16) -> &'static str {
+    match code {
+        200 => "OK",
+        201 => "Created",
+        202 => "Accepted",
+        204 => "No Content",
+        301 => "Moved Permanently",
+        302 => "Found",
+        303 => "See Other",
+        304 => "Not Modified",
+        307 => "Temporary Redirect",
+        308 => "Permanent Redirect",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        403 => "Forbidden",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        410 => "Gone",
+        429 => "Too Many Requests",
+        500 => "Internal Server Error",
+        501 => "Not Implemented",
+        502 => "Bad Gateway",
+        503 => "Service Unavailable",
+        504 => "Gateway Timeout",
+        _ => "",
+    }
+}
+
+fn fixed_responses(config: &Config) -> Result<Vec<FixedResponse>, String> {
+    let mut out = Vec::new();
+
+    for r in config.fixed_responses.iter() {
+        let body = match &r.body_file {
+            Some(path) => match fs::read(path) {
+                Ok(data) => data,
+                Err(e) => {
+                    return Err(format!(
+                        "failed to read fixed response body {:?}: {}",
+                        path, e
+                    ))
+                }
+            },
+            None => Vec::new(),
+        };
+
+        out.push(FixedResponse {
+            path: r.path.clone(),
+            code: r.code,
+            reason: status_reason(r.code).to_string(),
+            headers: r
+                .headers
+                .iter()
+                .map(|(name, value)| (name.clone(), value.clone().into_bytes()))
+                .collect(),
+            body,
+        });
+    }
 
-pub enum ListenSpec {
-    Tcp {
-        addr: std::net::SocketAddr,
-        tls: bool,
-        default_cert: Option<String>,
-    },
-    Local {
-        path: PathBuf,
-        mode: Option<u32>,
-        user: Option<String>,
-        group: Option<String>,
-    },
+    Ok(out)
 }
 
-pub struct ListenConfig {
-    pub spec: ListenSpec,
-    pub stream: bool,
+fn error_pages(config: &Config) -> Result<Vec<ErrorPage>, String> {
+    // error pages are written to the connection buffers in one go
+    let size_max = cmp::min(config.buffer_size, config.body_buffer_size);
+
+    let mut out = Vec::new();
+
+    for t in config.error_pages.iter() {
+        let body = match fs::read(&t.body_file) {
+            Ok(data) => data,
+            Err(e) => {
+                return Err(format!(
+                    "failed to read error page {:?}: {}",
+                    t.body_file, e
+                ))
+            }
+        };
+
+        if body.len() > size_max {
+            return Err(format!(
+                "error page {:?} is larger than the buffer size of {} bytes",
+                t.body_file, size_max
+            ));
+        }
+
+        let content_type = match &t.content_type {
+            Some(s) => s.clone(),
+            None => match t.body_file.extension().and_then(|s| s.to_str()) {
+                Some("html") | Some("htm") => "text/html",
+                Some("json") => "application/json",
+                _ => "text/plain",
+            }
+            .to_string(),
+        };
+
+        out.push(ErrorPage {
+            code: t.code,
+            content_type,
+            body,
+        });
+    }
+
+    Ok(out)
 }
 
-pub struct Config {
-    pub instance_id: String,
-    pub workers: usize,
-    pub req_maxconn: usize,
-    pub stream_maxconn: usize,
-    pub buffer_size: usize,
-    pub body_buffer_size: usize,
-    pub messages_max: usize,
-    pub req_timeout: Duration,
-    pub stream_timeout: Duration,
-    pub listen: Vec<ListenConfig>,
-    pub zclient_req: Vec<String>,
-    pub zclient_stream: Vec<String>,
-    pub zclient_connect: bool,
-    pub zserver_req: Vec<String>,
-    pub zserver_stream: Vec<String>,
-    pub zserver_connect: bool,
-    pub ipc_file_mode: u32,
-    pub certs_dir: PathBuf,
-    pub allow_compression: bool,
-    pub deny: Vec<IpNet>,
+fn signal_action(signal: i32) -> Option<SignalAction> {
+    match signal {
+        SIGTERM => Some(SignalAction::Stop(StopMode::Graceful)),
+        SIGINT => Some(SignalAction::Stop(StopMode::Immediate)),
+        #[cfg(unix)]
+        SIGQUIT => Some(SignalAction::Diagnostics),
+        #[cfg(unix)]
+        SIGUSR2 => Some(SignalAction::ToggleDebug),
+        _ => None,
+    }
 }
 
 pub struct App {
-    _server: Option<Server>,
+    // declared first so that they are dropped first
+    _control: Option<ControlServer>,
+    _admin: Option<AdminServer>,
+    _announcer: Option<Announcer>,
+    _reporter: Option<Reporter>,
+    server: Option<Server>,
     _client: Option<Client>,
+
+    // dropped after the sockets that use it
+    _zap: Option<ZapHandler>,
 }
 
 impl App {
@@ -123,104 +1453,156 @@ impl App {
 
         let zmq_context = Arc::new(zmq::Context::new());
 
-        // set hwm to 5% of maxconn
-        let other_hwm = cmp::max((config.req_maxconn + config.stream_maxconn) / 20, 1);
+        let other_hwm = config.other_hwm();
+
+        config.validate_bounds()?;
 
-        let handle_bound = cmp::max(other_hwm / config.workers, 1);
+        let (curve, zap) = zhttp_curve(&zmq_context, config)?;
+
+        let handle_bound = config.effective_handle_bound();
 
         let maxconn = config.req_maxconn + config.stream_maxconn;
 
+        let mut upgrade = match (&config.control, config.upgrade) {
+            (Some(path), true) => match control::upgrade(path) {
+                Ok(Some(upgrade)) => {
+                    info!("upgrading from the instance at {:?}", path);
+
+                    Some(upgrade)
+                }
+                Ok(None) => {
+                    info!("no instance to upgrade from at {:?}", path);
+
+                    None
+                }
+                Err(e) => return Err(format!("failed to upgrade: {}", e)),
+            },
+            (None, true) => return Err("upgrade requires control".into()),
+            (_, false) => None,
+        };
+
+        let mut inherited = InheritedListeners::new();
+
+        if let Some(upgrade) = &mut upgrade {
+            for (addr, fd) in upgrade.take_listeners() {
+                if let Err(e) = inherited.add(&addr, fd) {
+                    return Err(format!("failed to inherit listener {}: {}", addr, e));
+                }
+            }
+        }
+
         let server = if !config.listen.is_empty() {
             let mut any_req = false;
             let mut any_stream = false;
 
             for lc in config.listen.iter() {
-                if lc.stream {
+                if !lc.stream_rules.is_empty() {
+                    any_req = true;
+                    any_stream = true;
+                } else if lc.stream {
                     any_stream = true;
                 } else {
                     any_req = true;
                 }
             }
 
-            let mut zsockman = zhttpsocket::ClientSocketManager::new(
-                Arc::clone(&zmq_context),
-                &config.instance_id,
-                (MSG_RETAINED_PER_CONNECTION_MAX * maxconn)
-                    + (MSG_RETAINED_PER_WORKER_MAX * config.workers),
-                INIT_HWM,
-                other_hwm,
-                handle_bound,
-            );
+            let zsockman = client_socket_manager(
+                &zmq_context,
+                config,
+                &config.zclient_req,
+                &config.zclient_stream,
+                any_req,
+                any_stream,
+                curve,
+            )?;
 
-            if any_req {
-                let mut specs = Vec::new();
+            let mut sni_backends = Vec::new();
 
-                for spec in config.zclient_req.iter() {
-                    if config.zclient_connect {
-                        info!("zhttp client connect {}", spec);
-                    } else {
-                        info!("zhttp client bind {}", spec);
-                    }
+            for b in config.sni_backends.iter() {
+                info!("zhttp client backend for tls server name {}", b.domain);
 
-                    specs.push(SpecInfo {
-                        spec: spec.clone(),
-                        bind: !config.zclient_connect,
-                        ipc_file_mode: config.ipc_file_mode,
-                    });
-                }
+                let zsockman = client_socket_manager(
+                    &zmq_context,
+                    config,
+                    &b.zclient_req,
+                    &b.zclient_stream,
+                    any_req,
+                    any_stream,
+                    curve,
+                )?;
 
-                if let Err(e) = zsockman.set_client_req_specs(&specs) {
-                    return Err(format!("failed to set zhttp client req specs: {}", e));
-                }
+                sni_backends.push((b.domain.clone(), zsockman));
             }
 
-            if any_stream {
-                let mut out_specs = Vec::new();
-                let mut out_stream_specs = Vec::new();
-                let mut in_specs = Vec::new();
+            let mut backends = Vec::new();
 
-                for spec in config.zclient_stream.iter() {
-                    let (out_spec, out_stream_spec, in_spec) = make_specs(spec, false)?;
+            for b in config.backends.iter() {
+                info!("zhttp client backend {}", b.name);
 
-                    if config.zclient_connect {
-                        info!(
-                            "zhttp client connect {} {} {}",
-                            out_spec, out_stream_spec, in_spec
-                        );
-                    } else {
-                        info!(
-                            "zhttp client bind {} {} {}",
-                            out_spec, out_stream_spec, in_spec
-                        );
-                    }
+                let zsockman = client_socket_manager(
+                    &zmq_context,
+                    config,
+                    &b.zclient_req,
+                    &b.zclient_stream,
+                    any_req,
+                    any_stream,
+                    curve,
+                )?;
 
-                    out_specs.push(SpecInfo {
-                        spec: out_spec,
-                        bind: !config.zclient_connect,
-                        ipc_file_mode: config.ipc_file_mode,
-                    });
+                backends.push((b.name.clone(), zsockman));
+            }
 
-                    out_stream_specs.push(SpecInfo {
-                        spec: out_stream_spec,
-                        bind: !config.zclient_connect,
-                        ipc_file_mode: config.ipc_file_mode,
-                    });
+            let mirror = if any_req && !config.mirror_req.is_empty() {
+                info!(
+                    "zhttp client mirroring {}% of requests",
+                    config.mirror_percent
+                );
 
-                    in_specs.push(SpecInfo {
-                        spec: in_spec,
-                        bind: !config.zclient_connect,
-                        ipc_file_mode: config.ipc_file_mode,
-                    });
-                }
+                let zsockman = client_socket_manager(
+                    &zmq_context,
+                    config,
+                    &config.mirror_req,
+                    &[],
+                    true,
+                    false,
+                    curve,
+                )?;
 
-                if let Err(e) =
-                    zsockman.set_client_stream_specs(&out_specs, &out_stream_specs, &in_specs)
-                {
-                    return Err(format!("failed to set zhttp client stream specs: {}", e));
+                Some((zsockman, config.mirror_percent))
+            } else {
+                None
+            };
+
+            let access_log = match &config.access_log {
+                Some(path) => {
+                    info!("access log {}", path);
+
+                    match AccessLog::new(path, config.access_log_format) {
+                        Ok(log) => Some(log),
+                        Err(e) => return Err(format!("failed to start access log: {}", e)),
+                    }
                 }
-            }
+                None => None,
+            };
 
-            Some(Server::new(
+            let file_root = match &config.file_root {
+                Some(path) => match path.canonicalize() {
+                    Ok(path) if path.is_dir() => {
+                        info!("file root {}", path.display());
+
+                        Some(path)
+                    }
+                    Ok(_) => {
+                        return Err(format!("file root {} is not a directory", path.display()))
+                    }
+                    Err(e) => {
+                        return Err(format!("failed to use file root {}: {}", path.display(), e))
+                    }
+                },
+                None => None,
+            };
+
+            let server = Server::new(
                 &config.instance_id,
                 config.workers,
                 config.req_maxconn,
@@ -228,14 +1610,81 @@ impl App {
                 config.buffer_size,
                 config.body_buffer_size,
                 config.messages_max,
+                config.message_size_max,
                 config.req_timeout,
                 config.stream_timeout,
                 &config.listen,
+                &mut inherited,
+                config.reuse_port,
                 config.certs_dir.as_path(),
+                config.tls_ticket_key_rotation,
+                config.tls_ticket_key_overlap,
                 config.allow_compression,
+                config.download_rate,
+                config.keep_alive_session_info,
+                config.allow_http09,
+                config.strict_request_target,
+                config.uri_max,
+                config.forwarded_headers,
+                config.trusted_proxies.clone(),
+                config.req_retries,
+                config.req_retry_timeout,
+                config.req_decompress_max,
+                config.handler_timeout,
+                PhaseTimeouts {
+                    header: nonzero_duration(config.header_timeout),
+                    body: nonzero_duration(config.body_timeout),
+                    idle: nonzero_duration(config.idle_timeout),
+                },
+                config.min_transfer_rate,
+                config.sse_keep_alive_interval,
+                options_response(config)?,
+                config.health_live_path.clone(),
+                config.health_ready_path.clone(),
+                fixed_responses(config)?,
+                error_pages(config)?,
+                access_log,
+                file_root,
                 zsockman,
+                sni_backends,
+                backends,
+                &config.routes,
+                mirror,
                 handle_bound,
-            )?)
+                config.resp_sender_bound,
+                config.handle_accept_bound,
+                AcceptRateLimits {
+                    global: config.accept_rate,
+                    per_ip: config.accept_rate_per_ip,
+                },
+                RequestRateLimits {
+                    per_ip: config.request_rate_per_ip,
+                    key_header: config.request_rate_key_header.clone(),
+                },
+                config.accept_pause_memory,
+                config.worker_memory_budget,
+                config.memory_budget,
+                LoopPacing {
+                    accept_per_loop_max: config.accept_per_loop_max,
+                    poll_timeout_max: nonzero_duration(config.poll_timeout_max),
+                    reactor_budget: config.reactor_budget,
+                },
+            )?;
+
+            if !inherited.is_empty() {
+                info!(
+                    "closing {} inherited listeners not in the configuration",
+                    inherited.len()
+                );
+            }
+
+            if let Some(path) = &config.port_file {
+                if let Err(e) = write_port_file(path, &config.listen, server.addrs()) {
+                    return Err(format!("failed to write port file {:?}: {}", path, e));
+                }
+            }
+
+            Some(server)
         } else {
             None
         };
@@ -244,18 +1693,20 @@ impl App {
             let mut zsockman = zhttpsocket::ServerSocketManager::new(
                 Arc::clone(&zmq_context),
                 &config.instance_id,
-                (MSG_RETAINED_PER_CONNECTION_MAX * maxconn)
-                    + (MSG_RETAINED_PER_WORKER_MAX * config.workers),
+                (client::MSG_RETAINED_PER_CONNECTION_MAX * maxconn)
+                    + (client::MSG_RETAINED_PER_WORKER_MAX * config.workers),
                 INIT_HWM,
                 other_hwm,
                 handle_bound,
                 config.stream_maxconn,
-            );
+            )?;
 
             if !config.zserver_req.is_empty() {
                 let mut specs = Vec::new();
 
                 for spec in config.zserver_req.iter() {
+                    let (spec, opts) = parse_zhttp_spec_opts(spec, curve)?;
+
                     if config.zserver_connect {
                         info!("zhttp server connect {}", spec);
                     } else {
@@ -263,9 +1714,10 @@ impl App {
                     }
 
                     specs.push(SpecInfo {
-                        spec: spec.clone(),
+                        spec: spec.to_string(),
                         bind: !config.zserver_connect,
                         ipc_file_mode: config.ipc_file_mode,
+                        opts,
                     });
                 }
 
@@ -299,6 +1751,7 @@ impl App {
                 let mut out_specs = Vec::new();
 
                 for spec in config.zserver_stream.iter() {
+                    let (spec, opts) = parse_zhttp_spec_opts(spec, curve)?;
                     let (in_spec, in_stream_spec, out_spec) = make_specs(spec, true)?;
 
                     if config.zserver_connect {
@@ -317,18 +1770,21 @@ impl App {
                         spec: in_spec,
                         bind: !config.zserver_connect,
                         ipc_file_mode: config.ipc_file_mode,
+                        opts,
                     });
 
                     in_stream_specs.push(SpecInfo {
                         spec: in_stream_spec,
                         bind: !config.zserver_connect,
                         ipc_file_mode: config.ipc_file_mode,
+                        opts,
                     });
 
                     out_specs.push(SpecInfo {
                         spec: out_spec,
                         bind: !config.zserver_connect,
                         ipc_file_mode: config.ipc_file_mode,
+                        opts,
                     });
                 }
 
@@ -344,36 +1800,264 @@ impl App {
             None
         };
 
+        let control = match &config.control {
+            Some(path) => {
+                let listeners = match &server {
+                    Some(server) => listen_addrs(&config.listen, server.addrs()),
+                    None => Vec::new(),
+                };
+
+                let listener_fds = match &server {
+                    Some(server) => server.listener_fds().to_vec(),
+                    None => Vec::new(),
+                };
+
+                let commands = ServerCommands::new(&config.instance_id, server.as_ref(), listeners);
+
+                let control = ControlServer::new(
+                    path,
+                    commands,
+                    move || listener_fds.clone(),
+                    || {
+                        // stop gracefully, the same as for an operator
+                        let _ = signal_hook::low_level::raise(SIGTERM);
+                    },
+                );
+
+                match control {
+                    Ok(control) => Some(control),
+                    Err(e) => return Err(format!("failed to start control socket: {}", e)),
+                }
+            }
+            None => None,
+        };
+
+        let announcer = match &config.announce {
+            Some(spec) => {
+                let (spec, opts) = parse_spec_opts(spec)?;
+
+                if config.zclient_connect {
+                    info!("announce connect {}", spec);
+                } else {
+                    info!("announce bind {}", spec);
+                }
+
+                let spec = SpecInfo {
+                    spec: spec.to_string(),
+                    bind: !config.zclient_connect,
+                    ipc_file_mode: config.ipc_file_mode,
+                    opts,
+                };
+
+                let worker_stats = server
+                    .as_ref()
+                    .map(|s| s.worker_stats())
+                    .unwrap_or_default();
+
+                let announcer = Announcer::new(
+                    &zmq_context,
+                    &config.instance_id,
+                    spec,
+                    config.announce_interval,
+                    move || {
+                        let mut conns = Occupancy::default();
+
+                        for stats in worker_stats.iter() {
+                            let o = stats.occupancy().connections;
+
+                            conns.used += o.used;
+                            conns.capacity += o.capacity;
+                        }
+
+                        conns
+                    },
+                );
+
+                match announcer {
+                    Ok(announcer) => Some(announcer),
+                    Err(e) => return Err(format!("failed to start announcer: {}", e)),
+                }
+            }
+            None => None,
+        };
+
+        let reporter = match &config.stats {
+            Some(spec) => {
+                let (spec, opts) = parse_spec_opts(spec)?;
+
+                if config.zclient_connect {
+                    info!("stats connect {}", spec);
+                } else {
+                    info!("stats bind {}", spec);
+                }
+
+                let spec = SpecInfo {
+                    spec: spec.to_string(),
+                    bind: !config.zclient_connect,
+                    ipc_file_mode: config.ipc_file_mode,
+                    opts,
+                };
+
+                let worker_stats = server
+                    .as_ref()
+                    .map(|s| s.worker_stats())
+                    .unwrap_or_default();
+
+                let reporter = Reporter::new(
+                    &zmq_context,
+                    &config.instance_id,
+                    spec,
+                    config.stats_interval,
+                    worker_stats,
+                );
+
+                match reporter {
+                    Ok(reporter) => Some(reporter),
+                    Err(e) => return Err(format!("failed to start reporter: {}", e)),
+                }
+            }
+            None => None,
+        };
+
+        let admin = match (&config.admin, &server) {
+            (Some(spec), Some(server)) => {
+                let (spec, opts) = parse_admin_spec_opts(spec, curve, zap.is_some())?;
+
+                info!("admin bind {}", spec);
+
+                let spec = SpecInfo {
+                    spec: spec.to_string(),
+                    bind: true,
+                    ipc_file_mode: config.ipc_file_mode,
+                    opts,
+                };
+
+                let listeners = listen_addrs(&config.listen, server.addrs());
+
+                let commands = ServerCommands::new(&config.instance_id, Some(server), listeners);
+
+                match AdminServer::new(&zmq_context, spec, commands) {
+                    Ok(admin) => Some(admin),
+                    Err(e) => return Err(format!("failed to start admin socket: {}", e)),
+                }
+            }
+            (Some(_), None) => return Err("admin requires listen".into()),
+            (None, _) => None,
+        };
+
+        // the previous instance keeps its listeners open until now, in case
+        // this one fails to start
+        if let Some(upgrade) = upgrade {
+            if let Err(e) = upgrade.finish() {
+                warn!("failed to stop the previous instance: {}", e);
+            }
+        }
+
         Ok(Self {
-            _server: server,
+            _control: control,
+            _admin: admin,
+            _announcer: announcer,
+            _reporter: reporter,
+            server,
             _client: client,
+            _zap: zap,
         })
     }
 
-    pub fn wait_for_term(&self) {
-        let mut signals = Signals::new(TERM_SIGNALS).unwrap();
+    // wait for SIGTERM (graceful stop) or SIGINT (immediate stop). SIGQUIT
+    // logs diagnostics, and SIGUSR2 switches between the log filter and
+    // debug logging for all modules, and both keep waiting. windows has
+    // only the stop signals
+    pub fn wait_for_stop(&self) -> StopMode {
+        #[cfg(unix)]
+        let mut signals = Signals::new([SIGTERM, SIGINT, SIGQUIT, SIGUSR2]).unwrap();
+
+        #[cfg(windows)]
+        let mut signals = Signals::new([SIGTERM, SIGINT]).unwrap();
+
+        // the filter to go back to when debug logging is toggled off
+        let mut saved_filter = None;
 
         let term_now = Arc::new(AtomicBool::new(false));
 
-        // ensure two term signals in a row causes the app to immediately exit
-        for signal_type in TERM_SIGNALS {
+        // ensure two stop signals in a row causes the app to immediately exit
+        for signal_type in [SIGTERM, SIGINT] {
             signal_hook::flag::register_conditional_shutdown(
-                *signal_type,
+                signal_type,
                 1, // exit code
                 Arc::clone(&term_now),
             )
             .unwrap();
 
-            signal_hook::flag::register(*signal_type, Arc::clone(&term_now)).unwrap();
+            signal_hook::flag::register(signal_type, Arc::clone(&term_now)).unwrap();
         }
 
-        // wait for termination
         for signal in &mut signals {
-            match signal {
-                signal_type if TERM_SIGNALS.contains(&signal_type) => break,
-                _ => unreachable!(),
+            match signal_action(signal) {
+                Some(SignalAction::Stop(mode)) => return mode,
+                Some(SignalAction::Diagnostics) => self.log_diagnostics(),
+                Some(SignalAction::ToggleDebug) => {
+                    let filter = match saved_filter.take() {
+                        Some(filter) => filter,
+                        None => {
+                            saved_filter = Some(logfilter::get());
+
+                            LogFilter::new(LevelFilter::Debug)
+                        }
+                    };
+
+                    info!("log filter changed to {}", filter);
+
+                    logfilter::set(filter);
+                }
+                None => unreachable!(),
             }
         }
+
+        unreachable!();
+    }
+
+    fn log_diagnostics(&self) {
+        let server = match &self.server {
+            Some(server) => server,
+            None => return,
+        };
+
+        let mut used = 0;
+        let mut capacity = 0;
+
+        for o in server.occupancy() {
+            used += o.connections.used;
+            capacity += o.connections.capacity;
+        }
+
+        info!("diagnostics: connections {}/{}", used, capacity);
+
+        let mut mirror = MirrorCounters::default();
+
+        for m in server.mirror_counters() {
+            mirror += m;
+        }
+
+        if mirror != MirrorCounters::default() {
+            info!(
+                "diagnostics: mirrored {} requests, dropped {}",
+                mirror.sent, mirror.dropped
+            );
+        }
+
+        let oversized = zhttppacket::oversized_packets();
+
+        if oversized > 0 {
+            info!(
+                "diagnostics: {} zhttp packets exceeded their buffer and were allocated",
+                oversized
+            );
+        }
+
+        for line in server.dump_diagnostics().lines() {
+            info!("diagnostics: {}", line);
+        }
     }
 
     pub fn sizes() -> Vec<(String, usize)> {
@@ -389,3 +2073,434 @@ impl App {
         out
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn spec_opts() {
+        let (spec, opts) = parse_spec_opts("ipc://client").unwrap();
+        assert_eq!(spec, "ipc://client");
+        assert_eq!(opts, SpecOpts::default());
+
+        let (spec, opts) =
+            parse_spec_opts("tcp://127.0.0.1:10000?sndhwm=10&rcvbuf=65536&linger=-1").unwrap();
+        assert_eq!(spec, "tcp://127.0.0.1:10000");
+        assert_eq!(
+            opts,
+            SpecOpts {
+                sndhwm: Some(10),
+                rcvhwm: None,
+                sndbuf: None,
+                rcvbuf: Some(65536),
+                linger: Some(-1),
+                curve: None,
+            }
+        );
+
+        assert!(parse_spec_opts("ipc://client?rcvhwm").is_err());
+        assert!(parse_spec_opts("ipc://client?rcvhwm=-1").is_err());
+        assert!(parse_spec_opts("ipc://client?sndbuf=-1").is_err());
+        assert!(parse_spec_opts("ipc://client?affinity=1").is_err());
+    }
+
+    #[test]
+    fn admin_spec_opts() {
+        let server = CurveOpts::Server {
+            secret_key: [1; 32],
+        };
+
+        let client = CurveOpts::Client {
+            public_key: [1; 32],
+            secret_key: [2; 32],
+            server_key: [3; 32],
+        };
+
+        let (spec, opts) = parse_admin_spec_opts("ipc://admin", None, false).unwrap();
+        assert_eq!(spec, "ipc://admin");
+        assert_eq!(opts.curve, None);
+
+        // anything else requires curve with an allow list
+        assert!(parse_admin_spec_opts("tcp://127.0.0.1:10000", None, false).is_err());
+        assert!(parse_admin_spec_opts("tcp://127.0.0.1:10000", Some(server), false).is_err());
+        assert!(parse_admin_spec_opts("tcp://127.0.0.1:10000", Some(client), true).is_err());
+
+        let (spec, opts) =
+            parse_admin_spec_opts("tcp://127.0.0.1:10000?linger=0", Some(server), true).unwrap();
+        assert_eq!(spec, "tcp://127.0.0.1:10000");
+        assert_eq!(opts.linger, Some(0));
+        assert_eq!(opts.curve, Some(server));
+    }
+
+    #[test]
+    fn signal_actions() {
+        assert_eq!(
+            signal_action(SIGTERM),
+            Some(SignalAction::Stop(StopMode::Graceful))
+        );
+        assert_eq!(
+            signal_action(SIGINT),
+            Some(SignalAction::Stop(StopMode::Immediate))
+        );
+
+        #[cfg(unix)]
+        {
+            assert_eq!(signal_action(SIGQUIT), Some(SignalAction::Diagnostics));
+            assert_eq!(signal_action(SIGUSR2), Some(SignalAction::ToggleDebug));
+            assert_eq!(signal_action(signal_hook::consts::SIGHUP), None);
+        }
+    }
+
+    #[test]
+    fn listen_addrs() {
+        let listen = vec![
+            ListenConfig {
+                spec: ListenSpec::Tcp {
+                    addr: "0.0.0.0:0".parse().unwrap(),
+                    tls: false,
+                    default_cert: None,
+                    no_sni: NoSniPolicy::DefaultCert,
+                    early_data: None,
+                    tls_backend: TlsBackend::OpenSsl,
+                    bind_opts: BindOpts::default(),
+                    proxy: false,
+                },
+                stream: true,
+                stream_rules: Vec::new(),
+                raw: false,
+                messages_max: Some(1000),
+                message_size_max: Some(65536),
+                frame_size_max: Some(16384),
+                tags: Vec::new(),
+                allowed_hosts: Vec::new(),
+                body_size_max: None,
+                backend: None,
+            },
+            ListenConfig {
+                spec: ListenSpec::Tcp {
+                    addr: "[::1]:0".parse().unwrap(),
+                    tls: true,
+                    default_cert: None,
+                    no_sni: NoSniPolicy::Reject,
+                    early_data: Some(Duration::from_secs(10)),
+                    tls_backend: TlsBackend::OpenSsl,
+                    bind_opts: BindOpts {
+                        device: Some("eth1".to_string()),
+                        freebind: true,
+                        transparent: false,
+                        reuse_port: false,
+                        backlog: Some(4096),
+                    },
+                    proxy: true,
+                },
+                stream: false,
+                stream_rules: Vec::new(),
+                raw: false,
+                messages_max: None,
+                message_size_max: None,
+                frame_size_max: None,
+                tags: vec![("zone".to_string(), "internal".to_string())],
+                allowed_hosts: vec!["example.com".to_string(), "*.example.com".to_string()],
+                body_size_max: Some(1048576),
+                backend: None,
+            },
+            ListenConfig {
+                spec: ListenSpec::Tcp {
+                    addr: "127.0.0.1:0".parse().unwrap(),
+                    tls: false,
+                    default_cert: None,
+                    no_sni: NoSniPolicy::DefaultCert,
+                    early_data: None,
+                    tls_backend: TlsBackend::OpenSsl,
+                    bind_opts: BindOpts::default(),
+                    proxy: false,
+                },
+                stream: true,
+                stream_rules: Vec::new(),
+                raw: true,
+                messages_max: None,
+                message_size_max: None,
+                frame_size_max: None,
+                tags: Vec::new(),
+                allowed_hosts: Vec::new(),
+                body_size_max: None,
+                backend: Some("api".to_string()),
+            },
+            ListenConfig {
+                spec: ListenSpec::Tcp {
+                    addr: "127.0.0.1:0".parse().unwrap(),
+                    tls: false,
+                    default_cert: None,
+                    no_sni: NoSniPolicy::DefaultCert,
+                    early_data: None,
+                    tls_backend: TlsBackend::OpenSsl,
+                    bind_opts: BindOpts::default(),
+                    proxy: false,
+                },
+                stream: true,
+                stream_rules: vec![
+                    StreamRule::Upgrade,
+                    StreamRule::Method("PUT".to_string()),
+                    StreamRule::PathPrefix("/events/".to_string()),
+                ],
+                raw: false,
+                messages_max: None,
+                message_size_max: None,
+                frame_size_max: None,
+                tags: Vec::new(),
+                allowed_hosts: Vec::new(),
+                body_size_max: None,
+                backend: None,
+            },
+        ];
+
+        let addrs = vec![
+            SocketAddr::Ip("0.0.0.0:41000".parse().unwrap()),
+            SocketAddr::Ip("[::1]:41001".parse().unwrap()),
+            SocketAddr::Ip("127.0.0.1:41002".parse().unwrap()),
+            SocketAddr::Ip("127.0.0.1:41003".parse().unwrap()),
+        ];
+
+        let mut out = Vec::new();
+        write_listen_addrs(&mut out, &listen, &addrs).unwrap();
+
+        assert_eq!(
+            std::str::from_utf8(&out).unwrap(),
+            concat!(
+                "0.0.0.0:41000,stream,messages-max=1000,message-size-max=65536,frame-size-max=16384\n",
+                "[::1]:41001,req,tag=zone:internal,allowed-host=example.com,allowed-host=*.example.com,body-size-max=1048576,tls,no-sni=reject,early-data=10,device=eth1,freebind,backlog=4096,proxy\n",
+                "127.0.0.1:41002,raw,backend=api\n",
+                "127.0.0.1:41003,combined,stream-if=upgrade,stream-if=method:PUT,",
+                "stream-if=path:/events/\n"
+            )
+        );
+
+        assert_eq!(
+            super::listen_addrs(&listen, &addrs),
+            vec![
+                "0.0.0.0:41000,stream,messages-max=1000,message-size-max=65536,frame-size-max=16384",
+                "[::1]:41001,req,tag=zone:internal,allowed-host=example.com,allowed-host=*.example.com,body-size-max=1048576,tls,no-sni=reject,early-data=10,device=eth1,freebind,backlog=4096,proxy",
+                "127.0.0.1:41002,raw,backend=api",
+                "127.0.0.1:41003,combined,stream-if=upgrade,stream-if=method:PUT,stream-if=path:/events/"
+            ]
+        );
+    }
+
+    #[test]
+    fn toml_str() {
+        let mut out = Vec::new();
+        write_toml_str(&mut out, "a\"b\\c\n").unwrap();
+
+        assert_eq!(std::str::from_utf8(&out).unwrap(), r#""a\"b\\c\u000A""#);
+    }
+
+    #[test]
+    fn dump_config() {
+        let mut config = Config {
+            instance_id: "condure".to_string(),
+            workers: 2,
+            req_maxconn: 100,
+            stream_maxconn: 10000,
+            buffer_size: 8192,
+            body_buffer_size: 100000,
+            messages_max: 100,
+            message_size_max: 0,
+            req_timeout: Duration::from_secs(30),
+            stream_timeout: Duration::from_secs(1800),
+            req_retries: 0,
+            req_retry_timeout: Duration::from_millis(5000),
+            req_decompress_max: 0,
+            handler_timeout: Duration::from_secs(0),
+            header_timeout: Duration::from_secs(0),
+            body_timeout: Duration::from_secs(0),
+            idle_timeout: Duration::from_secs(0),
+            min_transfer_rate: 0,
+            sse_keep_alive_interval: Duration::from_secs(0),
+            listen: vec![ListenConfig {
+                spec: ListenSpec::Local {
+                    path: PathBuf::from("/tmp/condure.sock"),
+                    mode: Some(0o660),
+                    user: None,
+                    group: None,
+                },
+                stream: true,
+                stream_rules: Vec::new(),
+                raw: false,
+                messages_max: None,
+                message_size_max: None,
+                frame_size_max: None,
+                tags: Vec::new(),
+                allowed_hosts: Vec::new(),
+                body_size_max: None,
+                backend: None,
+            }],
+            zclient_req: vec!["ipc://client".to_string()],
+            zclient_stream: vec!["ipc://client".to_string()],
+            sni_backends: vec![SniBackend {
+                domain: "*.example.com".to_string(),
+                zclient_req: vec!["ipc://example".to_string()],
+                zclient_stream: Vec::new(),
+            }],
+            backends: vec![Backend {
+                name: "api".to_string(),
+                zclient_req: vec!["ipc://api".to_string()],
+                zclient_stream: vec!["ipc://api-stream".to_string()],
+            }],
+            routes: vec![Route {
+                backend: "api".to_string(),
+                host: Some("*.example.org".to_string()),
+                path_prefix: Some("/api/".to_string()),
+            }],
+            mirror_req: Vec::new(),
+            mirror_percent: 100,
+            announce: None,
+            announce_interval: Duration::from_secs(10),
+            stats: None,
+            stats_interval: Duration::from_secs(10),
+            admin: None,
+            zclient_connect: false,
+            zserver_req: Vec::new(),
+            zserver_stream: Vec::new(),
+            zserver_connect: false,
+            zmq_curve_cert: None,
+            zmq_curve_server_cert: None,
+            zmq_curve_allow: None,
+            ipc_file_mode: 0,
+            certs_dir: PathBuf::from("."),
+            tls_ticket_key_rotation: Duration::from_secs(3600),
+            tls_ticket_key_overlap: Duration::from_secs(7200),
+            allow_compression: false,
+            forwarded_headers: ForwardedHeaders::Both,
+            trusted_proxies: vec!["192.0.2.0/24".parse().unwrap()],
+            download_rate: 0,
+            keep_alive_session_info: true,
+            allow_http09: false,
+            strict_request_target: true,
+            uri_max: 8192,
+            options_allow: "GET, POST".to_string(),
+            options_body: None,
+            health_live_path: None,
+            health_ready_path: None,
+            fixed_responses: vec![FixedRoute {
+                path: "/robots.txt".to_string(),
+                code: 200,
+                headers: vec![("Content-Type".to_string(), "text/plain".to_string())],
+                body_file: Some(PathBuf::from("robots.txt")),
+            }],
+            error_pages: vec![ErrorTemplate {
+                code: 503,
+                content_type: Some("text/html; charset=utf-8".to_string()),
+                body_file: PathBuf::from("503.html"),
+            }],
+            access_log: None,
+            access_log_format: AccessLogFormat::Combined,
+            file_root: None,
+            deny: vec!["10.0.0.0/8".parse().unwrap()],
+            accept_rate: 0,
+            accept_rate_per_ip: 0,
+            request_rate_per_ip: 0,
+            request_rate_key_header: None,
+            accept_pause_memory: 0,
+            reuse_port: false,
+            worker_memory_budget: 0,
+            memory_budget: 0,
+            accept_per_loop_max: 100,
+            poll_timeout_max: Duration::from_millis(0),
+            reactor_budget: 100,
+            zmq_hwm: 0,
+            handle_bound: 0,
+            resp_sender_bound: 1,
+            handle_accept_bound: 100,
+            port_file: None,
+            control: None,
+            upgrade: false,
+        };
+
+        let mut out = Vec::new();
+        write_config(&mut out, &config).unwrap();
+
+        let out = std::str::from_utf8(&out).unwrap();
+
+        assert!(out.starts_with("id = \"condure\"\nworkers = 2\n"));
+        assert!(out.contains("\nlisten = [\"/tmp/condure.sock,stream,local,mode=660\"]\n"));
+        assert!(out.contains("\nsni-backend = [\"*.example.com,req=ipc://example\"]\n"));
+        assert!(out.contains(
+            "\nbackend = [\"api,req=ipc://api,stream=ipc://api-stream\"]\n\
+             route = [\"api,host=*.example.org,path=/api/\"]\n\
+             fixed-response = [\"/robots.txt,code=200,header=Content-Type:text/plain,file=robots.txt\"]\n\
+             error-page = [\"503,file=503.html,type=text/html; charset=utf-8\"]\n\
+             strict-request-target = true\n\
+             uri-max = 8192\n\
+             forwarded-headers = \"both\"\n\
+             trusted-proxy = [\"192.0.2.0/24\"]\n"
+        ));
+        assert!(out.contains("\nmirror-req = []\nmirror-percent = 100\n"));
+        assert!(
+            out.contains("\nmirror-percent = 100\nannounce-interval = 10\nstats-interval = 10\n")
+        );
+        assert!(out.contains(
+            "\ndownload-rate = 0\nkeep-alive-session-info = true\nallow-http09 = false\n"
+        ));
+        assert!(out.contains("\nmessages-max = 100\nmessage-size-max = 0\n"));
+        assert!(out.contains(
+            "\nreq-retries = 0\nreq-retry-timeout = 5000\nreq-decompress-max = 0\nhandler-timeout = 0\n"
+        ));
+        assert!(out.contains(
+            "\nhandler-timeout = 0\nheader-timeout = 0\nbody-timeout = 0\nidle-timeout = 0\n"
+        ));
+        assert!(out
+            .contains("\nidle-timeout = 0\nmin-transfer-rate = 0\nsse-keep-alive-interval = 0\n"));
+        assert!(out.contains("\ncompression = false\ndownload-rate = 0\n"));
+        assert!(out.contains(
+            "\ntls-ticket-key-rotation = 3600\ntls-ticket-key-overlap = 7200\ncompression"
+        ));
+        assert!(out.contains("\nallow-http09 = false\noptions-allow = \"GET, POST\"\naccess-log-format = \"combined\"\ndeny"));
+        assert!(!out.contains("options-body"));
+        assert!(out.contains("\naccept-rate-per-ip = 0\nrequest-rate-per-ip = 0\n"));
+        assert!(!out.contains("request-rate-key-header"));
+        assert!(out.contains("\nzserver-req = []\n"));
+        assert!(!out.contains("zmq-curve"));
+        assert!(out.contains("\ndeny = [\"10.0.0.0/8\"]\n"));
+        assert!(!out.contains("port-file"));
+        assert!(out.contains("\n[limits]\nheaders-max = 64\n"));
+        assert!(out.contains("\nworker-memory-budget = 0\nmemory-budget = 0\naccept-per-loop-max = 100\npoll-timeout-max = 0\nreactor-budget = 100\nresp-sender-bound = 1\nhandle-accept-bound = 100\n"));
+        assert!(out.contains("\nzmq-hwm = 505\nhandle-bound = 252\n"));
+
+        assert!(config.validate_bounds().is_ok());
+
+        config.handle_bound = 505;
+        assert_eq!(config.effective_handle_bound(), 505);
+        assert!(config.validate_bounds().is_ok());
+
+        config.handle_bound = 506;
+        assert!(config.validate_bounds().is_err());
+
+        config.handle_bound = 0;
+        config.resp_sender_bound = 0;
+        assert!(config.validate_bounds().is_err());
+
+        config.resp_sender_bound = server::RESP_SENDER_BOUND_MAX;
+        assert!(config.validate_bounds().is_ok());
+
+        config.handle_accept_bound = 0;
+        assert!(config.validate_bounds().is_err());
+
+        config.handle_accept_bound = 100;
+
+        // a larger hwm allows a larger handle bound
+        config.zmq_hwm = 1000;
+        config.handle_bound = 1000;
+        assert_eq!(config.other_hwm(), 1000);
+        assert!(config.validate_bounds().is_ok());
+
+        config.zmq_hwm = i32::MAX as usize + 1;
+        assert!(config.validate_bounds().is_err());
+
+        config.zmq_hwm = 0;
+        config.handle_bound = 0;
+
+        config.stream_maxconn = MSG_RETAINED_MAX;
+        assert!(config.validate_bounds().is_err());
+    }
+}
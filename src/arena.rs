@@ -64,13 +64,22 @@ impl<T> Memory<T> {
         }
     }
 
-    #[cfg(test)]
     pub fn len(&self) -> usize {
         let entries = self.entries.borrow();
 
         entries.len()
     }
 
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn capacity(&self) -> usize {
+        let entries = self.entries.borrow();
+
+        entries.capacity()
+    }
+
     fn insert(&self, e: T) -> Result<usize, ()> {
         let mut entries = self.entries.borrow_mut();
 
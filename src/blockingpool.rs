@@ -0,0 +1,445 @@
+/*
+ * Copyright (C) 2024 Fanout, Inc.
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+// a pool of threads for work that can block, such as name lookups and
+// file access, so that it doesn't run on a reactor thread. each job has a
+// registration that becomes readable once its result is available
+
+use crate::event;
+use crate::list;
+use crate::spawn_thread;
+use log::warn;
+use mio::Interest;
+use slab::Slab;
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+
+pub const REGISTRATIONS_PER_JOB: usize = 1;
+
+// performs tasks on the worker threads
+pub trait Runner<T, R>: Send + Sync + 'static {
+    fn run(&self, task: T) -> R;
+}
+
+impl<T, R, F> Runner<T, R> for F
+where
+    F: Fn(T) -> R + Send + Sync + 'static,
+{
+    fn run(&self, task: T) -> R {
+        self(task)
+    }
+}
+
+struct JobItem<T, R> {
+    task: Option<T>,
+    result: Option<R>,
+    set_readiness: event::SetReadiness,
+    invalidated: Option<Arc<AtomicBool>>,
+}
+
+struct JobsInner<T, R> {
+    stop: bool,
+    nodes: Slab<list::Node<JobItem<T, R>>>,
+    next: list::List,
+    registrations: VecDeque<(event::Registration, event::SetReadiness)>,
+    invalidated_count: u32,
+}
+
+struct Jobs<T, R> {
+    inner: Arc<(Mutex<JobsInner<T, R>>, Condvar)>,
+}
+
+impl<T, R> Clone for Jobs<T, R> {
+    fn clone(&self) -> Self {
+        Self {
+            inner: Arc::clone(&self.inner),
+        }
+    }
+}
+
+impl<T, R> Jobs<T, R> {
+    fn new(jobs_max: usize) -> Self {
+        let mut registrations = VecDeque::with_capacity(jobs_max);
+
+        for _ in 0..registrations.capacity() {
+            registrations.push_back(event::Registration::new());
+        }
+
+        let inner = JobsInner {
+            stop: false,
+            nodes: Slab::with_capacity(jobs_max),
+            next: list::List::default(),
+            registrations,
+            invalidated_count: 0,
+        };
+
+        Self {
+            inner: Arc::new((Mutex::new(inner), Condvar::new())),
+        }
+    }
+
+    fn set_stop_flag(&self) {
+        let (lock, cvar) = &*self.inner;
+
+        let mut jobs = lock.lock().unwrap();
+        jobs.stop = true;
+
+        cvar.notify_all();
+    }
+
+    // queue a task for the workers, or if a result is given, complete the
+    // job immediately with it
+    fn add(&self, task: Result<T, R>) -> Result<(usize, event::Registration), ()> {
+        let (lock, cvar) = &*self.inner;
+
+        let jobs = &mut *lock.lock().unwrap();
+
+        if jobs.nodes.len() == jobs.nodes.capacity() {
+            return Err(());
+        }
+
+        let (reg, sr) = jobs.registrations.pop_back().unwrap();
+
+        let nkey = match task {
+            Ok(task) => {
+                let nkey = jobs.nodes.insert(list::Node::new(JobItem {
+                    task: Some(task),
+                    result: None,
+                    set_readiness: sr,
+                    invalidated: None,
+                }));
+
+                jobs.next.push_back(&mut jobs.nodes, nkey);
+
+                cvar.notify_one();
+
+                nkey
+            }
+            Err(result) => {
+                sr.set_readiness(Interest::READABLE).unwrap();
+
+                jobs.nodes.insert(list::Node::new(JobItem {
+                    task: None,
+                    result: Some(result),
+                    set_readiness: sr,
+                    invalidated: None,
+                }))
+            }
+        };
+
+        Ok((nkey, reg))
+    }
+
+    // block until a job is available, or stopped
+    fn get_next(&self, invalidated: &Arc<AtomicBool>) -> Option<(usize, T)> {
+        let (lock, cvar) = &*self.inner;
+
+        let mut jobs_guard = lock.lock().unwrap();
+
+        loop {
+            let jobs = &mut *jobs_guard;
+
+            if jobs.stop {
+                return None;
+            }
+
+            if let Some(nkey) = jobs.next.pop_front(&mut jobs.nodes) {
+                let ji = &mut jobs.nodes[nkey].value;
+
+                invalidated.store(false, Ordering::Relaxed);
+                ji.invalidated = Some(invalidated.clone());
+
+                return Some((nkey, ji.task.take().unwrap()));
+            }
+
+            jobs_guard = cvar.wait(jobs_guard).unwrap();
+        }
+    }
+
+    fn set_result(&self, item_key: usize, result: R, invalidated: &AtomicBool) {
+        let mut jobs = self.inner.0.lock().unwrap();
+
+        if !invalidated.load(Ordering::Relaxed) {
+            let ji = &mut jobs.nodes[item_key].value;
+
+            ji.result = Some(result);
+            ji.invalidated = None;
+            ji.set_readiness.set_readiness(Interest::READABLE).unwrap();
+        } else {
+            jobs.invalidated_count += 1;
+        }
+    }
+
+    fn take_result(&self, item_key: usize) -> Option<R> {
+        let jobs = &mut *self.inner.0.lock().unwrap();
+
+        jobs.nodes[item_key].value.result.take()
+    }
+
+    fn remove(&self, item_key: usize, registration: event::Registration) {
+        let jobs = &mut *self.inner.0.lock().unwrap();
+
+        // remove from next list if present
+        jobs.next.remove(&mut jobs.nodes, item_key);
+
+        let ji = jobs.nodes.remove(item_key).value;
+
+        if let Some(invalidated) = &ji.invalidated {
+            invalidated.store(true, Ordering::Relaxed);
+        }
+
+        jobs.registrations
+            .push_back((registration, ji.set_readiness));
+    }
+
+    #[cfg(test)]
+    fn invalidated_count(&self) -> u32 {
+        let jobs = &*self.inner.0.lock().unwrap();
+
+        jobs.invalidated_count
+    }
+}
+
+pub struct BlockingPool<T, R> {
+    workers: Vec<thread::JoinHandle<()>>,
+    jobs: Jobs<T, R>,
+}
+
+impl<T, R> BlockingPool<T, R>
+where
+    T: Send + 'static,
+    R: Send + 'static,
+{
+    // name is used for the threads
+    pub fn new<U>(
+        name: &str,
+        num_threads: usize,
+        jobs_max: usize,
+        runner: U,
+    ) -> Result<Self, String>
+    where
+        U: Runner<T, R>,
+    {
+        let runner = Arc::new(runner);
+
+        let mut workers = Vec::with_capacity(num_threads);
+        let jobs = Jobs::new(jobs_max);
+
+        for _ in 0..workers.capacity() {
+            let jobs = jobs.clone();
+            let runner = Arc::clone(&runner);
+
+            let ret = spawn_thread(name.to_string(), move || {
+                let invalidated = Arc::new(AtomicBool::new(false));
+
+                loop {
+                    assert_eq!(Arc::strong_count(&invalidated), 1);
+
+                    let (item_key, task) = match jobs.get_next(&invalidated) {
+                        Some(ret) => ret,
+                        None => break,
+                    };
+
+                    let ret = runner.run(task);
+
+                    jobs.set_result(item_key, ret, &invalidated);
+                }
+            });
+
+            match ret {
+                Ok(thread) => workers.push(thread),
+                Err(e) if workers.is_empty() => return Err(e),
+                Err(e) => {
+                    // jobs only need one thread to make progress
+                    warn!(
+                        "{}. continuing with {} of {} {} threads",
+                        e,
+                        workers.len(),
+                        num_threads,
+                        name
+                    );
+                    break;
+                }
+            }
+        }
+
+        Ok(Self { workers, jobs })
+    }
+
+    #[allow(clippy::result_unit_err)]
+    pub fn run(&self, task: T) -> Result<Job<T, R>, ()> {
+        self.add(Ok(task))
+    }
+
+    // return a job that is already complete, without running anything.
+    // it counts against the max the same as other jobs
+    #[allow(clippy::result_unit_err)]
+    pub fn complete(&self, result: R) -> Result<Job<T, R>, ()> {
+        self.add(Err(result))
+    }
+
+    fn add(&self, task: Result<T, R>) -> Result<Job<T, R>, ()> {
+        let (item_key, reg) = self.jobs.add(task)?;
+
+        Ok(Job {
+            jobs: self.jobs.clone(),
+            item_key,
+            registration: Some(reg),
+        })
+    }
+}
+
+impl<T, R> BlockingPool<T, R> {
+    pub fn stop(&mut self) {
+        self.jobs.set_stop_flag();
+
+        for worker in self.workers.drain(..) {
+            worker.join().unwrap();
+        }
+    }
+
+    #[cfg(test)]
+    pub fn invalidated_count(&self) -> u32 {
+        self.jobs.invalidated_count()
+    }
+}
+
+impl<T, R> Drop for BlockingPool<T, R> {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+pub struct Job<T, R> {
+    jobs: Jobs<T, R>,
+    item_key: usize,
+    registration: Option<event::Registration>,
+}
+
+impl<T, R> Job<T, R> {
+    pub fn get_read_registration(&self) -> &event::Registration {
+        self.registration.as_ref().unwrap()
+    }
+
+    pub fn process(&self) -> Option<R> {
+        self.jobs.take_result(self.item_key)
+    }
+}
+
+impl<T, R> Drop for Job<T, R> {
+    fn drop(&mut self) {
+        let reg = self.registration.take().unwrap();
+
+        self.jobs.remove(self.item_key, reg);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn wait<T, R>(job: &Job<T, R>) -> R {
+        let mut poller = event::Poller::new(1).unwrap();
+
+        poller
+            .register_custom(
+                job.get_read_registration(),
+                mio::Token(1),
+                Interest::READABLE,
+            )
+            .unwrap();
+
+        let result = loop {
+            if let Some(result) = job.process() {
+                break result;
+            }
+
+            poller.poll(None).unwrap();
+
+            for _ in poller.iter_events() {}
+        };
+
+        poller
+            .deregister_custom(job.get_read_registration())
+            .unwrap();
+
+        result
+    }
+
+    #[test]
+    fn run() {
+        let pool = BlockingPool::new("test", 1, 2, |x: u32| x * 2).unwrap();
+
+        let job = pool.run(2).unwrap();
+        let completed = pool.complete(5).unwrap();
+
+        // jobs_max is 2, so this should error
+        assert!(pool.run(3).is_err());
+
+        assert_eq!(wait(&job), 4);
+        assert_eq!(wait(&completed), 5);
+
+        // the slots are reused once the jobs are dropped
+        drop(job);
+        drop(completed);
+
+        let job = pool.run(3).unwrap();
+        assert_eq!(wait(&job), 6);
+    }
+
+    #[test]
+    fn invalidate_job() {
+        let cond = Arc::new((Mutex::new(false), Condvar::new()));
+
+        let runner = {
+            let cond = cond.clone();
+
+            move |_: ()| {
+                let (lock, cvar) = &*cond;
+
+                let guard = lock.lock().unwrap();
+
+                // let main thread know we've started
+                cvar.notify_one();
+
+                // wait for job to be removed
+                let _guard = cvar.wait(guard).unwrap();
+            }
+        };
+
+        let (lock, cvar) = &*cond;
+        let guard = lock.lock().unwrap();
+
+        let mut pool = BlockingPool::new("test", 1, 1, runner).unwrap();
+
+        let job = pool.run(()).unwrap();
+
+        // wait for the runner to start
+        let guard = cvar.wait(guard).unwrap();
+
+        drop(job);
+
+        // let worker know the job has been removed
+        cvar.notify_one();
+        drop(guard);
+
+        pool.stop();
+
+        assert_eq!(pool.invalidated_count(), 1);
+    }
+}
@@ -396,6 +396,10 @@ impl Buffer {
         }
     }
 
+    pub fn capacity(&self) -> usize {
+        self.buf.len()
+    }
+
     pub fn clear(&mut self) {
         self.start = 0;
         self.end = 0;
@@ -430,6 +434,20 @@ impl Buffer {
 
         self.end += amount;
     }
+
+    // extract inner buffer, discarding any unread data, and replace it with
+    // an empty buffer. afterwards, the buffer will have a capacity of zero
+    // until set_inner is called
+    pub fn take_inner(&mut self) -> Vec<u8> {
+        self.clear();
+
+        mem::take(&mut self.buf)
+    }
+
+    pub fn set_inner(&mut self, buf: Vec<u8>) {
+        self.buf = buf;
+        self.clear();
+    }
 }
 
 #[cfg(test)]
@@ -469,6 +487,10 @@ impl Write for Buffer {
     }
 }
 
+// scratch space shared by the ring buffers of a worker, used to speed up
+// aligning wrapped buffers. ring buffers may be larger than it, and may be
+// aligned while it is in use elsewhere, in which case they are aligned in
+// place without it
 pub struct TmpBuffer(RefCell<Vec<u8>>);
 
 #[allow(clippy::len_without_is_empty)]
@@ -482,6 +504,52 @@ impl TmpBuffer {
     }
 }
 
+// worker-local pool of equally sized buffers, so that connections only
+// need to hold buffers while they are in use. up to max unused buffers are
+// kept for reuse, and any beyond that are freed
+pub struct BufferPool {
+    bufs: RefCell<Vec<Vec<u8>>>,
+    size: usize,
+    max: usize,
+}
+
+#[allow(clippy::len_without_is_empty)]
+impl BufferPool {
+    pub fn new(size: usize, max: usize) -> Self {
+        Self {
+            bufs: RefCell::new(Vec::new()),
+            size,
+            max,
+        }
+    }
+
+    pub fn buffer_size(&self) -> usize {
+        self.size
+    }
+
+    // number of unused buffers being kept
+    pub fn len(&self) -> usize {
+        self.bufs.borrow().len()
+    }
+
+    // returns an unused buffer, or allocates a new one
+    pub fn take(&self) -> Vec<u8> {
+        match self.bufs.borrow_mut().pop() {
+            Some(buf) => buf,
+            None => vec![0; self.size],
+        }
+    }
+
+    // buffers of the wrong size are freed rather than kept
+    pub fn give(&self, buf: Vec<u8>) {
+        let bufs = &mut *self.bufs.borrow_mut();
+
+        if buf.len() == self.size && bufs.len() < self.max {
+            bufs.push(buf);
+        }
+    }
+}
+
 // holds a Vec<u8> but only exposes the portion of it considered to be
 // readable ("filled"). any remaining bytes may be zeroed or uninitialized
 // and are not considered to be readable
@@ -606,7 +674,9 @@ impl<T: AsRef<[u8]> + AsMut<[u8]>> BaseRingBuffer<T> {
         }
 
         let buf = self.buf.as_mut();
-        let size = self.end - self.start;
+        let buf_len = buf.len();
+        let (start, end) = (self.start, self.end);
+        let size = end - start;
 
         if self.end <= buf.len() {
             // if the buffer hasn't wrapped, simply copy down
@@ -621,7 +691,13 @@ impl<T: AsRef<[u8]> + AsMut<[u8]>> BaseRingBuffer<T> {
 
             buf.copy_within(..left_size, right_size);
             buf.copy_within(self.start..(self.start + right_size), 0);
-        } else {
+        } else if let Some(mut tmp) = self
+            .tmp
+            .0
+            .try_borrow_mut()
+            .ok()
+            .filter(|tmp| cmp::min(end - buf_len, buf_len - start) <= tmp.len())
+        {
             // if the buffer has wrapped and the wrapped part can't be copied
             //   without overlapping, then use a temporary buffer to
             //   facilitate. smaller part is copied to the temp buffer, then
@@ -650,11 +726,14 @@ impl<T: AsRef<[u8]> + AsMut<[u8]>> BaseRingBuffer<T> {
                 hdest = lsize;
             }
 
-            let mut tmp = self.tmp.0.borrow_mut();
-
             tmp[..lsize].copy_from_slice(&buf[lsrc..(lsrc + lsize)]);
             buf.copy_within(hsrc..(hsrc + hsize), hdest);
             buf[ldest..(ldest + lsize)].copy_from_slice(&tmp[..lsize]);
+        } else {
+            // if the temporary buffer is too small or in use, rotate in
+            //   place. this moves every byte of the buffer, readable or not,
+            //   but doesn't need any extra space
+            buf.rotate_left(self.start);
         }
 
         self.start = 0;
@@ -783,8 +862,6 @@ impl<T: AsRef<[u8]> + AsMut<[u8]>> RefRead for BaseRingBuffer<T> {
 
 impl BaseRingBuffer<Vec<u8>> {
     pub fn new(size: usize, tmp: &Rc<TmpBuffer>) -> Self {
-        assert!(size <= tmp.len());
-
         let buf = vec![0; size];
 
         BaseRingBuffer {
@@ -811,14 +888,11 @@ impl BaseRingBuffer<Vec<u8>> {
     }
 
     // replace the inner buffer. this should be cheap if the original inner
-    // buffer is empty, which is the case if take_inner was called earlier.
-    // panics if the new buffer is larger than the tmp buffer
+    // buffer is empty, which is the case if take_inner was called earlier
     pub fn set_inner(&mut self, buf: FilledBuf) {
         let filled = buf.filled_len();
         let data = buf.into_inner();
 
-        assert!(data.len() <= self.tmp.len());
-
         self.buf = data;
         self.start = 0;
         self.end = filled;
@@ -833,8 +907,6 @@ impl BaseRingBuffer<Vec<u8>> {
 
 impl<'a> BaseRingBuffer<&'a mut [u8]> {
     pub fn new(buf: &'a mut [u8], tmp: &Rc<TmpBuffer>) -> Self {
-        assert!(buf.len() <= tmp.len());
-
         BaseRingBuffer {
             buf,
             start: 0,
@@ -1089,6 +1161,88 @@ mod tests {
         assert_eq!(r.read_buf().len(), 6);
     }
 
+    #[test]
+    fn test_ringbuffer_align_shared_tmp() {
+        let tmp = Rc::new(TmpBuffer::new(4));
+
+        // several buffers larger than the tmp buffer, all wrapped
+        let mut rs: Vec<RingBuffer> = (0..3).map(|_| RingBuffer::new(16, &tmp)).collect();
+
+        for r in rs.iter_mut() {
+            r.write(b"0123456789abcdef").unwrap();
+            r.read_commit(6);
+            r.write(b"ghijk").unwrap();
+            assert!(!r.is_readable_contiguous());
+        }
+
+        // wrapped part fits in the tmp buffer, but the tmp buffer is in use
+        {
+            let _in_use = tmp.0.borrow_mut();
+
+            assert_eq!(rs[0].align(), 15);
+            assert_eq!(rs[0].read_buf(), b"6789abcdefghijk");
+        }
+
+        // tmp buffer is free again
+        assert_eq!(rs[1].align(), 15);
+        assert_eq!(rs[1].read_buf(), b"6789abcdefghijk");
+
+        // neither part fits in the tmp buffer
+        let r = &mut rs[2];
+        r.read_commit(3);
+        r.write(b"lmn").unwrap();
+        assert_eq!(r.align(), 15);
+        assert_eq!(r.read_buf(), b"9abcdefghijklmn");
+
+        // large buffers can be swapped in
+        let mut r = RingBuffer::new(4, &tmp);
+        r.set_inner(FilledBuf::new(vec![b'x'; 64], 64));
+        assert_eq!(r.read_avail(), 64);
+    }
+
+    #[test]
+    fn test_buffer_pool() {
+        let pool = BufferPool::new(8, 2);
+        assert_eq!(pool.buffer_size(), 8);
+        assert_eq!(pool.len(), 0);
+
+        let mut a = pool.take();
+        assert_eq!(a.len(), 8);
+        a[0] = b'a';
+        let b = pool.take();
+        let c = pool.take();
+        assert_eq!(pool.len(), 0);
+
+        pool.give(a);
+        pool.give(b);
+        assert_eq!(pool.len(), 2);
+
+        // full
+        pool.give(c);
+        assert_eq!(pool.len(), 2);
+
+        // wrong size
+        pool.take();
+        pool.give(vec![0; 4]);
+        assert_eq!(pool.len(), 1);
+
+        // reused
+        let a = pool.take();
+        assert_eq!(a[0], b'a');
+        assert_eq!(pool.len(), 0);
+
+        let mut buf = Buffer::new(8);
+        buf.write_all(b"hello").unwrap();
+        let inner = buf.take_inner();
+        assert_eq!(inner.len(), 8);
+        assert_eq!(buf.read_avail(), 0);
+        assert_eq!(buf.write_avail(), 0);
+
+        buf.set_inner(inner);
+        assert_eq!(buf.read_avail(), 0);
+        assert_eq!(buf.write_avail(), 8);
+    }
+
     #[test]
     fn test_slice_ringbuffer() {
         let mut buf = [0; 8];
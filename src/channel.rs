@@ -345,6 +345,19 @@ impl<T> LocalSender<T> {
         }
     }
 
+    // number of items in the queue
+    pub fn len(&self) -> usize {
+        self.channel.queue.borrow().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.channel.queue.borrow().capacity()
+    }
+
     pub fn cancel(&self) {
         // if we were notified but never acted on it, notify the next waiting sender, if any
         if self.channel.sender_is_notified(self.key) {
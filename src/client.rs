@@ -30,16 +30,17 @@ use crate::future::{
 };
 use crate::list;
 use crate::pin;
+use crate::platform;
 use crate::reactor::Reactor;
 use crate::resolver::Resolver;
+use crate::spawn_thread;
 use crate::tnetstring;
 use crate::zhttppacket;
 use crate::zhttpsocket::{self, SessionKey, FROM_MAX, REQ_ID_MAX};
-use crate::zmq::{MultipartHeader, SpecInfo};
+use crate::zmq::{MultipartHeader, SpecInfo, SpecOpts};
 use arrayvec::ArrayVec;
 use ipnet::IpNet;
 use log::{debug, error, info, warn};
-use mio::unix::SourceFd;
 use slab::Slab;
 use std::cell::Cell;
 use std::cell::RefCell;
@@ -137,6 +138,7 @@ impl<'a> BatchGroup<'a, '_> {
 struct Batch {
     nodes: Slab<list::Node<usize>>,
     addrs: Vec<(ArrayVec<u8, FROM_MAX>, list::List)>,
+    addr_indexes: HashMap<ArrayVec<u8, FROM_MAX>, usize>,
     addr_index: usize,
     group_ids: arena::ReusableVec,
     last_group_ckeys: Vec<usize>,
@@ -147,6 +149,7 @@ impl Batch {
         Self {
             nodes: Slab::with_capacity(capacity),
             addrs: Vec::with_capacity(capacity),
+            addr_indexes: HashMap::with_capacity(capacity),
             addr_index: 0,
             group_ids: arena::ReusableVec::new::<zhttppacket::Id>(capacity),
             last_group_ckeys: Vec::with_capacity(capacity),
@@ -167,23 +170,22 @@ impl Batch {
 
     fn clear(&mut self) {
         self.addrs.clear();
+        self.addr_indexes.clear();
         self.nodes.clear();
         self.addr_index = 0;
     }
 
     fn add(&mut self, to_addr: &[u8], ckey: usize) -> Result<BatchKey, ()> {
-        let mut pos = self.addrs.len();
-
-        for (i, a) in self.addrs.iter().enumerate() {
-            if a.0.as_ref() == to_addr {
-                pos = i;
-            }
-        }
+        let pos = match self.addr_indexes.get(to_addr) {
+            Some(pos) => *pos,
+            None => self.addrs.len(),
+        };
 
         if pos == self.addrs.len() {
             // connection limits to_addr to FROM_MAX so this is guaranteed to succeed
             let a = ArrayVec::try_from(to_addr).unwrap();
 
+            self.addr_indexes.insert(a.clone(), pos);
             self.addrs.push((a, list::List::default()));
         }
 
@@ -554,15 +556,22 @@ impl Connections {
                 multi: true,
                 ptype: match btype {
                     BatchType::KeepAlive => zhttppacket::RequestPacket::KeepAlive,
-                    BatchType::Cancel => zhttppacket::RequestPacket::Cancel,
+                    BatchType::Cancel => {
+                        zhttppacket::RequestPacket::Cancel(zhttppacket::CancelData {
+                            condition: "",
+                        })
+                    }
                 },
                 ptype_str: "",
+                counters: None,
+                sessions: &[],
             };
 
             let mut data = [0; BULK_PACKET_SIZE_MAX];
 
-            let size = match zreq.serialize(&mut data) {
-                Ok(size) => size,
+            let data = match zhttppacket::serialize_or_alloc(&mut data, |dest| zreq.serialize(dest))
+            {
+                Ok(data) => data,
                 Err(e) => {
                     error!(
                         "failed to serialize keep-alive packet with {} ids: {}",
@@ -573,8 +582,6 @@ impl Connections {
                 }
             };
 
-            let data = &data[..size];
-
             let addr = group.addr();
 
             let msg = {
@@ -583,7 +590,7 @@ impl Connections {
                 v[..addr.len()].copy_from_slice(addr);
                 v[addr.len()] = b' ';
                 let pos = addr.len() + 1;
-                v[pos..(pos + data.len())].copy_from_slice(data);
+                v[pos..(pos + data.len())].copy_from_slice(&data);
 
                 // this takes over the vec's memory without copying
                 zmq::Message::from(v)
@@ -650,7 +657,7 @@ impl Worker {
         pool: &Arc<ConnectionPool>,
         zsockman: &Arc<zhttpsocket::ServerSocketManager>,
         handle_bound: usize,
-    ) -> Self {
+    ) -> Result<Self, String> {
         debug!("client worker {}: starting", id);
 
         let (stop, r_stop) = channel::channel(1);
@@ -662,62 +669,59 @@ impl Worker {
         let pool = Arc::clone(pool);
         let zsockman = Arc::clone(zsockman);
 
-        let thread = thread::Builder::new()
-            .name(format!("client-worker-{}", id))
-            .spawn(move || {
-                let maxconn = req_maxconn + stream_maxconn;
+        let thread = spawn_thread(format!("client-worker-{}", id), move || {
+            let maxconn = req_maxconn + stream_maxconn;
 
-                // 1 task per connection, plus a handful of supporting tasks
-                let tasks_max = maxconn + WORKER_NON_CONNECTION_TASKS_MAX;
+            // 1 task per connection, plus a handful of supporting tasks
+            let tasks_max = maxconn + WORKER_NON_CONNECTION_TASKS_MAX;
 
-                let registrations_max = REGISTRATIONS_PER_TASK_MAX * tasks_max;
+            let registrations_max = REGISTRATIONS_PER_TASK_MAX * tasks_max;
 
-                let reactor = Reactor::new(registrations_max);
+            let reactor = Reactor::new(registrations_max);
 
-                let executor = Executor::new(tasks_max);
+            let executor = Executor::new(tasks_max);
 
-                {
-                    let reactor = reactor.clone();
+            {
+                let reactor = reactor.clone();
 
-                    executor.set_pre_poll(move || {
-                        reactor.set_budget(Some(REACTOR_BUDGET));
-                    });
-                }
+                executor.set_pre_poll(move || {
+                    reactor.set_budget(Some(REACTOR_BUDGET));
+                });
+            }
 
-                executor
-                    .spawn(Self::run(
-                        r_stop,
-                        s_ready,
-                        instance_id,
-                        id,
-                        req_maxconn,
-                        stream_maxconn,
-                        buffer_size,
-                        body_buffer_size,
-                        messages_max,
-                        req_timeout,
-                        stream_timeout,
-                        allow_compression,
-                        deny,
-                        resolver,
-                        pool,
-                        zsockman,
-                        handle_bound,
-                    ))
-                    .unwrap();
-
-                executor.run(|timeout| reactor.poll(timeout)).unwrap();
-
-                debug!("client worker {}: stopped", id);
-            })
-            .unwrap();
+            executor
+                .spawn(Self::run(
+                    r_stop,
+                    s_ready,
+                    instance_id,
+                    id,
+                    req_maxconn,
+                    stream_maxconn,
+                    buffer_size,
+                    body_buffer_size,
+                    messages_max,
+                    req_timeout,
+                    stream_timeout,
+                    allow_compression,
+                    deny,
+                    resolver,
+                    pool,
+                    zsockman,
+                    handle_bound,
+                ))
+                .unwrap();
+
+            executor.run(|timeout| reactor.poll(timeout)).unwrap();
+
+            debug!("client worker {}: stopped", id);
+        })?;
 
         ready.recv().unwrap();
 
-        Self {
+        Ok(Self {
             thread: Some(thread),
             stop: Some(stop),
-        }
+        })
     }
 
     fn stop(&mut self) {
@@ -1717,7 +1721,7 @@ impl Client {
         // 1 active query per connection
         let queries_max = req_maxconn + stream_maxconn;
 
-        let resolver = Arc::new(Resolver::new(RESOLVER_THREADS, queries_max));
+        let resolver = Arc::new(Resolver::new(RESOLVER_THREADS, queries_max)?);
 
         let pool_max = if can_move_mio_sockets_between_threads() {
             (req_maxconn + stream_maxconn) / 10
@@ -1751,7 +1755,7 @@ impl Client {
                 &pool,
                 &zsockman,
                 handle_bound,
-            );
+            )?;
             workers.push(w);
         }
 
@@ -1787,7 +1791,7 @@ impl Client {
             let zreq = zhttppacket::OwnedRequest::parse(msg, 0, scratch).unwrap();
             let zreq = arena::Rc::new(zreq, &req_req_mem).unwrap();
 
-            let resolver = Arc::new(Resolver::new(1, 1));
+            let resolver = Arc::new(Resolver::new(1, 1).unwrap());
             let pool = Arc::new(ConnectionPool::new(0));
 
             let fut = Worker::req_connection_task(
@@ -1850,7 +1854,7 @@ impl Client {
             let zreq = zhttppacket::OwnedRequest::parse(msg, 0, scratch).unwrap();
             let zreq = arena::Rc::new(zreq, &req_req_mem).unwrap();
 
-            let resolver = Arc::new(Resolver::new(1, 1));
+            let resolver = Arc::new(Resolver::new(1, 1).unwrap());
             let pool = Arc::new(ConnectionPool::new(0));
 
             let stream_shared_mem = Rc::new(arena::RcMemory::new(1));
@@ -1945,13 +1949,15 @@ impl TestClient {
             100,
             100,
             stream_maxconn,
-        );
+        )
+        .unwrap();
 
         zsockman
             .set_server_req_specs(&[SpecInfo {
                 spec: String::from("inproc://client-test"),
                 bind: true,
                 ipc_file_mode: 0,
+                opts: SpecOpts::default(),
             }])
             .unwrap();
 
@@ -1980,16 +1986,19 @@ impl TestClient {
                     spec: String::from("inproc://client-test-out"),
                     bind: true,
                     ipc_file_mode: 0,
+                    opts: SpecOpts::default(),
                 }],
                 &[SpecInfo {
                     spec: String::from("inproc://client-test-out-stream"),
                     bind: true,
                     ipc_file_mode: 0,
+                    opts: SpecOpts::default(),
                 }],
                 &[SpecInfo {
                     spec: String::from("inproc://client-test-in"),
                     bind: true,
                     ipc_file_mode: 0,
+                    opts: SpecOpts::default(),
                 }],
             )
             .unwrap();
@@ -2249,20 +2258,14 @@ impl TestClient {
             )
             .unwrap();
 
-        poller
-            .register(
-                &mut SourceFd(&req_sock.get_fd().unwrap()),
-                mio::Token(2),
-                mio::Interest::READABLE,
-            )
+        let mut req_watch = platform::SocketWatch::new(req_sock.get_fd().unwrap()).unwrap();
+        req_watch
+            .register(&poller, mio::Token(2), mio::Interest::READABLE)
             .unwrap();
 
-        poller
-            .register(
-                &mut SourceFd(&in_sock.get_fd().unwrap()),
-                mio::Token(3),
-                mio::Interest::READABLE,
-            )
+        let mut in_watch = platform::SocketWatch::new(in_sock.get_fd().unwrap()).unwrap();
+        in_watch
+            .register(&poller, mio::Token(3), mio::Interest::READABLE)
             .unwrap();
 
         let mut req_events = req_sock.get_events().unwrap();
@@ -0,0 +1,452 @@
+/*
+ * Copyright (C) 2024 Fanout, Inc.
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+// commands for managing a running instance, shared by the control and
+// admin sockets. a command is a line of space-separated arguments, and the
+// reply is either "ok" or "error: {reason}", where an "ok" may be followed
+// by result lines. commands:
+//
+//   health           check whether the instance is healthy
+//   stats            show the totals of each worker, then across all
+//                    workers, one per line, as space-separated key=value
+//                    fields
+//   listeners        list the bound address of each listener, one per
+//                    line, in the same format as the listen option
+//   connections      list the active connections, one per line, as
+//                    space-separated key=value fields: id, peer, mode,
+//                    state, handler, bytes-in, bytes-out, and age (seconds)
+//   drain            gracefully close all connections
+//   drain id {id}    gracefully close the connection with the given id
+//   drain ip {addr}  gracefully close all connections from the address or
+//                    cidr range
+//   ban {addr}       refuse new connections from the address or cidr
+//                    range, and close the existing ones
+//   conn-kill {id}   close the connection with the given id right away
+//   pause-accept     stop accepting connections. new connections wait in
+//                    the listen backlog
+//   resume-accept    start accepting connections again
+//   reload-certs     read the certs in the certs dir again when next used.
+//                    the reply is followed by the number of certs found
+//   log-level        show the active log filter
+//   log-level {filter}
+//                    replace the log filter, such as with
+//                    "info,condure::connection=debug"
+
+use crate::listener::AcceptGate;
+use crate::logfilter::{self, LogFilter};
+use crate::report::Report;
+use crate::server::{DrainTarget, Drainer, Server};
+use crate::stats::{HealthCheck, WorkerStats};
+use crate::tls::IdentityCache;
+use ipnet::IpNet;
+use log::info;
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::time::Instant;
+
+// the actions commands can take on the instance
+pub trait Commands {
+    fn health(&self) -> Result<(), String>;
+
+    // a line per worker, then a line of totals
+    fn stats(&self) -> Vec<String>;
+
+    fn listeners(&self) -> Vec<String>;
+
+    fn connections(&self) -> Vec<String>;
+
+    // returns the number of connections closed
+    fn drain(&self, target: &DrainTarget) -> usize;
+
+    // returns the number of connections closed
+    fn ban(&self, net: IpNet) -> usize;
+
+    // returns the number of connections closed
+    fn kill(&self, id: &str) -> usize;
+
+    // returns false if already paused
+    fn pause_accept(&self) -> bool;
+
+    // returns false if not paused
+    fn resume_accept(&self) -> bool;
+
+    // returns the number of certs found
+    fn reload_certs(&self) -> usize;
+}
+
+struct ServerHandles {
+    health: HealthCheck,
+    drainer: Arc<Drainer>,
+    accept_gate: AcceptGate,
+    identities: Arc<IdentityCache>,
+    worker_stats: Vec<Arc<WorkerStats>>,
+}
+
+// commands for the instance. without a server, such as when only acting
+// as a client, there is nothing to act on, and the instance is considered
+// healthy
+pub struct ServerCommands {
+    instance_id: String,
+    listeners: Vec<String>,
+    server: Option<ServerHandles>,
+}
+
+impl ServerCommands {
+    // listeners are the descriptions of the listeners
+    pub fn new(instance_id: &str, server: Option<&Server>, listeners: Vec<String>) -> Self {
+        Self {
+            instance_id: instance_id.to_string(),
+            listeners,
+            server: server.map(|server| ServerHandles {
+                health: server.health_check(),
+                drainer: server.drainer(),
+                accept_gate: server.accept_gate(),
+                identities: server.identities(),
+                worker_stats: server.worker_stats(),
+            }),
+        }
+    }
+}
+
+impl Commands for ServerCommands {
+    fn health(&self) -> Result<(), String> {
+        match &self.server {
+            Some(s) => s.health.check(Instant::now()),
+            None => Ok(()),
+        }
+    }
+
+    fn stats(&self) -> Vec<String> {
+        let s = match &self.server {
+            Some(s) => s,
+            None => return Vec::new(),
+        };
+
+        let mut out = Vec::new();
+
+        let total = Report::collect(&self.instance_id, 0, &s.worker_stats, |r| {
+            out.push(r.to_string())
+        });
+
+        out.push(total.to_string());
+
+        out
+    }
+
+    fn listeners(&self) -> Vec<String> {
+        self.listeners.clone()
+    }
+
+    fn connections(&self) -> Vec<String> {
+        match &self.server {
+            Some(s) => s
+                .drainer
+                .connections()
+                .iter()
+                .map(|c| c.to_string())
+                .collect(),
+            None => Vec::new(),
+        }
+    }
+
+    fn drain(&self, target: &DrainTarget) -> usize {
+        match &self.server {
+            Some(s) => s.drainer.drain(target),
+            None => 0,
+        }
+    }
+
+    fn ban(&self, net: IpNet) -> usize {
+        match &self.server {
+            Some(s) => s.drainer.ban(net),
+            None => 0,
+        }
+    }
+
+    fn kill(&self, id: &str) -> usize {
+        match &self.server {
+            Some(s) => s.drainer.kill(id),
+            None => 0,
+        }
+    }
+
+    fn pause_accept(&self) -> bool {
+        match &self.server {
+            Some(s) => s.accept_gate.pause(),
+            None => false,
+        }
+    }
+
+    fn resume_accept(&self) -> bool {
+        match &self.server {
+            Some(s) => s.accept_gate.resume(),
+            None => false,
+        }
+    }
+
+    fn reload_certs(&self) -> usize {
+        match &self.server {
+            Some(s) => s.identities.reload(),
+            None => 0,
+        }
+    }
+}
+
+fn parse_net(value: &str) -> Result<IpNet, String> {
+    if value.contains('/') {
+        return value.parse().map_err(|e| format!("invalid address: {}", e));
+    }
+
+    match value.parse::<IpAddr>() {
+        Ok(ip) => Ok(IpNet::from(ip)),
+        Err(e) => Err(format!("invalid address: {}", e)),
+    }
+}
+
+fn parse_drain_target(kind: &str, value: &str) -> Result<DrainTarget, String> {
+    match kind {
+        "id" => Ok(DrainTarget::Id(value.to_string())),
+        "ip" if value.contains('/') => Ok(DrainTarget::Net(parse_net(value)?)),
+        "ip" => match value.parse() {
+            Ok(ip) => Ok(DrainTarget::Ip(ip)),
+            Err(e) => Err(format!("invalid address: {}", e)),
+        },
+        _ => Err(format!("unknown drain target: {}", kind)),
+    }
+}
+
+fn lines_reply(lines: Vec<String>) -> String {
+    let mut reply = "ok".to_string();
+
+    for l in lines {
+        reply.push('\n');
+        reply.push_str(&l);
+    }
+
+    reply
+}
+
+fn closed_reply(count: usize) -> String {
+    match count {
+        0 => "error: no matching connections".to_string(),
+        _ => "ok".to_string(),
+    }
+}
+
+// returns the reply to a command line
+pub fn handle_command<C: Commands>(commands: &C, line: &str) -> String {
+    let args: Vec<&str> = line.split_whitespace().collect();
+
+    match args.as_slice() {
+        ["health"] => match commands.health() {
+            Ok(()) => "ok".to_string(),
+            Err(e) => format!("error: {}", e),
+        },
+        ["stats"] => lines_reply(commands.stats()),
+        ["listeners"] => lines_reply(commands.listeners()),
+        ["connections"] => lines_reply(commands.connections()),
+        ["drain"] => closed_reply(commands.drain(&DrainTarget::All)),
+        ["drain", kind, value] => match parse_drain_target(kind, value) {
+            Ok(target) => closed_reply(commands.drain(&target)),
+            Err(e) => format!("error: {}", e),
+        },
+        ["ban", value] => match parse_net(value) {
+            Ok(net) => {
+                commands.ban(net);
+
+                "ok".to_string()
+            }
+            Err(e) => format!("error: {}", e),
+        },
+        ["conn-kill", id] => closed_reply(commands.kill(id)),
+        ["pause-accept"] => {
+            if commands.pause_accept() {
+                info!("paused accepting connections");
+            }
+
+            "ok".to_string()
+        }
+        ["resume-accept"] => {
+            if commands.resume_accept() {
+                info!("resumed accepting connections");
+            }
+
+            "ok".to_string()
+        }
+        ["reload-certs"] => {
+            let count = commands.reload_certs();
+
+            info!("reloading certs, {} found", count);
+
+            format!("ok\n{}", count)
+        }
+        ["log-level"] => format!("ok\n{}", logfilter::get()),
+        ["log-level", value] => match value.parse::<LogFilter>() {
+            Ok(filter) => {
+                info!("log filter changed to {}", filter);
+
+                logfilter::set(filter);
+
+                "ok".to_string()
+            }
+            Err(e) => format!("error: {}", e),
+        },
+        _ => format!("error: unknown command: {}", line),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::{Cell, RefCell};
+
+    #[derive(Default)]
+    struct TestCommands {
+        unhealthy: Cell<bool>,
+        paused: Cell<bool>,
+        drained: RefCell<Vec<DrainTarget>>,
+        banned: RefCell<Vec<IpNet>>,
+        killed: RefCell<Vec<String>>,
+    }
+
+    impl Commands for TestCommands {
+        fn health(&self) -> Result<(), String> {
+            if self.unhealthy.get() {
+                Err("worker 0: not started".to_string())
+            } else {
+                Ok(())
+            }
+        }
+
+        fn stats(&self) -> Vec<String> {
+            vec![
+                "worker=0 connections=1".to_string(),
+                "connections=1".to_string(),
+            ]
+        }
+
+        fn listeners(&self) -> Vec<String> {
+            vec!["0.0.0.0:41000,stream".to_string()]
+        }
+
+        fn connections(&self) -> Vec<String> {
+            Vec::new()
+        }
+
+        fn drain(&self, target: &DrainTarget) -> usize {
+            self.drained.borrow_mut().push(target.clone());
+
+            match target {
+                DrainTarget::Id(id) if id != "0-1-1" => 0,
+                _ => 2,
+            }
+        }
+
+        fn ban(&self, net: IpNet) -> usize {
+            self.banned.borrow_mut().push(net);
+
+            0
+        }
+
+        fn kill(&self, id: &str) -> usize {
+            self.killed.borrow_mut().push(id.to_string());
+
+            (id == "0-1-1") as usize
+        }
+
+        fn pause_accept(&self) -> bool {
+            !self.paused.replace(true)
+        }
+
+        fn resume_accept(&self) -> bool {
+            self.paused.replace(false)
+        }
+
+        fn reload_certs(&self) -> usize {
+            3
+        }
+    }
+
+    #[test]
+    fn commands() {
+        let c = TestCommands::default();
+
+        assert_eq!(handle_command(&c, "health"), "ok");
+        c.unhealthy.set(true);
+        assert_eq!(handle_command(&c, "health"), "error: worker 0: not started");
+
+        assert_eq!(
+            handle_command(&c, "stats"),
+            "ok\nworker=0 connections=1\nconnections=1"
+        );
+
+        assert_eq!(handle_command(&c, "listeners"), "ok\n0.0.0.0:41000,stream");
+
+        // commands without results still reply with a single line
+        assert_eq!(handle_command(&c, "connections"), "ok");
+
+        assert_eq!(handle_command(&c, "drain"), "ok");
+        assert_eq!(handle_command(&c, "drain id 0-1-1"), "ok");
+        assert_eq!(
+            handle_command(&c, "drain id 0-2-1"),
+            "error: no matching connections"
+        );
+        assert_eq!(handle_command(&c, "drain ip 10.0.0.0/8"), "ok");
+        assert!(handle_command(&c, "drain ip foo").starts_with("error: invalid address: "));
+        assert_eq!(
+            handle_command(&c, "drain port 80"),
+            "error: unknown drain target: port"
+        );
+        assert_eq!(
+            *c.drained.borrow(),
+            vec![
+                DrainTarget::All,
+                DrainTarget::Id("0-1-1".to_string()),
+                DrainTarget::Id("0-2-1".to_string()),
+                DrainTarget::Net("10.0.0.0/8".parse().unwrap()),
+            ]
+        );
+
+        // succeeds even if no connections were closed
+        assert_eq!(handle_command(&c, "ban 192.168.0.1"), "ok");
+        assert!(handle_command(&c, "ban foo").starts_with("error: invalid address: "));
+        assert_eq!(
+            *c.banned.borrow(),
+            vec!["192.168.0.1/32".parse::<IpNet>().unwrap()]
+        );
+
+        assert_eq!(handle_command(&c, "pause-accept"), "ok");
+        assert!(c.paused.get());
+        assert_eq!(handle_command(&c, "pause-accept"), "ok");
+        assert_eq!(handle_command(&c, "resume-accept"), "ok");
+        assert!(!c.paused.get());
+
+        assert_eq!(handle_command(&c, "reload-certs"), "ok\n3");
+
+        assert_eq!(handle_command(&c, "conn-kill 0-1-1"), "ok");
+        assert_eq!(
+            handle_command(&c, "conn-kill 0-2-1"),
+            "error: no matching connections"
+        );
+        assert_eq!(*c.killed.borrow(), vec!["0-1-1", "0-2-1"]);
+
+        assert_eq!(
+            handle_command(&c, "conn-kill"),
+            "error: unknown command: conn-kill"
+        );
+        assert_eq!(handle_command(&c, "foo"), "error: unknown command: foo");
+    }
+}
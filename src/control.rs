@@ -0,0 +1,800 @@
+/*
+ * Copyright (C) 2024 Fanout, Inc.
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+// control socket for querying a running instance. a client connects to the
+// unix socket, writes a command line, and reads back a reply line of
+// either "ok" or "error: {reason}". an "ok" may be followed by result
+// lines, until the connection is closed. the commands are described in the
+// command module, and there is one more:
+//
+//   upgrade         pass the listeners to a new instance. the reply line is
+//                   sent along with the listener fds, and is followed by the
+//                   bound address of each listener and an empty line. once
+//                   the new instance has started, it writes "done", and
+//                   this instance replies "ok" and stops gracefully
+
+use crate::command::{handle_command, Commands};
+use crate::platform::{
+    self, OwnedSocket, RawSocket, StdUnixListener as UnixListener, StdUnixStream as UnixStream,
+    PASS_SOCKETS_MAX,
+};
+use crate::server::DrainTarget;
+use crate::spawn_thread;
+use ipnet::IpNet;
+use log::{debug, info, warn};
+use std::fs;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::mem;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+const COMMAND_SIZE_MAX: u64 = 1_024;
+const REPLY_SIZE_MAX: u64 = 65_536;
+const IO_TIMEOUT: Duration = Duration::from_secs(5);
+
+// max number of clients handled at once
+const CLIENTS_MAX: usize = 16;
+
+// how long to wait for a new instance to start during an upgrade
+const UPGRADE_TIMEOUT: Duration = Duration::from_secs(60);
+
+fn read_line(stream: &UnixStream) -> Result<String, io::Error> {
+    let mut line = String::new();
+    BufReader::new(stream.take(COMMAND_SIZE_MAX)).read_line(&mut line)?;
+
+    Ok(line.trim().to_string())
+}
+
+// pass the listeners to a new instance, and stop once it has started
+fn handle_upgrade<U, S>(
+    mut stream: &UnixStream,
+    listener_fds: &U,
+    stop: &S,
+    upgrade_lock: &Mutex<()>,
+) -> Result<(), io::Error>
+where
+    U: Fn() -> Vec<(String, RawSocket)>,
+    S: Fn(),
+{
+    // one upgrade at a time
+    let _guard = upgrade_lock.lock().unwrap();
+
+    let listeners = listener_fds();
+
+    if listeners.len() > PASS_SOCKETS_MAX {
+        return writeln!(stream, "error: too many listeners");
+    }
+
+    let mut reply = "ok\n".to_string();
+
+    for (addr, _) in listeners.iter() {
+        reply.push_str(addr);
+        reply.push('\n');
+    }
+
+    reply.push('\n');
+
+    let fds: Vec<RawSocket> = listeners.iter().map(|(_, fd)| *fd).collect();
+
+    platform::send_with_sockets(stream, reply.as_bytes(), &fds)?;
+
+    info!("passed {} listeners to new instance", fds.len());
+
+    stream.set_read_timeout(Some(UPGRADE_TIMEOUT))?;
+
+    // if the new instance fails to start, keep going
+    match read_line(stream) {
+        Ok(line) if line == "done" => {}
+        Ok(_) | Err(_) => {
+            warn!("new instance didn't finish starting, upgrade aborted");
+
+            return Ok(());
+        }
+    }
+
+    writeln!(stream, "ok")?;
+
+    info!("upgrade completed");
+
+    stop();
+
+    Ok(())
+}
+
+// shared by the client threads
+struct Handlers<C, U, S> {
+    commands: C,
+    listener_fds: U,
+    stop: S,
+    upgrade_lock: Mutex<()>,
+}
+
+fn handle_client<C, U, S>(stream: UnixStream, handlers: &Handlers<C, U, S>) -> Result<(), io::Error>
+where
+    C: Commands,
+    U: Fn() -> Vec<(String, RawSocket)>,
+    S: Fn(),
+{
+    stream.set_read_timeout(Some(IO_TIMEOUT))?;
+    stream.set_write_timeout(Some(IO_TIMEOUT))?;
+
+    // the socket mode should already keep out other users, but check in
+    // case the socket was reachable before its mode was set. an upgrade
+    // hands the listeners over, so this must come first
+    if !platform::is_peer_trusted(&stream)? {
+        warn!("control client belongs to another user, refusing");
+
+        return writeln!(&stream, "error: permission denied");
+    }
+
+    let line = read_line(&stream)?;
+
+    if line == "upgrade" {
+        return handle_upgrade(
+            &stream,
+            &handlers.listener_fds,
+            &handlers.stop,
+            &handlers.upgrade_lock,
+        );
+    }
+
+    let reply = handle_command(&handlers.commands, &line);
+
+    writeln!(&stream, "{}", reply)
+}
+
+pub struct ControlServer {
+    path: PathBuf,
+    file_id: Option<(u64, u64)>,
+    listener: UnixListener,
+    stop: Arc<AtomicBool>,
+    thread: Option<thread::JoinHandle<()>>,
+}
+
+impl ControlServer {
+    // listener_fds provides the listeners to pass to a new instance during
+    // an upgrade, and stop is called once that instance has started
+    pub fn new<C, U, S>(path: &Path, commands: C, listener_fds: U, stop: S) -> Result<Self, String>
+    where
+        C: Commands + Send + Sync + 'static,
+        U: Fn() -> Vec<(String, RawSocket)> + Send + Sync + 'static,
+        S: Fn() + Send + Sync + 'static,
+    {
+        // ensure socket file from a previous run doesn't exist
+        match fs::remove_file(path) {
+            Ok(()) => {}
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(format!("failed to remove {:?}: {}", path, e)),
+        }
+
+        let listener = match UnixListener::bind(path) {
+            Ok(l) => l,
+            Err(e) => return Err(format!("failed to bind {:?}: {}", path, e)),
+        };
+
+        // commands can take over or stop this instance, so only the owner
+        // may connect
+        if let Err(e) = platform::set_mode(path, 0o600) {
+            let _ = fs::remove_file(path);
+
+            return Err(format!("failed to set mode of {:?}: {}", path, e));
+        }
+
+        let file_id = platform::file_id(path);
+
+        let thread_listener = match listener.try_clone() {
+            Ok(l) => l,
+            Err(e) => return Err(format!("failed to clone {:?}: {}", path, e)),
+        };
+
+        let handlers = Arc::new(Handlers {
+            commands,
+            listener_fds,
+            stop,
+            upgrade_lock: Mutex::new(()),
+        });
+
+        let stop_flag = Arc::new(AtomicBool::new(false));
+
+        let thread = {
+            let stop_flag = Arc::clone(&stop_flag);
+
+            spawn_thread("control".to_string(), move || {
+                let clients = Arc::new(AtomicUsize::new(0));
+
+                for stream in thread_listener.incoming() {
+                    if stop_flag.load(Ordering::Relaxed) {
+                        break;
+                    }
+
+                    let stream = match stream {
+                        Ok(stream) => stream,
+                        Err(e) => {
+                            debug!("control client error: {}", e);
+                            continue;
+                        }
+                    };
+
+                    // each client is handled on its own thread, so that one
+                    // that takes a while, such as an upgrade waiting for the
+                    // new instance to start, doesn't hold up the others
+                    if clients.fetch_add(1, Ordering::Relaxed) >= CLIENTS_MAX {
+                        clients.fetch_sub(1, Ordering::Relaxed);
+
+                        let _ = writeln!(&stream, "error: too many clients");
+
+                        continue;
+                    }
+
+                    let handlers = Arc::clone(&handlers);
+                    let client_count = Arc::clone(&clients);
+
+                    let ret = spawn_thread("control-client".to_string(), move || {
+                        if let Err(e) = handle_client(stream, &handlers) {
+                            debug!("control client error: {}", e);
+                        }
+
+                        client_count.fetch_sub(1, Ordering::Relaxed);
+                    });
+
+                    if let Err(e) = ret {
+                        clients.fetch_sub(1, Ordering::Relaxed);
+
+                        debug!("control client error: {}", e);
+                    }
+                }
+            })?
+        };
+
+        Ok(Self {
+            path: path.to_owned(),
+            file_id,
+            listener,
+            stop: stop_flag,
+            thread: Some(thread),
+        })
+    }
+}
+
+impl Drop for ControlServer {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+
+        // the path may have been taken over by a new instance after an
+        // upgrade, in which case its socket must be left alone
+        if self.file_id.is_none() || platform::file_id(&self.path) != self.file_id {
+            // wake the thread out of accept without the path, where
+            // supported. either way, leave the thread behind
+            platform::shutdown_listener(&self.listener);
+
+            return;
+        }
+
+        // wake the thread out of accept. if we can't connect, then leave
+        // the thread behind rather than block forever
+        if UnixStream::connect(&self.path).is_ok() {
+            let thread = self.thread.take().unwrap();
+            thread.join().unwrap();
+        }
+
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+// send a command and return the result lines of an "ok" reply
+fn request(path: &Path, cmd: &str) -> Result<Vec<String>, String> {
+    let stream = match UnixStream::connect(path) {
+        Ok(s) => s,
+        Err(e) => return Err(format!("failed to connect to {:?}: {}", path, e)),
+    };
+
+    let ret = (|| {
+        stream.set_read_timeout(Some(IO_TIMEOUT))?;
+        stream.set_write_timeout(Some(IO_TIMEOUT))?;
+
+        writeln!(&stream, "{}", cmd)?;
+
+        let mut lines = BufReader::new((&stream).take(REPLY_SIZE_MAX)).lines();
+
+        let status = match lines.next() {
+            Some(line) => line?.trim().to_string(),
+            None => String::new(),
+        };
+
+        Ok::<_, io::Error>((status, lines.collect::<Result<Vec<String>, _>>()?))
+    })();
+
+    match ret {
+        Ok((line, results)) if line == "ok" => Ok(results),
+        Ok((line, _)) => match line.strip_prefix("error: ") {
+            Some(e) => Err(e.to_string()),
+            None if line.is_empty() => Err("no response".to_string()),
+            None => Err(format!("unexpected response: {}", line)),
+        },
+        Err(e) => Err(format!("failed to query {:?}: {}", path, e)),
+    }
+}
+
+// ask the instance listening on the control socket at path whether it's
+// healthy
+pub fn check(path: &Path) -> Result<(), String> {
+    request(path, "health").map(|_| ())
+}
+
+// ask the instance listening on the control socket at path for the bound
+// address of each of its listeners
+pub fn listeners(path: &Path) -> Result<Vec<String>, String> {
+    request(path, "listeners")
+}
+
+// ask the instance listening on the control socket at path to describe its
+// active connections
+pub fn connections(path: &Path) -> Result<Vec<String>, String> {
+    request(path, "connections")
+}
+
+// ask the instance listening on the control socket at path for its log
+// filter, or to replace it if a filter is given
+pub fn log_level(path: &Path, filter: Option<&str>) -> Result<Option<String>, String> {
+    match filter {
+        Some(filter) => request(path, &format!("log-level {}", filter)).map(|_| None),
+        None => request(path, "log-level").map(|lines| lines.into_iter().next()),
+    }
+}
+
+// ask the instance listening on the control socket at path to close the
+// target connections
+pub fn drain(path: &Path, target: &DrainTarget) -> Result<(), String> {
+    let cmd = match target {
+        DrainTarget::All => "drain".to_string(),
+        DrainTarget::Id(id) => format!("drain id {}", id),
+        DrainTarget::Ip(ip) => format!("drain ip {}", ip),
+        DrainTarget::Net(net) => format!("drain ip {}", net),
+    };
+
+    request(path, &cmd).map(|_| ())
+}
+
+// ask the instance listening on the control socket at path to refuse
+// connections from the net and close the existing ones
+pub fn ban(path: &Path, net: IpNet) -> Result<(), String> {
+    request(path, &format!("ban {}", net)).map(|_| ())
+}
+
+// an upgrade in progress from a previous instance, which keeps running
+// until told that the new instance has started
+pub struct Upgrade {
+    stream: UnixStream,
+    listeners: Vec<(String, OwnedSocket)>,
+}
+
+impl Upgrade {
+    // the listeners passed by the previous instance, by bound address
+    pub fn take_listeners(&mut self) -> Vec<(String, OwnedSocket)> {
+        mem::take(&mut self.listeners)
+    }
+
+    // tell the previous instance that this one has started, so that it
+    // stops. dropping an upgrade without finishing lets the previous
+    // instance keep running
+    pub fn finish(self) -> Result<(), String> {
+        let ret = (|| {
+            writeln!(&self.stream, "done")?;
+
+            read_line(&self.stream)
+        })();
+
+        match ret {
+            Ok(line) if line == "ok" => Ok(()),
+            Ok(line) if line.is_empty() => Err("no response".to_string()),
+            Ok(line) => Err(format!("unexpected response: {}", line)),
+            Err(e) => Err(format!("failed to finish upgrade: {}", e)),
+        }
+    }
+}
+
+// ask the instance listening on the control socket at path for its
+// listeners, in order to take over from it. returns None if no instance
+// is listening
+pub fn upgrade(path: &Path) -> Result<Option<Upgrade>, String> {
+    let stream = match UnixStream::connect(path) {
+        Ok(s) => s,
+        Err(e)
+            if e.kind() == io::ErrorKind::NotFound
+                || e.kind() == io::ErrorKind::ConnectionRefused =>
+        {
+            return Ok(None)
+        }
+        Err(e) => return Err(format!("failed to connect to {:?}: {}", path, e)),
+    };
+
+    let ret = (|| {
+        stream.set_read_timeout(Some(IO_TIMEOUT))?;
+        stream.set_write_timeout(Some(IO_TIMEOUT))?;
+
+        writeln!(&stream, "upgrade")?;
+
+        let mut buf = vec![0; REPLY_SIZE_MAX as usize];
+
+        let (size, fds) = platform::recv_with_sockets(&stream, &mut buf)?;
+        buf.truncate(size);
+
+        // the rest of the reply may follow the part the fds came with
+        let mut lines =
+            BufReader::new(io::Cursor::new(buf).chain((&stream).take(REPLY_SIZE_MAX))).lines();
+
+        let status = match lines.next() {
+            Some(line) => line?.trim().to_string(),
+            None => String::new(),
+        };
+
+        let mut addrs = Vec::new();
+
+        if status == "ok" {
+            for line in lines {
+                let line = line?;
+
+                if line.is_empty() {
+                    break;
+                }
+
+                addrs.push(line);
+            }
+        }
+
+        Ok::<_, io::Error>((status, addrs, fds))
+    })();
+
+    match ret {
+        Ok((line, addrs, fds)) if line == "ok" => {
+            if addrs.len() != fds.len() {
+                return Err(format!(
+                    "received {} listener fds for {} addresses",
+                    fds.len(),
+                    addrs.len()
+                ));
+            }
+
+            // the new instance may take a while to start
+            if let Err(e) = stream.set_read_timeout(Some(UPGRADE_TIMEOUT)) {
+                return Err(format!("failed to upgrade from {:?}: {}", path, e));
+            }
+
+            Ok(Some(Upgrade {
+                stream,
+                listeners: addrs.into_iter().zip(fds).collect(),
+            }))
+        }
+        Ok((line, _, _)) => match line.strip_prefix("error: ") {
+            Some(e) => Err(e.to_string()),
+            None if line.is_empty() => Err("no response".to_string()),
+            None => Err(format!("unexpected response: {}", line)),
+        },
+        Err(e) => Err(format!("failed to upgrade from {:?}: {}", path, e)),
+    }
+}
+
+// the control socket is a unix socket
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use crate::logfilter;
+    use crate::platform::AsRawSocket;
+    use std::env;
+    use std::os::unix::fs::PermissionsExt;
+    use std::process;
+
+    fn test_path(name: &str) -> PathBuf {
+        env::temp_dir().join(format!("condure-control-{}-{}", process::id(), name))
+    }
+
+    #[derive(Clone, Default)]
+    struct TestCommands {
+        unhealthy: Arc<AtomicBool>,
+        banned: Arc<Mutex<Vec<IpNet>>>,
+    }
+
+    impl Commands for TestCommands {
+        fn health(&self) -> Result<(), String> {
+            if self.unhealthy.load(Ordering::Relaxed) {
+                Err("worker 0: not started".to_string())
+            } else {
+                Ok(())
+            }
+        }
+
+        fn stats(&self) -> Vec<String> {
+            Vec::new()
+        }
+
+        fn listeners(&self) -> Vec<String> {
+            vec![
+                "0.0.0.0:41000,stream".to_string(),
+                "[::1]:41001,req,tls".to_string(),
+            ]
+        }
+
+        fn connections(&self) -> Vec<String> {
+            vec!["id=0-0-1 peer=192.168.0.1".to_string()]
+        }
+
+        fn drain(&self, target: &DrainTarget) -> usize {
+            match target {
+                DrainTarget::Id(id) if id == "0-1-1" => 1,
+                DrainTarget::Ip(ip) if ip.is_loopback() => 3,
+                DrainTarget::Net(net) if net.prefix_len() == 8 => 5,
+                DrainTarget::All => 9,
+                _ => 0,
+            }
+        }
+
+        fn ban(&self, net: IpNet) -> usize {
+            self.banned.lock().unwrap().push(net);
+
+            0
+        }
+
+        fn kill(&self, _id: &str) -> usize {
+            0
+        }
+
+        fn pause_accept(&self) -> bool {
+            false
+        }
+
+        fn resume_accept(&self) -> bool {
+            false
+        }
+
+        fn reload_certs(&self) -> usize {
+            0
+        }
+    }
+
+    #[test]
+    fn health() {
+        let path = test_path("health");
+
+        let commands = TestCommands::default();
+
+        let server = ControlServer::new(&path, commands.clone(), Vec::new, || {}).unwrap();
+
+        let mode = fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+
+        assert_eq!(check(&path), Ok(()));
+
+        commands.unhealthy.store(true, Ordering::Relaxed);
+        assert_eq!(check(&path), Err("worker 0: not started".to_string()));
+
+        let stream = UnixStream::connect(&path).unwrap();
+        writeln!(&stream, "foo").unwrap();
+        assert_eq!(read_line(&stream).unwrap(), "error: unknown command: foo");
+
+        drop(server);
+
+        assert!(!path.exists());
+        assert!(check(&path).is_err());
+    }
+
+    #[test]
+    fn peer_trusted() {
+        // both ends belong to this process
+        let (a, _b) = UnixStream::pair().unwrap();
+
+        assert!(platform::is_peer_trusted(&a).unwrap());
+    }
+
+    #[test]
+    fn drain() {
+        let path = test_path("drain");
+
+        let server = ControlServer::new(&path, TestCommands::default(), Vec::new, || {}).unwrap();
+
+        assert_eq!(
+            super::drain(&path, &DrainTarget::Id("0-1-1".to_string())),
+            Ok(())
+        );
+        assert_eq!(
+            super::drain(&path, &DrainTarget::Ip("127.0.0.1".parse().unwrap())),
+            Ok(())
+        );
+        assert_eq!(
+            super::drain(&path, &DrainTarget::Net("10.0.0.0/8".parse().unwrap())),
+            Ok(())
+        );
+        assert_eq!(super::drain(&path, &DrainTarget::All), Ok(()));
+        assert_eq!(
+            super::drain(&path, &DrainTarget::Id("0-2-1".to_string())),
+            Err("no matching connections".to_string())
+        );
+
+        let stream = UnixStream::connect(&path).unwrap();
+        writeln!(&stream, "drain ip foo").unwrap();
+        assert!(read_line(&stream)
+            .unwrap()
+            .starts_with("error: invalid address: "));
+
+        let stream = UnixStream::connect(&path).unwrap();
+        writeln!(&stream, "drain port 80").unwrap();
+        assert_eq!(
+            read_line(&stream).unwrap(),
+            "error: unknown drain target: port"
+        );
+
+        drop(server);
+    }
+
+    #[test]
+    fn ban() {
+        let path = test_path("ban");
+
+        let commands = TestCommands::default();
+
+        let server = ControlServer::new(&path, commands.clone(), Vec::new, || {}).unwrap();
+
+        // succeeds even if no connections were closed
+        assert_eq!(super::ban(&path, "10.0.0.0/8".parse().unwrap()), Ok(()));
+
+        let stream = UnixStream::connect(&path).unwrap();
+        writeln!(&stream, "ban 192.168.0.1").unwrap();
+        assert_eq!(read_line(&stream).unwrap(), "ok");
+
+        let stream = UnixStream::connect(&path).unwrap();
+        writeln!(&stream, "ban foo").unwrap();
+        assert!(read_line(&stream)
+            .unwrap()
+            .starts_with("error: invalid address: "));
+
+        drop(server);
+
+        assert_eq!(
+            *commands.banned.lock().unwrap(),
+            vec![
+                "10.0.0.0/8".parse::<IpNet>().unwrap(),
+                "192.168.0.1/32".parse().unwrap()
+            ]
+        );
+    }
+
+    #[test]
+    fn listeners() {
+        let path = test_path("listeners");
+
+        let server = ControlServer::new(&path, TestCommands::default(), Vec::new, || {}).unwrap();
+
+        assert_eq!(
+            super::listeners(&path),
+            Ok(vec![
+                "0.0.0.0:41000,stream".to_string(),
+                "[::1]:41001,req,tls".to_string()
+            ])
+        );
+
+        // commands without results still reply with a single line
+        assert_eq!(request(&path, "health"), Ok(Vec::new()));
+
+        drop(server);
+    }
+
+    #[test]
+    fn connections() {
+        let path = test_path("connections");
+
+        let server = ControlServer::new(&path, TestCommands::default(), Vec::new, || {}).unwrap();
+
+        assert_eq!(
+            super::connections(&path),
+            Ok(vec!["id=0-0-1 peer=192.168.0.1".to_string()])
+        );
+
+        drop(server);
+    }
+
+    #[test]
+    fn log_level() {
+        let path = test_path("log-level");
+
+        let server = ControlServer::new(&path, TestCommands::default(), Vec::new, || {}).unwrap();
+
+        let prev = logfilter::get();
+
+        assert_eq!(
+            super::log_level(&path, Some("warn,condure::connection=debug")),
+            Ok(None)
+        );
+        assert_eq!(
+            super::log_level(&path, None),
+            Ok(Some("warn,condure::connection=debug".to_string()))
+        );
+        assert_eq!(
+            super::log_level(&path, Some("condure=loud")),
+            Err("invalid level: loud".to_string())
+        );
+
+        logfilter::set(prev);
+
+        drop(server);
+    }
+
+    #[test]
+    fn upgrade() {
+        let path = test_path("upgrade");
+
+        assert!(super::upgrade(&path).unwrap().is_none());
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let fd = listener.as_raw_socket();
+
+        let stopped = Arc::new(AtomicBool::new(false));
+
+        let server = {
+            let stopped = Arc::clone(&stopped);
+
+            ControlServer::new(
+                &path,
+                TestCommands::default(),
+                move || vec![(addr.to_string(), fd)],
+                move || stopped.store(true, Ordering::Relaxed),
+            )
+            .unwrap()
+        };
+
+        // aborted upgrade
+        let mut u = super::upgrade(&path).unwrap().unwrap();
+        assert_eq!(u.take_listeners().len(), 1);
+        drop(u);
+
+        assert_eq!(check(&path), Ok(()));
+        assert_eq!(stopped.load(Ordering::Relaxed), false);
+
+        let mut u = super::upgrade(&path).unwrap().unwrap();
+
+        let mut listeners = u.take_listeners();
+        assert_eq!(listeners.len(), 1);
+
+        let (laddr, lfd) = listeners.pop().unwrap();
+        assert_eq!(laddr, addr.to_string());
+
+        let l = std::net::TcpListener::from(lfd);
+        assert_eq!(l.local_addr().unwrap(), addr);
+
+        // other clients are served while the upgrade waits
+        assert_eq!(check(&path), Ok(()));
+        assert_eq!(stopped.load(Ordering::Relaxed), false);
+
+        // the new instance takes over the path. it reports itself as
+        // unhealthy, to tell the instances apart
+        let new_commands = TestCommands::default();
+        new_commands.unhealthy.store(true, Ordering::Relaxed);
+
+        let new_server = ControlServer::new(&path, new_commands, Vec::new, || {}).unwrap();
+
+        assert_eq!(u.finish(), Ok(()));
+        assert_eq!(stopped.load(Ordering::Relaxed), true);
+
+        // stopping the previous instance leaves the new one alone
+        drop(server);
+        assert_eq!(check(&path), Err("worker 0: not started".to_string()));
+
+        drop(new_server);
+        assert!(!path.exists());
+    }
+}
@@ -0,0 +1,308 @@
+/*
+ * Copyright (C) 2024 Fanout, Inc.
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+// CurveZMQ keys for the zhttp sockets, and a ZAP handler that limits which
+// clients may connect when this instance is the curve server.
+//
+// certificates use the czmq text format, where the keys are z85 strings:
+//
+//   curve
+//       public-key = "..."
+//       secret-key = "..."
+//
+// the secret key is only present in secret certificates. allow lists
+// contain one z85 public key per line, and blank lines and lines starting
+// with '#' are ignored
+
+use crate::spawn_thread;
+use crate::zmq::ZmqSocket;
+use log::{debug, error};
+use std::collections::HashSet;
+use std::convert::TryInto;
+use std::fs;
+use std::path::Path;
+use std::sync::mpsc;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+pub const KEY_SIZE: usize = 32;
+
+pub type CurveKey = [u8; KEY_SIZE];
+
+// libzmq sends authentication requests for all sockets of a context here
+const ZAP_ENDPOINT: &str = "inproc://zeromq.zap.01";
+
+const ZAP_VERSION: &[u8] = b"1.0";
+
+// how often to check for stop while waiting for requests
+const RECV_TIMEOUT: Duration = Duration::from_millis(100);
+
+pub fn decode_key(s: &str) -> Result<CurveKey, String> {
+    let data = match zmq::z85_decode(s) {
+        Ok(data) => data,
+        Err(e) => return Err(format!("invalid curve key: {}", e)),
+    };
+
+    match data.try_into() {
+        Ok(key) => Ok(key),
+        Err(_) => Err(format!("curve key must be {} bytes", KEY_SIZE)),
+    }
+}
+
+// returns the public key, and the secret key if present
+pub fn parse_cert(s: &str) -> Result<(CurveKey, Option<CurveKey>), String> {
+    let mut public_key = None;
+    let mut secret_key = None;
+
+    for line in s.lines() {
+        let line = line.trim();
+
+        if line.starts_with('#') {
+            continue;
+        }
+
+        // z85 may contain '=', but property names don't
+        let (name, value) = match line.split_once('=') {
+            Some((name, value)) => (name.trim(), value.trim()),
+            None => continue,
+        };
+
+        let value = value
+            .strip_prefix('"')
+            .and_then(|v| v.strip_suffix('"'))
+            .unwrap_or(value);
+
+        match name {
+            "public-key" => public_key = Some(decode_key(value)?),
+            "secret-key" => secret_key = Some(decode_key(value)?),
+            _ => {}
+        }
+    }
+
+    match public_key {
+        Some(public_key) => Ok((public_key, secret_key)),
+        None => Err("certificate has no public-key".to_string()),
+    }
+}
+
+pub fn read_cert(path: &Path) -> Result<(CurveKey, Option<CurveKey>), String> {
+    match fs::read_to_string(path) {
+        Ok(s) => parse_cert(&s).map_err(|e| format!("{}: {}", path.display(), e)),
+        Err(e) => Err(format!("failed to read {}: {}", path.display(), e)),
+    }
+}
+
+pub fn parse_allow_list(s: &str) -> Result<HashSet<CurveKey>, String> {
+    let mut keys = HashSet::new();
+
+    for line in s.lines() {
+        let line = line.trim();
+
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        keys.insert(decode_key(line)?);
+    }
+
+    Ok(keys)
+}
+
+pub fn read_allow_list(path: &Path) -> Result<HashSet<CurveKey>, String> {
+    match fs::read_to_string(path) {
+        Ok(s) => parse_allow_list(&s).map_err(|e| format!("{}: {}", path.display(), e)),
+        Err(e) => Err(format!("failed to read {}: {}", path.display(), e)),
+    }
+}
+
+// returns the reply frames for a ZAP request. only curve clients are
+// checked, since the other sockets of the context don't use ZAP
+fn zap_reply(allow: &HashSet<CurveKey>, req: &[Vec<u8>]) -> Vec<Vec<u8>> {
+    let request_id = req.get(1).cloned().unwrap_or_default();
+
+    let (status, text) = if req.len() < 6 || req[0] != ZAP_VERSION {
+        ("500", "invalid request")
+    } else if req[5] != b"CURVE" {
+        ("200", "OK")
+    } else {
+        let key: Option<CurveKey> = req.get(6).and_then(|k| k.as_slice().try_into().ok());
+
+        match key {
+            Some(key) if allow.contains(&key) => ("200", "OK"),
+            Some(key) => {
+                debug!(
+                    "zap: denied curve client {}",
+                    zmq::z85_encode(&key).unwrap_or_default()
+                );
+
+                ("400", "not allowed")
+            }
+            None => ("400", "invalid credentials"),
+        }
+    };
+
+    vec![
+        ZAP_VERSION.to_vec(),
+        request_id,
+        status.as_bytes().to_vec(),
+        text.as_bytes().to_vec(),
+        Vec::new(),
+        Vec::new(),
+    ]
+}
+
+pub struct ZapHandler {
+    stop: Option<mpsc::Sender<()>>,
+    thread: Option<thread::JoinHandle<()>>,
+}
+
+impl ZapHandler {
+    // must be created before the sockets that use it start accepting
+    pub fn new(zmq_context: &Arc<zmq::Context>, allow: HashSet<CurveKey>) -> Result<Self, String> {
+        let sock = ZmqSocket::new(zmq_context, zmq::REP);
+
+        if let Err(e) = sock.inner().set_rcvtimeo(RECV_TIMEOUT.as_millis() as i32) {
+            return Err(e.to_string());
+        }
+
+        if let Err(e) = sock.inner().bind(ZAP_ENDPOINT) {
+            return Err(format!("bind {}: {}", ZAP_ENDPOINT, e));
+        }
+
+        let (stop, r_stop) = mpsc::channel::<()>();
+
+        let thread = spawn_thread("zap".to_string(), move || loop {
+            if let Err(mpsc::TryRecvError::Disconnected) = r_stop.try_recv() {
+                break;
+            }
+
+            let req = match sock.inner().recv_multipart(0) {
+                Ok(req) => req,
+                Err(zmq::Error::EAGAIN) | Err(zmq::Error::EINTR) => continue,
+                Err(e) => {
+                    error!("zap recv: {}", e);
+                    break;
+                }
+            };
+
+            // a REP socket must reply before it can receive again
+            if let Err(e) = sock.inner().send_multipart(zap_reply(&allow, &req), 0) {
+                debug!("zap send: {}", e);
+            }
+        })?;
+
+        Ok(Self {
+            stop: Some(stop),
+            thread: Some(thread),
+        })
+    }
+}
+
+impl Drop for ZapHandler {
+    fn drop(&mut self) {
+        // the thread notices within the receive timeout
+        self.stop = None;
+
+        let thread = self.thread.take().unwrap();
+        thread.join().unwrap();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CLIENT_PUBLIC: &str = "Yne@$w-vo<fVvi]a<NY6T1ed:M$fCG*[IaLV{hID";
+    const CLIENT_SECRET: &str = "D:)Q[IlAW!ahhC2ac:9*A}h:p?([4%wOTJ%JR%cs";
+    const SERVER_PUBLIC: &str = "rq:rM>}U?@Lns47E1%kR.o@n%FcmmsL/@{H8]yf7";
+
+    fn zap_request(mechanism: &[u8], credentials: &[u8]) -> Vec<Vec<u8>> {
+        vec![
+            b"1.0".to_vec(),
+            b"1".to_vec(),
+            Vec::new(),
+            b"127.0.0.1".to_vec(),
+            Vec::new(),
+            mechanism.to_vec(),
+            credentials.to_vec(),
+        ]
+    }
+
+    #[test]
+    fn cert() {
+        let s = format!(
+            "#   ZeroMQ CURVE **Secret** Certificate\n\
+             \n\
+             metadata\n\
+             curve\n    \
+                 public-key = \"{}\"\n    \
+                 secret-key = \"{}\"\n",
+            CLIENT_PUBLIC, CLIENT_SECRET
+        );
+
+        let (public_key, secret_key) = parse_cert(&s).unwrap();
+        assert_eq!(public_key, decode_key(CLIENT_PUBLIC).unwrap());
+        assert_eq!(secret_key, Some(decode_key(CLIENT_SECRET).unwrap()));
+
+        let s = format!("curve\n    public-key = \"{}\"\n", SERVER_PUBLIC);
+
+        let (public_key, secret_key) = parse_cert(&s).unwrap();
+        assert_eq!(public_key, decode_key(SERVER_PUBLIC).unwrap());
+        assert_eq!(secret_key, None);
+
+        assert!(parse_cert("curve\n").is_err());
+        assert!(parse_cert("curve\n    public-key = \"abc\"\n").is_err());
+        assert!(decode_key("0000000000").is_err());
+    }
+
+    #[test]
+    fn allow() {
+        let s = format!("# clients\n{}\n\n{}\n", CLIENT_PUBLIC, SERVER_PUBLIC);
+
+        let allow = parse_allow_list(&s).unwrap();
+        assert_eq!(allow.len(), 2);
+        assert!(allow.contains(&decode_key(CLIENT_PUBLIC).unwrap()));
+
+        assert!(parse_allow_list("abc\n").is_err());
+
+        let allow = parse_allow_list(CLIENT_PUBLIC).unwrap();
+
+        let reply = zap_reply(
+            &allow,
+            &zap_request(b"CURVE", &decode_key(CLIENT_PUBLIC).unwrap()),
+        );
+        assert_eq!(reply[0], b"1.0");
+        assert_eq!(reply[1], b"1");
+        assert_eq!(reply[2], b"200");
+
+        let reply = zap_reply(
+            &allow,
+            &zap_request(b"CURVE", &decode_key(SERVER_PUBLIC).unwrap()),
+        );
+        assert_eq!(reply[2], b"400");
+
+        let reply = zap_reply(&allow, &zap_request(b"CURVE", b"short"));
+        assert_eq!(reply[2], b"400");
+
+        let reply = zap_reply(&allow, &zap_request(b"NULL", b""));
+        assert_eq!(reply[2], b"200");
+
+        let reply = zap_reply(&allow, &[b"2.0".to_vec(), b"1".to_vec()]);
+        assert_eq!(reply[1], b"1");
+        assert_eq!(reply[2], b"500");
+    }
+}
@@ -0,0 +1,242 @@
+/*
+ * Copyright (C) 2024 Fanout, Inc.
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+// decoding of compressed message bodies, for the gzip and deflate content
+// codings (RFC 9110 section 8.4.1)
+
+use miniz_oxide::inflate::{self, TINFLStatus};
+
+const GZIP_HEADER_LEN: usize = 10;
+const GZIP_TRAILER_LEN: usize = 8;
+
+const GZIP_FHCRC: u8 = 0x02;
+const GZIP_FEXTRA: u8 = 0x04;
+const GZIP_FNAME: u8 = 0x08;
+const GZIP_FCOMMENT: u8 = 0x10;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Coding {
+    Gzip,
+    Deflate,
+}
+
+impl Coding {
+    // parse a Content-Encoding value. returns None if the value is not a
+    // single coding that we can decode
+    pub fn from_header(value: &[u8]) -> Option<Self> {
+        let value = std::str::from_utf8(value).ok()?.trim();
+
+        if value.eq_ignore_ascii_case("gzip") || value.eq_ignore_ascii_case("x-gzip") {
+            Some(Self::Gzip)
+        } else if value.eq_ignore_ascii_case("deflate") {
+            Some(Self::Deflate)
+        } else {
+            None
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, thiserror::Error)]
+pub enum Error {
+    #[error("invalid gzip header")]
+    InvalidHeader,
+
+    #[error("invalid compressed data")]
+    InvalidData,
+
+    #[error("checksum mismatch")]
+    Checksum,
+
+    #[error("decompressed size exceeds {0} bytes")]
+    TooLarge(usize),
+}
+
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+
+    for b in data {
+        crc ^= *b as u32;
+
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0xedb8_8320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+
+    !crc
+}
+
+fn inflate_error(e: inflate::DecompressError, max: usize) -> Error {
+    match e.status {
+        TINFLStatus::HasMoreOutput => Error::TooLarge(max),
+        _ => Error::InvalidData,
+    }
+}
+
+// returns the offset of the deflate data
+fn parse_gzip_header(src: &[u8]) -> Result<usize, Error> {
+    if src.len() < GZIP_HEADER_LEN || src[0] != 0x1f || src[1] != 0x8b || src[2] != 8 {
+        return Err(Error::InvalidHeader);
+    }
+
+    let flags = src[3];
+    let mut pos = GZIP_HEADER_LEN;
+
+    if flags & GZIP_FEXTRA != 0 {
+        if src.len() < pos + 2 {
+            return Err(Error::InvalidHeader);
+        }
+
+        pos += 2 + u16::from_le_bytes([src[pos], src[pos + 1]]) as usize;
+    }
+
+    for flag in [GZIP_FNAME, GZIP_FCOMMENT] {
+        if flags & flag != 0 {
+            // zero-terminated string
+            match src.get(pos..).and_then(|s| s.iter().position(|b| *b == 0)) {
+                Some(end) => pos += end + 1,
+                None => return Err(Error::InvalidHeader),
+            }
+        }
+    }
+
+    if flags & GZIP_FHCRC != 0 {
+        pos += 2;
+    }
+
+    if src.len() < pos {
+        return Err(Error::InvalidHeader);
+    }
+
+    Ok(pos)
+}
+
+// decompress src, failing if the output would be larger than max bytes.
+// gzip data must consist of a single member
+pub fn decompress(coding: Coding, src: &[u8], max: usize) -> Result<Vec<u8>, Error> {
+    match coding {
+        Coding::Gzip => {
+            let start = parse_gzip_header(src)?;
+
+            if src.len() < start + GZIP_TRAILER_LEN {
+                return Err(Error::InvalidData);
+            }
+
+            let end = src.len() - GZIP_TRAILER_LEN;
+
+            let out = inflate::decompress_to_vec_with_limit(&src[start..end], max)
+                .map_err(|e| inflate_error(e, max))?;
+
+            let trailer = &src[end..];
+            let crc = u32::from_le_bytes([trailer[0], trailer[1], trailer[2], trailer[3]]);
+            let size = u32::from_le_bytes([trailer[4], trailer[5], trailer[6], trailer[7]]);
+
+            if crc != crc32(&out) || size != out.len() as u32 {
+                return Err(Error::Checksum);
+            }
+
+            Ok(out)
+        }
+        Coding::Deflate => {
+            inflate::decompress_to_vec_zlib_with_limit(src, max).map_err(|e| inflate_error(e, max))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use miniz_oxide::deflate;
+
+    fn gzip(data: &[u8], name: Option<&str>) -> Vec<u8> {
+        let mut out = vec![0x1f, 0x8b, 8, 0, 0, 0, 0, 0, 0, 0xff];
+
+        if let Some(name) = name {
+            out[3] |= GZIP_FNAME;
+            out.extend_from_slice(name.as_bytes());
+            out.push(0);
+        }
+
+        out.extend(deflate::compress_to_vec(data, 6));
+        out.extend_from_slice(&crc32(data).to_le_bytes());
+        out.extend_from_slice(&(data.len() as u32).to_le_bytes());
+
+        out
+    }
+
+    #[test]
+    fn from_header() {
+        assert_eq!(Coding::from_header(b"gzip"), Some(Coding::Gzip));
+        assert_eq!(Coding::from_header(b" X-Gzip "), Some(Coding::Gzip));
+        assert_eq!(Coding::from_header(b"deflate"), Some(Coding::Deflate));
+        assert_eq!(Coding::from_header(b"br"), None);
+        assert_eq!(Coding::from_header(b"gzip, br"), None);
+    }
+
+    #[test]
+    fn crc() {
+        assert_eq!(crc32(b""), 0);
+        assert_eq!(crc32(b"123456789"), 0xcbf4_3926);
+    }
+
+    #[test]
+    fn decompress_gzip() {
+        let data = b"hello world hello world hello world";
+
+        assert_eq!(
+            decompress(Coding::Gzip, &gzip(data, None), 1024).unwrap(),
+            data
+        );
+        assert_eq!(
+            decompress(Coding::Gzip, &gzip(data, Some("hello.txt")), 1024).unwrap(),
+            data
+        );
+
+        assert_eq!(
+            decompress(Coding::Gzip, &gzip(data, None), 10),
+            Err(Error::TooLarge(10))
+        );
+
+        assert_eq!(
+            decompress(Coding::Gzip, b"hello", 1024),
+            Err(Error::InvalidHeader)
+        );
+
+        let mut src = gzip(data, None);
+        let len = src.len();
+        src[len - 8] ^= 0xff;
+        assert_eq!(decompress(Coding::Gzip, &src, 1024), Err(Error::Checksum));
+    }
+
+    #[test]
+    fn decompress_deflate() {
+        let data = b"hello world hello world hello world";
+        let src = deflate::compress_to_vec_zlib(data, 6);
+
+        assert_eq!(decompress(Coding::Deflate, &src, 1024).unwrap(), data);
+        assert_eq!(
+            decompress(Coding::Deflate, &src, 10),
+            Err(Error::TooLarge(10))
+        );
+        assert_eq!(
+            decompress(Coding::Deflate, b"hello", 1024),
+            Err(Error::InvalidData)
+        );
+    }
+}
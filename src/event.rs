@@ -556,6 +556,22 @@ impl Poller {
         self.poll.registry().register(source, token, interests)
     }
 
+    pub fn reregister<S>(
+        &self,
+        source: &mut S,
+        token: Token,
+        interests: Interest,
+    ) -> Result<(), io::Error>
+    where
+        S: Source + ?Sized,
+    {
+        if token == Token(0) {
+            return Err(io::Error::from(io::ErrorKind::InvalidInput));
+        }
+
+        self.poll.registry().reregister(source, token, interests)
+    }
+
     pub fn deregister<S>(&self, source: &mut S) -> Result<(), io::Error>
     where
         S: Source + ?Sized,
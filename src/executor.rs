@@ -33,6 +33,16 @@ thread_local! {
 
 type BoxFuture = Pin<Box<dyn Future<Output = ()>>>;
 
+// woken tasks are processed in order of priority, and then in the order
+// they were woken. high priority is meant for work that keeps the rest of
+// the system accurate, such as handling timer expirations and cleaning up
+// after finished connections
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Priority {
+    Normal,
+    High,
+}
+
 struct TaskWaker {
     tasks: Weak<Tasks>,
     task_id: usize,
@@ -58,12 +68,17 @@ fn poll_fut(fut: &mut BoxFuture, waker: Waker) -> bool {
 struct Task {
     fut: Option<Pin<Box<dyn Future<Output = ()>>>>,
     wakeable: bool,
+    priority: Priority,
 }
 
 struct TasksData {
     nodes: Slab<list::Node<Task>>,
     next: list::List,
+    next_high: list::List,
     wakers: Vec<Rc<TaskWaker>>,
+
+    // if set, all wakes are treated as high priority
+    wake_high: bool,
 }
 
 struct Tasks {
@@ -76,7 +91,9 @@ impl Tasks {
         let data = TasksData {
             nodes: Slab::with_capacity(max),
             next: list::List::default(),
+            next_high: list::List::default(),
             wakers: Vec::with_capacity(max),
+            wake_high: false,
         };
 
         let tasks = Rc::new(Self {
@@ -103,10 +120,12 @@ impl Tasks {
     }
 
     fn have_next(&self) -> bool {
-        !self.data.borrow().next.is_empty()
+        let data = &*self.data.borrow();
+
+        !data.next.is_empty() || !data.next_high.is_empty()
     }
 
-    fn add<F>(&self, fut: F) -> Result<(), ()>
+    fn add<F>(&self, fut: F, priority: Priority) -> Result<(), ()>
     where
         F: Future<Output = ()> + 'static,
     {
@@ -122,11 +141,15 @@ impl Tasks {
         let task = Task {
             fut: Some(Box::pin(fut)),
             wakeable: false,
+            priority,
         };
 
         entry.insert(list::Node::new(task));
 
-        data.next.push_back(&mut data.nodes, nkey);
+        match priority {
+            Priority::Normal => data.next.push_back(&mut data.nodes, nkey),
+            Priority::High => data.next_high.push_back(&mut data.nodes, nkey),
+        }
 
         Ok(())
     }
@@ -145,18 +168,27 @@ impl Tasks {
         assert_eq!(Rc::strong_count(&data.wakers[nkey]), 1);
 
         data.next.remove(&mut data.nodes, nkey);
+        data.next_high.remove(&mut data.nodes, nkey);
         data.nodes.remove(nkey);
     }
 
+    // take all woken tasks, with high priority tasks first
     fn take_next_list(&self) -> list::List {
         let data = &mut *self.data.borrow_mut();
 
         let mut l = list::List::default();
+        l.concat(&mut data.nodes, &mut data.next_high);
         l.concat(&mut data.nodes, &mut data.next);
 
         l
     }
 
+    fn set_wake_high(&self, enabled: bool) -> bool {
+        let data = &mut *self.data.borrow_mut();
+
+        mem::replace(&mut data.wake_high, enabled)
+    }
+
     fn append_to_next_list(&self, mut l: list::List) {
         let data = &mut *self.data.borrow_mut();
 
@@ -226,7 +258,11 @@ impl Tasks {
 
         node.value.wakeable = false;
 
-        data.next.push_back(&mut data.nodes, nkey);
+        if node.value.priority == Priority::High || data.wake_high {
+            data.next_high.push_back(&mut data.nodes, nkey);
+        } else {
+            data.next.push_back(&mut data.nodes, nkey);
+        }
     }
 
     fn set_pre_poll<F>(&self, pre_poll_fn: F)
@@ -266,12 +302,20 @@ impl Executor {
 
     #[allow(clippy::result_unit_err)]
     pub fn spawn<F>(&self, fut: F) -> Result<(), ()>
+    where
+        F: Future<Output = ()> + 'static,
+    {
+        self.spawn_with_priority(fut, Priority::Normal)
+    }
+
+    #[allow(clippy::result_unit_err)]
+    pub fn spawn_with_priority<F>(&self, fut: F, priority: Priority) -> Result<(), ()>
     where
         F: Future<Output = ()> + 'static,
     {
         debug!("spawning future with size {}", mem::size_of::<F>());
 
-        self.tasks.add(fut)
+        self.tasks.add(fut, priority)
     }
 
     pub fn set_pre_poll<F>(&self, pre_poll_fn: F)
@@ -353,6 +397,28 @@ impl Drop for Executor {
     }
 }
 
+// any tasks woken by the current thread's executor while calling f are
+// treated as high priority
+pub fn with_high_priority_wakes<F, R>(f: F) -> R
+where
+    F: FnOnce() -> R,
+{
+    let tasks = EXECUTOR.with(|ex| ex.borrow().as_ref().and_then(|tasks| tasks.upgrade()));
+
+    let tasks = match tasks {
+        Some(tasks) => tasks,
+        None => return f(),
+    };
+
+    let prev = tasks.set_wake_high(true);
+
+    let ret = f();
+
+    tasks.set_wake_high(prev);
+
+    ret
+}
+
 pub struct Spawner {
     tasks: Weak<Tasks>,
 }
@@ -679,4 +745,46 @@ mod tests {
 
         assert_eq!(flag.get(), true);
     }
+
+    #[test]
+    fn test_executor_priority() {
+        let executor = Executor::new(3);
+
+        let order = Rc::new(RefCell::new(Vec::new()));
+
+        let mut handles = Vec::new();
+
+        for (name, priority) in [
+            ("a", Priority::Normal),
+            ("b", Priority::High),
+            ("c", Priority::Normal),
+        ] {
+            let fut = TestFuture::new();
+            handles.push(fut.handle());
+
+            let order = order.clone();
+
+            executor
+                .spawn_with_priority(
+                    async move {
+                        fut.await;
+                        order.borrow_mut().push(name);
+                    },
+                    priority,
+                )
+                .unwrap();
+        }
+
+        executor.run_until_stalled();
+        assert!(order.borrow().is_empty());
+
+        // b was spawned with high priority, and c is woken with high priority
+        handles[0].set_ready();
+        handles[1].set_ready();
+        with_high_priority_wakes(|| handles[2].set_ready());
+
+        executor.run_until_stalled();
+        assert_eq!(*order.borrow(), vec!["b", "c", "a"]);
+        assert_eq!(executor.have_tasks(), false);
+    }
 }
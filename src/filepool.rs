@@ -0,0 +1,292 @@
+/*
+ * Copyright (C) 2024 Fanout, Inc.
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+// opening and reading files can block on disk access, so it is done by
+// worker threads rather than on a reactor thread
+
+use crate::blockingpool::{self, BlockingPool};
+use crate::platform::{self, Dir};
+use std::fs::File;
+use std::io;
+use std::path::{Component, Path};
+use std::sync::Arc;
+
+pub const REGISTRATIONS_PER_JOB: usize = blockingpool::REGISTRATIONS_PER_JOB;
+
+pub enum Task {
+    // open a file within the root, by path
+    Open(String),
+
+    // read up to size bytes at offset into the buffer, replacing its content
+    Read(Arc<File>, u64, usize, Vec<u8>),
+}
+
+pub enum Output {
+    // an opened file and its size
+    Opened(File, u64),
+
+    // the buffer, holding what was read
+    Read(Vec<u8>),
+}
+
+fn outside_root() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::PermissionDenied,
+        "file is outside of the file root",
+    )
+}
+
+// open a regular file within the root directory. each component is opened
+// relative to the previous one without following symbolic links, so the
+// file can't end up outside of the root even if the tree changes meanwhile.
+// absolute paths must be within root_path, the canonical path of the root
+fn open_file(root: &Dir, root_path: &Path, path: &str) -> Result<(File, u64), io::Error> {
+    let mut path = Path::new(path);
+
+    if path.is_absolute() {
+        path = match path.strip_prefix(root_path) {
+            Ok(path) => path,
+            Err(_) => return Err(outside_root()),
+        };
+    }
+
+    // with no links followed, parent components can be resolved lexically
+    let mut names = Vec::new();
+
+    for c in path.components() {
+        match c {
+            Component::Normal(name) => names.push(name),
+            Component::CurDir => {}
+            Component::ParentDir => {
+                if names.pop().is_none() {
+                    return Err(outside_root());
+                }
+            }
+            Component::RootDir | Component::Prefix(_) => return Err(outside_root()),
+        }
+    }
+
+    let (name, dir_names) = match names.split_last() {
+        Some(ret) => ret,
+        None => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "not a regular file",
+            ))
+        }
+    };
+
+    let mut dir = None;
+
+    for dir_name in dir_names {
+        let next = dir.as_ref().unwrap_or(root).open_dir(dir_name)?;
+
+        dir = Some(next);
+    }
+
+    let file = dir.as_ref().unwrap_or(root).open_file(name)?;
+
+    let meta = file.metadata()?;
+
+    if !meta.is_file() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "not a regular file",
+        ));
+    }
+
+    Ok((file, meta.len()))
+}
+
+fn run_task(root: &Dir, root_path: &Path, task: Task) -> Result<Output, io::Error> {
+    match task {
+        Task::Open(path) => {
+            let (file, len) = open_file(root, root_path, &path)?;
+
+            Ok(Output::Opened(file, len))
+        }
+        Task::Read(file, offset, size, mut buf) => {
+            buf.resize(size, 0);
+
+            let size = platform::read_at(&file, &mut buf, offset)?;
+            buf.truncate(size);
+
+            Ok(Output::Read(buf))
+        }
+    }
+}
+
+pub type Job = blockingpool::Job<Task, Result<Output, io::Error>>;
+
+pub struct FilePool {
+    pool: BlockingPool<Task, Result<Output, io::Error>>,
+}
+
+impl FilePool {
+    // root is expected to be canonical
+    pub fn new(root: &Path, num_threads: usize, jobs_max: usize) -> Result<Self, String> {
+        let root_dir = match Dir::open(root) {
+            Ok(f) => f,
+            Err(e) => return Err(format!("failed to open {}: {}", root.display(), e)),
+        };
+
+        let root_path = root.to_path_buf();
+
+        let pool = BlockingPool::new("filepool", num_threads, jobs_max, move |task| {
+            run_task(&root_dir, &root_path, task)
+        })?;
+
+        Ok(Self { pool })
+    }
+
+    #[allow(clippy::result_unit_err)]
+    pub fn run(&self, task: Task) -> Result<Job, ()> {
+        self.pool.run(task)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::event;
+    use mio::Interest;
+    use std::fs;
+
+    fn wait(job: &Job) -> Result<Output, io::Error> {
+        let mut poller = event::Poller::new(1).unwrap();
+
+        poller
+            .register_custom(
+                job.get_read_registration(),
+                mio::Token(1),
+                Interest::READABLE,
+            )
+            .unwrap();
+
+        let result = loop {
+            if let Some(result) = job.process() {
+                break result;
+            }
+
+            poller.poll(None).unwrap();
+
+            for _ in poller.iter_events() {}
+        };
+
+        poller
+            .deregister_custom(job.get_read_registration())
+            .unwrap();
+
+        result
+    }
+
+    #[test]
+    fn open_and_read() {
+        let root = std::env::temp_dir().join(format!("condure-filepool-{}", std::process::id()));
+        fs::create_dir_all(&root).unwrap();
+        let root = fs::canonicalize(&root).unwrap();
+
+        fs::write(root.join("a.txt"), "hello world\n").unwrap();
+
+        let pool = FilePool::new(&root, 1, 1).unwrap();
+
+        let job = pool.run(Task::Open("a.txt".to_string())).unwrap();
+
+        // jobs_max is 1, so this should error
+        assert!(pool.run(Task::Open("a.txt".to_string())).is_err());
+
+        let (file, len) = match wait(&job).unwrap() {
+            Output::Opened(file, len) => (file, len),
+            _ => panic!("unexpected output"),
+        };
+        drop(job);
+
+        assert_eq!(len, 12);
+
+        let job = pool
+            .run(Task::Read(Arc::new(file), 6, 100, Vec::new()))
+            .unwrap();
+
+        let buf = match wait(&job).unwrap() {
+            Output::Read(buf) => buf,
+            _ => panic!("unexpected output"),
+        };
+
+        assert_eq!(buf, b"world\n");
+
+        drop(job);
+        drop(pool);
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn open_within_root() {
+        use std::os::unix::fs::symlink;
+
+        let base =
+            std::env::temp_dir().join(format!("condure-filepool-open-{}", std::process::id()));
+        fs::create_dir_all(base.join("root/sub")).unwrap();
+        let base = fs::canonicalize(&base).unwrap();
+        let root_path = base.join("root");
+
+        fs::write(base.join("secret.txt"), "secret\n").unwrap();
+        fs::write(root_path.join("a.txt"), "hello world\n").unwrap();
+        symlink(base.join("secret.txt"), root_path.join("link.txt")).unwrap();
+        symlink(&base, root_path.join("linkdir")).unwrap();
+
+        let root = Dir::open(&root_path).unwrap();
+
+        let (_, len) = open_file(&root, &root_path, "a.txt").unwrap();
+        assert_eq!(len, 12);
+
+        let path = root_path.join("a.txt");
+        assert!(open_file(&root, &root_path, path.to_str().unwrap()).is_ok());
+        assert!(open_file(&root, &root_path, "./sub/../a.txt").is_ok());
+
+        // outside of the root
+        let path = base.join("secret.txt");
+        let e = open_file(&root, &root_path, path.to_str().unwrap())
+            .err()
+            .unwrap();
+        assert_eq!(e.kind(), io::ErrorKind::PermissionDenied);
+
+        let e = open_file(&root, &root_path, "sub/../../secret.txt")
+            .err()
+            .unwrap();
+        assert_eq!(e.kind(), io::ErrorKind::PermissionDenied);
+
+        // links are not followed
+        let e = open_file(&root, &root_path, "link.txt").err().unwrap();
+        assert_eq!(e.kind(), io::ErrorKind::PermissionDenied);
+
+        // a link to a directory fails as not being a directory
+        assert!(open_file(&root, &root_path, "linkdir/secret.txt").is_err());
+
+        // not a file
+        let e = open_file(&root, &root_path, "sub").err().unwrap();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidInput);
+
+        let e = open_file(&root, &root_path, ".").err().unwrap();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidInput);
+
+        let e = open_file(&root, &root_path, "missing.txt").err().unwrap();
+        assert_eq!(e.kind(), io::ErrorKind::NotFound);
+
+        fs::remove_dir_all(&base).unwrap();
+    }
+}
@@ -15,27 +15,30 @@
  */
 
 use crate::arena;
+use crate::buffer::{BufferPool, FilledBuf};
 use crate::channel;
 use crate::event::{self, ReadinessExt};
+use crate::filepool;
 use crate::net::{NetListener, NetStream, SocketAddr};
+use crate::platform::{self, UnixListener, UnixSocketAddr, UnixStream};
 use crate::reactor::{CustomEvented, FdEvented, IoEvented, Reactor, Registration, TimerEvented};
 use crate::resolver;
 use crate::shuffle::shuffle;
 use crate::tls::{TlsStream, TlsStreamError, VerifyMode};
 use crate::waker::{RefWake, RefWaker, RefWakerData};
 use crate::zmq::{MultipartHeader, ZmqSocket};
-use mio::net::{TcpListener, TcpStream, UnixListener, UnixStream};
+use mio::net::{TcpListener, TcpStream};
 use openssl::ssl;
 use paste::paste;
 use std::cell::{Cell, Ref, RefCell};
+use std::fs::File;
 use std::future::Future;
 use std::io::{self, Read, Write};
 use std::mem;
-use std::os::fd::{FromRawFd, IntoRawFd};
 use std::path::Path;
 use std::pin::Pin;
 use std::rc::Rc;
-use std::sync::mpsc;
+use std::sync::{mpsc, Arc};
 use std::task::{Context, Poll, Waker};
 use std::time::{Duration, Instant};
 
@@ -244,6 +247,11 @@ pub trait AsyncRead: Unpin {
     ) -> Poll<Result<usize, io::Error>>;
 
     fn cancel(&mut self);
+
+    // stop or resume watching for readability. while paused, incoming data
+    // is left with the kernel, so that the peer becomes subject to flow
+    // control. sources that can't be paused ignore this
+    fn set_read_paused(&mut self, _paused: bool) {}
 }
 
 pub trait AsyncWrite: Unpin {
@@ -269,6 +277,19 @@ pub trait AsyncWrite: Unpin {
 
     fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), io::Error>>;
 
+    // write up to size bytes of the file at offset, without copying through
+    // userspace. streams that can't do this, such as tls streams, return
+    // an Unsupported error, and the caller must copy the data instead
+    fn poll_write_file(
+        self: Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+        _file: &File,
+        _offset: u64,
+        _size: usize,
+    ) -> Poll<Result<usize, io::Error>> {
+        Poll::Ready(Err(io::Error::from(io::ErrorKind::Unsupported)))
+    }
+
     // for use with std Write
     fn is_writable(&self) -> bool;
 
@@ -287,6 +308,10 @@ impl<T: ?Sized + AsyncRead> AsyncRead for &mut T {
     fn cancel(&mut self) {
         AsyncRead::cancel(&mut **self)
     }
+
+    fn set_read_paused(&mut self, paused: bool) {
+        AsyncRead::set_read_paused(&mut **self, paused)
+    }
 }
 
 impl<T: ?Sized + AsyncWrite> AsyncWrite for &mut T {
@@ -310,6 +335,16 @@ impl<T: ?Sized + AsyncWrite> AsyncWrite for &mut T {
         Pin::new(&mut **self).poll_close(cx)
     }
 
+    fn poll_write_file(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        file: &File,
+        offset: u64,
+        size: usize,
+    ) -> Poll<Result<usize, io::Error>> {
+        Pin::new(&mut **self).poll_write_file(cx, file, offset, size)
+    }
+
     fn is_writable(&self) -> bool {
         AsyncWrite::is_writable(&**self)
     }
@@ -323,6 +358,12 @@ pub trait AsyncReadExt: AsyncRead {
     fn read<'a>(&'a mut self, buf: &'a mut [u8]) -> ReadFuture<'a, Self> {
         ReadFuture { r: self, buf }
     }
+
+    // read into a buffer taken from the pool. the buffer is only held while
+    // attempting a read, so that nothing is held while waiting for data
+    fn read_pooled<'a>(&'a mut self, pool: &'a BufferPool) -> ReadPooledFuture<'a, Self> {
+        ReadPooledFuture { r: self, pool }
+    }
 }
 
 pub trait AsyncWriteExt: AsyncWrite {
@@ -355,6 +396,20 @@ pub trait AsyncWriteExt: AsyncWrite {
     {
         WriteSharedFuture { w: self, buf }
     }
+
+    fn write_file<'a>(
+        &'a mut self,
+        file: &'a File,
+        offset: u64,
+        size: usize,
+    ) -> WriteFileFuture<'a, Self> {
+        WriteFileFuture {
+            w: self,
+            file,
+            offset,
+            size,
+        }
+    }
 }
 
 impl<R: AsyncRead + ?Sized> AsyncReadExt for R {}
@@ -409,6 +464,10 @@ impl<T: AsyncRead> AsyncRead for ReadHalf<'_, T> {
     fn cancel(&mut self) {
         self.handle.borrow_mut().cancel();
     }
+
+    fn set_read_paused(&mut self, paused: bool) {
+        self.handle.borrow_mut().set_read_paused(paused);
+    }
 }
 
 pub struct WriteHalf<'a, T: AsyncWrite> {
@@ -442,6 +501,18 @@ impl<T: AsyncWrite> AsyncWrite for WriteHalf<'_, T> {
         Pin::new(&mut *handle).poll_close(cx)
     }
 
+    fn poll_write_file(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        file: &File,
+        offset: u64,
+        size: usize,
+    ) -> Poll<Result<usize, io::Error>> {
+        let mut handle = self.handle.borrow_mut();
+
+        Pin::new(&mut *handle).poll_write_file(cx, file, offset, size)
+    }
+
     fn is_writable(&self) -> bool {
         self.handle.borrow().is_writable()
     }
@@ -588,6 +659,11 @@ impl<T> AsyncLocalSender<T> {
         self.inner.try_send(t)
     }
 
+    // unlike check_send, this doesn't add the sender to the wait list
+    pub fn is_full(&self) -> bool {
+        self.inner.len() >= self.inner.capacity()
+    }
+
     pub fn cancel(&self) {
         self.inner.cancel();
     }
@@ -651,6 +727,48 @@ impl<'a> AsyncResolver<'a> {
     }
 }
 
+pub struct AsyncFilePool<'a> {
+    pool: &'a filepool::FilePool,
+}
+
+impl<'a> AsyncFilePool<'a> {
+    pub fn new(pool: &'a filepool::FilePool) -> Self {
+        Self { pool }
+    }
+
+    // returns the file and its size
+    pub async fn open(&self, path: &str) -> Result<(File, u64), io::Error> {
+        match self.run(filepool::Task::Open(path.to_string())).await? {
+            filepool::Output::Opened(file, len) => Ok((file, len)),
+            _ => unreachable!(),
+        }
+    }
+
+    // returns the buffer, holding what was read
+    pub async fn read(
+        &self,
+        file: &Arc<File>,
+        offset: u64,
+        size: usize,
+        buf: Vec<u8>,
+    ) -> Result<Vec<u8>, io::Error> {
+        match self
+            .run(filepool::Task::Read(Arc::clone(file), offset, size, buf))
+            .await?
+        {
+            filepool::Output::Read(buf) => Ok(buf),
+            _ => unreachable!(),
+        }
+    }
+
+    fn run(&self, task: filepool::Task) -> FileJobFuture {
+        FileJobFuture {
+            evented: None,
+            job: self.pool.run(task).ok(),
+        }
+    }
+}
+
 pub struct AsyncTcpListener {
     evented: IoEvented<TcpListener>,
 }
@@ -702,7 +820,7 @@ impl AsyncUnixListener {
         Ok(Self::new(listener))
     }
 
-    pub fn local_addr(&self) -> Result<mio::net::SocketAddr, io::Error> {
+    pub fn local_addr(&self) -> Result<UnixSocketAddr, io::Error> {
         self.evented.io().local_addr()
     }
 
@@ -789,12 +907,22 @@ impl AsyncTcpStream {
         self.evented.io().peer_addr()
     }
 
+    // wait until more than known bytes of incoming data are available, and
+    // copy them without consuming them. returns 0 at eof
+    pub fn peek<'a>(&'a self, buf: &'a mut [u8], known: usize) -> TcpPeekFuture<'a> {
+        TcpPeekFuture {
+            s: self,
+            buf,
+            known,
+        }
+    }
+
     pub fn into_inner(self) -> TcpStream {
         self.evented.into_inner()
     }
 
     pub fn into_std(self) -> std::net::TcpStream {
-        unsafe { std::net::TcpStream::from_raw_fd(self.evented.into_inner().into_raw_fd()) }
+        platform::tcp_stream_into_std(self.evented.into_inner())
     }
 
     // assumes stream is in non-blocking mode
@@ -1054,9 +1182,7 @@ impl<'a: 'b, 'b> AsyncTlsStream<'a> {
             .deregister_io(stream.get_inner())
             .unwrap();
 
-        stream.change_inner(|stream| unsafe {
-            std::net::TcpStream::from_raw_fd(stream.into_raw_fd())
-        })
+        stream.change_inner(platform::tcp_stream_into_std)
     }
 
     // assumes stream is in non-blocking mode
@@ -1122,6 +1248,34 @@ impl Timeout {
     pub fn elapsed(&self) -> TimeoutFuture<'_> {
         TimeoutFuture { t: self }
     }
+
+    // for use outside of a future. the waker remains registered until
+    // cancel is called
+    pub fn poll_elapsed(&self, cx: &mut Context) -> Poll<()> {
+        let evented = &self.evented;
+
+        evented
+            .registration()
+            .set_waker(cx.waker(), mio::Interest::READABLE);
+
+        if !evented.registration().is_ready() {
+            return Poll::Pending;
+        }
+
+        let now = get_reactor().now();
+
+        if now >= evented.expires() {
+            Poll::Ready(())
+        } else {
+            evented.registration().set_ready(false);
+
+            Poll::Pending
+        }
+    }
+
+    pub fn cancel(&self) {
+        self.evented.registration().clear_waker();
+    }
 }
 
 pub struct CancellationSender {
@@ -1213,7 +1367,11 @@ impl AsyncZmqSocket {
     }
 
     pub fn send(&self, msg: zmq::Message) -> ZmqSendFuture<'_> {
-        ZmqSendFuture { s: self, msg }
+        ZmqSendFuture {
+            s: self,
+            msg,
+            blocked_since: None,
+        }
     }
 
     pub fn send_to(&self, header: MultipartHeader, content: zmq::Message) -> ZmqSendToFuture<'_> {
@@ -1222,6 +1380,7 @@ impl AsyncZmqSocket {
             header,
             content,
             timer_evented: None,
+            blocked_since: None,
         }
     }
 
@@ -1576,6 +1735,73 @@ impl Drop for QueryFuture {
     }
 }
 
+pub struct FileJobFuture {
+    evented: Option<CustomEvented>,
+    job: Option<filepool::Job>,
+}
+
+impl Future for FileJobFuture {
+    type Output = Result<filepool::Output, io::Error>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        let f = &mut *self;
+
+        let job = match &f.job {
+            Some(job) => job,
+            None => return Poll::Ready(Err(io::Error::from(io::ErrorKind::OutOfMemory))),
+        };
+
+        let evented = match &f.evented {
+            Some(evented) => evented,
+            None => {
+                let evented = CustomEvented::new(
+                    job.get_read_registration(),
+                    mio::Interest::READABLE,
+                    &get_reactor(),
+                )
+                .unwrap();
+
+                evented.registration().set_ready(true);
+
+                f.evented = Some(evented);
+
+                f.evented.as_ref().unwrap()
+            }
+        };
+
+        evented
+            .registration()
+            .set_waker(cx.waker(), mio::Interest::READABLE);
+
+        if !evented.registration().is_ready() {
+            return Poll::Pending;
+        }
+
+        match job.process() {
+            Some(ret) => Poll::Ready(ret),
+            None => {
+                evented.registration().set_ready(false);
+
+                Poll::Pending
+            }
+        }
+    }
+}
+
+impl Drop for FileJobFuture {
+    fn drop(&mut self) {
+        if let Some(evented) = &self.evented {
+            let job = self.job.as_ref().unwrap();
+
+            // the job's registration outlives the job, as with queries
+            evented
+                .registration()
+                .deregister_custom(job.get_read_registration())
+                .unwrap();
+        }
+    }
+}
+
 pub struct AcceptFuture<'a> {
     l: &'a AsyncTcpListener,
 }
@@ -1621,7 +1847,7 @@ pub struct UnixAcceptFuture<'a> {
 }
 
 impl Future for UnixAcceptFuture<'_> {
-    type Output = Result<(UnixStream, mio::net::SocketAddr), io::Error>;
+    type Output = Result<(UnixStream, UnixSocketAddr), io::Error>;
 
     fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
         let f = &mut *self;
@@ -1713,6 +1939,43 @@ impl<'a, R: AsyncRead + ?Sized> Drop for ReadFuture<'a, R> {
     }
 }
 
+pub struct ReadPooledFuture<'a, R: AsyncRead + ?Sized> {
+    r: &'a mut R,
+    pool: &'a BufferPool,
+}
+
+impl<'a, R: AsyncRead + ?Sized> Future for ReadPooledFuture<'a, R> {
+    type Output = Result<FilledBuf, io::Error>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        let f = &mut *self;
+
+        let mut buf = f.pool.take();
+
+        let r: Pin<&mut R> = Pin::new(f.r);
+
+        match r.poll_read(cx, &mut buf) {
+            Poll::Ready(Ok(size)) => Poll::Ready(Ok(FilledBuf::new(buf, size))),
+            Poll::Ready(Err(e)) => {
+                f.pool.give(buf);
+
+                Poll::Ready(Err(e))
+            }
+            Poll::Pending => {
+                f.pool.give(buf);
+
+                Poll::Pending
+            }
+        }
+    }
+}
+
+impl<'a, R: AsyncRead + ?Sized> Drop for ReadPooledFuture<'a, R> {
+    fn drop(&mut self) {
+        self.r.cancel();
+    }
+}
+
 pub struct WriteFuture<'a, W: AsyncWrite + ?Sized + Unpin> {
     w: &'a mut W,
     buf: &'a [u8],
@@ -1748,6 +2011,31 @@ impl<'a, W: AsyncWrite + ?Sized> Drop for WriteFuture<'a, W> {
     }
 }
 
+pub struct WriteFileFuture<'a, W: AsyncWrite + ?Sized + Unpin> {
+    w: &'a mut W,
+    file: &'a File,
+    offset: u64,
+    size: usize,
+}
+
+impl<'a, W: AsyncWrite + ?Sized> Future for WriteFileFuture<'a, W> {
+    type Output = Result<usize, io::Error>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        let f = &mut *self;
+
+        let w: Pin<&mut W> = Pin::new(f.w);
+
+        w.poll_write_file(cx, f.file, f.offset, f.size)
+    }
+}
+
+impl<'a, W: AsyncWrite + ?Sized> Drop for WriteFileFuture<'a, W> {
+    fn drop(&mut self) {
+        self.w.cancel();
+    }
+}
+
 pub struct CloseFuture<'a, W: AsyncWrite + ?Sized> {
     w: &'a mut W,
 }
@@ -1905,6 +2193,64 @@ impl Drop for TcpConnectFuture<'_> {
     }
 }
 
+pub struct TcpPeekFuture<'a> {
+    s: &'a AsyncTcpStream,
+    buf: &'a mut [u8],
+    known: usize,
+}
+
+impl Future for TcpPeekFuture<'_> {
+    type Output = Result<usize, io::Error>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        let f = &mut *self;
+
+        f.s.evented
+            .registration()
+            .set_waker(cx.waker(), mio::Interest::READABLE);
+
+        if !f
+            .s
+            .evented
+            .registration()
+            .readiness()
+            .contains_any(mio::Interest::READABLE)
+        {
+            return Poll::Pending;
+        }
+
+        match f.s.evented.io().peek(f.buf) {
+            Ok(size) if size == 0 || size > f.known => Poll::Ready(Ok(size)),
+            // nothing new. peeking leaves the data in place, so wait for the
+            // next readiness indication as if the read would have blocked
+            Ok(_) => {
+                f.s.evented
+                    .registration()
+                    .clear_readiness(mio::Interest::READABLE);
+
+                Poll::Pending
+            }
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                f.s.evented
+                    .registration()
+                    .clear_readiness(mio::Interest::READABLE);
+
+                Poll::Pending
+            }
+            Err(e) => Poll::Ready(Err(e)),
+        }
+    }
+}
+
+impl Drop for TcpPeekFuture<'_> {
+    fn drop(&mut self) {
+        self.s
+            .evented
+            .registration()
+            .clear_waker_interest(mio::Interest::READABLE);
+    }
+}
+
 pub struct UnixConnectFuture<'a> {
     s: &'a mut AsyncUnixStream,
 }
@@ -1993,6 +2339,17 @@ impl AsyncRead for AsyncTcpStream {
             .registration()
             .clear_waker_interest(mio::Interest::READABLE);
     }
+
+    fn set_read_paused(&mut self, paused: bool) {
+        let interest = if paused {
+            mio::Interest::WRITABLE
+        } else {
+            mio::Interest::READABLE | mio::Interest::WRITABLE
+        };
+
+        // if this fails, the socket is broken and subsequent I/O will fail
+        let _ = self.evented.set_interest(interest);
+    }
 }
 
 impl AsyncWrite for AsyncTcpStream {
@@ -2070,6 +2427,45 @@ impl AsyncWrite for AsyncTcpStream {
         }
     }
 
+    fn poll_write_file(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context,
+        file: &File,
+        offset: u64,
+        size: usize,
+    ) -> Poll<Result<usize, io::Error>> {
+        let f = &mut *self;
+
+        f.evented
+            .registration()
+            .set_waker(cx.waker(), mio::Interest::WRITABLE);
+
+        if !f
+            .evented
+            .registration()
+            .readiness()
+            .contains_any(mio::Interest::WRITABLE)
+        {
+            return Poll::Pending;
+        }
+
+        if !f.evented.registration().pull_from_budget() {
+            return Poll::Pending;
+        }
+
+        match platform::send_file(f.evented.io(), file, offset, size) {
+            Ok(size) => Poll::Ready(Ok(size)),
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                f.evented
+                    .registration()
+                    .clear_readiness(mio::Interest::WRITABLE);
+
+                Poll::Pending
+            }
+            Err(e) => Poll::Ready(Err(e)),
+        }
+    }
+
     fn poll_close(self: Pin<&mut Self>, _cx: &mut Context) -> Poll<Result<(), io::Error>> {
         Poll::Ready(Ok(()))
     }
@@ -2131,6 +2527,17 @@ impl AsyncRead for AsyncUnixStream {
             .registration()
             .clear_waker_interest(mio::Interest::READABLE);
     }
+
+    fn set_read_paused(&mut self, paused: bool) {
+        let interest = if paused {
+            mio::Interest::WRITABLE
+        } else {
+            mio::Interest::READABLE | mio::Interest::WRITABLE
+        };
+
+        // if this fails, the socket is broken and subsequent I/O will fail
+        let _ = self.evented.set_interest(interest);
+    }
 }
 
 impl AsyncWrite for AsyncUnixStream {
@@ -2208,6 +2615,45 @@ impl AsyncWrite for AsyncUnixStream {
         }
     }
 
+    fn poll_write_file(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context,
+        file: &File,
+        offset: u64,
+        size: usize,
+    ) -> Poll<Result<usize, io::Error>> {
+        let f = &mut *self;
+
+        f.evented
+            .registration()
+            .set_waker(cx.waker(), mio::Interest::WRITABLE);
+
+        if !f
+            .evented
+            .registration()
+            .readiness()
+            .contains_any(mio::Interest::WRITABLE)
+        {
+            return Poll::Pending;
+        }
+
+        if !f.evented.registration().pull_from_budget() {
+            return Poll::Pending;
+        }
+
+        match platform::send_file(f.evented.io(), file, offset, size) {
+            Ok(size) => Poll::Ready(Ok(size)),
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                f.evented
+                    .registration()
+                    .clear_readiness(mio::Interest::WRITABLE);
+
+                Poll::Pending
+            }
+            Err(e) => Poll::Ready(Err(e)),
+        }
+    }
+
     fn poll_close(self: Pin<&mut Self>, _cx: &mut Context) -> Poll<Result<(), io::Error>> {
         Poll::Ready(Ok(()))
     }
@@ -2270,6 +2716,22 @@ impl AsyncRead for AsyncTlsStream<'_> {
 
         op.clear_waker();
     }
+
+    fn set_read_paused(&mut self, paused: bool) {
+        let interest = if paused {
+            mio::Interest::WRITABLE
+        } else {
+            mio::Interest::READABLE | mio::Interest::WRITABLE
+        };
+
+        let registration = self.waker.registration();
+        let stream = self.stream.as_mut().unwrap();
+
+        // a write that needs to read, such as during renegotiation, waits
+        // until reads resume. if this fails, the socket is broken and
+        // subsequent I/O will fail
+        let _ = registration.reregister_io(stream.get_inner(), interest);
+    }
 }
 
 impl AsyncWrite for AsyncTlsStream<'_> {
@@ -2423,31 +2885,13 @@ impl Future for TimeoutFuture<'_> {
     type Output = ();
 
     fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
-        let evented = &self.t.evented;
-
-        evented
-            .registration()
-            .set_waker(cx.waker(), mio::Interest::READABLE);
-
-        if !evented.registration().is_ready() {
-            return Poll::Pending;
-        }
-
-        let now = get_reactor().now();
-
-        if now >= evented.expires() {
-            Poll::Ready(())
-        } else {
-            evented.registration().set_ready(false);
-
-            Poll::Pending
-        }
+        self.t.poll_elapsed(cx)
     }
 }
 
 impl Drop for TimeoutFuture<'_> {
     fn drop(&mut self) {
-        self.t.evented.registration().clear_waker();
+        self.t.cancel();
     }
 }
 
@@ -2482,6 +2926,21 @@ impl Drop for CancelledFuture<'_> {
 pub struct ZmqSendFuture<'a> {
     s: &'a AsyncZmqSocket,
     msg: zmq::Message,
+    blocked_since: Option<Instant>,
+}
+
+impl ZmqSendFuture<'_> {
+    // when the message started waiting for the socket to become writable,
+    // for example because the high water mark was reached
+    pub fn blocked_since(&self) -> Option<Instant> {
+        self.blocked_since
+    }
+
+    fn set_blocked(&mut self) {
+        if self.blocked_since.is_none() {
+            self.blocked_since = Some(self.s.evented.registration().reactor().now());
+        }
+    }
 }
 
 impl Future for ZmqSendFuture<'_> {
@@ -2500,6 +2959,8 @@ impl Future for ZmqSendFuture<'_> {
         }
 
         if !f.s.inner.events().contains(zmq::POLLOUT) {
+            f.set_blocked();
+
             return Poll::Pending;
         }
 
@@ -2510,7 +2971,11 @@ impl Future for ZmqSendFuture<'_> {
 
         match f.s.inner.send(msg, zmq::DONTWAIT) {
             Ok(()) => Poll::Ready(Ok(())),
-            Err(zmq::Error::EAGAIN) => Poll::Pending,
+            Err(zmq::Error::EAGAIN) => {
+                f.set_blocked();
+
+                Poll::Pending
+            }
             Err(e) => Poll::Ready(Err(e)),
         }
     }
@@ -2527,6 +2992,21 @@ pub struct ZmqSendToFuture<'a> {
     header: MultipartHeader,
     content: zmq::Message,
     timer_evented: Option<TimerEvented>,
+    blocked_since: Option<Instant>,
+}
+
+impl ZmqSendToFuture<'_> {
+    // when the message started waiting for the socket or the peer to become
+    // writable, for example because the high water mark was reached
+    pub fn blocked_since(&self) -> Option<Instant> {
+        self.blocked_since
+    }
+
+    fn set_blocked(&mut self) {
+        if self.blocked_since.is_none() {
+            self.blocked_since = Some(self.s.evented.registration().reactor().now());
+        }
+    }
 }
 
 impl Future for ZmqSendToFuture<'_> {
@@ -2563,6 +3043,8 @@ impl Future for ZmqSendToFuture<'_> {
         }
 
         if !f.s.inner.events().contains(zmq::POLLOUT) {
+            f.set_blocked();
+
             return Poll::Pending;
         }
 
@@ -2574,6 +3056,8 @@ impl Future for ZmqSendToFuture<'_> {
         match f.s.inner.send_to(&f.header, content, zmq::DONTWAIT) {
             Ok(()) => Poll::Ready(Ok(())),
             Err(zmq::Error::EAGAIN) => {
+                f.set_blocked();
+
                 if let Some(timeout) = f.s.timeout.get() {
                     let expires = reactor.now() + timeout;
                     let timer_evented = TimerEvented::new(expires, &reactor).unwrap();
@@ -2806,7 +3290,7 @@ mod tests {
     use super::*;
     use crate::executor::Executor;
     use crate::tls::TlsAcceptor;
-    use crate::zmq::SpecInfo;
+    use crate::zmq::{SpecInfo, SpecOpts};
     use std::cmp;
     use std::fs;
     use std::mem;
@@ -3219,7 +3703,7 @@ mod tests {
 
         executor
             .spawn(async {
-                let resolver = resolver::Resolver::new(1, 1);
+                let resolver = resolver::Resolver::new(1, 1).unwrap();
                 let resolver = AsyncResolver::new(&resolver);
 
                 let f1 = resolver.resolve("127.0.0.1");
@@ -3285,6 +3769,167 @@ mod tests {
         executor.run(|timeout| reactor.poll(timeout)).unwrap();
     }
 
+    #[test]
+    fn test_tcpstream_write_file() {
+        let path = std::env::temp_dir().join(format!("condure-write-file-{}", std::process::id()));
+        fs::write(&path, "hello world").unwrap();
+        let file = File::open(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        let reactor = Reactor::new(3); // 3 registrations
+        let executor = Executor::new(2); // 2 tasks
+
+        let spawner = executor.spawner();
+
+        executor
+            .spawn(async move {
+                let addr = "127.0.0.1:0".parse().unwrap();
+                let listener = AsyncTcpListener::bind(addr).expect("failed to bind");
+                let addr = listener.local_addr().unwrap();
+
+                spawner
+                    .spawn(async move {
+                        let mut stream = AsyncTcpStream::connect(&[addr]).await.unwrap();
+
+                        let size = stream.write_file(&file, 6, 100).await.unwrap();
+                        assert_eq!(size, 5);
+                    })
+                    .unwrap();
+
+                let (stream, _) = listener.accept().await.unwrap();
+                let mut stream = AsyncTcpStream::new(stream);
+
+                let mut resp = Vec::new();
+
+                loop {
+                    let mut buf = [0; 1024];
+
+                    let size = stream.read(&mut buf).await.unwrap();
+                    if size == 0 {
+                        break;
+                    }
+
+                    resp.extend(&buf[..size]);
+                }
+
+                assert_eq!(str::from_utf8(&resp).unwrap(), "world");
+            })
+            .unwrap();
+
+        executor.run(|timeout| reactor.poll(timeout)).unwrap();
+    }
+
+    #[test]
+    fn test_tcpstream_peek() {
+        let reactor = Reactor::new(5); // 5 registrations
+        let executor = Executor::new(2); // 2 tasks
+
+        let spawner = executor.spawner();
+
+        let (s_sent, r_sent) =
+            channel::local_channel::<()>(1, 1, &reactor.local_registration_memory());
+
+        let s_sent = AsyncLocalSender::new(s_sent);
+        let r_sent = AsyncLocalReceiver::new(r_sent);
+
+        executor
+            .spawn(async move {
+                let addr = "127.0.0.1:0".parse().unwrap();
+                let listener = AsyncTcpListener::bind(addr).expect("failed to bind");
+                let addr = listener.local_addr().unwrap();
+
+                spawner
+                    .spawn(async move {
+                        let mut stream = AsyncTcpStream::connect(&[addr]).await.unwrap();
+
+                        let size = stream.write("hel".as_bytes()).await.unwrap();
+                        assert_eq!(size, 3);
+
+                        // wait for the peer to see the first part
+                        r_sent.recv().await.unwrap();
+
+                        let size = stream.write("lo".as_bytes()).await.unwrap();
+                        assert_eq!(size, 2);
+                    })
+                    .unwrap();
+
+                let (stream, _) = listener.accept().await.unwrap();
+                let mut stream = AsyncTcpStream::new(stream);
+
+                let mut buf = [0; 1024];
+
+                let size = stream.peek(&mut buf, 0).await.unwrap();
+                assert_eq!(&buf[..size], b"hel");
+
+                s_sent.send(()).await.unwrap();
+
+                let size = stream.peek(&mut buf, size).await.unwrap();
+                assert_eq!(&buf[..size], b"hello");
+
+                // the data was not consumed
+                let size = stream.read(&mut buf).await.unwrap();
+                assert_eq!(&buf[..size], b"hello");
+            })
+            .unwrap();
+
+        executor.run(|timeout| reactor.poll(timeout)).unwrap();
+    }
+
+    #[test]
+    fn test_tcpstream_read_pooled() {
+        let reactor = Reactor::new(5); // 5 registrations
+        let executor = Executor::new(2); // 2 tasks
+
+        let spawner = executor.spawner();
+
+        let (s_waiting, r_waiting) =
+            channel::local_channel::<()>(1, 1, &reactor.local_registration_memory());
+
+        let s_waiting = AsyncLocalSender::new(s_waiting);
+        let r_waiting = AsyncLocalReceiver::new(r_waiting);
+
+        executor
+            .spawn(async move {
+                let addr = "127.0.0.1:0".parse().unwrap();
+                let listener = AsyncTcpListener::bind(addr).expect("failed to bind");
+                let addr = listener.local_addr().unwrap();
+
+                spawner
+                    .spawn(async move {
+                        let mut stream = AsyncTcpStream::connect(&[addr]).await.unwrap();
+
+                        // wait for the peer to start reading
+                        r_waiting.recv().await.unwrap();
+
+                        let size = stream.write("hello".as_bytes()).await.unwrap();
+                        assert_eq!(size, 5);
+                    })
+                    .unwrap();
+
+                let (stream, _) = listener.accept().await.unwrap();
+                let mut stream = AsyncTcpStream::new(stream);
+
+                let pool = BufferPool::new(1024, 1);
+
+                let mut fut = stream.read_pooled(&pool);
+
+                assert!(poll_async(&mut fut).await.is_pending());
+
+                // the buffer is not held while waiting
+                assert_eq!(pool.len(), 1);
+
+                s_waiting.send(()).await.unwrap();
+
+                let buf = fut.await.unwrap();
+                assert_eq!(buf.filled(), b"hello");
+                assert_eq!(pool.len(), 0);
+            })
+            .unwrap();
+
+        executor.run(|timeout| reactor.poll(timeout)).unwrap();
+    }
+
+    #[cfg(unix)]
     #[test]
     fn test_unixstream() {
         // ensure pipe file doesn't exist
@@ -3507,6 +4152,7 @@ mod tests {
             spec: spec.to_string(),
             bind: true,
             ipc_file_mode: 0,
+            opts: SpecOpts::default(),
         }])
         .unwrap();
 
@@ -3516,6 +4162,7 @@ mod tests {
             spec: spec.to_string(),
             bind: false,
             ipc_file_mode: 0,
+            opts: SpecOpts::default(),
         }])
         .unwrap();
 
@@ -3525,6 +4172,7 @@ mod tests {
             spec: spec.to_string(),
             bind: false,
             ipc_file_mode: 0,
+            opts: SpecOpts::default(),
         }])
         .unwrap();
 
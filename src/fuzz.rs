@@ -0,0 +1,193 @@
+/*
+ * Copyright (C) 2024 Fanout, Inc.
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+// entry points for fuzzing the parsers. each accepts arbitrary input and
+// drives a parser over it until the input is exhausted or rejected. inputs
+// are truncated to INPUT_MAX and parsing uses fixed size buffers, so the
+// work done per call is bounded and doesn't depend on anything other than
+// the input
+
+use crate::buffer::{RefRead, RingBuffer, TmpBuffer};
+use crate::http1;
+use crate::websocket;
+use crate::zhttppacket::{self, PacketParse};
+use std::cmp;
+use std::io;
+use std::rc::Rc;
+
+pub const INPUT_MAX: usize = 64 * 1024;
+
+const HEADERS_MAX: usize = 64;
+const BUF_SIZE: usize = 1024;
+
+fn truncate(data: &[u8]) -> &[u8] {
+    &data[..cmp::min(data.len(), INPUT_MAX)]
+}
+
+// parse a request header followed by its body, if any
+pub fn http1_request(data: &[u8]) {
+    let data = truncate(data);
+
+    let mut p = http1::ServerProtocol::new();
+    let mut rbuf = io::Cursor::new(data);
+
+    {
+        let mut headers = [httparse::EMPTY_HEADER; HEADERS_MAX];
+
+        match p.recv_request(&mut rbuf, &mut headers) {
+            Some(Ok(_)) => {}
+            _ => return,
+        }
+    }
+
+    let mut dest = [0; BUF_SIZE];
+
+    while p.state() == http1::ServerState::ReceivingBody {
+        let pos = rbuf.position();
+
+        let mut headers = [httparse::EMPTY_HEADER; HEADERS_MAX];
+
+        match p.recv_body(&mut rbuf, &mut dest, &mut headers) {
+            Ok((size, _)) => {
+                // stop if no progress
+                if size == 0 && rbuf.position() == pos {
+                    break;
+                }
+            }
+            Err(_) => break,
+        }
+    }
+}
+
+fn websocket_recv<T: AsRef<[u8]> + AsMut<[u8]>>(p: &websocket::Protocol<T>, data: &mut [u8]) {
+    let mut rbuf = io::Cursor::new(data);
+    let mut dest = [0; BUF_SIZE];
+
+    // receiving is only allowed in these states
+    while let websocket::State::Connected | websocket::State::Closing = p.state() {
+        let avail = rbuf.len();
+
+        match p.recv_message_content(&mut rbuf, &mut dest) {
+            Some(Ok((_, size, _))) => {
+                // stop if no progress
+                if size == 0 && rbuf.len() == avail {
+                    break;
+                }
+            }
+            Some(Err(_)) | None => break,
+        }
+    }
+}
+
+// read frames as message content. the first byte of the input selects
+// whether compression is enabled, and the rest is the frame data
+pub fn websocket_frames(data: &[u8]) {
+    let (deflate, data) = match truncate(data).split_first() {
+        Some((b, rest)) => (b & 0x01 != 0, rest),
+        None => return,
+    };
+
+    // frames are unmasked in place
+    let mut data = data.to_vec();
+
+    if deflate {
+        let tmp = Rc::new(TmpBuffer::new(BUF_SIZE));
+        let p = websocket::Protocol::new(Some((true, RingBuffer::new(BUF_SIZE, &tmp))));
+
+        websocket_recv(&p, &mut data);
+    } else {
+        let p = websocket::Protocol::<[u8; 0]>::new(None);
+
+        websocket_recv(&p, &mut data);
+    }
+}
+
+// parse the input as both a zhttp request and a zhttp response
+pub fn zhttp_packet(data: &[u8]) {
+    let data = truncate(data);
+
+    {
+        let mut scratch = zhttppacket::ParseScratch::new();
+        let _ = zhttppacket::Request::parse(data, &mut scratch);
+    }
+
+    {
+        let mut scratch = zhttppacket::ParseScratch::new();
+        let _ = zhttppacket::Response::parse(data, &mut scratch);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // deterministic garbage, to check that nothing panics
+    fn noise(seed: u32, len: usize) -> Vec<u8> {
+        let mut x = seed;
+
+        (0..len)
+            .map(|_| {
+                x ^= x << 13;
+                x ^= x >> 17;
+                x ^= x << 5;
+                x as u8
+            })
+            .collect()
+    }
+
+    #[test]
+    fn http1() {
+        http1_request(b"");
+        http1_request(b"GET / HTTP/1.1\r\nHost: example.com\r\n\r\n");
+        http1_request(b"POST / HTTP/1.1\r\nContent-Length: 5\r\n\r\nhello");
+        http1_request(
+            b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n5\r\nhello\r\n0\r\n\r\n",
+        );
+        http1_request(b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\nzz\r\n");
+
+        for seed in 1..100 {
+            let mut data = b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n".to_vec();
+            data.extend(noise(seed, 64));
+            http1_request(&data);
+        }
+    }
+
+    #[test]
+    fn websocket() {
+        websocket_frames(b"");
+        websocket_frames(b"\x00\x81\x05hello");
+        websocket_frames(b"\x00\x01\x03hel\x80\x02lo");
+        websocket_frames(b"\x00\x88\x02\x03\xe8\x81\x05hello");
+        websocket_frames(b"\x01\xc1\x07\xf2\x48\xcd\xc9\xc9\x07\x00");
+
+        for seed in 1..100 {
+            websocket_frames(&noise(seed, 64));
+        }
+    }
+
+    #[test]
+    fn zhttp() {
+        zhttp_packet(b"");
+        zhttp_packet(b"T17:2:id,1:1,4:from,0:,}");
+        zhttp_packet(b"T10:4:body,1:");
+
+        for seed in 1..100 {
+            let mut data = b"T".to_vec();
+            data.extend(noise(seed, 64));
+            zhttp_packet(&data);
+        }
+    }
+}
@@ -53,50 +53,55 @@ fn parse_as_int(src: &[u8]) -> Result<usize, io::Error> {
     Ok(x)
 }
 
+fn trim_spaces(s: &[u8]) -> &[u8] {
+    let start = s.iter().position(|b| !b.is_ascii_whitespace());
+    let end = s.iter().rposition(|b| !b.is_ascii_whitespace());
+
+    match (start, end) {
+        (Some(start), Some(end)) => &s[start..=end],
+        _ => &[],
+    }
+}
+
+// compare as bytes, to avoid validating utf-8 for every header checked
 fn header_contains_param(value: &[u8], param: &[u8], ignore_case: bool) -> bool {
-    let param_str = str::from_utf8(param);
-    let param_str = match param_str {
-        Ok(param_str) => param_str,
-        Err(_) => {
-            return false;
-        }
-    };
+    let mut value = value;
 
-    for part in value.split(|b| *b == b',') {
-        let part_str = str::from_utf8(part);
-        let part_str = match part_str {
-            Ok(part_str) => part_str,
-            Err(_) => {
-                continue;
-            }
+    loop {
+        let (part, rest) = match memchr::memchr(b',', value) {
+            Some(pos) => (&value[..pos], Some(&value[(pos + 1)..])),
+            None => (value, None),
         };
 
-        let part_str = part_str.trim();
+        let part = trim_spaces(part);
 
         if ignore_case {
-            if part_str.eq_ignore_ascii_case(param_str) {
+            if part.eq_ignore_ascii_case(param) {
                 return true;
             }
         } else {
-            if part_str == param_str {
+            if part == param {
                 return true;
             }
         }
-    }
 
-    false
+        match rest {
+            Some(rest) => value = rest,
+            None => return false,
+        }
+    }
 }
 
 fn find_one_of(s: &str, values: &[u8]) -> Option<(usize, u8)> {
-    for (pos, &c) in s.as_bytes().iter().enumerate() {
-        for v in values {
-            if c == *v {
-                return Some((pos, c));
-            }
-        }
-    }
+    let s = s.as_bytes();
 
-    None
+    let pos = match *values {
+        [a, b] => memchr::memchr2(a, b, s),
+        [a, b, c] => memchr::memchr3(a, b, c, s),
+        _ => s.iter().position(|c| values.contains(c)),
+    }?;
+
+    Some((pos, s[pos]))
 }
 
 fn find_non_space(s: &str) -> Option<usize> {
@@ -488,8 +493,141 @@ struct OwnedParsedInner<'s, T, const N: usize> {
     size: usize,
 }
 
+// parse an HTTP/0.9 simple request, which is a request line without a
+// version and no headers. returns (method, path, size)
+fn parse_simple_request(buf: &[u8]) -> Option<httparse::Status<(&str, &str, usize)>> {
+    let (line, size) = match buf.iter().position(|b| *b == b'\n') {
+        Some(pos) => {
+            let line = &buf[..pos];
+
+            (line.strip_suffix(b"\r").unwrap_or(line), Some(pos + 1))
+        }
+        None => (buf.strip_suffix(b"\r").unwrap_or(buf), None),
+    };
+
+    let path = line.strip_prefix(b"GET ")?;
+
+    if !path.iter().all(|b| b.is_ascii_graphic()) {
+        return None;
+    }
+
+    match size {
+        Some(size) if !path.is_empty() => {
+            // the checks above ensure these are valid utf-8
+            let method = str::from_utf8(&line[..3]).unwrap();
+            let path = str::from_utf8(path).unwrap();
+
+            Some(httparse::Status::Complete((method, path, size)))
+        }
+        Some(_) => None,
+        None => Some(httparse::Status::Partial),
+    }
+}
+
+// the length of the request target, as far as it has been received
+fn partial_target_len(buf: &[u8]) -> usize {
+    // like httparse, skip empty lines before the request line
+    let start = buf
+        .iter()
+        .position(|b| *b != b'\r' && *b != b'\n')
+        .unwrap_or(buf.len());
+    let buf = &buf[start..];
+
+    let line = match buf.iter().position(|b| *b == b'\n') {
+        Some(pos) => &buf[..pos],
+        None => buf,
+    };
+
+    let mut parts = line.splitn(3, |b| *b == b' ');
+    parts.next();
+
+    parts.next().map(|t| t.len()).unwrap_or(0)
+}
+
+// whether a path segment is a dot segment, allowing for percent-encoded
+// dots
+fn is_dot_segment(mut s: &str) -> bool {
+    let mut dots = 0;
+
+    while !s.is_empty() {
+        if let Some(rest) = s.strip_prefix('.') {
+            s = rest;
+        } else if s.len() >= 3 && s.as_bytes()[..3].eq_ignore_ascii_case(b"%2e") {
+            s = &s[3..];
+        } else {
+            return false;
+        }
+
+        dots += 1;
+    }
+
+    dots == 1 || dots == 2
+}
+
+// whether a path contains a percent-encoded slash or backslash. decoding
+// these would create segments the dot segment check didn't see
+fn has_encoded_separator(path: &str) -> bool {
+    path.as_bytes().windows(3).any(|w| {
+        w[0] == b'%' && (w[1..].eq_ignore_ascii_case(b"2f") || w[1..].eq_ignore_ascii_case(b"5c"))
+    })
+}
+
+// whether a request target is free of things the parser tolerates but that
+// applications may interpret differently: control characters, backslashes,
+// fragments, dot segments, and encoded separators in the path
+fn is_strict_target(uri: &str) -> bool {
+    if uri
+        .bytes()
+        .any(|b| b.is_ascii_control() || b == b'\\' || b == b'#')
+    {
+        return false;
+    }
+
+    let path = match uri.find("://") {
+        Some(pos) if !uri.starts_with('/') => {
+            let rest = &uri[(pos + 3)..];
+
+            match rest.find('/') {
+                Some(pos) => &rest[pos..],
+                None => return true,
+            }
+        }
+        _ => uri,
+    };
+
+    let path = path.split('?').next().unwrap();
+
+    !has_encoded_separator(path) && !path.split('/').any(is_dot_segment)
+}
+
+// parse a request, falling back to a simple request if the request line
+// can't be parsed normally. returns (size, simple)
+fn parse_request<'h, 'b>(
+    req: &mut httparse::Request<'h, 'b>,
+    buf: &'b [u8],
+) -> Result<httparse::Status<(usize, bool)>, httparse::Error> {
+    match req.parse(buf) {
+        Ok(httparse::Status::Complete(size)) => Ok(httparse::Status::Complete((size, false))),
+        Ok(httparse::Status::Partial) => Ok(httparse::Status::Partial),
+        Err(httparse::Error::Token) => match parse_simple_request(buf) {
+            Some(httparse::Status::Complete((method, path, size))) => {
+                req.method = Some(method);
+                req.path = Some(path);
+                req.version = Some(0);
+                req.headers = &mut [];
+
+                Ok(httparse::Status::Complete((size, true)))
+            }
+            Some(httparse::Status::Partial) => Ok(httparse::Status::Partial),
+            None => Err(httparse::Error::Token),
+        },
+        Err(e) => Err(e),
+    }
+}
+
 struct OwnedHttparseRequest<'s, const N: usize> {
     inner: Option<OwnedParsedInner<'s, httparse::Request<'s, 'static>, N>>,
+    simple: bool,
 }
 
 impl<'s, const N: usize> OwnedHttparseRequest<'s, N> {
@@ -522,8 +660,8 @@ impl<'s, const N: usize> OwnedHttparseRequest<'s, N> {
 
         let mut req = httparse::Request::new(headers_mut);
 
-        let size = match req.parse(buf_ref) {
-            Ok(httparse::Status::Complete(size)) => size,
+        let (size, simple) = match parse_request(&mut req, buf_ref) {
+            Ok(httparse::Status::Complete(ret)) => ret,
             Ok(httparse::Status::Partial) => return ParseStatus::Incomplete((), buf, scratch),
             Err(e) => return ParseStatus::Error(e, buf, scratch),
         };
@@ -535,6 +673,7 @@ impl<'s, const N: usize> OwnedHttparseRequest<'s, N> {
                 buf,
                 size,
             }),
+            simple,
         })
     }
 
@@ -777,17 +916,26 @@ pub enum Error {
 
     #[error("invalid chunk suffix")]
     InvalidChunkSuffix,
+
+    #[error("invalid request target")]
+    InvalidRequestTarget,
+
+    #[error("request target too long")]
+    UriTooLong,
 }
 
 pub struct ServerProtocol {
     state: ServerState,
     ver_min: u8,
+    simple: bool,
     body_size: BodySize,
     chunk_left: Option<usize>,
     chunk_size: usize,
     persistent: bool,
     chunked: bool,
     sending_chunk: Option<Chunk>,
+    strict_target: bool,
+    uri_max: usize,
 }
 
 #[allow(clippy::new_without_default)]
@@ -796,15 +944,34 @@ impl<'buf, 'headers> ServerProtocol {
         Self {
             state: ServerState::ReceivingRequest,
             ver_min: 0,
+            simple: false,
             body_size: BodySize::NoBody,
             chunk_left: None,
             chunk_size: 0,
             persistent: false,
             chunked: false,
             sending_chunk: None,
+            strict_target: false,
+            uri_max: 0,
         }
     }
 
+    // if enabled, requests are refused if their target contains control
+    // characters, backslashes, a fragment, or dot segments
+    pub fn set_strict_target(&mut self, enabled: bool) {
+        self.strict_target = enabled;
+    }
+
+    // if non-zero, requests are refused if their target is longer, as soon
+    // as that much of the request line is received
+    pub fn set_uri_max(&mut self, max: usize) {
+        self.uri_max = max;
+    }
+
+    fn is_uri_too_long(&self, buf: &[u8]) -> bool {
+        self.uri_max > 0 && partial_target_len(buf) > self.uri_max
+    }
+
     pub fn state(&self) -> ServerState {
         self.state
     }
@@ -813,6 +980,27 @@ impl<'buf, 'headers> ServerProtocol {
         self.persistent
     }
 
+    // whether the request was an HTTP/0.9 simple request, in which case the
+    // response has no header
+    pub fn is_simple(&self) -> bool {
+        self.simple
+    }
+
+    // protocol version of the request, as it appears in a request line
+    pub fn version(&self) -> &'static str {
+        if self.simple {
+            "HTTP/0.9"
+        } else if self.ver_min == 0 {
+            "HTTP/1.0"
+        } else {
+            "HTTP/1.1"
+        }
+    }
+
+    pub fn body_size(&self) -> BodySize {
+        self.body_size
+    }
+
     pub fn recv_request(
         &mut self,
         rbuf: &mut io::Cursor<&'buf [u8]>,
@@ -824,8 +1012,11 @@ impl<'buf, 'headers> ServerProtocol {
 
         let buf = &rbuf.get_ref()[(rbuf.position() as usize)..];
 
-        let size = match req.parse(buf) {
-            Ok(httparse::Status::Complete(size)) => size,
+        let (size, simple) = match parse_request(&mut req, buf) {
+            Ok(httparse::Status::Complete(ret)) => ret,
+            Ok(httparse::Status::Partial) if self.is_uri_too_long(buf) => {
+                return Some(Err(Error::UriTooLong))
+            }
             Ok(httparse::Status::Partial) => return None,
             Err(e) => return Some(Err(Error::ParseError(e))),
         };
@@ -835,6 +1026,8 @@ impl<'buf, 'headers> ServerProtocol {
             Err(e) => return Some(Err(e)),
         };
 
+        self.simple = simple;
+
         rbuf.set_position(rbuf.position() + (size as u64));
 
         Some(Ok(Request {
@@ -856,7 +1049,11 @@ impl<'buf, 'headers> ServerProtocol {
         let req = match OwnedHttparseRequest::parse(rbuf, scratch) {
             ParseStatus::Complete(req) => req,
             ParseStatus::Incomplete((), rbuf, scratch) => {
-                return ParseStatus::Incomplete((), rbuf, scratch)
+                if self.is_uri_too_long(rbuf.filled()) {
+                    return ParseStatus::Error(Error::UriTooLong, rbuf, scratch);
+                }
+
+                return ParseStatus::Incomplete((), rbuf, scratch);
             }
             ParseStatus::Error(e, rbuf, scratch) => {
                 return ParseStatus::Error(Error::ParseError(e), rbuf, scratch)
@@ -871,6 +1068,8 @@ impl<'buf, 'headers> ServerProtocol {
             }
         };
 
+        self.simple = req.simple;
+
         ParseStatus::Complete(OwnedRequest {
             req,
             body_size: self.body_size,
@@ -1025,6 +1224,15 @@ impl<'buf, 'headers> ServerProtocol {
             _ => {}
         }
 
+        if self.simple {
+            // the body is sent as-is and ends when the connection closes
+            self.state = ServerState::SendingBody;
+            self.body_size = body_size;
+            self.chunked = false;
+
+            return Ok(());
+        }
+
         let chunked = body_size == BodySize::Unknown && self.ver_min >= 1;
 
         if self.ver_min >= 1 {
@@ -1228,7 +1436,24 @@ impl<'buf, 'headers> ServerProtocol {
         Ok(content_written)
     }
 
+    // end a body of known size whose content was written to the connection
+    // by other means than send_body, such as directly from a file
+    pub fn end_body(&mut self) {
+        assert_eq!(self.state, ServerState::SendingBody);
+        assert!(!self.chunked);
+
+        self.state = ServerState::Finished;
+    }
+
     fn process_request(&mut self, req: &httparse::Request) -> Result<bool, Error> {
+        if self.uri_max > 0 && req.path.unwrap().len() > self.uri_max {
+            return Err(Error::UriTooLong);
+        }
+
+        if self.strict_target && !is_strict_target(req.path.unwrap()) {
+            return Err(Error::InvalidRequestTarget);
+        }
+
         let version = req.version.unwrap();
 
         let mut content_len = None;
@@ -2052,6 +2277,44 @@ mod tests {
         assert_eq!(header_contains_param(b"A", b"a", true), true);
     }
 
+    #[test]
+    fn test_recv_request_many_headers() {
+        let mut data = Vec::new();
+
+        write!(
+            data,
+            "POST /api/v1/items?page=2 HTTP/1.1\r\n\
+             Host: example.com\r\n\
+             Connection: keep-alive\r\n\
+             Content-Length: 0\r\n"
+        )
+        .unwrap();
+
+        for i in 0..20 {
+            write!(
+                data,
+                "X-Header-{}: some value, with params; a=1; b=\"two\"\r\n",
+                i
+            )
+            .unwrap();
+        }
+
+        write!(data, "\r\n").unwrap();
+
+        let mut protocol = ServerProtocol::new();
+        let mut headers = [httparse::EMPTY_HEADER; HEADERS_MAX];
+        let mut rbuf = io::Cursor::new(&data[..]);
+
+        let req = protocol
+            .recv_request(&mut rbuf, &mut headers)
+            .unwrap()
+            .unwrap();
+
+        assert_eq!(req.headers.len(), 23);
+        assert_eq!(req.body_size, BodySize::Known(0));
+        assert_eq!(rbuf.position() as usize, data.len());
+    }
+
     #[test]
     fn test_write_chunk() {
         struct Test {
@@ -2843,12 +3106,15 @@ mod tests {
             let mut p = ServerProtocol {
                 state: ServerState::ReceivingBody,
                 ver_min: 0,
+                simple: false,
                 body_size: test.body_size,
                 chunk_left: test.chunk_left,
                 chunk_size: test.chunk_size,
                 persistent: false,
                 chunked: test.body_size == BodySize::Unknown,
                 sending_chunk: None,
+                strict_target: false,
+                uri_max: 0,
             };
 
             let mut c = io::Cursor::new(test.data.as_bytes());
@@ -3314,12 +3580,15 @@ mod tests {
             let mut p = ServerProtocol {
                 state: ServerState::AwaitingResponse,
                 ver_min: test.ver_min,
+                simple: false,
                 body_size: BodySize::NoBody,
                 chunk_left: None,
                 chunk_size: 0,
                 persistent: test.persistent,
                 chunked: false,
                 sending_chunk: None,
+                strict_target: false,
+                uri_max: 0,
             };
 
             let mut w = MyBuffer::new(test.write_space, false);
@@ -3601,12 +3870,15 @@ mod tests {
             let mut p = ServerProtocol {
                 state: ServerState::SendingBody,
                 ver_min: 0,
+                simple: false,
                 body_size: test.body_size,
                 chunk_left: None,
                 chunk_size: 0,
                 persistent: false,
                 chunked: test.chunked,
                 sending_chunk: test.sending_chunk,
+                strict_target: false,
+                uri_max: 0,
             };
 
             let mut w = MyBuffer::new(test.write_space, true);
@@ -3819,6 +4091,150 @@ mod tests {
         assert_eq!(str::from_utf8(&out).unwrap(), data);
     }
 
+    #[test]
+    fn test_server_simple_req() {
+        let data = "GET /foo\r\n".as_bytes();
+
+        let mut p = ServerProtocol::new();
+        let req = read_req(&mut p, data, 2);
+
+        assert_eq!(p.is_simple(), true);
+        assert_eq!(req.method, "GET");
+        assert_eq!(req.uri, "/foo");
+        assert_eq!(req.headers.len(), 0);
+        assert_eq!(req.body.len(), 0);
+        assert_eq!(req.persistent, false);
+
+        let mut resp = TestResponse::new();
+        resp.code = 200;
+        resp.reason = String::from("OK");
+        resp.headers = vec![(String::from("Content-Type"), b"text/plain".to_vec())];
+        resp.body = b"hello\n".to_vec();
+
+        let out = write_resp(&mut p, resp, 2);
+
+        // no header
+        assert_eq!(str::from_utf8(&out).unwrap(), "hello\n");
+
+        assert_eq!(
+            parse_simple_request(b"GET /foo"),
+            Some(httparse::Status::Partial)
+        );
+        assert_eq!(parse_simple_request(b"GET \r\n"), None);
+        assert_eq!(parse_simple_request(b"POST /foo\r\n"), None);
+        assert_eq!(parse_simple_request(b"GET /foo bar\r\n"), None);
+
+        let mut p = ServerProtocol::new();
+        let mut headers = [httparse::EMPTY_HEADER; HEADERS_MAX];
+        let mut c = io::Cursor::new("GET /foo HTTP/2.0\r\n\r\n".as_bytes());
+
+        assert!(matches!(
+            p.recv_request(&mut c, &mut headers),
+            Some(Err(Error::ParseError(httparse::Error::Version)))
+        ));
+    }
+
+    #[test]
+    fn test_server_strict_target() {
+        let data = "GET /a/../b HTTP/1.1\r\nHost: example.com\r\n\r\n".as_bytes();
+
+        // permissive by default
+        let mut p = ServerProtocol::new();
+        let req = read_req(&mut p, data, 2);
+        assert_eq!(req.uri, "/a/../b");
+
+        let mut p = ServerProtocol::new();
+        p.set_strict_target(true);
+        let mut headers = [httparse::EMPTY_HEADER; HEADERS_MAX];
+        let mut c = io::Cursor::new(data);
+
+        assert!(matches!(
+            p.recv_request(&mut c, &mut headers),
+            Some(Err(Error::InvalidRequestTarget))
+        ));
+        assert_eq!(p.state(), ServerState::ReceivingRequest);
+
+        assert!(is_strict_target("/"));
+        assert!(is_strict_target("*"));
+        assert!(is_strict_target("example.com:443"));
+        assert!(is_strict_target("/a/b.c/..d/...?x=../y"));
+        assert!(is_strict_target("/a/%2e%2e%2e"));
+        assert!(is_strict_target("/a?x=%2f..%5c"));
+        assert!(is_strict_target("http://example.com"));
+        assert!(is_strict_target("http://example.com/a/b"));
+
+        assert!(!is_strict_target("/a/./b"));
+        assert!(!is_strict_target("/a/.."));
+        assert!(!is_strict_target("../a"));
+        assert!(!is_strict_target("/a/%2e%2E/b"));
+        assert!(!is_strict_target("/a/.%2e/b"));
+        assert!(!is_strict_target("/a/%2e?x"));
+        assert!(!is_strict_target("/a/..%2fb"));
+        assert!(!is_strict_target("/%2e%2e%2fetc"));
+        assert!(!is_strict_target("/a/..%5c"));
+        assert!(!is_strict_target("/a%2Fb"));
+        assert!(!is_strict_target("/a%5Cb"));
+        assert!(!is_strict_target("http://example.com/%2e%2e%2f"));
+        assert!(!is_strict_target("http://example.com/a/../b"));
+        assert!(!is_strict_target("/a\\b"));
+        assert!(!is_strict_target("/a#b"));
+        assert!(!is_strict_target("/a\tb"));
+        assert!(!is_strict_target("/a\x7fb"));
+    }
+
+    #[test]
+    fn test_server_uri_max() {
+        let data = "GET /abcdefgh HTTP/1.1\r\nHost: example.com\r\n\r\n".as_bytes();
+
+        let mut p = ServerProtocol::new();
+        p.set_uri_max(9);
+        let req = read_req(&mut p, data, 2);
+        assert_eq!(req.uri, "/abcdefgh");
+
+        let mut p = ServerProtocol::new();
+        p.set_uri_max(8);
+        let mut headers = [httparse::EMPTY_HEADER; HEADERS_MAX];
+        let mut c = io::Cursor::new(data);
+
+        assert!(matches!(
+            p.recv_request(&mut c, &mut headers),
+            Some(Err(Error::UriTooLong))
+        ));
+
+        // refused before the request line is complete
+        let mut p = ServerProtocol::new();
+        p.set_uri_max(8);
+        let mut headers = [httparse::EMPTY_HEADER; HEADERS_MAX];
+        let mut c = io::Cursor::new("GET /abcdefg".as_bytes());
+
+        assert!(p.recv_request(&mut c, &mut headers).is_none());
+
+        let mut c = io::Cursor::new("GET /abcdefgh".as_bytes());
+
+        assert!(matches!(
+            p.recv_request(&mut c, &mut headers),
+            Some(Err(Error::UriTooLong))
+        ));
+
+        // empty lines before the request line don't hide the target
+        let mut p = ServerProtocol::new();
+        p.set_uri_max(8);
+        let mut c = io::Cursor::new("\r\n\r\nGET /abcdefgh".as_bytes());
+
+        assert!(matches!(
+            p.recv_request(&mut c, &mut headers),
+            Some(Err(Error::UriTooLong))
+        ));
+
+        assert_eq!(partial_target_len(b""), 0);
+        assert_eq!(partial_target_len(b"GET"), 0);
+        assert_eq!(partial_target_len(b"GET /a"), 2);
+        assert_eq!(partial_target_len(b"GET /a HTTP/1.1\r\nX-A: b c\r\n"), 2);
+        assert_eq!(partial_target_len(b"\r\n\r\n"), 0);
+        assert_eq!(partial_target_len(b"\r\n\nGET /a"), 2);
+        assert_eq!(partial_target_len(b"\r\nGET /a HTTP/1.1\r\n"), 2);
+    }
+
     #[test]
     fn test_server_persistent() {
         // http 1.0 without keep alive
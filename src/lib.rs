@@ -14,24 +14,41 @@
  * limitations under the License.
  */
 
+pub mod accesslog;
+pub mod admin;
+pub mod announce;
 pub mod app;
 pub mod arena;
+pub mod blockingpool;
 pub mod buffer;
 pub mod channel;
 pub mod client;
+pub mod command;
 pub mod connection;
+pub mod control;
+pub mod curve;
+pub mod decompress;
 pub mod event;
 pub mod executor;
+pub mod filepool;
 pub mod future;
+pub mod fuzz;
 pub mod http1;
 pub mod list;
 pub mod listener;
+pub mod logfilter;
+pub mod memory;
 pub mod net;
+pub mod platform;
 pub mod pool;
+pub mod proxy;
+pub mod ratelimit;
 pub mod reactor;
+pub mod report;
 pub mod resolver;
 pub mod server;
 pub mod shuffle;
+pub mod stats;
 pub mod timer;
 pub mod tls;
 pub mod tnetstring;
@@ -45,16 +62,12 @@ pub mod zmq;
 use app::Config;
 use log::info;
 use std::error::Error;
-use std::ffi::CString;
 use std::future::Future;
-use std::io;
-use std::mem;
 use std::ops::Deref;
-use std::os::unix::ffi::OsStrExt;
-use std::path::Path;
 use std::pin::Pin;
-use std::ptr;
+use std::process;
 use std::task::{Context, Poll};
+use std::thread;
 
 pub struct Defer<T: FnOnce()> {
     f: Option<T>,
@@ -132,102 +145,22 @@ macro_rules! pin {
     };
 }
 
-fn try_with_increasing_buffer<T, U>(starting_size: usize, f: T) -> Result<U, io::Error>
+// spawn a named thread. thread creation can be denied in restricted
+// containers (e.g. by a seccomp profile or a pids limit), and the os error
+// alone doesn't make the cause obvious, so the error mentions it
+pub fn spawn_thread<F, T>(name: String, f: F) -> Result<thread::JoinHandle<T>, String>
 where
-    T: Fn(&mut [u8]) -> Result<U, io::Error>,
+    F: FnOnce() -> T + Send + 'static,
+    T: Send + 'static,
 {
-    let mut buf = Vec::new();
-    buf.resize(starting_size, 0);
-
-    loop {
-        match f(&mut buf) {
-            Ok(v) => return Ok(v),
-            Err(e) if e.raw_os_error() == Some(libc::ERANGE) => buf.resize(buf.len() * 2, 0),
-            Err(e) => return Err(e),
-        }
-    }
-}
-
-fn get_user_uid(name: &str) -> Result<libc::gid_t, io::Error> {
-    let name = CString::new(name).unwrap();
-
-    try_with_increasing_buffer(1024, |buf| unsafe {
-        let mut pwd = mem::MaybeUninit::uninit();
-        let mut passwd = ptr::null_mut();
-
-        if libc::getpwnam_r(
-            name.as_ptr(),
-            pwd.as_mut_ptr(),
-            buf.as_mut_ptr() as *mut i8,
-            buf.len(),
-            &mut passwd,
-        ) != 0
-        {
-            return Err(io::Error::last_os_error());
-        }
-
-        let passwd = match passwd.as_ref() {
-            Some(r) => r,
-            None => return Err(io::Error::from(io::ErrorKind::NotFound)),
-        };
-
-        Ok(passwd.pw_uid)
-    })
-}
-
-fn get_group_gid(name: &str) -> Result<libc::gid_t, io::Error> {
-    let name = CString::new(name).unwrap();
-
-    try_with_increasing_buffer(1024, |buf| unsafe {
-        let mut grp = mem::MaybeUninit::uninit();
-        let mut group = ptr::null_mut();
-
-        if libc::getgrnam_r(
-            name.as_ptr(),
-            grp.as_mut_ptr(),
-            buf.as_mut_ptr() as *mut i8,
-            buf.len(),
-            &mut group,
-        ) != 0
-        {
-            return Err(io::Error::last_os_error());
-        }
-
-        let group = match group.as_ref() {
-            Some(r) => r,
-            None => return Err(io::Error::from(io::ErrorKind::NotFound)),
-        };
-
-        Ok(group.gr_gid)
-    })
-}
-
-pub fn set_user(path: &Path, user: &str) -> Result<(), io::Error> {
-    let uid = get_user_uid(user)?;
-
-    unsafe {
-        let path = CString::new(path.as_os_str().as_bytes()).unwrap();
-
-        if libc::chown(path.as_ptr(), uid, u32::MAX) != 0 {
-            return Err(io::Error::last_os_error());
-        }
-    }
-
-    Ok(())
-}
-
-pub fn set_group(path: &Path, group: &str) -> Result<(), io::Error> {
-    let gid = get_group_gid(group)?;
-
-    unsafe {
-        let path = CString::new(path.as_os_str().as_bytes()).unwrap();
-
-        if libc::chown(path.as_ptr(), u32::MAX, gid) != 0 {
-            return Err(io::Error::last_os_error());
-        }
+    match thread::Builder::new().name(name.clone()).spawn(f) {
+        Ok(handle) => Ok(handle),
+        Err(e) => Err(format!(
+            "failed to create thread {}: {}. thread creation may be restricted by the \
+             environment (e.g. seccomp profile or pids limit), or try fewer workers",
+            name, e
+        )),
     }
-
-    Ok(())
 }
 
 pub fn can_move_mio_sockets_between_threads() -> bool {
@@ -250,9 +183,14 @@ pub fn run(config: &Config) -> Result<(), Box<dyn Error>> {
 
         info!("started");
 
-        a.wait_for_term();
+        match a.wait_for_stop() {
+            app::StopMode::Graceful => info!("stopping..."),
+            app::StopMode::Immediate => {
+                info!("stopping immediately");
 
-        info!("stopping...");
+                process::exit(0);
+            }
+        }
     }
 
     info!("stopped");
@@ -19,17 +19,254 @@ use crate::channel;
 use crate::executor::Executor;
 use crate::future::{
     select_2, select_slice, AsyncNetListener, AsyncReceiver, AsyncSender, NetAcceptFuture, Select2,
-    WaitWritableFuture,
+    Timeout, WaitWritableFuture,
 };
+use crate::memory::MemoryThreshold;
 use crate::net::{NetListener, NetStream, SocketAddr};
+use crate::pin;
+use crate::ratelimit::{KeyedRateLimiter, TokenBucket};
 use crate::reactor::Reactor;
-use log::{debug, error};
-use std::cmp;
-use std::sync::mpsc;
+use crate::spawn_thread;
+use log::{debug, error, info, warn};
+use std::mem;
+use std::net::{IpAddr, Ipv6Addr};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Arc, Mutex};
 use std::thread;
+use std::time::{Duration, Instant};
 
 const REACTOR_REGISTRATIONS_MAX: usize = 128;
 const EXECUTOR_TASKS_MAX: usize = 1;
+const ACCEPT_RATE_IPS_MAX: usize = 100_000;
+const MEMORY_CHECK_INTERVAL: Duration = Duration::from_millis(100);
+const PAUSE_CHECK_INTERVAL: Duration = Duration::from_millis(100);
+
+// limits on the number of connections accepted per second. a value of 0
+// means no limit
+#[derive(Clone, Copy, Default)]
+pub struct AcceptRateLimits {
+    pub global: u32,
+    pub per_ip: u32,
+}
+
+struct AcceptLimiterInner {
+    global: Option<TokenBucket>,
+    per_ip: Option<KeyedRateLimiter<IpAddr>>,
+}
+
+// the address that per-ip limits apply to. an ipv6 client typically has a
+// whole /64 to pick addresses from, so ipv6 addresses are limited by their
+// /64 prefix
+fn per_ip_key(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V6(addr) => match addr.to_ipv4_mapped() {
+            Some(addr) => IpAddr::V4(addr),
+            None => IpAddr::V6(Ipv6Addr::from(
+                u128::from(addr) & 0xffff_ffff_ffff_ffff_0000_0000_0000_0000,
+            )),
+        },
+        ip => ip,
+    }
+}
+
+// shared between listeners, so that limits apply across all of them
+#[derive(Clone)]
+pub struct AcceptLimiter {
+    inner: Arc<Mutex<AcceptLimiterInner>>,
+}
+
+impl AcceptLimiter {
+    pub fn new(limits: AcceptRateLimits) -> Self {
+        let now = Instant::now();
+
+        let global = if limits.global > 0 {
+            Some(TokenBucket::new(limits.global, now))
+        } else {
+            None
+        };
+
+        let per_ip = if limits.per_ip > 0 {
+            Some(KeyedRateLimiter::new(limits.per_ip, ACCEPT_RATE_IPS_MAX))
+        } else {
+            None
+        };
+
+        Self {
+            inner: Arc::new(Mutex::new(AcceptLimiterInner { global, per_ip })),
+        }
+    }
+
+    pub fn check(&self, peer_addr: &SocketAddr, now: Instant) -> bool {
+        let inner = &mut *self.inner.lock().unwrap();
+
+        // ensure the global limit has room before taking from the per-ip
+        // limit, so that a connection refused globally doesn't count
+        // against its address. the global token is taken last, so that a
+        // flood from a single address doesn't consume the global budget
+        if let Some(global) = &mut inner.global {
+            if global.available(now) == 0 {
+                return false;
+            }
+        }
+
+        if let (Some(per_ip), SocketAddr::Ip(addr)) = (&mut inner.per_ip, peer_addr) {
+            if !per_ip.try_take(per_ip_key(addr.ip()), now) {
+                return false;
+            }
+        }
+
+        if let Some(global) = &mut inner.global {
+            global.take(1);
+        }
+
+        true
+    }
+}
+
+// lets an operator stop the listeners from accepting connections without
+// closing them, so that new connections wait in the backlog. shared between
+// listeners, and it can be used from any thread
+#[derive(Clone, Default)]
+pub struct AcceptGate {
+    paused: Arc<AtomicBool>,
+}
+
+impl AcceptGate {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // returns false if already paused
+    pub fn pause(&self) -> bool {
+        !self.paused.swap(true, Ordering::Relaxed)
+    }
+
+    // returns false if not paused
+    pub fn resume(&self) -> bool {
+        self.paused.swap(false, Ordering::Relaxed)
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.paused.load(Ordering::Relaxed)
+    }
+}
+
+// accepts connections from a set of listeners, holding off while accepting
+// is paused or memory usage is too high, and dropping connections over the
+// rate limits. it must be created and used within a reactor
+pub struct Acceptor {
+    listeners: Vec<AsyncNetListener>,
+    limiter: AcceptLimiter,
+    gate: AcceptGate,
+    mem_threshold: Option<MemoryThreshold>,
+    mem_paused: bool,
+    check_timeout: Timeout,
+    pos: usize,
+    tasks_mem: Vec<NetAcceptFuture<'static>>,
+    scratch: Vec<usize>,
+}
+
+impl Acceptor {
+    pub fn new(
+        listeners: Vec<NetListener>,
+        limiter: AcceptLimiter,
+        gate: AcceptGate,
+        mem_threshold: Option<MemoryThreshold>,
+    ) -> Self {
+        let reactor = Reactor::current().unwrap();
+
+        let listeners: Vec<AsyncNetListener> =
+            listeners.into_iter().map(AsyncNetListener::new).collect();
+
+        let count = listeners.len();
+
+        Self {
+            listeners,
+            limiter,
+            gate,
+            mem_threshold,
+            mem_paused: false,
+            check_timeout: Timeout::new(reactor.now()),
+            pos: 0,
+            tasks_mem: Vec::with_capacity(count),
+            scratch: Vec::with_capacity(count),
+        }
+    }
+
+    // returns the position of the listener along with the connection.
+    // listeners take turns, so that a busy one can't starve the others
+    pub async fn accept(&mut self) -> (usize, NetStream, SocketAddr) {
+        let reactor = Reactor::current().unwrap();
+
+        loop {
+            // if an operator has paused accepting, wait until resumed
+
+            if self.gate.is_paused() {
+                self.check_timeout
+                    .set_deadline(reactor.now() + PAUSE_CHECK_INTERVAL);
+                self.check_timeout.elapsed().await;
+
+                continue;
+            }
+
+            // if memory usage is too high, don't accept until it drops
+
+            if let Some(t) = &mut self.mem_threshold {
+                if t.check() {
+                    if !self.mem_paused {
+                        self.mem_paused = true;
+                        warn!("memory usage {} bytes, pausing accept", t.used());
+                    }
+
+                    self.check_timeout
+                        .set_deadline(reactor.now() + MEMORY_CHECK_INTERVAL);
+                    self.check_timeout.elapsed().await;
+
+                    continue;
+                }
+
+                if self.mem_paused {
+                    self.mem_paused = false;
+                    info!("memory usage {} bytes, resuming accept", t.used());
+                }
+            }
+
+            let mut tasks = recycle_vec(mem::take(&mut self.tasks_mem));
+
+            let (b, a) = self.listeners.split_at(self.pos);
+
+            for l in a.iter().chain(b.iter()) {
+                tasks.push(l.accept());
+            }
+
+            let (pos, result) = select_slice(&mut tasks, &mut self.scratch).await;
+
+            self.tasks_mem = recycle_vec(tasks);
+
+            let pos = (self.pos + pos) % self.listeners.len();
+
+            self.pos = (pos + 1) % self.listeners.len();
+
+            let (stream, peer_addr) = match result {
+                Ok(ret) => ret,
+                Err(e) => {
+                    error!("accept error: {:?}", e);
+                    continue;
+                }
+            };
+
+            if !self.limiter.check(&peer_addr, reactor.now()) {
+                // dropping the stream closes it
+                debug!("accept rate exceeded, rejecting {}", peer_addr);
+                continue;
+            }
+
+            debug!("accepted connection from {}", peer_addr);
+
+            return (pos, stream, peer_addr);
+        }
+    }
+}
 
 pub struct Listener {
     thread: Option<thread::JoinHandle<()>>,
@@ -37,62 +274,81 @@ pub struct Listener {
 }
 
 impl Listener {
+    // connections go to the overflow senders only if none of the main
+    // senders can take them
     pub fn new(
         name: &str,
         listeners: Vec<NetListener>,
         senders: Vec<channel::Sender<(usize, NetStream, SocketAddr)>>,
-    ) -> Listener {
+        overflow_senders: Vec<channel::Sender<(usize, NetStream, SocketAddr)>>,
+        limiter: AcceptLimiter,
+        gate: AcceptGate,
+        mem_threshold: Option<MemoryThreshold>,
+    ) -> Result<Listener, String> {
         let (s, r) = channel::channel(1);
 
-        let thread = thread::Builder::new()
-            .name(name.to_string())
-            .spawn(move || {
-                let reactor = Reactor::new(REACTOR_REGISTRATIONS_MAX);
-                let executor = Executor::new(EXECUTOR_TASKS_MAX);
-
-                executor.spawn(Self::run(r, listeners, senders)).unwrap();
-
-                executor.run(|timeout| reactor.poll(timeout)).unwrap();
-            })
-            .unwrap();
-
-        Self {
+        let thread = spawn_thread(name.to_string(), move || {
+            let reactor = Reactor::new(REACTOR_REGISTRATIONS_MAX);
+            let executor = Executor::new(EXECUTOR_TASKS_MAX);
+
+            executor
+                .spawn(Self::run(
+                    r,
+                    listeners,
+                    senders,
+                    overflow_senders,
+                    limiter,
+                    gate,
+                    mem_threshold,
+                ))
+                .unwrap();
+
+            executor.run(|timeout| reactor.poll(timeout)).unwrap();
+        })?;
+
+        Ok(Self {
             thread: Some(thread),
             stop: s,
-        }
+        })
     }
 
     async fn run(
         stop: channel::Receiver<()>,
         listeners: Vec<NetListener>,
         senders: Vec<channel::Sender<(usize, NetStream, SocketAddr)>>,
+        overflow_senders: Vec<channel::Sender<(usize, NetStream, SocketAddr)>>,
+        limiter: AcceptLimiter,
+        gate: AcceptGate,
+        mem_threshold: Option<MemoryThreshold>,
     ) {
         let stop = AsyncReceiver::new(stop);
 
-        let mut listeners: Vec<AsyncNetListener> =
-            listeners.into_iter().map(AsyncNetListener::new).collect();
+        let mut acceptor = Acceptor::new(listeners, limiter, gate, mem_threshold);
 
         let mut senders: Vec<AsyncSender<(usize, NetStream, SocketAddr)>> =
             senders.into_iter().map(AsyncSender::new).collect();
 
-        let mut listeners_pos = 0;
+        let mut overflow_senders: Vec<AsyncSender<(usize, NetStream, SocketAddr)>> =
+            overflow_senders.into_iter().map(AsyncSender::new).collect();
+
         let mut senders_pos = 0;
+        let mut overflow_senders_pos = 0;
 
-        let mut sender_tasks_mem: Vec<WaitWritableFuture<(usize, NetStream, SocketAddr)>> =
-            Vec::with_capacity(senders.len());
+        let senders_count = senders.len() + overflow_senders.len();
 
-        let mut listener_tasks_mem: Vec<NetAcceptFuture> = Vec::with_capacity(listeners.len());
+        let mut sender_tasks_mem: Vec<WaitWritableFuture<(usize, NetStream, SocketAddr)>> =
+            Vec::with_capacity(senders_count);
 
-        let mut slice_scratch = Vec::with_capacity(cmp::max(senders.len(), listeners.len()));
+        let mut slice_scratch = Vec::with_capacity(senders_count);
 
         let mut stop_recv = stop.recv();
 
-        'accept: loop {
+        loop {
             // wait for a sender to become writable
 
             let mut sender_tasks = recycle_vec(sender_tasks_mem);
 
-            for s in senders.iter_mut() {
+            for s in senders.iter_mut().chain(overflow_senders.iter_mut()) {
                 sender_tasks.push(s.wait_writable());
             }
 
@@ -111,68 +367,52 @@ impl Listener {
 
             // accept a connection
 
-            let mut listener_tasks = recycle_vec(listener_tasks_mem);
-
-            let (b, a) = listeners.split_at_mut(listeners_pos);
-
-            for l in a.iter_mut().chain(b.iter_mut()) {
-                listener_tasks.push(l.accept());
-            }
-
-            let (pos, stream, peer_addr) = loop {
-                match select_2(
-                    &mut stop_recv,
-                    select_slice(&mut listener_tasks, &mut slice_scratch),
-                )
-                .await
-                {
-                    Select2::R1(_) => break 'accept,
-                    Select2::R2((pos, result)) => match result {
-                        Ok((stream, peer_addr)) => break (pos, stream, peer_addr),
-                        Err(e) => error!("accept error: {:?}", e),
-                    },
-                }
-            };
-
-            listener_tasks_mem = recycle_vec(listener_tasks);
-
-            let pos = (listeners_pos + pos) % listeners.len();
-
-            debug!("accepted connection from {}", peer_addr);
-
-            listeners_pos = (pos + 1) % listeners.len();
+            let (pos, stream, peer_addr) =
+                match select_2(&mut stop_recv, pin!(acceptor.accept())).await {
+                    Select2::R1(_) => break,
+                    Select2::R2(ret) => ret,
+                };
 
             // write connection to sender
 
-            let mut pending_sock = Some((pos, stream, peer_addr));
+            let s = (pos, stream, peer_addr);
 
-            for _ in 0..senders.len() {
-                let sender = &mut senders[senders_pos];
+            if let Some(s) = send_next(&senders, &mut senders_pos, s) {
+                send_next(&overflow_senders, &mut overflow_senders_pos, s);
+            }
+        }
+    }
+}
 
-                if !sender.is_writable() {
-                    senders_pos = (senders_pos + 1) % senders.len();
-                    continue;
-                }
+// try the senders in turn, starting from pos. returns the item if none of
+// them could take it
+fn send_next<T>(senders: &[AsyncSender<T>], pos: &mut usize, t: T) -> Option<T> {
+    let mut pending = Some(t);
 
-                let s = pending_sock.take().unwrap();
+    for _ in 0..senders.len() {
+        let sender = &senders[*pos];
 
-                match sender.try_send(s) {
-                    Ok(()) => {}
-                    Err(mpsc::TrySendError::Full(s)) => pending_sock = Some(s),
-                    Err(mpsc::TrySendError::Disconnected(_)) => {
-                        // this could happen during shutdown
-                        debug!("receiver disconnected");
-                    }
-                }
+        *pos = (*pos + 1) % senders.len();
 
-                senders_pos = (senders_pos + 1) % senders.len();
+        if !sender.is_writable() {
+            continue;
+        }
 
-                if pending_sock.is_none() {
-                    break;
-                }
+        match sender.try_send(pending.take().unwrap()) {
+            Ok(()) => {}
+            Err(mpsc::TrySendError::Full(t)) => pending = Some(t),
+            Err(mpsc::TrySendError::Disconnected(_)) => {
+                // this could happen during shutdown
+                debug!("receiver disconnected");
             }
         }
+
+        if pending.is_none() {
+            break;
+        }
     }
+
+    pending
 }
 
 impl Drop for Listener {
@@ -192,9 +432,65 @@ mod tests {
     use crate::event;
     use mio::net::TcpListener;
     use std::io::{Read, Write};
-    use std::mem;
     use std::sync::mpsc;
 
+    #[test]
+    fn test_accept_limiter() {
+        let limiter = AcceptLimiter::new(AcceptRateLimits {
+            global: 2,
+            per_ip: 1,
+        });
+
+        let now = Instant::now();
+
+        let addr1 = SocketAddr::Ip("192.0.2.1:41000".parse().unwrap());
+        let addr2 = SocketAddr::Ip("192.0.2.2:41000".parse().unwrap());
+        let addr3 = SocketAddr::Ip("192.0.2.3:41000".parse().unwrap());
+
+        assert!(limiter.check(&addr1, now));
+
+        // over the per-ip limit, without using the global budget
+        assert!(!limiter.check(&addr1, now));
+
+        assert!(limiter.check(&addr2, now));
+
+        // over the global limit, without using the per-ip budget
+        assert!(!limiter.check(&addr3, now));
+
+        // half a second refills one global token, but not a per-ip one, so
+        // only an address that hasn't been counted can get through
+        let now = now + Duration::from_millis(500);
+        assert!(!limiter.check(&addr1, now));
+        assert!(limiter.check(&addr3, now));
+    }
+
+    #[test]
+    fn test_accept_limiter_ipv6() {
+        let limiter = AcceptLimiter::new(AcceptRateLimits {
+            global: 0,
+            per_ip: 1,
+        });
+
+        let now = Instant::now();
+
+        let addr1 = SocketAddr::Ip("[2001:db8:0:1::1]:41000".parse().unwrap());
+        let addr2 = SocketAddr::Ip("[2001:db8:0:1::2]:41000".parse().unwrap());
+        let addr3 = SocketAddr::Ip("[2001:db8:0:2::1]:41000".parse().unwrap());
+
+        assert!(limiter.check(&addr1, now));
+
+        // same /64
+        assert!(!limiter.check(&addr2, now));
+
+        // different /64
+        assert!(limiter.check(&addr3, now));
+
+        assert_eq!(
+            per_ip_key("::ffff:192.0.2.1".parse().unwrap()),
+            "192.0.2.1".parse::<IpAddr>().unwrap()
+        );
+    }
+
     #[test]
     fn test_accept() {
         let mut addrs = Vec::new();
@@ -213,7 +509,16 @@ mod tests {
             receivers.push(receiver);
         }
 
-        let _l = Listener::new("listener-test", listeners, senders);
+        let _l = Listener::new(
+            "listener-test",
+            listeners,
+            senders,
+            Vec::new(),
+            AcceptLimiter::new(AcceptRateLimits::default()),
+            AcceptGate::new(),
+            None,
+        )
+        .unwrap();
 
         let mut poller = event::Poller::new(1024).unwrap();
 
@@ -267,4 +572,74 @@ mod tests {
         client.read_to_end(&mut buf).unwrap();
         assert_eq!(&buf, b"hello");
     }
+
+    #[test]
+    fn test_accept_paused() {
+        let addr = "127.0.0.1:0".parse().unwrap();
+        let l = TcpListener::bind(addr).unwrap();
+        let addr = l.local_addr().unwrap();
+
+        let (sender, receiver) = channel::channel(1);
+
+        let gate = AcceptGate::new();
+        assert!(gate.pause());
+        assert!(!gate.pause());
+
+        let _l = Listener::new(
+            "listener-test",
+            vec![NetListener::Tcp(l)],
+            vec![sender],
+            Vec::new(),
+            AcceptLimiter::new(AcceptRateLimits::default()),
+            gate.clone(),
+            None,
+        )
+        .unwrap();
+
+        let _client = std::net::TcpStream::connect(addr).unwrap();
+
+        // the connection waits in the backlog
+        thread::sleep(PAUSE_CHECK_INTERVAL * 3);
+        assert_eq!(receiver.try_recv().unwrap_err(), mpsc::TryRecvError::Empty);
+
+        assert!(gate.resume());
+        assert!(!gate.resume());
+
+        let (lnum, _, _) = receiver.recv().unwrap();
+        assert_eq!(lnum, 0);
+    }
+
+    #[test]
+    fn test_accept_overflow() {
+        let addr = "127.0.0.1:0".parse().unwrap();
+        let l = TcpListener::bind(addr).unwrap();
+        let addr = l.local_addr().unwrap();
+
+        let (sender, receiver) = channel::channel(0);
+        let (overflow_sender, overflow_receiver) = channel::channel(0);
+
+        let _l = Listener::new(
+            "listener-test",
+            vec![NetListener::Tcp(l)],
+            vec![sender],
+            vec![overflow_sender],
+            AcceptLimiter::new(AcceptRateLimits::default()),
+            AcceptGate::new(),
+            None,
+        )
+        .unwrap();
+
+        // only the overflow receiver is ready
+        assert_eq!(
+            overflow_receiver.try_recv().unwrap_err(),
+            mpsc::TryRecvError::Empty
+        );
+
+        let _client = std::net::TcpStream::connect(addr).unwrap();
+
+        let (lnum, _, _) = overflow_receiver.recv().unwrap();
+        assert_eq!(lnum, 0);
+
+        assert_eq!(receiver.try_recv().unwrap_err(), mpsc::TryRecvError::Empty);
+    }
 }
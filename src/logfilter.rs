@@ -0,0 +1,190 @@
+/*
+ * Copyright (C) 2024 Fanout, Inc.
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+// which log records to emit, by module. a filter is written as a comma-
+// separated list of directives, each either a level that applies by
+// default, or module=level for a module and its submodules, for example
+// "info,condure::connection=debug". levels are names (off, error, warn,
+// info, debug, trace) or numbers (0 for error through 4 for trace). the
+// active filter is global, so that it can be changed at runtime
+
+use log::{LevelFilter, Metadata};
+use std::cmp;
+use std::fmt;
+use std::str::FromStr;
+use std::sync::RwLock;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LogFilter {
+    default: LevelFilter,
+    modules: Vec<(String, LevelFilter)>,
+}
+
+fn parse_level(s: &str) -> Result<LevelFilter, String> {
+    if let Ok(x) = s.parse::<usize>() {
+        return Ok(match x {
+            0 => LevelFilter::Error,
+            1 => LevelFilter::Warn,
+            2 => LevelFilter::Info,
+            3 => LevelFilter::Debug,
+            _ => LevelFilter::Trace,
+        });
+    }
+
+    s.parse().map_err(|_| format!("invalid level: {}", s))
+}
+
+impl LogFilter {
+    pub const fn new(default: LevelFilter) -> Self {
+        Self {
+            default,
+            modules: Vec::new(),
+        }
+    }
+
+    // the level of the most specific module matching the target
+    pub fn level(&self, target: &str) -> LevelFilter {
+        let mut best: Option<(&str, LevelFilter)> = None;
+
+        for (module, level) in self.modules.iter() {
+            let matches = match target.strip_prefix(module.as_str()) {
+                Some(rest) => rest.is_empty() || rest.starts_with("::"),
+                None => false,
+            };
+
+            if matches && best.is_none_or(|(m, _)| m.len() < module.len()) {
+                best = Some((module, *level));
+            }
+        }
+
+        best.map_or(self.default, |(_, level)| level)
+    }
+
+    // the most verbose level of any module
+    pub fn max_level(&self) -> LevelFilter {
+        self.modules
+            .iter()
+            .map(|(_, level)| *level)
+            .fold(self.default, cmp::max)
+    }
+}
+
+impl FromStr for LogFilter {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut filter = Self::new(LevelFilter::Info);
+
+        for directive in s.split(',').map(str::trim) {
+            if directive.is_empty() {
+                continue;
+            }
+
+            match directive.split_once('=') {
+                Some((module, level)) => {
+                    let module = module.trim();
+
+                    if module.is_empty() {
+                        return Err(format!("missing module: {}", directive));
+                    }
+
+                    let level = parse_level(level.trim())?;
+
+                    filter.modules.retain(|(m, _)| m != module);
+                    filter.modules.push((module.to_string(), level));
+                }
+                None => filter.default = parse_level(directive)?,
+            }
+        }
+
+        Ok(filter)
+    }
+}
+
+impl fmt::Display for LogFilter {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        write!(f, "{}", self.default.as_str().to_lowercase())?;
+
+        for (module, level) in self.modules.iter() {
+            write!(f, ",{}={}", module, level.as_str().to_lowercase())?;
+        }
+
+        Ok(())
+    }
+}
+
+static FILTER: RwLock<LogFilter> = RwLock::new(LogFilter::new(LevelFilter::Info));
+
+// replace the active filter
+pub fn set(filter: LogFilter) {
+    // let the log macros skip records no module wants
+    log::set_max_level(filter.max_level());
+
+    *FILTER.write().unwrap() = filter;
+}
+
+pub fn get() -> LogFilter {
+    FILTER.read().unwrap().clone()
+}
+
+// for use by a logger, to check records against the active filter
+pub fn enabled(metadata: &Metadata) -> bool {
+    metadata.level() <= FILTER.read().unwrap().level(metadata.target())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse() {
+        let f: LogFilter = "2".parse().unwrap();
+        assert_eq!(f, LogFilter::new(LevelFilter::Info));
+
+        let f: LogFilter = "4".parse().unwrap();
+        assert_eq!(f, LogFilter::new(LevelFilter::Trace));
+
+        let f: LogFilter = "warn, condure::connection=debug,condure=3".parse().unwrap();
+        assert_eq!(
+            f.to_string(),
+            "warn,condure::connection=debug,condure=debug"
+        );
+        assert_eq!(f.max_level(), LevelFilter::Debug);
+
+        // later directives for the same module win
+        let f: LogFilter = "condure=debug,condure=error".parse().unwrap();
+        assert_eq!(f.to_string(), "info,condure=error");
+
+        assert!("verbose".parse::<LogFilter>().is_err());
+        assert!("=debug".parse::<LogFilter>().is_err());
+        assert!("condure=".parse::<LogFilter>().is_err());
+    }
+
+    #[test]
+    fn level() {
+        let f: LogFilter = "warn,condure=info,condure::connection=trace,condure::conn=off"
+            .parse()
+            .unwrap();
+
+        assert_eq!(f.level("other"), LevelFilter::Warn);
+        assert_eq!(f.level("condure"), LevelFilter::Info);
+        assert_eq!(f.level("condure::server"), LevelFilter::Info);
+        assert_eq!(f.level("condure::connection"), LevelFilter::Trace);
+        assert_eq!(f.level("condure::connection::x"), LevelFilter::Trace);
+        assert_eq!(f.level("condure::conn"), LevelFilter::Off);
+        assert_eq!(f.level("condurex"), LevelFilter::Warn);
+    }
+}
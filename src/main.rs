@@ -15,11 +15,17 @@
  */
 
 use clap::{crate_version, Arg, ArgAction, Command};
+use condure::accesslog::AccessLogFormat;
 use condure::app;
-use log::{error, Level, LevelFilter, Metadata, Record};
+use condure::connection::{ForwardedHeaders, FIXED_RESPONSE_HEADERS_MAX, TAGS_MAX};
+use condure::logfilter::{self, LogFilter};
+use condure::net::BindOpts;
+use ipnet::IpNet;
+use log::{error, LevelFilter, Metadata, Record};
 use std::error::Error;
 use std::io;
 use std::mem;
+use std::net::IpAddr;
 use std::path::PathBuf;
 use std::process;
 use std::str;
@@ -31,6 +37,7 @@ use time::{OffsetDateTime, UtcOffset};
 // safety values
 const WORKERS_MAX: usize = 1024;
 const CONNS_MAX: usize = 10_000_000;
+const EARLY_DATA_WINDOW_DEFAULT: u64 = 10;
 
 const PRIVATE_SUBNETS: &[&str] = &[
     "127.0.0.0/8",
@@ -49,7 +56,7 @@ struct SimpleLogger {
 
 impl log::Log for SimpleLogger {
     fn enabled(&self, metadata: &Metadata) -> bool {
-        metadata.level() <= Level::Trace
+        logfilter::enabled(metadata)
     }
 
     fn log(&self, record: &Record) {
@@ -115,19 +122,79 @@ struct Args {
     buffer_size: usize,
     body_buffer_size: usize,
     messages_max: usize,
+    message_size_max: usize,
     req_timeout: usize,
     stream_timeout: usize,
+    req_retries: usize,
+    req_retry_timeout: usize,
+    req_decompress_max: usize,
+    handler_timeout: usize,
+    header_timeout: usize,
+    body_timeout: usize,
+    idle_timeout: usize,
+    min_transfer_rate: u32,
+    sse_keep_alive_interval: usize,
     listen: Vec<String>,
     zclient_req_specs: Vec<String>,
     zclient_stream_specs: Vec<String>,
+    sni_backends: Vec<String>,
+    backends: Vec<String>,
+    routes: Vec<String>,
+    mirror_req_specs: Vec<String>,
+    mirror_percent: u32,
+    announce_spec: Option<String>,
+    announce_interval: usize,
+    stats_spec: Option<String>,
+    stats_interval: usize,
+    admin_spec: Option<String>,
     zclient_connect: bool,
     zserver_req_specs: Vec<String>,
     zserver_stream_specs: Vec<String>,
     zserver_connect: bool,
+    zmq_curve_cert: Option<String>,
+    zmq_curve_server_cert: Option<String>,
+    zmq_curve_allow: Option<String>,
     ipc_file_mode: u32,
     tls_identities_dir: String,
+    tls_ticket_key_rotation: usize,
+    tls_ticket_key_overlap: usize,
     allow_compression: bool,
+    forwarded_headers: String,
+    trusted_proxies: Vec<String>,
+    download_rate: u32,
+    keep_alive_session_info: bool,
+    allow_http09: bool,
+    strict_request_target: bool,
+    uri_max: usize,
+    options_allow: String,
+    options_body: Option<String>,
+    health_live_path: Option<String>,
+    health_ready_path: Option<String>,
+    fixed_responses: Vec<String>,
+    error_pages: Vec<String>,
+    access_log: Option<String>,
+    access_log_format: String,
+    file_root: Option<String>,
     deny_out_internal: bool,
+    accept_rate: u32,
+    accept_rate_per_ip: u32,
+    request_rate_per_ip: u32,
+    request_rate_key_header: Option<String>,
+    accept_pause_memory: usize,
+    reuse_port: bool,
+    worker_memory_budget: usize,
+    memory_budget: usize,
+    accept_per_loop_max: usize,
+    poll_timeout_max: usize,
+    reactor_budget: u32,
+    zmq_hwm: usize,
+    handle_bound: usize,
+    resp_sender_bound: usize,
+    handle_accept_bound: usize,
+    port_file: Option<String>,
+    control: Option<String>,
+    upgrade: bool,
+    dump_config: bool,
 }
 
 fn process_args_and_run(args: Args) -> Result<(), Box<dyn Error>> {
@@ -143,6 +210,65 @@ fn process_args_and_run(args: Args) -> Result<(), Box<dyn Error>> {
         return Err("total maxconn is too large".into());
     }
 
+    if args.mirror_percent > 100 {
+        return Err("failed to parse mirror-percent: value must be at most 100".into());
+    }
+
+    if args.announce_interval == 0 {
+        return Err("failed to parse announce-interval: value must be greater than 0".into());
+    }
+
+    if args.stats_interval == 0 {
+        return Err("failed to parse stats-interval: value must be greater than 0".into());
+    }
+
+    if args.tls_ticket_key_rotation == 0 {
+        return Err("failed to parse tls-ticket-key-rotation: value must be greater than 0".into());
+    }
+
+    if (args.zmq_curve_server_cert.is_some() || args.zmq_curve_allow.is_some())
+        && args.zmq_curve_cert.is_none()
+    {
+        return Err("zmq-curve-server-cert and zmq-curve-allow require zmq-curve-cert".into());
+    }
+
+    if args.options_body.is_some() && args.options_allow.is_empty() {
+        return Err("options-body requires options-allow".into());
+    }
+
+    for (name, path) in [
+        ("health-live-path", &args.health_live_path),
+        ("health-ready-path", &args.health_ready_path),
+    ] {
+        if let Some(path) = path {
+            if !path.starts_with('/') || path.contains('?') {
+                return Err(format!("{} must be a path starting with /", name).into());
+            }
+        }
+    }
+
+    let forwarded_headers: ForwardedHeaders = match args.forwarded_headers.parse() {
+        Ok(f) => f,
+        Err(e) => return Err(format!("failed to parse forwarded-headers: {}", e).into()),
+    };
+
+    let access_log_format: AccessLogFormat = match args.access_log_format.parse() {
+        Ok(f) => f,
+        Err(e) => return Err(format!("failed to parse access-log-format: {}", e).into()),
+    };
+
+    if args.request_rate_key_header.is_some() && args.request_rate_per_ip == 0 {
+        return Err("request-rate-key-header requires request-rate-per-ip".into());
+    }
+
+    if args.upgrade && args.control.is_none() {
+        return Err("upgrade requires control".into());
+    }
+
+    if args.req_retry_timeout == 0 {
+        return Err("failed to parse req-retry-timeout: value must be greater than 0".into());
+    }
+
     let mut config = app::Config {
         instance_id: args.id,
         workers: args.workers,
@@ -151,19 +277,78 @@ fn process_args_and_run(args: Args) -> Result<(), Box<dyn Error>> {
         buffer_size: args.buffer_size,
         body_buffer_size: args.body_buffer_size,
         messages_max: args.messages_max,
+        message_size_max: args.message_size_max,
         req_timeout: Duration::from_secs(args.req_timeout as u64),
         stream_timeout: Duration::from_secs(args.stream_timeout as u64),
+        req_retries: args.req_retries,
+        req_retry_timeout: Duration::from_millis(args.req_retry_timeout as u64),
+        req_decompress_max: args.req_decompress_max,
+        handler_timeout: Duration::from_secs(args.handler_timeout as u64),
+        header_timeout: Duration::from_secs(args.header_timeout as u64),
+        body_timeout: Duration::from_secs(args.body_timeout as u64),
+        idle_timeout: Duration::from_secs(args.idle_timeout as u64),
+        min_transfer_rate: args.min_transfer_rate,
+        sse_keep_alive_interval: Duration::from_secs(args.sse_keep_alive_interval as u64),
         listen: Vec::new(),
         zclient_req: args.zclient_req_specs,
         zclient_stream: args.zclient_stream_specs,
+        sni_backends: Vec::new(),
+        backends: Vec::new(),
+        routes: Vec::new(),
+        mirror_req: args.mirror_req_specs,
+        mirror_percent: args.mirror_percent,
+        announce: args.announce_spec,
+        announce_interval: Duration::from_secs(args.announce_interval as u64),
+        stats: args.stats_spec,
+        stats_interval: Duration::from_secs(args.stats_interval as u64),
+        admin: args.admin_spec,
         zclient_connect: args.zclient_connect,
         zserver_req: args.zserver_req_specs,
         zserver_stream: args.zserver_stream_specs,
         zserver_connect: args.zserver_connect,
+        zmq_curve_cert: args.zmq_curve_cert.map(PathBuf::from),
+        zmq_curve_server_cert: args.zmq_curve_server_cert.map(PathBuf::from),
+        zmq_curve_allow: args.zmq_curve_allow.map(PathBuf::from),
         ipc_file_mode: args.ipc_file_mode,
         certs_dir: PathBuf::from(args.tls_identities_dir),
+        tls_ticket_key_rotation: Duration::from_secs(args.tls_ticket_key_rotation as u64),
+        tls_ticket_key_overlap: Duration::from_secs(args.tls_ticket_key_overlap as u64),
         allow_compression: args.allow_compression,
+        forwarded_headers,
+        trusted_proxies: Vec::new(),
+        download_rate: args.download_rate,
+        keep_alive_session_info: args.keep_alive_session_info,
+        allow_http09: args.allow_http09,
+        strict_request_target: args.strict_request_target,
+        uri_max: args.uri_max,
+        options_allow: args.options_allow,
+        options_body: args.options_body.map(PathBuf::from),
+        health_live_path: args.health_live_path,
+        health_ready_path: args.health_ready_path,
+        fixed_responses: Vec::new(),
+        error_pages: Vec::new(),
+        access_log: args.access_log,
+        access_log_format,
+        file_root: args.file_root.map(PathBuf::from),
         deny: Vec::new(),
+        accept_rate: args.accept_rate,
+        accept_rate_per_ip: args.accept_rate_per_ip,
+        request_rate_per_ip: args.request_rate_per_ip,
+        request_rate_key_header: args.request_rate_key_header,
+        accept_pause_memory: args.accept_pause_memory,
+        reuse_port: args.reuse_port,
+        worker_memory_budget: args.worker_memory_budget,
+        memory_budget: args.memory_budget,
+        accept_per_loop_max: args.accept_per_loop_max,
+        poll_timeout_max: Duration::from_millis(args.poll_timeout_max as u64),
+        reactor_budget: args.reactor_budget,
+        zmq_hwm: args.zmq_hwm,
+        handle_bound: args.handle_bound,
+        resp_sender_bound: args.resp_sender_bound,
+        handle_accept_bound: args.handle_accept_bound,
+        port_file: args.port_file.map(PathBuf::from),
+        control: args.control.map(PathBuf::from),
+        upgrade: args.upgrade,
     };
 
     for v in args.listen.iter() {
@@ -173,8 +358,23 @@ fn process_args_and_run(args: Args) -> Result<(), Box<dyn Error>> {
         let part1 = parts.next().unwrap();
 
         let mut stream = true;
+        let mut raw = false;
+        let mut combined = false;
+        let mut stream_rules = Vec::new();
+        let mut messages_max = None;
+        let mut message_size_max = None;
+        let mut frame_size_max = None;
+        let mut tags = Vec::new();
+        let mut allowed_hosts = Vec::new();
+        let mut body_size_max = None;
+        let mut backend = None;
         let mut tls = false;
         let mut default_cert = None;
+        let mut no_sni = app::NoSniPolicy::DefaultCert;
+        let mut early_data = None;
+        let mut tls_backend = app::TlsBackend::OpenSsl;
+        let mut bind_opts = BindOpts::default();
+        let mut proxy = false;
         let mut local = false;
         let mut mode = None;
         let mut user = None;
@@ -189,8 +389,141 @@ fn process_args_and_run(args: Args) -> Result<(), Box<dyn Error>> {
             match k {
                 "req" => stream = false,
                 "stream" => stream = true,
+                "raw" => {
+                    stream = true;
+                    raw = true;
+                }
+                "combined" => combined = true,
+                "stream-if" => {
+                    let rule = match v.split_once(':') {
+                        Some(("method", method)) if !method.is_empty() => {
+                            app::StreamRule::Method(String::from(method))
+                        }
+                        Some(("path", prefix)) if !prefix.is_empty() => {
+                            app::StreamRule::PathPrefix(String::from(prefix))
+                        }
+                        None if v == "upgrade" => app::StreamRule::Upgrade,
+                        _ => {
+                            return Err(format!(
+                                "failed to parse listen: invalid stream-if rule: {}",
+                                v
+                            )
+                            .into())
+                        }
+                    };
+
+                    stream_rules.push(rule);
+                }
+                "messages-max" => match v.parse::<usize>() {
+                    Ok(x) if x > 0 => messages_max = Some(x),
+                    Ok(_) => {
+                        return Err(
+                            "failed to parse messages-max: value must be greater than 0".into()
+                        )
+                    }
+                    Err(e) => return Err(format!("failed to parse messages-max: {}", e).into()),
+                },
+                "message-size-max" => match v.parse::<usize>() {
+                    Ok(x) => message_size_max = Some(x),
+                    Err(e) => return Err(format!("failed to parse message-size-max: {}", e).into()),
+                },
+                "frame-size-max" => match v.parse::<usize>() {
+                    Ok(x) => frame_size_max = Some(x),
+                    Err(e) => return Err(format!("failed to parse frame-size-max: {}", e).into()),
+                },
+                "tag" => {
+                    let (name, value) = match v.find(':') {
+                        Some(pos) if pos > 0 => (&v[..pos], &v[(pos + 1)..]),
+                        _ => {
+                            return Err(
+                                "failed to parse listen: tag must be of the form key:value".into()
+                            )
+                        }
+                    };
+
+                    if tags.len() >= TAGS_MAX {
+                        return Err(
+                            format!("failed to parse listen: more than {} tags", TAGS_MAX).into(),
+                        );
+                    }
+
+                    tags.push((String::from(name), String::from(value)));
+                }
+                "allowed-host" if !v.is_empty() => {
+                    // a wildcard may only replace the first label
+                    if v.strip_prefix("*.").unwrap_or(v).contains('*') {
+                        return Err(
+                            format!("failed to parse listen: invalid allowed-host: {}", v).into(),
+                        );
+                    }
+
+                    allowed_hosts.push(String::from(v));
+                }
+                "body-size-max" => match v.parse::<usize>() {
+                    Ok(x) if x > 0 => body_size_max = Some(x),
+                    Ok(_) => {
+                        return Err(
+                            "failed to parse body-size-max: value must be greater than 0".into(),
+                        )
+                    }
+                    Err(e) => return Err(format!("failed to parse body-size-max: {}", e).into()),
+                },
+                "backend" if !v.is_empty() => backend = Some(String::from(v)),
                 "tls" => tls = true,
                 "default-cert" => default_cert = Some(String::from(v)),
+                "no-sni" => {
+                    no_sni = match v {
+                        "" => return Err("failed to parse listen: no-sni requires a value".into()),
+                        "default" => app::NoSniPolicy::DefaultCert,
+                        "reject" => app::NoSniPolicy::Reject,
+                        domain => app::NoSniPolicy::Backend(String::from(domain)),
+                    }
+                }
+                "early-data" => {
+                    let window = if v.is_empty() {
+                        EARLY_DATA_WINDOW_DEFAULT
+                    } else {
+                        match v.parse::<u64>() {
+                            Ok(x) => x,
+                            Err(e) => {
+                                return Err(format!("failed to parse early-data: {}", e).into())
+                            }
+                        }
+                    };
+
+                    early_data = Some(Duration::from_secs(window));
+                }
+                "tls-backend" => {
+                    tls_backend = match v {
+                        "openssl" => app::TlsBackend::OpenSsl,
+                        #[cfg(feature = "rustls")]
+                        "rustls" => app::TlsBackend::Rustls,
+                        _ => {
+                            return Err(format!(
+                                "failed to parse listen: unsupported tls-backend: {}",
+                                v
+                            )
+                            .into())
+                        }
+                    }
+                }
+                "device" => {
+                    if v.is_empty() {
+                        return Err("failed to parse listen: device requires a value".into());
+                    }
+
+                    bind_opts.device = Some(String::from(v));
+                }
+                "freebind" => bind_opts.freebind = true,
+                "transparent" => bind_opts.transparent = true,
+                "backlog" => match v.parse::<i32>() {
+                    Ok(x) if x > 0 => bind_opts.backlog = Some(x),
+                    Ok(_) => {
+                        return Err("failed to parse backlog: value must be greater than 0".into())
+                    }
+                    Err(e) => return Err(format!("failed to parse backlog: {}", e).into()),
+                },
+                "proxy" => proxy = true,
                 "local" => local = true,
                 "mode" => match u32::from_str_radix(v, 8) {
                     Ok(x) => mode = Some(x),
@@ -203,6 +536,16 @@ fn process_args_and_run(args: Args) -> Result<(), Box<dyn Error>> {
         }
 
         let spec = if local {
+            if bind_opts != BindOpts::default() {
+                return Err(
+                    "failed to parse listen: device, freebind, transparent, and backlog require tcp".into(),
+                );
+            }
+
+            if proxy {
+                return Err("failed to parse listen: proxy requires tcp".into());
+            }
+
             app::ListenSpec::Local {
                 path: PathBuf::from(part1),
                 mode,
@@ -233,14 +576,379 @@ fn process_args_and_run(args: Args) -> Result<(), Box<dyn Error>> {
                 }
             };
 
+            if early_data.is_some() && !tls {
+                return Err("failed to parse listen: early-data requires tls".into());
+            }
+
+            if tls_backend != app::TlsBackend::OpenSsl {
+                if !tls {
+                    return Err("failed to parse listen: tls-backend requires tls".into());
+                }
+
+                if early_data.is_some() {
+                    return Err(format!(
+                        "failed to parse listen: early-data is not supported by tls-backend {}",
+                        tls_backend.as_str()
+                    )
+                    .into());
+                }
+            }
+
             app::ListenSpec::Tcp {
                 addr,
                 tls,
                 default_cert,
+                no_sni,
+                early_data,
+                tls_backend,
+                bind_opts,
+                proxy,
+            }
+        };
+
+        if messages_max.is_some() && !stream {
+            return Err("failed to parse listen: messages-max requires stream mode".into());
+        }
+
+        if raw && !stream {
+            return Err("failed to parse listen: raw requires stream mode".into());
+        }
+
+        if raw && !allowed_hosts.is_empty() {
+            return Err("failed to parse listen: allowed-host does not apply to raw mode".into());
+        }
+
+        if raw && body_size_max.is_some() {
+            return Err("failed to parse listen: body-size-max does not apply to raw mode".into());
+        }
+
+        if raw && messages_max.is_some() {
+            return Err("failed to parse listen: messages-max does not apply to raw mode".into());
+        }
+
+        for (name, value) in [
+            ("message-size-max", message_size_max),
+            ("frame-size-max", frame_size_max),
+        ] {
+            if value.is_some() && !stream {
+                return Err(
+                    format!("failed to parse listen: {} requires stream mode", name).into(),
+                );
+            }
+
+            if value.is_some() && raw {
+                return Err(format!(
+                    "failed to parse listen: {} does not apply to raw mode",
+                    name
+                )
+                .into());
+            }
+        }
+
+        if combined {
+            if stream_rules.is_empty() {
+                return Err("failed to parse listen: combined requires stream-if rules".into());
+            }
+
+            if raw {
+                return Err("failed to parse listen: combined does not apply to raw mode".into());
+            }
+
+            // the mode is chosen by peeking at the request, which is only
+            // possible on plain tcp connections
+            if !matches!(spec, app::ListenSpec::Tcp { tls: false, .. }) {
+                return Err(
+                    "failed to parse listen: combined requires a plain tcp listener".into(),
+                );
+            }
+
+            stream = true;
+        } else if !stream_rules.is_empty() {
+            return Err("failed to parse listen: stream-if requires combined mode".into());
+        }
+
+        config.listen.push(app::ListenConfig {
+            spec,
+            stream,
+            stream_rules,
+            raw,
+            messages_max,
+            message_size_max,
+            frame_size_max,
+            tags,
+            allowed_hosts,
+            body_size_max,
+            backend,
+        });
+    }
+
+    for v in args.sni_backends.iter() {
+        let mut parts = v.split(',');
+
+        // there's always a first part
+        let domain = parts.next().unwrap();
+
+        if domain.is_empty() {
+            return Err("failed to parse sni-backend: empty domain".into());
+        }
+
+        let mut zclient_req = Vec::new();
+        let mut zclient_stream = Vec::new();
+
+        for part in parts {
+            let (k, v) = match part.find('=') {
+                Some(pos) => (&part[..pos], &part[(pos + 1)..]),
+                None => (part, ""),
+            };
+
+            match k {
+                "req" if !v.is_empty() => zclient_req.push(String::from(v)),
+                "stream" if !v.is_empty() => zclient_stream.push(String::from(v)),
+                _ => {
+                    return Err(
+                        format!("failed to parse sni-backend: invalid param: {}", part).into(),
+                    )
+                }
+            }
+        }
+
+        if zclient_req.is_empty() && zclient_stream.is_empty() {
+            return Err(format!(
+                "failed to parse sni-backend: no req or stream spec for {}",
+                domain
+            )
+            .into());
+        }
+
+        config.sni_backends.push(app::SniBackend {
+            domain: String::from(domain),
+            zclient_req,
+            zclient_stream,
+        });
+    }
+
+    for v in args.backends.iter() {
+        let mut parts = v.split(',');
+
+        // there's always a first part
+        let name = parts.next().unwrap();
+
+        if name.is_empty() {
+            return Err("failed to parse backend: empty name".into());
+        }
+
+        if config.backends.iter().any(|b| b.name == name) {
+            return Err(format!("failed to parse backend: duplicate name {}", name).into());
+        }
+
+        let mut zclient_req = Vec::new();
+        let mut zclient_stream = Vec::new();
+
+        for part in parts {
+            let (k, v) = match part.find('=') {
+                Some(pos) => (&part[..pos], &part[(pos + 1)..]),
+                None => (part, ""),
+            };
+
+            match k {
+                "req" if !v.is_empty() => zclient_req.push(String::from(v)),
+                "stream" if !v.is_empty() => zclient_stream.push(String::from(v)),
+                _ => return Err(format!("failed to parse backend: invalid param: {}", part).into()),
+            }
+        }
+
+        if zclient_req.is_empty() && zclient_stream.is_empty() {
+            return Err(format!(
+                "failed to parse backend: no req or stream spec for {}",
+                name
+            )
+            .into());
+        }
+
+        config.backends.push(app::Backend {
+            name: String::from(name),
+            zclient_req,
+            zclient_stream,
+        });
+    }
+
+    for v in args.routes.iter() {
+        let mut parts = v.split(',');
+
+        // there's always a first part
+        let backend = parts.next().unwrap();
+
+        if backend.is_empty() {
+            return Err("failed to parse route: empty backend".into());
+        }
+
+        let mut host = None;
+        let mut path_prefix = None;
+
+        for part in parts {
+            let (k, v) = match part.find('=') {
+                Some(pos) => (&part[..pos], &part[(pos + 1)..]),
+                None => (part, ""),
+            };
+
+            match k {
+                "host" if !v.is_empty() => host = Some(String::from(v)),
+                "path" if v.starts_with('/') => path_prefix = Some(String::from(v)),
+                _ => return Err(format!("failed to parse route: invalid param: {}", part).into()),
+            }
+        }
+
+        if host.is_none() && path_prefix.is_none() {
+            return Err(format!("failed to parse route: no host or path for {}", backend).into());
+        }
+
+        config.routes.push(app::Route {
+            backend: String::from(backend),
+            host,
+            path_prefix,
+        });
+    }
+
+    for v in args.fixed_responses.iter() {
+        let mut parts = v.split(',');
+
+        // there's always a first part
+        let path = parts.next().unwrap();
+
+        if !path.starts_with('/') || path.contains('?') {
+            return Err(format!(
+                "failed to parse fixed-response: path must start with /: {}",
+                path
+            )
+            .into());
+        }
+
+        let mut code = 200;
+        let mut headers = Vec::new();
+        let mut body_file = None;
+
+        for part in parts {
+            let (k, v) = match part.find('=') {
+                Some(pos) => (&part[..pos], &part[(pos + 1)..]),
+                None => (part, ""),
+            };
+
+            match k {
+                "code" => match v.parse() {
+                    Ok(x) if (200..=599).contains(&x) => code = x,
+                    _ => {
+                        return Err(
+                            format!("failed to parse fixed-response: invalid code: {}", v).into(),
+                        )
+                    }
+                },
+                "header" => {
+                    let (name, value) = match v.find(':') {
+                        Some(pos) if pos > 0 => (&v[..pos], v[(pos + 1)..].trim()),
+                        _ => return Err(
+                            "failed to parse fixed-response: header must be of the form name:value"
+                                .into(),
+                        ),
+                    };
+
+                    // framing is determined by the body
+                    if name.eq_ignore_ascii_case("Content-Length")
+                        || name.eq_ignore_ascii_case("Transfer-Encoding")
+                    {
+                        return Err(format!(
+                            "failed to parse fixed-response: header not allowed: {}",
+                            name
+                        )
+                        .into());
+                    }
+
+                    if headers.len() >= FIXED_RESPONSE_HEADERS_MAX {
+                        return Err(format!(
+                            "failed to parse fixed-response: more than {} headers",
+                            FIXED_RESPONSE_HEADERS_MAX
+                        )
+                        .into());
+                    }
+
+                    headers.push((String::from(name), String::from(value)));
+                }
+                "file" if !v.is_empty() => body_file = Some(PathBuf::from(v)),
+                _ => {
+                    return Err(
+                        format!("failed to parse fixed-response: invalid param: {}", part).into(),
+                    )
+                }
+            }
+        }
+
+        config.fixed_responses.push(app::FixedRoute {
+            path: String::from(path),
+            code,
+            headers,
+            body_file,
+        });
+    }
+
+    for v in args.error_pages.iter() {
+        let mut parts = v.split(',');
+
+        // there's always a first part
+        let code = parts.next().unwrap();
+
+        let code: u16 = match code.parse() {
+            Ok(x) if (400..=599).contains(&x) => x,
+            _ => return Err(format!("failed to parse error-page: invalid code: {}", code).into()),
+        };
+
+        if config.error_pages.iter().any(|t| t.code == code) {
+            return Err(format!("failed to parse error-page: duplicate code: {}", code).into());
+        }
+
+        let mut content_type = None;
+        let mut body_file = None;
+
+        for part in parts {
+            let (k, v) = match part.find('=') {
+                Some(pos) => (&part[..pos], &part[(pos + 1)..]),
+                None => (part, ""),
+            };
+
+            match k {
+                "file" if !v.is_empty() => body_file = Some(PathBuf::from(v)),
+                "type" if !v.is_empty() => content_type = Some(String::from(v)),
+                _ => {
+                    return Err(
+                        format!("failed to parse error-page: invalid param: {}", part).into(),
+                    )
+                }
             }
+        }
+
+        let body_file = match body_file {
+            Some(f) => f,
+            None => return Err(format!("failed to parse error-page: no file for {}", code).into()),
+        };
+
+        config.error_pages.push(app::ErrorTemplate {
+            code,
+            content_type,
+            body_file,
+        });
+    }
+
+    for v in args.trusted_proxies.iter() {
+        // a single address is a net of its own
+        let net = match v.parse::<IpNet>() {
+            Ok(net) => net,
+            Err(_) => match v.parse::<IpAddr>() {
+                Ok(addr) => IpNet::from(addr),
+                Err(_) => {
+                    return Err(format!("failed to parse trusted-proxy: invalid net: {}", v).into())
+                }
+            },
         };
 
-        config.listen.push(app::ListenConfig { spec, stream });
+        config.trusted_proxies.push(net);
     }
 
     if args.deny_out_internal {
@@ -249,6 +957,12 @@ fn process_args_and_run(args: Args) -> Result<(), Box<dyn Error>> {
         }
     }
 
+    if args.dump_config {
+        app::write_config(&mut io::stdout(), &config)?;
+
+        return Ok(());
+    }
+
     condure::run(&config)
 }
 
@@ -260,8 +974,8 @@ fn main() {
             Arg::new("log-level")
                 .long("log-level")
                 .num_args(1)
-                .value_name("N")
-                .help("Log level")
+                .value_name("filter")
+                .help("Log level from 0 (errors only) to 4 (trace), optionally followed by per-module levels, such as 2,condure::connection=3. Can be changed at runtime with the control socket, and SIGUSR2 toggles debug logging")
                 .default_value("2"),
         )
         .arg(
@@ -317,9 +1031,17 @@ fn main() {
                 .long("messages-max")
                 .num_args(1)
                 .value_name("N")
-                .help("Maximum number of queued WebSocket messages per connection")
+                .help("Maximum number of queued WebSocket messages per connection. Stream listeners may override this with a messages-max=N param")
                 .default_value("100"),
         )
+        .arg(
+            Arg::new("message-size-max")
+                .long("message-size-max")
+                .num_args(1)
+                .value_name("N")
+                .help("Maximum size of a received WebSocket message, or 0 for no limit. Stream listeners may override this with a message-size-max=N param, and limit the size of received frames with a frame-size-max=N param")
+                .default_value("0"),
+        )
         .arg(
             Arg::new("req-timeout")
                 .long("req-timeout")
@@ -336,6 +1058,78 @@ fn main() {
                 .help("Connection timeout in stream mode (seconds)")
                 .default_value("1800"),
         )
+        .arg(
+            Arg::new("req-retries")
+                .long("req-retries")
+                .num_args(1)
+                .value_name("N")
+                .help("Number of times to resend an idempotent req mode request that gets no response")
+                .default_value("0"),
+        )
+        .arg(
+            Arg::new("req-retry-timeout")
+                .long("req-retry-timeout")
+                .num_args(1)
+                .value_name("N")
+                .help("Time to wait for a response before resending a req mode request (milliseconds)")
+                .default_value("5000"),
+        )
+        .arg(
+            Arg::new("req-decompress-max")
+                .long("req-decompress-max")
+                .num_args(1)
+                .value_name("N")
+                .help("Decode gzip and deflate req mode request bodies before forwarding, rejecting those larger than N bytes when decoded, or 0 to forward them as-is")
+                .default_value("0"),
+        )
+        .arg(
+            Arg::new("handler-timeout")
+                .long("handler-timeout")
+                .num_args(1)
+                .value_name("N")
+                .help("Time to wait for a handler response before responding with 504 (seconds), or 0 for no limit")
+                .default_value("0"),
+        )
+        .arg(
+            Arg::new("header-timeout")
+                .long("header-timeout")
+                .num_args(1)
+                .value_name("N")
+                .help("Time allowed for receiving a request header (seconds), or 0 to use the connection timeout")
+                .default_value("0"),
+        )
+        .arg(
+            Arg::new("body-timeout")
+                .long("body-timeout")
+                .num_args(1)
+                .value_name("N")
+                .help("Time allowed for receiving a request body (seconds), or 0 to use the connection timeout")
+                .default_value("0"),
+        )
+        .arg(
+            Arg::new("idle-timeout")
+                .long("idle-timeout")
+                .num_args(1)
+                .value_name("N")
+                .help("Time to wait for the next request on a persistent connection (seconds), or 0 to use the connection timeout")
+                .default_value("0"),
+        )
+        .arg(
+            Arg::new("min-transfer-rate")
+                .long("min-transfer-rate")
+                .num_args(1)
+                .value_name("N")
+                .help("Minimum bytes per second while reading a request or writing a response, below which clients are disconnected (0 = no minimum)")
+                .default_value("0"),
+        )
+        .arg(
+            Arg::new("sse-keep-alive-interval")
+                .long("sse-keep-alive-interval")
+                .num_args(1)
+                .value_name("N")
+                .help("Send a comment to idle stream mode event streams at this interval (seconds), or 0 to disable")
+                .default_value("0"),
+        )
         .arg(
             Arg::new("listen")
                 .long("listen")
@@ -350,7 +1144,7 @@ fn main() {
                 .num_args(1)
                 .value_name("spec")
                 .action(ArgAction::Append)
-                .help("ZeroMQ client REQ spec")
+                .help("ZeroMQ client REQ spec, with optional ?sndhwm=N&rcvhwm=N&sndbuf=N&rcvbuf=N&linger=N socket options")
                 .default_value("ipc://client"),
         )
         .arg(
@@ -359,36 +1153,134 @@ fn main() {
                 .num_args(1)
                 .value_name("spec-base")
                 .action(ArgAction::Append)
-                .help("ZeroMQ client PUSH/ROUTER/SUB spec base")
+                .help("ZeroMQ client PUSH/ROUTER/SUB spec base, with optional ?sndhwm=N&rcvhwm=N&sndbuf=N&rcvbuf=N&linger=N socket options")
                 .default_value("ipc://client"),
         )
         .arg(
-            Arg::new("zclient-connect")
-                .long("zclient-connect")
-                .action(ArgAction::SetTrue)
-                .help("ZeroMQ client sockets should connect instead of bind"),
+            Arg::new("sni-backend")
+                .long("sni-backend")
+                .num_args(1)
+                .value_name("domain,params...")
+                .action(ArgAction::Append)
+                .help("ZeroMQ client specs for TLS connections with the given server name, as req=spec and/or stream=spec-base"),
         )
         .arg(
-            Arg::new("zserver-req")
-                .long("zserver-req")
+            Arg::new("backend")
+                .long("backend")
                 .num_args(1)
-                .value_name("spec")
+                .value_name("name,params...")
                 .action(ArgAction::Append)
-                .help("ZeroMQ server REQ spec"),
+                .help("ZeroMQ client specs of a named backend, as req=spec and/or stream=spec-base. Listeners select it with backend=name"),
         )
         .arg(
-            Arg::new("zserver-stream")
-                .long("zserver-stream")
+            Arg::new("route")
+                .long("route")
                 .num_args(1)
-                .value_name("spec-base")
+                .value_name("backend,params...")
                 .action(ArgAction::Append)
-                .help("ZeroMQ server PULL/ROUTER/PUB spec base"),
+                .help("Send requests matching host=pattern and/or path=prefix to a named backend. Routes are checked in order"),
         )
         .arg(
-            Arg::new("zserver-connect")
-                .long("zserver-connect")
-                .action(ArgAction::SetTrue)
-                .help("ZeroMQ server sockets should connect instead of bind"),
+            Arg::new("mirror-req")
+                .long("mirror-req")
+                .num_args(1)
+                .value_name("spec")
+                .action(ArgAction::Append)
+                .help("ZeroMQ client REQ spec of a shadow handler to send copies of requests to. Responses are discarded"),
+        )
+        .arg(
+            Arg::new("mirror-percent")
+                .long("mirror-percent")
+                .num_args(1)
+                .value_name("N")
+                .help("Percentage of requests to copy to the shadow handler")
+                .default_value("100"),
+        )
+        .arg(
+            Arg::new("announce")
+                .long("announce")
+                .num_args(1)
+                .value_name("spec")
+                .help("ZeroMQ PUB spec to periodically announce this instance on, for handlers to discover it"),
+        )
+        .arg(
+            Arg::new("announce-interval")
+                .long("announce-interval")
+                .num_args(1)
+                .value_name("N")
+                .help("Interval between announcements (seconds)")
+                .default_value("10"),
+        )
+        .arg(
+            Arg::new("stats")
+                .long("stats")
+                .num_args(1)
+                .value_name("spec")
+                .help("ZeroMQ PUB spec to periodically publish worker and aggregate stats on"),
+        )
+        .arg(
+            Arg::new("stats-interval")
+                .long("stats-interval")
+                .num_args(1)
+                .value_name("N")
+                .help("Interval between stats reports (seconds)")
+                .default_value("10"),
+        )
+        .arg(
+            Arg::new("admin")
+                .long("admin")
+                .num_args(1)
+                .value_name("spec")
+                .help("ZeroMQ REP spec to bind for admin commands, such as stats, drain, pause-accept, resume-accept, reload-certs, and conn-kill. Must be ipc, unless --zmq-curve-cert and --zmq-curve-allow are set"),
+        )
+        .arg(
+            Arg::new("zclient-connect")
+                .long("zclient-connect")
+                .action(ArgAction::SetTrue)
+                .help("ZeroMQ client sockets should connect instead of bind"),
+        )
+        .arg(
+            Arg::new("zserver-req")
+                .long("zserver-req")
+                .num_args(1)
+                .value_name("spec")
+                .action(ArgAction::Append)
+                .help("ZeroMQ server REQ spec, with optional ?sndhwm=N&rcvhwm=N&sndbuf=N&rcvbuf=N&linger=N socket options"),
+        )
+        .arg(
+            Arg::new("zserver-stream")
+                .long("zserver-stream")
+                .num_args(1)
+                .value_name("spec-base")
+                .action(ArgAction::Append)
+                .help("ZeroMQ server PULL/ROUTER/PUB spec base, with optional ?sndhwm=N&rcvhwm=N&sndbuf=N&rcvbuf=N&linger=N socket options"),
+        )
+        .arg(
+            Arg::new("zserver-connect")
+                .long("zserver-connect")
+                .action(ArgAction::SetTrue)
+                .help("ZeroMQ server sockets should connect instead of bind"),
+        )
+        .arg(
+            Arg::new("zmq-curve-cert")
+                .long("zmq-curve-cert")
+                .num_args(1)
+                .value_name("file")
+                .help("CurveZMQ secret certificate (czmq format) for the ZHTTP sockets. Without zmq-curve-server-cert, the sockets are curve servers"),
+        )
+        .arg(
+            Arg::new("zmq-curve-server-cert")
+                .long("zmq-curve-server-cert")
+                .num_args(1)
+                .value_name("file")
+                .help("CurveZMQ certificate of the handlers, making the ZHTTP sockets curve clients"),
+        )
+        .arg(
+            Arg::new("zmq-curve-allow")
+                .long("zmq-curve-allow")
+                .num_args(1)
+                .value_name("file")
+                .help("File of client public keys allowed to connect to the ZHTTP curve servers, one Z85 key per line"),
         )
         .arg(
             Arg::new("ipc-file-mode")
@@ -405,18 +1297,308 @@ fn main() {
                 .help("Directory containing certificates and private keys")
                 .default_value("."),
         )
+        .arg(
+            Arg::new("tls-ticket-key-rotation")
+                .long("tls-ticket-key-rotation")
+                .num_args(1)
+                .value_name("N")
+                .help("Interval between generating new TLS session ticket keys (seconds). Keys are shared by all workers")
+                .default_value("3600"),
+        )
+        .arg(
+            Arg::new("tls-ticket-key-overlap")
+                .long("tls-ticket-key-overlap")
+                .num_args(1)
+                .value_name("N")
+                .help("How long replaced TLS session ticket keys can still be used to resume sessions (seconds)")
+                .default_value("7200"),
+        )
         .arg(
             Arg::new("compression")
                 .long("compression")
                 .action(ArgAction::SetTrue)
                 .help("Allow compression to be used"),
         )
+        .arg(
+            Arg::new("download-rate")
+                .long("download-rate")
+                .num_args(1)
+                .value_name("N")
+                .help("Maximum bytes per second written to each client (0 = no limit)")
+                .default_value("0"),
+        )
+        .arg(
+            Arg::new("keep-alive-session-info")
+                .long("keep-alive-session-info")
+                .action(ArgAction::SetTrue)
+                .help("Include bytes transferred and idle time of sessions in keep-alives"),
+        )
+        .arg(
+            Arg::new("forwarded-headers")
+                .long("forwarded-headers")
+                .num_args(1)
+                .value_name("style")
+                .help("Headers describing the client to add to requests sent to handlers: none, x-forwarded (appending to X-Forwarded-For and setting X-Forwarded-Proto), forwarded (appending to Forwarded, as in RFC 7239), or both")
+                .default_value("none"),
+        )
+        .arg(
+            Arg::new("trusted-proxy")
+                .long("trusted-proxy")
+                .num_args(1)
+                .value_name("net")
+                .action(ArgAction::Append)
+                .help("Address or CIDR of an upstream proxy whose X-Forwarded-For and X-Forwarded-Proto headers determine the client address and protocol"),
+        )
+        .arg(
+            Arg::new("allow-http09")
+                .long("allow-http09")
+                .action(ArgAction::SetTrue)
+                .help("Accept HTTP/0.9 simple requests instead of responding with 505"),
+        )
+        .arg(
+            Arg::new("strict-request-target")
+                .long("strict-request-target")
+                .action(ArgAction::SetTrue)
+                .help("Respond with 400 to requests whose target contains control characters, backslashes, a fragment, . or .. path segments, or encoded slashes or backslashes in the path, instead of forwarding them"),
+        )
+        .arg(
+            Arg::new("uri-max")
+                .long("uri-max")
+                .num_args(1)
+                .value_name("N")
+                .help("Maximum length of a request target, or 0 to limit it only by the buffer size. Longer targets are responded to with 414")
+                .default_value("0"),
+        )
+        .arg(
+            Arg::new("options-allow")
+                .long("options-allow")
+                .num_args(1)
+                .value_name("methods")
+                .help("Allow header to respond with to OPTIONS * requests, or empty to forward them to the handler")
+                .default_value("GET, HEAD, POST, PUT, PATCH, DELETE, OPTIONS"),
+        )
+        .arg(
+            Arg::new("options-body")
+                .long("options-body")
+                .num_args(1)
+                .value_name("file")
+                .help("File containing a body to respond with to OPTIONS * requests, such as a description of capabilities. Served as application/json if the name ends in .json, otherwise as text/plain"),
+        )
+        .arg(
+            Arg::new("health-live-path")
+                .long("health-live-path")
+                .num_args(1)
+                .value_name("path")
+                .help("Path to answer directly with 200 while the server is running, such as /healthz"),
+        )
+        .arg(
+            Arg::new("health-ready-path")
+                .long("health-ready-path")
+                .num_args(1)
+                .value_name("path")
+                .help("Path to answer directly with 200 if the handlers are reachable, or 503 otherwise, such as /readyz"),
+        )
+        .arg(
+            Arg::new("fixed-response")
+                .long("fixed-response")
+                .num_args(1)
+                .value_name("path,params...")
+                .action(ArgAction::Append)
+                .help("Answer requests for a path directly, with code=status, header=name:value, and file=body-file. A path ending in * matches as a prefix"),
+        )
+        .arg(
+            Arg::new("error-page")
+                .long("error-page")
+                .num_args(1)
+                .value_name("code,params...")
+                .action(ArgAction::Append)
+                .help("Respond with file=body-file for errors with the code generated by condure itself, such as 503 or 504. The content type may be set with type=value, otherwise it is based on the file extension"),
+        )
+        .arg(
+            Arg::new("access-log")
+                .long("access-log")
+                .num_args(1)
+                .value_name("file")
+                .help("File to append a line to for each completed request, or - for stdout"),
+        )
+        .arg(
+            Arg::new("access-log-format")
+                .long("access-log-format")
+                .num_args(1)
+                .value_name("format")
+                .help("Format of access log lines: common, or combined (which adds referer, user agent, and duration in microseconds)")
+                .default_value("combined"),
+        )
+        .arg(
+            Arg::new("file-root")
+                .long("file-root")
+                .num_args(1)
+                .value_name("dir")
+                .help("Directory of files that handlers may respond with by path, to be sent as the response body without passing through zmq. Symbolic links within it are not followed"),
+        )
         .arg(
             Arg::new("deny-out-internal")
                 .long("deny-out-internal")
                 .action(ArgAction::SetTrue)
                 .help("Block outbound connections to local/internal IP address ranges"),
         )
+        .arg(
+            Arg::new("accept-rate")
+                .long("accept-rate")
+                .num_args(1)
+                .value_name("N")
+                .help("Maximum number of new connections accepted per second (0 = no limit)")
+                .default_value("0"),
+        )
+        .arg(
+            Arg::new("accept-rate-per-ip")
+                .long("accept-rate-per-ip")
+                .num_args(1)
+                .value_name("N")
+                .help("Maximum number of new connections accepted per second from a single IP address (0 = no limit)")
+                .default_value("0"),
+        )
+        .arg(
+            Arg::new("request-rate-per-ip")
+                .long("request-rate-per-ip")
+                .num_args(1)
+                .value_name("N")
+                .help("Maximum number of requests forwarded per second from a single IP address, responding to others with 429 (0 = no limit)")
+                .default_value("0"),
+        )
+        .arg(
+            Arg::new("request-rate-key-header")
+                .long("request-rate-key-header")
+                .num_args(1)
+                .value_name("name")
+                .help("Limit requests having this header by its value rather than by IP address. Only used for requests from a trusted proxy"),
+        )
+        .arg(
+            Arg::new("accept-pause-memory")
+                .long("accept-pause-memory")
+                .num_args(1)
+                .value_name("N")
+                .help("Pause accepting new connections while connection buffers use at least this many bytes (0 = never pause)")
+                .default_value("0"),
+        )
+        .arg(
+            Arg::new("reuse-port")
+                .long("reuse-port")
+                .action(ArgAction::SetTrue)
+                .help("Have each worker bind its own socket for each TCP listener with SO_REUSEPORT and accept directly, instead of a listener thread handing out connections. The kernel spreads connections across the workers, even if some are busier than others"),
+        )
+        .arg(
+            Arg::new("worker-memory-budget")
+                .long("worker-memory-budget")
+                .num_args(1)
+                .value_name("N")
+                .help("Per-worker memory budget for connection buffers in bytes. When exceeded, idle connections are closed to make room, and new stream requests and requests with large bodies are rejected (0 = no budget)")
+                .default_value("0"),
+        )
+        .arg(
+            Arg::new("memory-budget")
+                .long("memory-budget")
+                .num_args(1)
+                .value_name("N")
+                .help("Memory budget for buffers and arenas across all workers in bytes. When exceeded, the same applies as with --worker-memory-budget, and accepting pauses unless --accept-pause-memory is set (0 = no budget)")
+                .default_value("0"),
+        )
+        .arg(
+            Arg::new("accept-per-loop-max")
+                .long("accept-per-loop-max")
+                .num_args(1)
+                .value_name("N")
+                .help("Number of connections a worker accepts in a row before letting other tasks run (0 = no limit)")
+                .default_value("100"),
+        )
+        .arg(
+            Arg::new("poll-timeout-max")
+                .long("poll-timeout-max")
+                .num_args(1)
+                .value_name("N")
+                .help("Maximum time a worker waits for events before checking on its tasks, in milliseconds (0 = no limit)")
+                .default_value("0"),
+        )
+        .arg(
+            Arg::new("reactor-budget")
+                .long("reactor-budget")
+                .num_args(1)
+                .value_name("N")
+                .help("Number of events a worker processes per poll before running tasks")
+                .default_value("100"),
+        )
+        .arg(
+            Arg::new("zmq-hwm")
+                .long("zmq-hwm")
+                .num_args(1)
+                .value_name("N")
+                .help("High-water mark of the ZHTTP sockets, other than for initial requests. Spec options override it per spec (0 = 5% of maxconn)")
+                .default_value("0"),
+        )
+        .arg(
+            Arg::new("handle-bound")
+                .long("handle-bound")
+                .num_args(1)
+                .value_name("N")
+                .help("Per-worker queue size for messages to and from zhttp sockets. A larger queue absorbs bursts, but buffers more messages in memory and delays backpressure to clients (0 = 5% of maxconn spread across workers)")
+                .default_value("0"),
+        )
+        .arg(
+            Arg::new("resp-sender-bound")
+                .long("resp-sender-bound")
+                .num_args(1)
+                .value_name("N")
+                .help("Per-connection queue size for handler responses. A larger queue keeps a slow client from stalling deliveries to other connections, but preallocates more messages for every connection")
+                .default_value("1"),
+        )
+        .arg(
+            Arg::new("handle-accept-bound")
+                .long("handle-accept-bound")
+                .num_args(1)
+                .value_name("N")
+                .help("Per-worker queue size for notifications of finished connections. A larger queue absorbs bursts of connections ending at once without stalling the delivery of handler messages")
+                .default_value("100"),
+        )
+        .arg(
+            Arg::new("port-file")
+                .long("port-file")
+                .num_args(1)
+                .value_name("file")
+                .help("File to write the bound listen addresses to, one per line, in the same format as --listen"),
+        )
+        .arg(
+            Arg::new("control")
+                .long("control")
+                .num_args(1)
+                .value_name("file")
+                .help("Unix socket to listen on for control commands, such as health checks and draining connections"),
+        )
+        .arg(
+            Arg::new("upgrade")
+                .long("upgrade")
+                .action(ArgAction::SetTrue)
+                .help("Take over the listeners of the instance on the control socket, if any, and have it stop gracefully once this instance has started"),
+        )
+        .arg(
+            Arg::new("check")
+                .long("check")
+                .num_args(1)
+                .value_name("file")
+                .help("Checks the health of the instance with the given control socket, and exits nonzero if unhealthy"),
+        )
+        .arg(
+            Arg::new("connections")
+                .long("connections")
+                .num_args(1)
+                .value_name("file")
+                .help("Lists the active connections of the instance with the given control socket"),
+        )
+        .arg(
+            Arg::new("dump-config")
+                .long("dump-config")
+                .action(ArgAction::SetTrue)
+                .help("Prints the effective configuration, including defaults, and exits"),
+        )
         .arg(
             Arg::new("sizes")
                 .long("sizes")
@@ -431,7 +1613,7 @@ fn main() {
 
     let level = matches.get_one::<String>("log-level").unwrap();
 
-    let level: usize = match level.parse() {
+    let filter: LogFilter = match level.parse() {
         Ok(x) => x,
         Err(e) => {
             error!("failed to parse log-level: {}", e);
@@ -439,16 +1621,7 @@ fn main() {
         }
     };
 
-    let level = match level {
-        0 => LevelFilter::Error,
-        1 => LevelFilter::Warn,
-        2 => LevelFilter::Info,
-        3 => LevelFilter::Debug,
-        4..=core::usize::MAX => LevelFilter::Trace,
-        _ => unreachable!(),
-    };
-
-    log::set_max_level(level);
+    logfilter::set(filter);
 
     if *matches.get_one("sizes").unwrap() {
         for (name, size) in condure::app::App::sizes() {
@@ -457,6 +1630,32 @@ fn main() {
         process::exit(0);
     }
 
+    if let Some(path) = matches.get_one::<String>("check") {
+        if let Err(e) = condure::control::check(&PathBuf::from(path)) {
+            error!("unhealthy: {}", e);
+            process::exit(1);
+        }
+
+        println!("ok");
+        process::exit(0);
+    }
+
+    if let Some(path) = matches.get_one::<String>("connections") {
+        match condure::control::connections(&PathBuf::from(path)) {
+            Ok(conns) => {
+                for c in conns {
+                    println!("{}", c);
+                }
+            }
+            Err(e) => {
+                error!("failed to list connections: {}", e);
+                process::exit(1);
+            }
+        }
+
+        process::exit(0);
+    }
+
     let id = matches.get_one::<String>("id").unwrap();
 
     let workers = matches.get_one::<String>("workers").unwrap();
@@ -519,6 +1718,16 @@ fn main() {
         }
     };
 
+    let message_size_max = matches.get_one::<String>("message-size-max").unwrap();
+
+    let message_size_max: usize = match message_size_max.parse() {
+        Ok(x) => x,
+        Err(e) => {
+            error!("failed to parse message-size-max: {}", e);
+            process::exit(1);
+        }
+    };
+
     let req_timeout = matches.get_one::<String>("req-timeout").unwrap();
 
     let req_timeout: usize = match req_timeout.parse() {
@@ -539,6 +1748,98 @@ fn main() {
         }
     };
 
+    let req_retries = matches.get_one::<String>("req-retries").unwrap();
+
+    let req_retries: usize = match req_retries.parse() {
+        Ok(x) => x,
+        Err(e) => {
+            error!("failed to parse req-retries: {}", e);
+            process::exit(1);
+        }
+    };
+
+    let req_retry_timeout = matches.get_one::<String>("req-retry-timeout").unwrap();
+
+    let req_retry_timeout: usize = match req_retry_timeout.parse() {
+        Ok(x) => x,
+        Err(e) => {
+            error!("failed to parse req-retry-timeout: {}", e);
+            process::exit(1);
+        }
+    };
+
+    let req_decompress_max = matches.get_one::<String>("req-decompress-max").unwrap();
+
+    let req_decompress_max: usize = match req_decompress_max.parse() {
+        Ok(x) => x,
+        Err(e) => {
+            error!("failed to parse req-decompress-max: {}", e);
+            process::exit(1);
+        }
+    };
+
+    let handler_timeout = matches.get_one::<String>("handler-timeout").unwrap();
+
+    let handler_timeout: usize = match handler_timeout.parse() {
+        Ok(x) => x,
+        Err(e) => {
+            error!("failed to parse handler-timeout: {}", e);
+            process::exit(1);
+        }
+    };
+
+    let header_timeout = matches.get_one::<String>("header-timeout").unwrap();
+
+    let header_timeout: usize = match header_timeout.parse() {
+        Ok(x) => x,
+        Err(e) => {
+            error!("failed to parse header-timeout: {}", e);
+            process::exit(1);
+        }
+    };
+
+    let body_timeout = matches.get_one::<String>("body-timeout").unwrap();
+
+    let body_timeout: usize = match body_timeout.parse() {
+        Ok(x) => x,
+        Err(e) => {
+            error!("failed to parse body-timeout: {}", e);
+            process::exit(1);
+        }
+    };
+
+    let idle_timeout = matches.get_one::<String>("idle-timeout").unwrap();
+
+    let idle_timeout: usize = match idle_timeout.parse() {
+        Ok(x) => x,
+        Err(e) => {
+            error!("failed to parse idle-timeout: {}", e);
+            process::exit(1);
+        }
+    };
+
+    let min_transfer_rate = matches.get_one::<String>("min-transfer-rate").unwrap();
+
+    let min_transfer_rate: u32 = match min_transfer_rate.parse() {
+        Ok(x) => x,
+        Err(e) => {
+            error!("failed to parse min-transfer-rate: {}", e);
+            process::exit(1);
+        }
+    };
+
+    let sse_keep_alive_interval = matches
+        .get_one::<String>("sse-keep-alive-interval")
+        .unwrap();
+
+    let sse_keep_alive_interval: usize = match sse_keep_alive_interval.parse() {
+        Ok(x) => x,
+        Err(e) => {
+            error!("failed to parse sse-keep-alive-interval: {}", e);
+            process::exit(1);
+        }
+    };
+
     let mut listen: Vec<String> = matches
         .get_many::<String>("listen")
         .unwrap_or_default()
@@ -557,6 +1858,66 @@ fn main() {
         .map(|v| v.to_owned())
         .collect();
 
+    let sni_backends: Vec<String> = matches
+        .get_many::<String>("sni-backend")
+        .unwrap_or_default()
+        .map(|v| v.to_owned())
+        .collect();
+
+    let backends: Vec<String> = matches
+        .get_many::<String>("backend")
+        .unwrap_or_default()
+        .map(|v| v.to_owned())
+        .collect();
+
+    let routes: Vec<String> = matches
+        .get_many::<String>("route")
+        .unwrap_or_default()
+        .map(|v| v.to_owned())
+        .collect();
+
+    let mirror_req_specs: Vec<String> = matches
+        .get_many::<String>("mirror-req")
+        .unwrap_or_default()
+        .map(|v| v.to_owned())
+        .collect();
+
+    let mirror_percent = matches.get_one::<String>("mirror-percent").unwrap();
+
+    let mirror_percent: u32 = match mirror_percent.parse() {
+        Ok(x) => x,
+        Err(e) => {
+            error!("failed to parse mirror-percent: {}", e);
+            process::exit(1);
+        }
+    };
+
+    let announce_spec = matches.get_one::<String>("announce").cloned();
+
+    let announce_interval = matches.get_one::<String>("announce-interval").unwrap();
+
+    let announce_interval: usize = match announce_interval.parse() {
+        Ok(x) => x,
+        Err(e) => {
+            error!("failed to parse announce-interval: {}", e);
+            process::exit(1);
+        }
+    };
+
+    let stats_spec = matches.get_one::<String>("stats").cloned();
+
+    let stats_interval = matches.get_one::<String>("stats-interval").unwrap();
+
+    let stats_interval: usize = match stats_interval.parse() {
+        Ok(x) => x,
+        Err(e) => {
+            error!("failed to parse stats-interval: {}", e);
+            process::exit(1);
+        }
+    };
+
+    let admin_spec = matches.get_one::<String>("admin").cloned();
+
     let zclient_connect = *matches.get_one("zclient-connect").unwrap();
 
     let zserver_req_specs: Vec<String> = matches
@@ -573,6 +1934,12 @@ fn main() {
 
     let zserver_connect = *matches.get_one("zserver-connect").unwrap();
 
+    let zmq_curve_cert = matches.get_one::<String>("zmq-curve-cert").cloned();
+
+    let zmq_curve_server_cert = matches.get_one::<String>("zmq-curve-server-cert").cloned();
+
+    let zmq_curve_allow = matches.get_one::<String>("zmq-curve-allow").cloned();
+
     let ipc_file_mode = matches
         .get_one::<String>("ipc-file-mode")
         .cloned()
@@ -588,10 +1955,253 @@ fn main() {
 
     let tls_identities_dir = matches.get_one::<String>("tls-identities-dir").unwrap();
 
+    let tls_ticket_key_rotation = matches
+        .get_one::<String>("tls-ticket-key-rotation")
+        .unwrap();
+
+    let tls_ticket_key_rotation: usize = match tls_ticket_key_rotation.parse() {
+        Ok(x) => x,
+        Err(e) => {
+            error!("failed to parse tls-ticket-key-rotation: {}", e);
+            process::exit(1);
+        }
+    };
+
+    let tls_ticket_key_overlap = matches.get_one::<String>("tls-ticket-key-overlap").unwrap();
+
+    let tls_ticket_key_overlap: usize = match tls_ticket_key_overlap.parse() {
+        Ok(x) => x,
+        Err(e) => {
+            error!("failed to parse tls-ticket-key-overlap: {}", e);
+            process::exit(1);
+        }
+    };
+
     let allow_compression = *matches.get_one("compression").unwrap();
 
+    let download_rate = matches.get_one::<String>("download-rate").unwrap();
+
+    let download_rate: u32 = match download_rate.parse() {
+        Ok(x) => x,
+        Err(e) => {
+            error!("failed to parse download-rate: {}", e);
+            process::exit(1);
+        }
+    };
+
+    let keep_alive_session_info = *matches.get_one("keep-alive-session-info").unwrap();
+
+    let allow_http09 = *matches.get_one("allow-http09").unwrap();
+
+    let strict_request_target = *matches.get_one("strict-request-target").unwrap();
+
+    let uri_max = matches.get_one::<String>("uri-max").unwrap();
+
+    let uri_max: usize = match uri_max.parse() {
+        Ok(x) if x <= buffer_size => x,
+        Ok(_) => {
+            error!("failed to parse uri-max: value must not exceed buffer-size");
+            process::exit(1);
+        }
+        Err(e) => {
+            error!("failed to parse uri-max: {}", e);
+            process::exit(1);
+        }
+    };
+
+    let forwarded_headers = matches
+        .get_one::<String>("forwarded-headers")
+        .unwrap()
+        .to_owned();
+
+    let trusted_proxies: Vec<String> = matches
+        .get_many::<String>("trusted-proxy")
+        .unwrap_or_default()
+        .map(|v| v.to_owned())
+        .collect();
+
+    let options_allow = matches
+        .get_one::<String>("options-allow")
+        .unwrap()
+        .to_owned();
+
+    let options_body = matches.get_one::<String>("options-body").cloned();
+
+    let health_live_path = matches.get_one::<String>("health-live-path").cloned();
+
+    let health_ready_path = matches.get_one::<String>("health-ready-path").cloned();
+
+    let fixed_responses: Vec<String> = matches
+        .get_many::<String>("fixed-response")
+        .unwrap_or_default()
+        .map(|v| v.to_owned())
+        .collect();
+
+    let error_pages: Vec<String> = matches
+        .get_many::<String>("error-page")
+        .unwrap_or_default()
+        .map(|v| v.to_owned())
+        .collect();
+
+    let access_log = matches.get_one::<String>("access-log").cloned();
+
+    let access_log_format = matches
+        .get_one::<String>("access-log-format")
+        .unwrap()
+        .to_owned();
+
+    let file_root = matches.get_one::<String>("file-root").cloned();
+
     let deny_out_internal = *matches.get_one("deny-out-internal").unwrap();
 
+    let accept_rate = matches.get_one::<String>("accept-rate").unwrap();
+
+    let accept_rate: u32 = match accept_rate.parse() {
+        Ok(x) => x,
+        Err(e) => {
+            error!("failed to parse accept-rate: {}", e);
+            process::exit(1);
+        }
+    };
+
+    let accept_rate_per_ip = matches.get_one::<String>("accept-rate-per-ip").unwrap();
+
+    let accept_rate_per_ip: u32 = match accept_rate_per_ip.parse() {
+        Ok(x) => x,
+        Err(e) => {
+            error!("failed to parse accept-rate-per-ip: {}", e);
+            process::exit(1);
+        }
+    };
+
+    let request_rate_per_ip = matches.get_one::<String>("request-rate-per-ip").unwrap();
+
+    let request_rate_per_ip: u32 = match request_rate_per_ip.parse() {
+        Ok(x) => x,
+        Err(e) => {
+            error!("failed to parse request-rate-per-ip: {}", e);
+            process::exit(1);
+        }
+    };
+
+    let request_rate_key_header = matches
+        .get_one::<String>("request-rate-key-header")
+        .cloned();
+
+    let accept_pause_memory = matches.get_one::<String>("accept-pause-memory").unwrap();
+
+    let accept_pause_memory: usize = match accept_pause_memory.parse() {
+        Ok(x) => x,
+        Err(e) => {
+            error!("failed to parse accept-pause-memory: {}", e);
+            process::exit(1);
+        }
+    };
+
+    let reuse_port = *matches.get_one("reuse-port").unwrap();
+
+    let worker_memory_budget = matches.get_one::<String>("worker-memory-budget").unwrap();
+
+    let worker_memory_budget: usize = match worker_memory_budget.parse() {
+        Ok(x) => x,
+        Err(e) => {
+            error!("failed to parse worker-memory-budget: {}", e);
+            process::exit(1);
+        }
+    };
+
+    let memory_budget = matches.get_one::<String>("memory-budget").unwrap();
+
+    let memory_budget: usize = match memory_budget.parse() {
+        Ok(x) => x,
+        Err(e) => {
+            error!("failed to parse memory-budget: {}", e);
+            process::exit(1);
+        }
+    };
+
+    let accept_per_loop_max = matches.get_one::<String>("accept-per-loop-max").unwrap();
+
+    let accept_per_loop_max: usize = match accept_per_loop_max.parse() {
+        Ok(x) => x,
+        Err(e) => {
+            error!("failed to parse accept-per-loop-max: {}", e);
+            process::exit(1);
+        }
+    };
+
+    let poll_timeout_max = matches.get_one::<String>("poll-timeout-max").unwrap();
+
+    let poll_timeout_max: usize = match poll_timeout_max.parse() {
+        Ok(x) => x,
+        Err(e) => {
+            error!("failed to parse poll-timeout-max: {}", e);
+            process::exit(1);
+        }
+    };
+
+    let reactor_budget = matches.get_one::<String>("reactor-budget").unwrap();
+
+    let reactor_budget: u32 = match reactor_budget.parse() {
+        Ok(x) if x > 0 => x,
+        Ok(_) => {
+            error!("reactor-budget must be greater than 0");
+            process::exit(1);
+        }
+        Err(e) => {
+            error!("failed to parse reactor-budget: {}", e);
+            process::exit(1);
+        }
+    };
+
+    let zmq_hwm = matches.get_one::<String>("zmq-hwm").unwrap();
+
+    let zmq_hwm: usize = match zmq_hwm.parse() {
+        Ok(x) => x,
+        Err(e) => {
+            error!("failed to parse zmq-hwm: {}", e);
+            process::exit(1);
+        }
+    };
+
+    let handle_bound = matches.get_one::<String>("handle-bound").unwrap();
+
+    let handle_bound: usize = match handle_bound.parse() {
+        Ok(x) => x,
+        Err(e) => {
+            error!("failed to parse handle-bound: {}", e);
+            process::exit(1);
+        }
+    };
+
+    let resp_sender_bound = matches.get_one::<String>("resp-sender-bound").unwrap();
+
+    let resp_sender_bound: usize = match resp_sender_bound.parse() {
+        Ok(x) => x,
+        Err(e) => {
+            error!("failed to parse resp-sender-bound: {}", e);
+            process::exit(1);
+        }
+    };
+
+    let handle_accept_bound = matches.get_one::<String>("handle-accept-bound").unwrap();
+
+    let handle_accept_bound: usize = match handle_accept_bound.parse() {
+        Ok(x) => x,
+        Err(e) => {
+            error!("failed to parse handle-accept-bound: {}", e);
+            process::exit(1);
+        }
+    };
+
+    let port_file = matches.get_one::<String>("port-file").cloned();
+
+    let control = matches.get_one::<String>("control").cloned();
+
+    let upgrade = *matches.get_one("upgrade").unwrap();
+
+    let dump_config = *matches.get_one("dump-config").unwrap();
+
     // if no zmq server specs are set (needed by client mode), specify
     // default listen configuration in order to enable server mode. this
     // means if zmq server specs are set, then server mode won't be enabled
@@ -608,19 +2218,79 @@ fn main() {
         buffer_size,
         body_buffer_size,
         messages_max,
+        message_size_max,
         req_timeout,
         stream_timeout,
+        req_retries,
+        req_retry_timeout,
+        req_decompress_max,
+        handler_timeout,
+        header_timeout,
+        body_timeout,
+        idle_timeout,
+        min_transfer_rate,
+        sse_keep_alive_interval,
         listen,
         zclient_req_specs,
         zclient_stream_specs,
+        sni_backends,
+        backends,
+        routes,
+        mirror_req_specs,
+        mirror_percent,
+        announce_spec,
+        announce_interval,
+        stats_spec,
+        stats_interval,
+        admin_spec,
         zclient_connect,
         zserver_req_specs,
         zserver_stream_specs,
         zserver_connect,
+        zmq_curve_cert,
+        zmq_curve_server_cert,
+        zmq_curve_allow,
         ipc_file_mode,
         tls_identities_dir: tls_identities_dir.to_string(),
+        tls_ticket_key_rotation,
+        tls_ticket_key_overlap,
         allow_compression,
+        forwarded_headers,
+        trusted_proxies,
+        download_rate,
+        keep_alive_session_info,
+        allow_http09,
+        strict_request_target,
+        uri_max,
+        options_allow,
+        options_body,
+        health_live_path,
+        health_ready_path,
+        fixed_responses,
+        error_pages,
+        access_log,
+        access_log_format,
+        file_root,
         deny_out_internal,
+        accept_rate,
+        accept_rate_per_ip,
+        request_rate_per_ip,
+        request_rate_key_header,
+        accept_pause_memory,
+        reuse_port,
+        worker_memory_budget,
+        memory_budget,
+        accept_per_loop_max,
+        poll_timeout_max,
+        reactor_budget,
+        zmq_hwm,
+        handle_bound,
+        resp_sender_bound,
+        handle_accept_bound,
+        port_file,
+        control,
+        upgrade,
+        dump_config,
     };
 
     if let Err(e) = process_args_and_run(args) {
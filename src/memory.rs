@@ -0,0 +1,284 @@
+/*
+ * Copyright (C) 2024 Fanout, Inc.
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+// tracks the amount of memory in use, as an estimate provided by the
+// holders of reservations. this is shared between threads. if a parent is
+// set, reservations are also applied to the parent
+#[derive(Default)]
+pub struct MemoryUsage {
+    used: AtomicUsize,
+    parent: Option<Arc<MemoryUsage>>,
+}
+
+impl MemoryUsage {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_parent(parent: &Arc<MemoryUsage>) -> Self {
+        Self {
+            used: AtomicUsize::new(0),
+            parent: Some(Arc::clone(parent)),
+        }
+    }
+
+    pub fn used(&self) -> usize {
+        self.used.load(Ordering::Relaxed)
+    }
+
+    pub fn reserve(self: &Arc<Self>, size: usize) -> MemoryReservation {
+        self.add(size);
+
+        MemoryReservation {
+            usage: Arc::clone(self),
+            size,
+        }
+    }
+
+    fn add(&self, size: usize) {
+        self.used.fetch_add(size, Ordering::Relaxed);
+
+        if let Some(parent) = &self.parent {
+            parent.add(size);
+        }
+    }
+
+    fn sub(&self, size: usize) {
+        self.used.fetch_sub(size, Ordering::Relaxed);
+
+        if let Some(parent) = &self.parent {
+            parent.sub(size);
+        }
+    }
+}
+
+// returns the reserved amount to the usage on drop
+pub struct MemoryReservation {
+    usage: Arc<MemoryUsage>,
+    size: usize,
+}
+
+impl MemoryReservation {
+    pub fn size(&self) -> usize {
+        self.size
+    }
+}
+
+impl Drop for MemoryReservation {
+    fn drop(&mut self) {
+        self.usage.sub(self.size);
+    }
+}
+
+// reports whether usage is over a threshold. once exceeded, usage must drop
+// below a lower mark before the threshold is considered cleared, to avoid
+// flapping
+pub struct MemoryThreshold {
+    usage: Arc<MemoryUsage>,
+    high: usize,
+    low: usize,
+    exceeded: bool,
+}
+
+impl MemoryThreshold {
+    pub fn new(usage: &Arc<MemoryUsage>, high: usize) -> Self {
+        Self {
+            usage: Arc::clone(usage),
+            high,
+            low: high - (high / 10),
+            exceeded: false,
+        }
+    }
+
+    pub fn used(&self) -> usize {
+        self.usage.used()
+    }
+
+    pub fn check(&mut self) -> bool {
+        let used = self.usage.used();
+
+        if self.exceeded {
+            if used < self.low {
+                self.exceeded = false;
+            }
+        } else if used >= self.high {
+            self.exceeded = true;
+        }
+
+        self.exceeded
+    }
+}
+
+// a limit on usage. a limit of 0 means no limit. if a parent is set, such
+// as a global budget for a worker's budget, the budget is also considered
+// exceeded when the parent is
+#[derive(Clone)]
+pub struct MemoryBudget {
+    usage: Arc<MemoryUsage>,
+    limit: usize,
+    parent: Option<Arc<MemoryBudget>>,
+}
+
+impl MemoryBudget {
+    pub fn new(usage: &Arc<MemoryUsage>, limit: usize) -> Self {
+        Self {
+            usage: Arc::clone(usage),
+            limit,
+            parent: None,
+        }
+    }
+
+    pub fn with_parent(usage: &Arc<MemoryUsage>, limit: usize, parent: &Arc<MemoryBudget>) -> Self {
+        Self {
+            usage: Arc::clone(usage),
+            limit,
+            parent: Some(Arc::clone(parent)),
+        }
+    }
+
+    pub fn usage(&self) -> &Arc<MemoryUsage> {
+        &self.usage
+    }
+
+    pub fn limit(&self) -> usize {
+        self.limit
+    }
+
+    pub fn is_exceeded(&self) -> bool {
+        if self.limit > 0 && self.usage.used() >= self.limit {
+            return true;
+        }
+
+        match &self.parent {
+            Some(parent) => parent.is_exceeded(),
+            None => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reservations() {
+        let usage = Arc::new(MemoryUsage::new());
+        assert_eq!(usage.used(), 0);
+
+        let r1 = usage.reserve(100);
+        let r2 = usage.reserve(50);
+        assert_eq!(r1.size(), 100);
+        assert_eq!(usage.used(), 150);
+
+        drop(r1);
+        assert_eq!(usage.used(), 50);
+
+        drop(r2);
+        assert_eq!(usage.used(), 0);
+    }
+
+    #[test]
+    fn parent() {
+        let parent = Arc::new(MemoryUsage::new());
+        let child = Arc::new(MemoryUsage::with_parent(&parent));
+
+        let r1 = parent.reserve(100);
+        let r2 = child.reserve(50);
+        assert_eq!(parent.used(), 150);
+        assert_eq!(child.used(), 50);
+
+        drop(r2);
+        assert_eq!(parent.used(), 100);
+        assert_eq!(child.used(), 0);
+
+        drop(r1);
+        assert_eq!(parent.used(), 0);
+    }
+
+    #[test]
+    fn budget() {
+        let usage = Arc::new(MemoryUsage::new());
+
+        let b = MemoryBudget::new(&usage, 100);
+        assert!(!b.is_exceeded());
+
+        let r1 = usage.reserve(99);
+        assert!(!b.is_exceeded());
+
+        let r2 = usage.reserve(1);
+        assert!(b.is_exceeded());
+
+        drop(r1);
+        drop(r2);
+        assert!(!b.is_exceeded());
+
+        let b = MemoryBudget::new(&usage, 0);
+        let _r = usage.reserve(1000);
+        assert!(!b.is_exceeded());
+    }
+
+    #[test]
+    fn budget_parent() {
+        let global = Arc::new(MemoryUsage::new());
+        let w1 = Arc::new(MemoryUsage::with_parent(&global));
+        let w2 = Arc::new(MemoryUsage::with_parent(&global));
+
+        let parent = Arc::new(MemoryBudget::new(&global, 100));
+        let b1 = MemoryBudget::with_parent(&w1, 60, &parent);
+        let b2 = MemoryBudget::with_parent(&w2, 0, &parent);
+
+        let r1 = w1.reserve(60);
+        assert!(b1.is_exceeded());
+        assert!(!b2.is_exceeded());
+
+        // over the parent limit, but not the limit of the first budget
+        drop(r1);
+        let r1 = w1.reserve(50);
+        let r2 = w2.reserve(50);
+        assert!(parent.is_exceeded());
+        assert!(b1.is_exceeded());
+        assert!(b2.is_exceeded());
+
+        drop(r1);
+        drop(r2);
+        assert!(!b1.is_exceeded());
+        assert!(!b2.is_exceeded());
+    }
+
+    #[test]
+    fn threshold() {
+        let usage = Arc::new(MemoryUsage::new());
+
+        let mut t = MemoryThreshold::new(&usage, 1000);
+        assert!(!t.check());
+
+        let r1 = usage.reserve(950);
+        assert!(!t.check());
+
+        let r2 = usage.reserve(50);
+        assert!(t.check());
+
+        // still exceeded until usage drops below the low mark
+        drop(r2);
+        assert!(t.check());
+
+        drop(r1);
+        assert!(!t.check());
+    }
+}
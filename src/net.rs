@@ -14,37 +14,100 @@
  * limitations under the License.
  */
 
+use crate::platform::{self, OwnedSocket, UnixListener, UnixSocketAddr, UnixStream};
 use log::error;
-use mio::net::{TcpListener, TcpStream, UnixListener, UnixStream};
-use socket2::Socket;
+use mio::net::{TcpListener, TcpStream};
+use socket2::{Domain, SockRef, Socket, Type};
 use std::fmt;
-use std::os::unix::io::{FromRawFd, IntoRawFd};
-use std::ptr;
+use std::io;
+use std::path::{Path, PathBuf};
 
-pub fn set_socket_opts(stream: &mut TcpStream) {
-    if let Err(e) = stream.set_nodelay(true) {
-        error!("set nodelay failed: {:?}", e);
+// same as mio
+const LISTEN_BACKLOG: i32 = 1024;
+
+// apply options through a borrowed reference to the socket, so this works
+// the same way on every platform socket2 supports, without taking
+// ownership of the fd
+fn apply_socket_opts(socket: SockRef) -> Result<(), (&'static str, io::Error)> {
+    socket.set_nodelay(true).map_err(|e| ("nodelay", e))?;
+    socket.set_keepalive(true).map_err(|e| ("keepalive", e))?;
+
+    Ok(())
+}
+
+pub fn set_socket_opts(stream: &TcpStream) {
+    if let Err((name, e)) = apply_socket_opts(SockRef::from(stream)) {
+        error!("set {} failed: {:?}", name, e);
+    }
+}
+
+// settings applied to a tcp listener before it is bound
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct BindOpts {
+    // only accept connections arriving on this network interface
+    pub device: Option<String>,
+
+    // allow binding to an address that isn't configured on the host (yet)
+    pub freebind: bool,
+
+    // allow binding to any address, for transparent proxying. requires
+    // CAP_NET_ADMIN
+    pub transparent: bool,
+
+    // allow other sockets with this option to bind to the same address,
+    // with the kernel distributing connections between them
+    pub reuse_port: bool,
+
+    // max number of connections waiting to be accepted. if not set,
+    // LISTEN_BACKLOG is used. the kernel may cap it lower
+    pub backlog: Option<i32>,
+}
+
+// like TcpListener::bind, but applying the given options first. on error,
+// returns the name of the step that failed
+pub fn bind_tcp_listener(
+    addr: std::net::SocketAddr,
+    opts: &BindOpts,
+) -> Result<TcpListener, (&'static str, io::Error)> {
+    let socket =
+        Socket::new(Domain::for_address(addr), Type::STREAM, None).map_err(|e| ("socket", e))?;
+
+    socket
+        .set_reuse_address(true)
+        .map_err(|e| ("reuseaddr", e))?;
+
+    if opts.reuse_port {
+        platform::set_reuse_port(&socket).map_err(|e| ("reuseport", e))?;
     }
 
-    // safety: we move the value out of stream and replace it at the end
-    let ret = unsafe {
-        let s = ptr::read(stream);
-        let socket = Socket::from_raw_fd(s.into_raw_fd());
-        let ret = socket.set_keepalive(true);
-        ptr::write(stream, TcpStream::from_raw_fd(socket.into_raw_fd()));
+    if let Some(device) = &opts.device {
+        platform::bind_device(&socket, device).map_err(|e| ("device", e))?;
+    }
 
-        ret
-    };
+    if opts.freebind {
+        platform::set_freebind(&socket, addr.is_ipv6()).map_err(|e| ("freebind", e))?;
+    }
 
-    if let Err(e) = ret {
-        error!("set keepalive failed: {:?}", e);
+    if opts.transparent {
+        platform::set_transparent(&socket, addr.is_ipv6()).map_err(|e| ("transparent", e))?;
     }
+
+    socket.bind(&addr.into()).map_err(|e| ("bind", e))?;
+    socket
+        .listen(opts.backlog.unwrap_or(LISTEN_BACKLOG))
+        .map_err(|e| ("listen", e))?;
+
+    socket
+        .set_nonblocking(true)
+        .map_err(|e| ("nonblocking", e))?;
+
+    Ok(TcpListener::from_std(socket.into()))
 }
 
 #[derive(Debug)]
 pub enum SocketAddr {
     Ip(std::net::SocketAddr),
-    Unix(mio::net::SocketAddr),
+    Unix(UnixSocketAddr),
 }
 
 impl fmt::Display for SocketAddr {
@@ -67,3 +130,222 @@ pub enum NetStream {
     Tcp(TcpStream),
     Unix(UnixStream),
 }
+
+// listeners taken over from a previous instance, to be used instead of
+// binding new ones
+#[derive(Default)]
+pub struct InheritedListeners {
+    tcp: Vec<TcpListener>,
+    unix: Vec<(PathBuf, UnixListener)>,
+}
+
+impl InheritedListeners {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // add a listener by its socket and bound address, which is either an
+    // ip address and port or a unix socket path. the socket must refer to a
+    // listener bound to that address
+    pub fn add(&mut self, addr: &str, socket: OwnedSocket) -> Result<(), io::Error> {
+        let mismatch = || io::Error::new(io::ErrorKind::InvalidData, "address mismatch");
+
+        match addr.parse::<std::net::SocketAddr>() {
+            Ok(addr) => {
+                let l = std::net::TcpListener::from(socket);
+
+                if l.local_addr()? != addr {
+                    return Err(mismatch());
+                }
+
+                l.set_nonblocking(true)?;
+
+                self.tcp.push(TcpListener::from_std(l));
+            }
+            Err(_) => {
+                let l = platform::unix_listener_from_owned(socket)?;
+
+                if l.local_addr()?.as_pathname() != Some(Path::new(addr)) {
+                    return Err(mismatch());
+                }
+
+                self.unix.push((PathBuf::from(addr), l));
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn len(&self) -> usize {
+        self.tcp.len() + self.unix.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn take_tcp(&mut self, addr: std::net::SocketAddr) -> Option<TcpListener> {
+        let pos = self
+            .tcp
+            .iter()
+            .position(|l| l.local_addr().ok() == Some(addr))?;
+
+        Some(self.tcp.swap_remove(pos))
+    }
+
+    pub fn take_unix(&mut self, path: &Path) -> Option<UnixListener> {
+        let pos = self.unix.iter().position(|(p, _)| p == path)?;
+
+        Some(self.unix.swap_remove(pos).1)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn socket_opts() {
+        let listener = TcpListener::bind("127.0.0.1:0".parse().unwrap()).unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let stream = std::net::TcpStream::connect(addr).unwrap();
+        let stream = TcpStream::from_std(stream);
+
+        let socket = SockRef::from(&stream);
+        assert_eq!(socket.nodelay().unwrap(), false);
+        assert_eq!(socket.keepalive().unwrap(), false);
+
+        set_socket_opts(&stream);
+
+        assert_eq!(socket.nodelay().unwrap(), true);
+        assert_eq!(socket.keepalive().unwrap(), true);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn bind_freebind() {
+        // documentation address, not configured on the host
+        let addr = "192.0.2.1:0".parse().unwrap();
+
+        let e = bind_tcp_listener(addr, &BindOpts::default()).unwrap_err();
+        assert_eq!(e.0, "bind");
+        assert_eq!(e.1.kind(), io::ErrorKind::AddrNotAvailable);
+
+        let opts = BindOpts {
+            freebind: true,
+            ..Default::default()
+        };
+
+        let listener = bind_tcp_listener(addr, &opts).unwrap();
+        assert_eq!(listener.local_addr().unwrap().ip(), addr.ip());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn bind_reuse_port() {
+        let opts = BindOpts {
+            reuse_port: true,
+            ..Default::default()
+        };
+
+        let l1 = bind_tcp_listener("127.0.0.1:0".parse().unwrap(), &opts).unwrap();
+        let addr = l1.local_addr().unwrap();
+
+        let l2 = bind_tcp_listener(addr, &opts).unwrap();
+        assert_eq!(l2.local_addr().unwrap(), addr);
+
+        let e = bind_tcp_listener(addr, &BindOpts::default()).unwrap_err();
+        assert_eq!(e.0, "bind");
+        assert_eq!(e.1.kind(), io::ErrorKind::AddrInUse);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn send_file_to_socket() {
+        let path = std::env::temp_dir().join(format!("condure-sendfile-{}", std::process::id()));
+        std::fs::write(&path, b"hello world").unwrap();
+        let file = std::fs::File::open(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let (a, mut b) = platform::StdUnixStream::pair().unwrap();
+
+        assert_eq!(platform::send_file(&a, &file, 6, 100).unwrap(), 5);
+        assert_eq!(platform::send_file(&a, &file, 11, 100).unwrap(), 0);
+        drop(a);
+
+        let mut buf = Vec::new();
+        io::Read::read_to_end(&mut b, &mut buf).unwrap();
+        assert_eq!(buf, b"world");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn pass_listeners() {
+        use crate::platform::AsRawSocket;
+
+        let (a, b) = platform::StdUnixStream::pair().unwrap();
+
+        let tcp = TcpListener::bind("127.0.0.1:0".parse().unwrap()).unwrap();
+        let tcp_addr = tcp.local_addr().unwrap();
+
+        let path = std::env::temp_dir().join(format!("condure-pass-{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let unix = UnixListener::bind(&path).unwrap();
+
+        platform::send_with_sockets(&a, b"hello", &[tcp.as_raw_socket(), unix.as_raw_socket()])
+            .unwrap();
+
+        let mut buf = [0; 64];
+        let (size, mut fds) = platform::recv_with_sockets(&b, &mut buf).unwrap();
+        assert_eq!(&buf[..size], b"hello");
+        assert_eq!(fds.len(), 2);
+
+        let unix_fd = fds.pop().unwrap();
+        let tcp_fd = fds.pop().unwrap();
+
+        let mut inherited = InheritedListeners::new();
+
+        // wrong address
+        assert!(inherited
+            .add("127.0.0.1:1", tcp_fd.try_clone().unwrap())
+            .is_err());
+        assert!(inherited
+            .add(&tcp_addr.to_string(), unix_fd.try_clone().unwrap())
+            .is_err());
+
+        inherited.add(&tcp_addr.to_string(), tcp_fd).unwrap();
+        inherited.add(path.to_str().unwrap(), unix_fd).unwrap();
+        assert_eq!(inherited.len(), 2);
+
+        assert!(inherited.take_unix(Path::new("/nonexistent")).is_none());
+
+        let l = inherited.take_tcp(tcp_addr).unwrap();
+        let _l = inherited.take_unix(&path).unwrap();
+        assert!(inherited.is_empty());
+
+        // the passed listener accepts connections for the original
+        drop(tcp);
+
+        let _stream = std::net::TcpStream::connect(tcp_addr).unwrap();
+
+        let mut accepted = None;
+
+        for _ in 0..100 {
+            match l.accept() {
+                Ok((s, _)) => {
+                    accepted = Some(s);
+                    break;
+                }
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                    std::thread::sleep(std::time::Duration::from_millis(10));
+                }
+                Err(e) => panic!("{}", e),
+            }
+        }
+
+        assert!(accepted.is_some());
+
+        std::fs::remove_file(&path).unwrap();
+    }
+}
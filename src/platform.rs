@@ -0,0 +1,42 @@
+/*
+ * Copyright (C) 2024 Fanout, Inc.
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+// os-specific functionality. the rest of the crate uses what is exported
+// here rather than os apis, and each platform module exports the same
+// items.
+//
+// some features have no windows equivalent: unix domain sockets, passing
+// sockets to another process, file ownership and modes, and the SIGQUIT
+// and SIGUSR2 signals. on windows, the types for these can't be
+// constructed, and the functions return an error of kind Unsupported
+
+#[cfg(unix)]
+mod unix;
+
+#[cfg(unix)]
+pub use self::unix::*;
+
+#[cfg(windows)]
+mod windows;
+
+#[cfg(windows)]
+pub use self::windows::*;
+
+#[cfg(not(any(unix, windows)))]
+compile_error!("condure supports unix and windows platforms only");
+
+// max number of sockets that can be passed along with a message
+pub const PASS_SOCKETS_MAX: usize = 64;
@@ -0,0 +1,517 @@
+/*
+ * Copyright (C) 2024 Fanout, Inc.
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use super::PASS_SOCKETS_MAX;
+use crate::event;
+use mio::unix::SourceFd;
+use socket2::Socket;
+use std::ffi::{CString, OsStr};
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::mem;
+use std::os::fd::BorrowedFd;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{FileExt, MetadataExt, PermissionsExt};
+use std::os::unix::io::{AsRawFd, FromRawFd, IntoRawFd};
+use std::path::Path;
+use std::ptr;
+
+pub use mio::net::{SocketAddr as UnixSocketAddr, UnixListener, UnixStream};
+pub use signal_hook::consts::{SIGINT, SIGQUIT, SIGTERM, SIGUSR2};
+pub use signal_hook::iterator::Signals;
+pub use std::os::fd::OwnedFd as OwnedSocket;
+pub use std::os::unix::io::RawFd as RawSocket;
+pub use std::os::unix::net::{UnixListener as StdUnixListener, UnixStream as StdUnixStream};
+
+// like AsRawSocket on windows, so that the same name works everywhere
+pub trait AsRawSocket {
+    fn as_raw_socket(&self) -> RawSocket;
+}
+
+impl<T: AsRawFd> AsRawSocket for T {
+    fn as_raw_socket(&self) -> RawSocket {
+        self.as_raw_fd()
+    }
+}
+
+// a socket not otherwise known to mio, such as the fd of a zmq socket, to
+// be watched for readability
+pub struct SocketWatch {
+    fd: RawSocket,
+}
+
+impl SocketWatch {
+    pub fn new(fd: RawSocket) -> Result<Self, io::Error> {
+        Ok(Self { fd })
+    }
+
+    pub fn socket(&self) -> RawSocket {
+        self.fd
+    }
+
+    pub fn register(
+        &mut self,
+        poller: &event::Poller,
+        token: mio::Token,
+        interests: mio::Interest,
+    ) -> Result<(), io::Error> {
+        poller.register(&mut SourceFd(&self.fd), token, interests)
+    }
+
+    pub fn deregister(&mut self, poller: &event::Poller) -> Result<(), io::Error> {
+        poller.deregister(&mut SourceFd(&self.fd))
+    }
+}
+
+pub fn tcp_stream_into_std(stream: mio::net::TcpStream) -> std::net::TcpStream {
+    unsafe { std::net::TcpStream::from_raw_fd(stream.into_raw_fd()) }
+}
+
+pub fn try_clone_unix_listener(l: &UnixListener) -> Result<UnixListener, io::Error> {
+    let fd = unsafe { BorrowedFd::borrow_raw(l.as_raw_fd()) }.try_clone_to_owned()?;
+
+    Ok(UnixListener::from_std(StdUnixListener::from(fd)))
+}
+
+// a unix listener from a socket passed by another process, in
+// non-blocking mode
+pub fn unix_listener_from_owned(socket: OwnedSocket) -> Result<UnixListener, io::Error> {
+    let l = StdUnixListener::from(socket);
+    l.set_nonblocking(true)?;
+
+    Ok(UnixListener::from_std(l))
+}
+
+// unblock any thread waiting to accept on the listener
+pub fn shutdown_listener(l: &StdUnixListener) {
+    unsafe { libc::shutdown(l.as_raw_fd(), libc::SHUT_RDWR) };
+}
+
+pub fn set_reuse_port(socket: &Socket) -> Result<(), io::Error> {
+    socket.set_reuse_port(true)
+}
+
+pub fn bind_device(socket: &Socket, device: &str) -> Result<(), io::Error> {
+    socket.bind_device(Some(device.as_bytes()))
+}
+
+pub fn set_freebind(socket: &Socket, ipv6: bool) -> Result<(), io::Error> {
+    if ipv6 {
+        socket.set_freebind_ipv6(true)
+    } else {
+        socket.set_freebind(true)
+    }
+}
+
+pub fn set_transparent(socket: &Socket, ipv6: bool) -> Result<(), io::Error> {
+    if !ipv6 {
+        return socket.set_ip_transparent(true);
+    }
+
+    let value: libc::c_int = 1;
+
+    let ret = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            libc::IPPROTO_IPV6,
+            libc::IPV6_TRANSPARENT,
+            &value as *const libc::c_int as *const libc::c_void,
+            mem::size_of_val(&value) as libc::socklen_t,
+        )
+    };
+
+    if ret != 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(())
+}
+
+// cmsg buffers must be aligned for cmsghdr
+fn cmsg_buf(fds_max: usize) -> Vec<u64> {
+    let space =
+        unsafe { libc::CMSG_SPACE((mem::size_of::<RawSocket>() * fds_max) as u32) } as usize;
+
+    vec![0; space.div_ceil(mem::size_of::<u64>())]
+}
+
+// write data to a unix socket, passing the sockets along with it
+pub fn send_with_sockets(
+    stream: &StdUnixStream,
+    data: &[u8],
+    fds: &[RawSocket],
+) -> Result<(), io::Error> {
+    assert!(!data.is_empty());
+
+    if fds.len() > PASS_SOCKETS_MAX {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "too many fds to pass",
+        ));
+    }
+
+    let mut buf = cmsg_buf(fds.len());
+
+    let mut iov = libc::iovec {
+        iov_base: data.as_ptr() as *mut libc::c_void,
+        iov_len: data.len(),
+    };
+
+    let mut msg: libc::msghdr = unsafe { mem::zeroed() };
+    msg.msg_iov = &mut iov;
+    msg.msg_iovlen = 1;
+
+    if !fds.is_empty() {
+        let size = mem::size_of_val(fds);
+
+        msg.msg_control = buf.as_mut_ptr() as *mut libc::c_void;
+        msg.msg_controllen = unsafe { libc::CMSG_SPACE(size as u32) } as _;
+
+        unsafe {
+            let cmsg = libc::CMSG_FIRSTHDR(&msg);
+            (*cmsg).cmsg_level = libc::SOL_SOCKET;
+            (*cmsg).cmsg_type = libc::SCM_RIGHTS;
+            (*cmsg).cmsg_len = libc::CMSG_LEN(size as u32) as _;
+
+            ptr::copy_nonoverlapping(fds.as_ptr() as *const u8, libc::CMSG_DATA(cmsg), size);
+        }
+    }
+
+    let size = loop {
+        let ret = unsafe { libc::sendmsg(stream.as_raw_fd(), &msg, 0) };
+
+        if ret < 0 {
+            let e = io::Error::last_os_error();
+
+            if e.kind() == io::ErrorKind::Interrupted {
+                continue;
+            }
+
+            return Err(e);
+        }
+
+        break ret as usize;
+    };
+
+    // the fds went out with the first part of the data
+    let mut stream = stream;
+    stream.write_all(&data[size..])
+}
+
+// read from a unix socket into buf, receiving any sockets passed along with
+// the data. returns the number of bytes read and the sockets
+fn peer_uid(stream: &StdUnixStream) -> Result<libc::uid_t, io::Error> {
+    let fd = stream.as_raw_fd();
+
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    {
+        let mut cred = mem::MaybeUninit::<libc::ucred>::uninit();
+        let mut len = mem::size_of::<libc::ucred>() as libc::socklen_t;
+
+        let ret = unsafe {
+            libc::getsockopt(
+                fd,
+                libc::SOL_SOCKET,
+                libc::SO_PEERCRED,
+                cred.as_mut_ptr() as *mut libc::c_void,
+                &mut len,
+            )
+        };
+
+        if ret != 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(unsafe { cred.assume_init() }.uid)
+    }
+
+    #[cfg(not(any(target_os = "linux", target_os = "android")))]
+    {
+        let mut uid = 0;
+        let mut gid = 0;
+
+        if unsafe { libc::getpeereid(fd, &mut uid, &mut gid) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(uid)
+    }
+}
+
+// whether the process at the other end of a unix socket runs as the same
+// user as this process, or as root
+pub fn is_peer_trusted(stream: &StdUnixStream) -> Result<bool, io::Error> {
+    let uid = peer_uid(stream)?;
+
+    Ok(uid == 0 || uid == unsafe { libc::geteuid() })
+}
+
+pub fn recv_with_sockets(
+    stream: &StdUnixStream,
+    buf: &mut [u8],
+) -> Result<(usize, Vec<OwnedSocket>), io::Error> {
+    let mut cbuf = cmsg_buf(PASS_SOCKETS_MAX);
+
+    let mut iov = libc::iovec {
+        iov_base: buf.as_mut_ptr() as *mut libc::c_void,
+        iov_len: buf.len(),
+    };
+
+    let mut msg: libc::msghdr = unsafe { mem::zeroed() };
+    msg.msg_iov = &mut iov;
+    msg.msg_iovlen = 1;
+    msg.msg_control = cbuf.as_mut_ptr() as *mut libc::c_void;
+    msg.msg_controllen = (cbuf.len() * mem::size_of::<u64>()) as _;
+
+    let size = loop {
+        let ret = unsafe { libc::recvmsg(stream.as_raw_fd(), &mut msg, 0) };
+
+        if ret < 0 {
+            let e = io::Error::last_os_error();
+
+            if e.kind() == io::ErrorKind::Interrupted {
+                continue;
+            }
+
+            return Err(e);
+        }
+
+        break ret as usize;
+    };
+
+    let mut fds = Vec::new();
+
+    unsafe {
+        let mut cmsg = libc::CMSG_FIRSTHDR(&msg);
+
+        while !cmsg.is_null() {
+            if (*cmsg).cmsg_level == libc::SOL_SOCKET && (*cmsg).cmsg_type == libc::SCM_RIGHTS {
+                let data = libc::CMSG_DATA(cmsg) as *const RawSocket;
+                let count = ((*cmsg).cmsg_len as usize - libc::CMSG_LEN(0) as usize)
+                    / mem::size_of::<RawSocket>();
+
+                for i in 0..count {
+                    let fd = ptr::read_unaligned(data.add(i));
+
+                    libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC);
+
+                    fds.push(OwnedSocket::from_raw_fd(fd));
+                }
+            }
+
+            cmsg = libc::CMSG_NXTHDR(&msg, cmsg);
+        }
+    }
+
+    if msg.msg_flags & libc::MSG_CTRUNC != 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "too many fds received",
+        ));
+    }
+
+    Ok((size, fds))
+}
+
+// write up to size bytes of the file at offset to a socket, without
+// copying through userspace. returns the number of bytes written
+pub fn send_file<S: AsRawSocket>(
+    stream: &S,
+    file: &File,
+    offset: u64,
+    size: usize,
+) -> Result<usize, io::Error> {
+    let mut offset = offset as libc::off_t;
+
+    loop {
+        let ret =
+            unsafe { libc::sendfile(stream.as_raw_socket(), file.as_raw_fd(), &mut offset, size) };
+
+        if ret < 0 {
+            let e = io::Error::last_os_error();
+
+            if e.kind() == io::ErrorKind::Interrupted {
+                continue;
+            }
+
+            return Err(e);
+        }
+
+        return Ok(ret as usize);
+    }
+}
+
+pub fn read_at(file: &File, buf: &mut [u8], offset: u64) -> Result<usize, io::Error> {
+    file.read_at(buf, offset)
+}
+
+// a directory to open files within. files are opened relative to it
+// without following symbolic links, so a path can't lead outside of it
+// even if the tree changes meanwhile
+pub struct Dir(File);
+
+impl Dir {
+    pub fn open(path: &Path) -> Result<Self, io::Error> {
+        Ok(Self(File::open(path)?))
+    }
+
+    pub fn open_dir(&self, name: &OsStr) -> Result<Self, io::Error> {
+        Ok(Self(self.openat(
+            name,
+            libc::O_RDONLY | libc::O_DIRECTORY | libc::O_NOFOLLOW,
+        )?))
+    }
+
+    // non-blocking, in case the file is a fifo
+    pub fn open_file(&self, name: &OsStr) -> Result<File, io::Error> {
+        self.openat(name, libc::O_RDONLY | libc::O_NOFOLLOW | libc::O_NONBLOCK)
+    }
+
+    fn openat(&self, name: &OsStr, flags: libc::c_int) -> Result<File, io::Error> {
+        let name = match CString::new(name.as_bytes()) {
+            Ok(s) => s,
+            Err(_) => return Err(io::Error::from(io::ErrorKind::InvalidInput)),
+        };
+
+        loop {
+            let fd =
+                unsafe { libc::openat(self.0.as_raw_fd(), name.as_ptr(), flags | libc::O_CLOEXEC) };
+
+            if fd < 0 {
+                let e = io::Error::last_os_error();
+
+                match e.raw_os_error() {
+                    Some(libc::EINTR) => continue,
+                    Some(libc::ELOOP) => {
+                        return Err(io::Error::new(
+                            io::ErrorKind::PermissionDenied,
+                            "symbolic links are not followed",
+                        ))
+                    }
+                    _ => return Err(e),
+                }
+            }
+
+            return Ok(unsafe { File::from_raw_fd(fd) });
+        }
+    }
+}
+
+// identifies the file at a path, to tell whether it has been replaced
+pub fn file_id(path: &Path) -> Option<(u64, u64)> {
+    fs::metadata(path).ok().map(|m| (m.dev(), m.ino()))
+}
+
+pub fn set_mode(path: &Path, mode: u32) -> Result<(), io::Error> {
+    fs::set_permissions(path, fs::Permissions::from_mode(mode))
+}
+
+fn try_with_increasing_buffer<T, U>(starting_size: usize, f: T) -> Result<U, io::Error>
+where
+    T: Fn(&mut [u8]) -> Result<U, io::Error>,
+{
+    let mut buf = vec![0; starting_size];
+
+    loop {
+        match f(&mut buf) {
+            Ok(v) => return Ok(v),
+            Err(e) if e.raw_os_error() == Some(libc::ERANGE) => buf.resize(buf.len() * 2, 0),
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+fn get_user_uid(name: &str) -> Result<libc::gid_t, io::Error> {
+    let name = CString::new(name).unwrap();
+
+    try_with_increasing_buffer(1024, |buf| unsafe {
+        let mut pwd = mem::MaybeUninit::uninit();
+        let mut passwd = ptr::null_mut();
+
+        if libc::getpwnam_r(
+            name.as_ptr(),
+            pwd.as_mut_ptr(),
+            buf.as_mut_ptr() as *mut i8,
+            buf.len(),
+            &mut passwd,
+        ) != 0
+        {
+            return Err(io::Error::last_os_error());
+        }
+
+        let passwd = match passwd.as_ref() {
+            Some(r) => r,
+            None => return Err(io::Error::from(io::ErrorKind::NotFound)),
+        };
+
+        Ok(passwd.pw_uid)
+    })
+}
+
+fn get_group_gid(name: &str) -> Result<libc::gid_t, io::Error> {
+    let name = CString::new(name).unwrap();
+
+    try_with_increasing_buffer(1024, |buf| unsafe {
+        let mut grp = mem::MaybeUninit::uninit();
+        let mut group = ptr::null_mut();
+
+        if libc::getgrnam_r(
+            name.as_ptr(),
+            grp.as_mut_ptr(),
+            buf.as_mut_ptr() as *mut i8,
+            buf.len(),
+            &mut group,
+        ) != 0
+        {
+            return Err(io::Error::last_os_error());
+        }
+
+        let group = match group.as_ref() {
+            Some(r) => r,
+            None => return Err(io::Error::from(io::ErrorKind::NotFound)),
+        };
+
+        Ok(group.gr_gid)
+    })
+}
+
+pub fn set_user(path: &Path, user: &str) -> Result<(), io::Error> {
+    let uid = get_user_uid(user)?;
+
+    unsafe {
+        let path = CString::new(path.as_os_str().as_bytes()).unwrap();
+
+        if libc::chown(path.as_ptr(), uid, u32::MAX) != 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+
+    Ok(())
+}
+
+pub fn set_group(path: &Path, group: &str) -> Result<(), io::Error> {
+    let gid = get_group_gid(group)?;
+
+    unsafe {
+        let path = CString::new(path.as_os_str().as_bytes()).unwrap();
+
+        if libc::chown(path.as_ptr(), u32::MAX, gid) != 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,552 @@
+/*
+ * Copyright (C) 2024 Fanout, Inc.
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use crate::event;
+use crate::spawn_thread;
+use socket2::Socket;
+use std::borrow::Borrow;
+use std::ffi::OsStr;
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::mem::ManuallyDrop;
+use std::net::Shutdown;
+use std::os::windows::fs::FileExt;
+use std::os::windows::io::{FromRawSocket, IntoRawSocket};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+use windows_sys::Win32::Networking::WinSock::{WSAPoll, POLLRDNORM, SOCKET, WSAPOLLFD};
+
+pub use signal_hook::consts::{SIGINT, SIGTERM};
+pub use std::os::windows::io::{AsRawSocket, OwnedSocket, RawSocket};
+
+// how long a socket watch waits for readability before checking whether
+// it should stop
+const WATCH_INTERVAL: Duration = Duration::from_millis(10);
+
+// a watched socket stays readable until its owner handles the readiness,
+// so after signaling, wait a moment before checking it again
+const WATCH_REARM_DELAY: Duration = Duration::from_millis(1);
+
+// how often pending signals are checked for
+const SIGNALS_INTERVAL: Duration = Duration::from_millis(100);
+
+fn unsupported(what: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::Unsupported,
+        format!("{} not supported on this platform", what),
+    )
+}
+
+// the unix socket types below can't be constructed, since there is no way
+// to create them. their methods exist only so that code using them builds
+#[derive(Debug)]
+enum Void {}
+
+#[derive(Debug)]
+pub struct UnixSocketAddr(Void);
+
+impl UnixSocketAddr {
+    pub fn as_pathname(&self) -> Option<&Path> {
+        match self.0 {}
+    }
+}
+
+#[derive(Debug)]
+pub struct UnixListener(Void);
+
+impl UnixListener {
+    pub fn bind<P: AsRef<Path>>(_path: P) -> Result<Self, io::Error> {
+        Err(unsupported("unix sockets"))
+    }
+
+    pub fn accept(&self) -> Result<(UnixStream, UnixSocketAddr), io::Error> {
+        match self.0 {}
+    }
+
+    pub fn local_addr(&self) -> Result<UnixSocketAddr, io::Error> {
+        match self.0 {}
+    }
+}
+
+impl AsRawSocket for UnixListener {
+    fn as_raw_socket(&self) -> RawSocket {
+        match self.0 {}
+    }
+}
+
+impl mio::event::Source for UnixListener {
+    fn register(
+        &mut self,
+        _registry: &mio::Registry,
+        _token: mio::Token,
+        _interests: mio::Interest,
+    ) -> Result<(), io::Error> {
+        match self.0 {}
+    }
+
+    fn reregister(
+        &mut self,
+        _registry: &mio::Registry,
+        _token: mio::Token,
+        _interests: mio::Interest,
+    ) -> Result<(), io::Error> {
+        match self.0 {}
+    }
+
+    fn deregister(&mut self, _registry: &mio::Registry) -> Result<(), io::Error> {
+        match self.0 {}
+    }
+}
+
+#[derive(Debug)]
+pub struct UnixStream(Void);
+
+impl UnixStream {
+    pub fn connect<P: AsRef<Path>>(_path: P) -> Result<Self, io::Error> {
+        Err(unsupported("unix sockets"))
+    }
+
+    pub fn peer_addr(&self) -> Result<UnixSocketAddr, io::Error> {
+        match self.0 {}
+    }
+
+    pub fn take_error(&self) -> Result<Option<io::Error>, io::Error> {
+        match self.0 {}
+    }
+
+    pub fn shutdown(&self, _how: Shutdown) -> Result<(), io::Error> {
+        match self.0 {}
+    }
+}
+
+impl Read for UnixStream {
+    fn read(&mut self, _buf: &mut [u8]) -> Result<usize, io::Error> {
+        match self.0 {}
+    }
+}
+
+impl Read for &UnixStream {
+    fn read(&mut self, _buf: &mut [u8]) -> Result<usize, io::Error> {
+        match self.0 {}
+    }
+}
+
+impl Write for UnixStream {
+    fn write(&mut self, _buf: &[u8]) -> Result<usize, io::Error> {
+        match self.0 {}
+    }
+
+    fn flush(&mut self) -> Result<(), io::Error> {
+        match self.0 {}
+    }
+}
+
+impl Write for &UnixStream {
+    fn write(&mut self, _buf: &[u8]) -> Result<usize, io::Error> {
+        match self.0 {}
+    }
+
+    fn flush(&mut self) -> Result<(), io::Error> {
+        match self.0 {}
+    }
+}
+
+impl AsRawSocket for UnixStream {
+    fn as_raw_socket(&self) -> RawSocket {
+        match self.0 {}
+    }
+}
+
+impl mio::event::Source for UnixStream {
+    fn register(
+        &mut self,
+        _registry: &mio::Registry,
+        _token: mio::Token,
+        _interests: mio::Interest,
+    ) -> Result<(), io::Error> {
+        match self.0 {}
+    }
+
+    fn reregister(
+        &mut self,
+        _registry: &mio::Registry,
+        _token: mio::Token,
+        _interests: mio::Interest,
+    ) -> Result<(), io::Error> {
+        match self.0 {}
+    }
+
+    fn deregister(&mut self, _registry: &mio::Registry) -> Result<(), io::Error> {
+        match self.0 {}
+    }
+}
+
+#[derive(Debug)]
+pub struct StdUnixListener(Void);
+
+impl StdUnixListener {
+    pub fn bind<P: AsRef<Path>>(_path: P) -> Result<Self, io::Error> {
+        Err(unsupported("unix sockets"))
+    }
+
+    pub fn accept(&self) -> Result<(StdUnixStream, UnixSocketAddr), io::Error> {
+        match self.0 {}
+    }
+
+    pub fn try_clone(&self) -> Result<Self, io::Error> {
+        match self.0 {}
+    }
+
+    pub fn incoming(&self) -> std::iter::Empty<Result<StdUnixStream, io::Error>> {
+        match self.0 {}
+    }
+}
+
+#[derive(Debug)]
+pub struct StdUnixStream(Void);
+
+impl StdUnixStream {
+    pub fn connect<P: AsRef<Path>>(_path: P) -> Result<Self, io::Error> {
+        Err(unsupported("unix sockets"))
+    }
+
+    pub fn set_read_timeout(&self, _timeout: Option<Duration>) -> Result<(), io::Error> {
+        match self.0 {}
+    }
+
+    pub fn set_write_timeout(&self, _timeout: Option<Duration>) -> Result<(), io::Error> {
+        match self.0 {}
+    }
+
+    pub fn shutdown(&self, _how: Shutdown) -> Result<(), io::Error> {
+        match self.0 {}
+    }
+}
+
+impl Read for StdUnixStream {
+    fn read(&mut self, _buf: &mut [u8]) -> Result<usize, io::Error> {
+        match self.0 {}
+    }
+}
+
+impl Read for &StdUnixStream {
+    fn read(&mut self, _buf: &mut [u8]) -> Result<usize, io::Error> {
+        match self.0 {}
+    }
+}
+
+impl Write for StdUnixStream {
+    fn write(&mut self, _buf: &[u8]) -> Result<usize, io::Error> {
+        match self.0 {}
+    }
+
+    fn flush(&mut self) -> Result<(), io::Error> {
+        match self.0 {}
+    }
+}
+
+impl Write for &StdUnixStream {
+    fn write(&mut self, _buf: &[u8]) -> Result<usize, io::Error> {
+        match self.0 {}
+    }
+
+    fn flush(&mut self) -> Result<(), io::Error> {
+        match self.0 {}
+    }
+}
+
+// mio can't watch arbitrary sockets on windows, so a thread polls the
+// socket and reports readability through a custom registration
+pub struct SocketWatch {
+    socket: RawSocket,
+    registration: event::Registration,
+    stop: Arc<AtomicBool>,
+    thread: Option<thread::JoinHandle<()>>,
+}
+
+impl SocketWatch {
+    pub fn new(socket: RawSocket) -> Result<Self, io::Error> {
+        let (registration, set_readiness) = event::Registration::new();
+
+        let stop = Arc::new(AtomicBool::new(false));
+
+        let thread = {
+            let stop = Arc::clone(&stop);
+
+            spawn_thread("socket-watch".to_string(), move || {
+                while !stop.load(Ordering::Relaxed) {
+                    let mut fd = WSAPOLLFD {
+                        fd: socket as SOCKET,
+                        events: POLLRDNORM,
+                        revents: 0,
+                    };
+
+                    let ret = unsafe { WSAPoll(&mut fd, 1, WATCH_INTERVAL.as_millis() as i32) };
+
+                    if ret < 0 {
+                        break;
+                    }
+
+                    if ret > 0 {
+                        if set_readiness
+                            .set_readiness(mio::Interest::READABLE)
+                            .is_err()
+                        {
+                            break;
+                        }
+
+                        thread::sleep(WATCH_REARM_DELAY);
+                    }
+                }
+            })
+            .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?
+        };
+
+        Ok(Self {
+            socket,
+            registration,
+            stop,
+            thread: Some(thread),
+        })
+    }
+
+    pub fn socket(&self) -> RawSocket {
+        self.socket
+    }
+
+    pub fn register(
+        &mut self,
+        poller: &event::Poller,
+        token: mio::Token,
+        interests: mio::Interest,
+    ) -> Result<(), io::Error> {
+        poller.register_custom(&self.registration, token, interests)
+    }
+
+    pub fn deregister(&mut self, poller: &event::Poller) -> Result<(), io::Error> {
+        poller.deregister_custom(&self.registration)
+    }
+}
+
+impl Drop for SocketWatch {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+
+        // the socket is still open, so the thread won't poll a closed or
+        // reused handle before it notices
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+pub fn tcp_stream_into_std(stream: mio::net::TcpStream) -> std::net::TcpStream {
+    unsafe { std::net::TcpStream::from_raw_socket(stream.into_raw_socket()) }
+}
+
+pub fn try_clone_unix_listener(l: &UnixListener) -> Result<UnixListener, io::Error> {
+    match l.0 {}
+}
+
+pub fn unix_listener_from_owned(_socket: OwnedSocket) -> Result<UnixListener, io::Error> {
+    Err(unsupported("unix sockets"))
+}
+
+pub fn shutdown_listener(l: &StdUnixListener) {
+    match l.0 {}
+}
+
+pub fn set_reuse_port(_socket: &Socket) -> Result<(), io::Error> {
+    Err(unsupported("SO_REUSEPORT"))
+}
+
+pub fn bind_device(_socket: &Socket, _device: &str) -> Result<(), io::Error> {
+    Err(unsupported("binding to a device"))
+}
+
+pub fn set_freebind(_socket: &Socket, _ipv6: bool) -> Result<(), io::Error> {
+    Err(unsupported("freebind"))
+}
+
+pub fn set_transparent(_socket: &Socket, _ipv6: bool) -> Result<(), io::Error> {
+    Err(unsupported("transparent"))
+}
+
+pub fn send_with_sockets(
+    stream: &StdUnixStream,
+    _data: &[u8],
+    _sockets: &[RawSocket],
+) -> Result<(), io::Error> {
+    match stream.0 {}
+}
+
+pub fn is_peer_trusted(stream: &StdUnixStream) -> Result<bool, io::Error> {
+    match stream.0 {}
+}
+
+pub fn recv_with_sockets(
+    stream: &StdUnixStream,
+    _buf: &mut [u8],
+) -> Result<(usize, Vec<OwnedSocket>), io::Error> {
+    match stream.0 {}
+}
+
+// there is TransmitFile, but it's meant for blocking sockets. instead,
+// read a chunk of the file and write what the socket accepts. returns the
+// number of bytes written
+pub fn send_file<S: AsRawSocket>(
+    stream: &S,
+    file: &File,
+    offset: u64,
+    size: usize,
+) -> Result<usize, io::Error> {
+    let mut buf = [0; 16_384];
+
+    let size = file.seek_read(&mut buf[..size.min(16_384)], offset)?;
+
+    if size == 0 {
+        return Ok(0);
+    }
+
+    // borrow the socket without taking ownership
+    let stream =
+        ManuallyDrop::new(unsafe { std::net::TcpStream::from_raw_socket(stream.as_raw_socket()) });
+
+    (&*stream).write(&buf[..size])
+}
+
+pub fn read_at(file: &File, buf: &mut [u8], offset: u64) -> Result<usize, io::Error> {
+    file.seek_read(buf, offset)
+}
+
+// a directory to open files within, without following symbolic links.
+// there is no equivalent of openat, so each path is checked before it is
+// opened. unlike on unix, a link swapped in between the check and the open
+// would be followed
+pub struct Dir(PathBuf);
+
+impl Dir {
+    pub fn open(path: &Path) -> Result<Self, io::Error> {
+        if !fs::metadata(path)?.is_dir() {
+            return Err(io::Error::new(io::ErrorKind::Other, "not a directory"));
+        }
+
+        Ok(Self(path.to_path_buf()))
+    }
+
+    pub fn open_dir(&self, name: &OsStr) -> Result<Self, io::Error> {
+        let path = self.entry(name)?;
+
+        if !fs::symlink_metadata(&path)?.is_dir() {
+            return Err(io::Error::new(io::ErrorKind::Other, "not a directory"));
+        }
+
+        Ok(Self(path))
+    }
+
+    pub fn open_file(&self, name: &OsStr) -> Result<File, io::Error> {
+        File::open(self.entry(name)?)
+    }
+
+    fn entry(&self, name: &OsStr) -> Result<PathBuf, io::Error> {
+        let path = self.0.join(name);
+
+        if fs::symlink_metadata(&path)?.file_type().is_symlink() {
+            return Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                "symbolic links are not followed",
+            ));
+        }
+
+        Ok(path)
+    }
+}
+
+pub fn file_id(_path: &Path) -> Option<(u64, u64)> {
+    None
+}
+
+pub fn set_mode(_path: &Path, _mode: u32) -> Result<(), io::Error> {
+    Err(unsupported("file modes"))
+}
+
+pub fn set_user(_path: &Path, _user: &str) -> Result<(), io::Error> {
+    Err(unsupported("file ownership"))
+}
+
+pub fn set_group(_path: &Path, _group: &str) -> Result<(), io::Error> {
+    Err(unsupported("file ownership"))
+}
+
+// signal_hook's iterator isn't available on windows, so pending signals
+// are recorded in a flag and checked periodically
+pub struct Signals {
+    pending: Arc<AtomicUsize>,
+}
+
+impl Signals {
+    pub fn new<I>(signals: I) -> Result<Self, io::Error>
+    where
+        I: IntoIterator,
+        I::Item: Borrow<i32>,
+    {
+        let pending = Arc::new(AtomicUsize::new(0));
+
+        for signal in signals {
+            let signal = *signal.borrow();
+
+            signal_hook::flag::register_usize(signal, Arc::clone(&pending), signal as usize)?;
+        }
+
+        Ok(Self { pending })
+    }
+
+    pub fn forever(&mut self) -> Forever<'_> {
+        Forever { signals: self }
+    }
+}
+
+impl<'a> IntoIterator for &'a mut Signals {
+    type Item = i32;
+    type IntoIter = Forever<'a>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.forever()
+    }
+}
+
+pub struct Forever<'a> {
+    signals: &'a mut Signals,
+}
+
+impl Iterator for Forever<'_> {
+    type Item = i32;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let signal = self.signals.pending.swap(0, Ordering::Relaxed);
+
+            if signal != 0 {
+                return Some(signal as i32);
+            }
+
+            thread::sleep(SIGNALS_INTERVAL);
+        }
+    }
+}
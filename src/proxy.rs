@@ -0,0 +1,303 @@
+/*
+ * Copyright (C) 2024 Fanout, Inc.
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+// parsing of the PROXY protocol header sent by load balancers ahead of the
+// proxied connection's data, versions 1 and 2
+// (https://www.haproxy.org/download/2.9/doc/proxy-protocol.txt)
+
+use std::convert::TryInto;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::str;
+
+const V1_PREFIX: &[u8] = b"PROXY ";
+const V1_LEN_MAX: usize = 107;
+
+const V2_SIGNATURE: &[u8] = b"\r\n\r\n\0\r\nQUIT\n";
+const V2_HEADER_LEN: usize = 16;
+
+const V2_VERSION: u8 = 0x20;
+const V2_CMD_LOCAL: u8 = 0x00;
+const V2_CMD_PROXY: u8 = 0x01;
+
+const V2_FAMILY_TCP4: u8 = 0x11;
+const V2_FAMILY_TCP6: u8 = 0x21;
+
+#[derive(Debug, PartialEq, Eq, thiserror::Error)]
+pub enum Error {
+    #[error("missing proxy header")]
+    Missing,
+
+    #[error("invalid proxy header")]
+    Invalid,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct Header {
+    // size of the header, to be skipped before the proxied data
+    pub size: usize,
+
+    // the original client address, if conveyed. health checks from the
+    // balancer itself don't convey any
+    pub source: Option<SocketAddr>,
+}
+
+// whether data could be the start of the given prefix, or the prefix
+// followed by more
+fn starts_with_partial(data: &[u8], prefix: &[u8]) -> bool {
+    let len = data.len().min(prefix.len());
+
+    data[..len] == prefix[..len]
+}
+
+fn parse_v1(data: &[u8]) -> Result<Option<Header>, Error> {
+    let end = match data.windows(2).position(|w| w == b"\r\n") {
+        Some(pos) => pos,
+        None if data.len() < V1_LEN_MAX => return Ok(None),
+        None => return Err(Error::Invalid),
+    };
+
+    if end + 2 > V1_LEN_MAX {
+        return Err(Error::Invalid);
+    }
+
+    let line = str::from_utf8(&data[V1_PREFIX.len()..end]).map_err(|_| Error::Invalid)?;
+
+    let mut parts = line.split(' ');
+
+    let source = match parts.next() {
+        Some("TCP4") | Some("TCP6") => {
+            let src_ip: IpAddr = parts
+                .next()
+                .and_then(|s| s.parse().ok())
+                .ok_or(Error::Invalid)?;
+
+            // destination address
+            parts
+                .next()
+                .and_then(|s| s.parse::<IpAddr>().ok())
+                .ok_or(Error::Invalid)?;
+
+            let src_port: u16 = parts
+                .next()
+                .and_then(|s| s.parse().ok())
+                .ok_or(Error::Invalid)?;
+
+            // destination port
+            parts
+                .next()
+                .and_then(|s| s.parse::<u16>().ok())
+                .ok_or(Error::Invalid)?;
+
+            if parts.next().is_some() {
+                return Err(Error::Invalid);
+            }
+
+            Some(SocketAddr::new(src_ip, src_port))
+        }
+        // the rest of the line is to be ignored
+        Some("UNKNOWN") => None,
+        _ => return Err(Error::Invalid),
+    };
+
+    Ok(Some(Header {
+        size: end + 2,
+        source,
+    }))
+}
+
+fn parse_v2(data: &[u8]) -> Result<Option<Header>, Error> {
+    if data.len() < V2_HEADER_LEN {
+        return Ok(None);
+    }
+
+    let ver_cmd = data[12];
+    let family = data[13];
+    let len = u16::from_be_bytes([data[14], data[15]]) as usize;
+
+    if ver_cmd & 0xf0 != V2_VERSION {
+        return Err(Error::Invalid);
+    }
+
+    let size = V2_HEADER_LEN + len;
+
+    if data.len() < size {
+        return Ok(None);
+    }
+
+    let addrs = &data[V2_HEADER_LEN..size];
+
+    let source = match ver_cmd & 0x0f {
+        V2_CMD_LOCAL => None,
+        V2_CMD_PROXY => match family {
+            V2_FAMILY_TCP4 => {
+                if addrs.len() < 12 {
+                    return Err(Error::Invalid);
+                }
+
+                let ip: [u8; 4] = addrs[..4].try_into().unwrap();
+                let port = u16::from_be_bytes([addrs[8], addrs[9]]);
+
+                Some(SocketAddr::new(Ipv4Addr::from(ip).into(), port))
+            }
+            V2_FAMILY_TCP6 => {
+                if addrs.len() < 36 {
+                    return Err(Error::Invalid);
+                }
+
+                let ip: [u8; 16] = addrs[..16].try_into().unwrap();
+                let port = u16::from_be_bytes([addrs[32], addrs[33]]);
+
+                Some(SocketAddr::new(Ipv6Addr::from(ip).into(), port))
+            }
+            // unspecified or unsupported protocol. the addresses are to be
+            // ignored
+            _ => None,
+        },
+        _ => return Err(Error::Invalid),
+    };
+
+    Ok(Some(Header { size, source }))
+}
+
+// parse the header at the start of data, of either version. returns None if
+// more data is needed
+pub fn parse_header(data: &[u8]) -> Result<Option<Header>, Error> {
+    if data.starts_with(V1_PREFIX) {
+        parse_v1(data)
+    } else if data.starts_with(V2_SIGNATURE) {
+        parse_v2(data)
+    } else if starts_with_partial(data, V1_PREFIX) || starts_with_partial(data, V2_SIGNATURE) {
+        Ok(None)
+    } else {
+        Err(Error::Missing)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_v1() {
+        let data = b"PROXY TCP4 192.0.2.1 192.0.2.2 41000 80\r\nGET / HTTP/1.1\r\n";
+
+        assert_eq!(parse_header(&data[..3]), Ok(None));
+        assert_eq!(parse_header(&data[..20]), Ok(None));
+
+        assert_eq!(
+            parse_header(data),
+            Ok(Some(Header {
+                size: 41,
+                source: Some("192.0.2.1:41000".parse().unwrap()),
+            }))
+        );
+
+        let data = b"PROXY TCP6 2001:db8::1 2001:db8::2 41000 443\r\n";
+
+        assert_eq!(
+            parse_header(data),
+            Ok(Some(Header {
+                size: data.len(),
+                source: Some("[2001:db8::1]:41000".parse().unwrap()),
+            }))
+        );
+
+        let data = b"PROXY UNKNOWN ffff::1 ffff::2 1 2\r\n";
+
+        assert_eq!(
+            parse_header(data),
+            Ok(Some(Header {
+                size: data.len(),
+                source: None,
+            }))
+        );
+
+        assert_eq!(
+            parse_header(b"PROXY TCP4 192.0.2.1 192.0.2.2 41000\r\n"),
+            Err(Error::Invalid)
+        );
+        assert_eq!(
+            parse_header(b"PROXY TCP4 bogus 192.0.2.2 41000 80\r\n"),
+            Err(Error::Invalid)
+        );
+        assert_eq!(parse_header(&[b'P'; 200]), Err(Error::Missing));
+
+        let mut data = b"PROXY UNKNOWN ".to_vec();
+        data.resize(200, b'a');
+        assert_eq!(parse_header(&data), Err(Error::Invalid));
+    }
+
+    #[test]
+    fn test_parse_v2() {
+        let mut data = V2_SIGNATURE.to_vec();
+        data.extend_from_slice(&[0x21, 0x11, 0x00, 0x0c]);
+        data.extend_from_slice(&[192, 0, 2, 1, 192, 0, 2, 2, 0xa0, 0x28, 0x00, 0x50]);
+        data.extend_from_slice(b"GET / HTTP/1.1\r\n");
+
+        assert_eq!(parse_header(&data[..8]), Ok(None));
+        assert_eq!(parse_header(&data[..20]), Ok(None));
+
+        assert_eq!(
+            parse_header(&data),
+            Ok(Some(Header {
+                size: 28,
+                source: Some("192.0.2.1:41000".parse().unwrap()),
+            }))
+        );
+
+        let mut data = V2_SIGNATURE.to_vec();
+        data.extend_from_slice(&[0x21, 0x21, 0x00, 0x24]);
+        data.extend_from_slice(&Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 1).octets());
+        data.extend_from_slice(&Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 2).octets());
+        data.extend_from_slice(&[0xa0, 0x28, 0x01, 0xbb]);
+
+        assert_eq!(
+            parse_header(&data),
+            Ok(Some(Header {
+                size: 52,
+                source: Some("[2001:db8::1]:41000".parse().unwrap()),
+            }))
+        );
+
+        // local, with a tlv that should be skipped
+        let mut data = V2_SIGNATURE.to_vec();
+        data.extend_from_slice(&[0x20, 0x00, 0x00, 0x04, 0x04, 0x00, 0x01, 0x00]);
+
+        assert_eq!(
+            parse_header(&data),
+            Ok(Some(Header {
+                size: 20,
+                source: None,
+            }))
+        );
+
+        // wrong version
+        let mut data = V2_SIGNATURE.to_vec();
+        data.extend_from_slice(&[0x11, 0x11, 0x00, 0x00]);
+        assert_eq!(parse_header(&data), Err(Error::Invalid));
+
+        // addresses don't fit
+        let mut data = V2_SIGNATURE.to_vec();
+        data.extend_from_slice(&[0x21, 0x11, 0x00, 0x04, 192, 0, 2, 1]);
+        assert_eq!(parse_header(&data), Err(Error::Invalid));
+    }
+
+    #[test]
+    fn test_parse_missing() {
+        assert_eq!(parse_header(b"GET / HTTP/1.1\r\n"), Err(Error::Missing));
+        assert_eq!(parse_header(b""), Ok(None));
+    }
+}
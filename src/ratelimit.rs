@@ -0,0 +1,461 @@
+/*
+ * Copyright (C) 2024 Fanout, Inc.
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use crate::list;
+use slab::Slab;
+use std::cmp;
+use std::collections::hash_map::RandomState;
+use std::collections::HashMap;
+use std::hash::{BuildHasher, Hash};
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+const REQUEST_RATE_KEYS_MAX: usize = 100_000;
+
+// token bucket allowing up to `rate` events per second, with bursts of up
+// to `rate` events. tokens are tracked as whole units, and any partial
+// token is retained by only advancing the last refill time by the amount
+// of time that was converted into tokens
+pub struct TokenBucket {
+    rate: u32,
+    tokens: u32,
+    last: Instant,
+}
+
+impl TokenBucket {
+    pub fn new(rate: u32, now: Instant) -> Self {
+        assert!(rate > 0);
+
+        Self {
+            rate,
+            tokens: rate,
+            last: now,
+        }
+    }
+
+    pub fn is_full(&mut self, now: Instant) -> bool {
+        self.refill(now);
+
+        self.tokens == self.rate
+    }
+
+    pub fn try_take(&mut self, now: Instant) -> bool {
+        self.refill(now);
+
+        if self.tokens == 0 {
+            return false;
+        }
+
+        self.tokens -= 1;
+
+        true
+    }
+
+    pub fn rate(&self) -> u32 {
+        self.rate
+    }
+
+    // change the rate, keeping any tokens that fit within it
+    pub fn set_rate(&mut self, rate: u32, now: Instant) {
+        assert!(rate > 0);
+
+        self.refill(now);
+
+        self.rate = rate;
+        self.tokens = cmp::min(self.tokens, rate);
+    }
+
+    pub fn available(&mut self, now: Instant) -> u32 {
+        self.refill(now);
+
+        self.tokens
+    }
+
+    pub fn take(&mut self, count: u32) {
+        assert!(count <= self.tokens);
+
+        self.tokens -= count;
+    }
+
+    // earliest time at which a token will be available, if none are
+    pub fn next_refill(&self) -> Instant {
+        let micros = 1_000_000u64.div_ceil(self.rate as u64);
+
+        self.last + Duration::from_micros(micros)
+    }
+
+    fn refill(&mut self, now: Instant) {
+        if now <= self.last {
+            return;
+        }
+
+        if self.tokens == self.rate {
+            self.last = now;
+            return;
+        }
+
+        let elapsed = now - self.last;
+
+        let added = (elapsed.as_micros() * (self.rate as u128)) / 1_000_000;
+
+        if added == 0 {
+            return;
+        }
+
+        let missing = self.rate - self.tokens;
+
+        if added >= missing as u128 {
+            self.tokens = self.rate;
+            self.last = now;
+        } else {
+            let added = added as u32;
+
+            self.tokens += added;
+
+            let used_micros = ((added as u64) * 1_000_000) / (self.rate as u64);
+            self.last += Duration::from_micros(used_micros);
+        }
+    }
+}
+
+// number of refilled buckets to look for when adding a key
+const CLEANUP_PER_ADD: usize = 2;
+
+struct KeyedBucket<K> {
+    key: K,
+    bucket: TokenBucket,
+}
+
+// token buckets indexed by key, for limiting per client. the number of
+// tracked keys is capped, with the least recently used bucket evicted to
+// make room for a new key. buckets that have fully refilled are equivalent
+// to new buckets, so a few are discarded whenever a key is added
+pub struct KeyedRateLimiter<K> {
+    rate: u32,
+    keys: HashMap<K, usize>,
+    nodes: Slab<list::Node<KeyedBucket<K>>>,
+
+    // least recently used first
+    by_use: list::List,
+
+    capacity: usize,
+}
+
+impl<K> KeyedRateLimiter<K>
+where
+    K: Eq + Hash + Clone,
+{
+    pub fn new(rate: u32, capacity: usize) -> Self {
+        assert!(capacity > 0);
+
+        Self {
+            rate,
+            keys: HashMap::new(),
+            nodes: Slab::new(),
+            by_use: list::List::default(),
+            capacity,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.nodes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.nodes.is_empty()
+    }
+
+    pub fn try_take(&mut self, key: K, now: Instant) -> bool {
+        if let Some(&nkey) = self.keys.get(&key) {
+            self.by_use.remove(&mut self.nodes, nkey);
+            self.by_use.push_back(&mut self.nodes, nkey);
+
+            return self.nodes[nkey].value.bucket.try_take(now);
+        }
+
+        for _ in 0..CLEANUP_PER_ADD {
+            match self.by_use.head {
+                Some(nkey) if self.nodes[nkey].value.bucket.is_full(now) => self.remove(nkey),
+                _ => break,
+            }
+        }
+
+        if self.nodes.len() >= self.capacity {
+            if let Some(nkey) = self.by_use.head {
+                self.remove(nkey);
+            }
+        }
+
+        let mut bucket = TokenBucket::new(self.rate, now);
+        let ret = bucket.try_take(now);
+
+        let nkey = self.nodes.insert(list::Node::new(KeyedBucket {
+            key: key.clone(),
+            bucket,
+        }));
+
+        self.by_use.push_back(&mut self.nodes, nkey);
+        self.keys.insert(key, nkey);
+
+        ret
+    }
+
+    fn remove(&mut self, nkey: usize) {
+        self.by_use.remove(&mut self.nodes, nkey);
+
+        let kb = self.nodes.remove(nkey).value;
+        self.keys.remove(&kb.key);
+    }
+}
+
+// limits on the number of requests forwarded to handlers per second, per
+// client. a value of 0 means no limit
+#[derive(Clone, Default)]
+pub struct RequestRateLimits {
+    pub per_ip: u32,
+
+    // if set, requests having this header are limited by its value rather
+    // than by ip. since clients can set any header, it is only believed for
+    // requests relayed by a trusted proxy
+    pub key_header: Option<String>,
+}
+
+// header values are stored as hashes, so that the memory used by a key
+// doesn't depend on what clients send
+#[derive(Clone, PartialEq, Eq, Hash)]
+enum RequestKey {
+    Ip(IpAddr),
+    Header(u64),
+}
+
+struct RequestLimiterInner {
+    limiter: Mutex<KeyedRateLimiter<RequestKey>>,
+    key_header: Option<String>,
+
+    // randomly seeded, so that colliding values can't be chosen
+    hasher: RandomState,
+}
+
+// shared between workers, so that limits apply across all of them
+#[derive(Clone)]
+pub struct RequestLimiter {
+    inner: Arc<RequestLimiterInner>,
+}
+
+impl RequestLimiter {
+    // returns None if there is no limit
+    pub fn new(limits: RequestRateLimits) -> Option<Self> {
+        if limits.per_ip == 0 {
+            return None;
+        }
+
+        Some(Self {
+            inner: Arc::new(RequestLimiterInner {
+                limiter: Mutex::new(KeyedRateLimiter::new(limits.per_ip, REQUEST_RATE_KEYS_MAX)),
+                key_header: limits.key_header,
+                hasher: RandomState::new(),
+            }),
+        })
+    }
+
+    // the key header is only used if from_trusted_proxy is set. requests
+    // from clients that can't be identified are allowed
+    pub fn check(
+        &self,
+        ip: Option<IpAddr>,
+        headers: &[httparse::Header],
+        from_trusted_proxy: bool,
+        now: Instant,
+    ) -> bool {
+        let key = match &self.inner.key_header {
+            Some(name) if from_trusted_proxy => headers
+                .iter()
+                .find(|h| h.name.eq_ignore_ascii_case(name))
+                .map(|h| RequestKey::Header(self.inner.hasher.hash_one(h.value))),
+            _ => None,
+        };
+
+        let key = match key.or(ip.map(RequestKey::Ip)) {
+            Some(key) => key,
+            None => return true,
+        };
+
+        self.inner.limiter.lock().unwrap().try_take(key, now)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn token_bucket() {
+        let now = Instant::now();
+
+        let mut b = TokenBucket::new(2, now);
+        assert!(b.is_full(now));
+        assert!(b.try_take(now));
+        assert!(b.try_take(now));
+        assert!(!b.try_take(now));
+        assert!(!b.is_full(now));
+
+        // half a second refills one token
+        let now = now + Duration::from_millis(500);
+        assert!(b.try_take(now));
+        assert!(!b.try_take(now));
+
+        // partial refills are retained
+        let now = now + Duration::from_millis(300);
+        assert!(!b.try_take(now));
+        let now = now + Duration::from_millis(200);
+        assert!(b.try_take(now));
+
+        // refill never exceeds the rate
+        let now = now + Duration::from_secs(10);
+        assert!(b.is_full(now));
+        assert!(b.try_take(now));
+        assert!(b.try_take(now));
+        assert!(!b.try_take(now));
+    }
+
+    #[test]
+    fn token_bucket_counts() {
+        let now = Instant::now();
+
+        let mut b = TokenBucket::new(1000, now);
+        assert_eq!(b.available(now), 1000);
+        b.take(1000);
+        assert_eq!(b.available(now), 0);
+        assert_eq!(b.next_refill(), now + Duration::from_millis(1));
+
+        let now = now + Duration::from_millis(100);
+        assert_eq!(b.available(now), 100);
+
+        // lowering the rate discards tokens beyond it
+        b.set_rate(10, now);
+        assert_eq!(b.rate(), 10);
+        assert_eq!(b.available(now), 10);
+        b.take(10);
+        assert_eq!(b.next_refill(), now + Duration::from_millis(100));
+    }
+
+    #[test]
+    fn keyed_rate_limiter() {
+        let now = Instant::now();
+
+        let mut l = KeyedRateLimiter::new(1, 2);
+        assert!(l.try_take(1, now));
+        assert!(!l.try_take(1, now));
+        assert!(l.try_take(2, now));
+        assert!(!l.try_take(2, now));
+        assert_eq!(l.len(), 2);
+
+        // no room, so the least recently used key is evicted
+        assert!(l.try_take(3, now));
+        assert!(!l.try_take(3, now));
+        assert_eq!(l.len(), 2);
+
+        // the other tracked key is unaffected
+        assert!(!l.try_take(2, now));
+
+        // the evicted key starts over
+        assert!(l.try_take(1, now));
+        assert_eq!(l.len(), 2);
+
+        // refilled buckets are discarded when a key is added
+        let now = now + Duration::from_secs(1);
+        assert!(l.try_take(4, now));
+        assert!(!l.try_take(4, now));
+        assert_eq!(l.len(), 1);
+    }
+
+    #[test]
+    fn keyed_rate_limiter_full() {
+        let now = Instant::now();
+
+        let mut l = KeyedRateLimiter::new(2, 100);
+
+        // fill the table with buckets that have been used
+        for key in 0..100 {
+            assert!(l.try_take(key, now));
+        }
+        assert_eq!(l.len(), 100);
+
+        // a flood of new keys doesn't lock out other new keys, and the
+        // table stays bounded
+        for key in 100..1000 {
+            assert!(l.try_take(key, now));
+        }
+        assert_eq!(l.len(), 100);
+
+        assert!(l.try_take(1000, now));
+
+        // recently used keys keep their limits
+        assert!(l.try_take(999, now));
+        assert!(!l.try_take(999, now));
+    }
+
+    #[test]
+    fn request_limiter() {
+        let now = Instant::now();
+
+        assert!(RequestLimiter::new(RequestRateLimits::default()).is_none());
+
+        let l = RequestLimiter::new(RequestRateLimits {
+            per_ip: 1,
+            key_header: Some("X-Api-Key".to_string()),
+        })
+        .unwrap();
+
+        let ip1: IpAddr = "192.0.2.1".parse().unwrap();
+        let ip2: IpAddr = "192.0.2.2".parse().unwrap();
+
+        let key1 = [httparse::Header {
+            name: "x-api-key",
+            value: b"a",
+        }];
+
+        let key2 = [httparse::Header {
+            name: "X-Api-Key",
+            value: b"b",
+        }];
+
+        assert!(l.check(Some(ip1), &[], true, now));
+        assert!(!l.check(Some(ip1), &[], true, now));
+        assert!(l.check(Some(ip2), &[], true, now));
+
+        // the header takes precedence over the address
+        assert!(l.check(Some(ip1), &key1, true, now));
+        assert!(!l.check(Some(ip2), &key1, true, now));
+        assert!(l.check(Some(ip1), &key2, true, now));
+
+        // unless not from a trusted proxy, in which case it's ignored
+        assert!(!l.check(Some(ip1), &key2, false, now));
+
+        // unidentified clients are not limited
+        assert!(l.check(None, &[], true, now));
+        assert!(l.check(None, &[], true, now));
+
+        let now = now + Duration::from_secs(1);
+        assert!(l.check(Some(ip1), &[], true, now));
+        assert!(l.check(None, &key1, true, now));
+
+        // a key header without trust doesn't identify the client
+        assert!(l.check(None, &key1, false, now));
+        assert!(l.check(None, &key1, false, now));
+    }
+}
@@ -17,12 +17,14 @@
 use crate::arena;
 use crate::event;
 use crate::event::ReadinessExt;
+use crate::executor;
+use crate::platform;
 use crate::timer::TimerWheel;
+use log::warn;
 use slab::Slab;
 use std::cell::{Cell, RefCell};
 use std::cmp;
 use std::io;
-use std::os::unix::io::RawFd;
 use std::rc::{Rc, Weak};
 use std::task::Waker;
 use std::time::{Duration, Instant};
@@ -30,6 +32,10 @@ use std::time::{Duration, Instant};
 const TICK_DURATION_MS: u64 = 10;
 const EXPIRE_MAX: usize = 100;
 
+// if the clock advances this much beyond what a poll could account for, the
+// process is assumed to have been paused (e.g. a VM freeze)
+const TIME_GAP_THRESHOLD: Duration = Duration::from_secs(5);
+
 thread_local! {
     static REACTOR: RefCell<Option<Weak<ReactorData>>> = RefCell::new(None);
 }
@@ -347,6 +353,17 @@ impl Registration {
         }
     }
 
+    pub fn reregister_io<S: mio::event::Source>(
+        &self,
+        source: &mut S,
+        interest: mio::Interest,
+    ) -> Result<(), io::Error> {
+        let reactor = self.reactor.upgrade().expect("reactor is gone");
+        let poll = &reactor.poll.borrow();
+
+        poll.reregister(source, mio::Token(self.key + 1), interest)
+    }
+
     pub fn deregister_io<S: mio::event::Source>(&self, source: &mut S) -> Result<(), io::Error> {
         let reactor = self.reactor.upgrade().expect("reactor is gone");
         let poll = &reactor.poll.borrow();
@@ -354,6 +371,13 @@ impl Registration {
         poll.deregister(source)
     }
 
+    pub fn deregister_socket(&self, watch: &mut platform::SocketWatch) -> Result<(), io::Error> {
+        let reactor = self.reactor.upgrade().expect("reactor is gone");
+        let poll = &reactor.poll.borrow();
+
+        watch.deregister(poll)
+    }
+
     pub fn deregister_custom(&self, handle: &event::Registration) -> Result<(), io::Error> {
         let reactor = self.reactor.upgrade().expect("reactor is gone");
         let poll = &reactor.poll.borrow();
@@ -574,6 +598,36 @@ impl Reactor {
         })
     }
 
+    pub fn register_socket(
+        &self,
+        watch: &mut platform::SocketWatch,
+        interest: mio::Interest,
+    ) -> Result<Registration, io::Error> {
+        let registrations = &mut *self.inner.registrations.borrow_mut();
+
+        if registrations.len() == registrations.capacity() {
+            return Err(io::Error::from(io::ErrorKind::WriteZero));
+        }
+
+        let key = registrations.insert(RegistrationData {
+            readiness: None,
+            waker: None,
+            timer_key: None,
+            waker_persistent: false,
+        });
+
+        if let Err(e) = watch.register(&self.inner.poll.borrow(), mio::Token(key + 1), interest) {
+            registrations.remove(key);
+
+            return Err(e);
+        }
+
+        Ok(Registration {
+            reactor: Rc::downgrade(&self.inner),
+            key,
+        })
+    }
+
     pub fn register_custom(
         &self,
         handle: &event::Registration,
@@ -683,8 +737,12 @@ impl Reactor {
     // during task processing. we assume the actual time doesn't change much
     // between task processing and the next poll
     pub fn poll(&self, timeout: Option<Duration>) -> Result<(), io::Error> {
-        self.poll_for_events(self.next_timeout(timeout))?;
-        self.advance_time(Instant::now());
+        let timeout = self.next_timeout(timeout);
+
+        let poll_start = Instant::now();
+
+        self.poll_for_events(timeout)?;
+        self.advance_time_compensated(poll_start, Instant::now(), timeout);
         self.process_events();
 
         Ok(())
@@ -707,6 +765,27 @@ impl Reactor {
         timer.start + ticks_to_duration(timer.current_ticks)
     }
 
+    // returns (used, capacity)
+    pub fn registrations_occupancy(&self) -> (usize, usize) {
+        let registrations = &*self.inner.registrations.borrow();
+
+        (registrations.len(), registrations.capacity())
+    }
+
+    // returns (used, capacity)
+    pub fn timers_occupancy(&self) -> (usize, usize) {
+        let timer = &*self.inner.timer.borrow();
+
+        (timer.wheel.len(), timer.wheel.capacity())
+    }
+
+    // number of expired timers left for the next poll to process
+    pub fn timers_backlog(&self) -> usize {
+        let timer = &*self.inner.timer.borrow();
+
+        timer.wheel.expired_count()
+    }
+
     pub fn set_budget(&self, budget: Option<u32>) {
         *self.inner.budget.borrow_mut() = budget;
     }
@@ -743,6 +822,42 @@ impl Reactor {
         poll.poll(timeout)
     }
 
+    // like advance_time, but if the poll that started at poll_start took
+    // longer than its timeout can explain, the excess is treated as a pause
+    // and pending timers are delayed by it. otherwise a long freeze would
+    // expire every pending timer at once. time spent running tasks before
+    // the poll doesn't count
+    fn advance_time_compensated(
+        &self,
+        poll_start: Instant,
+        current_time: Instant,
+        timeout: Option<Duration>,
+    ) {
+        {
+            let timer = &mut *self.inner.timer.borrow_mut();
+
+            let elapsed = current_time.saturating_duration_since(poll_start);
+
+            // with no timeout, any amount of time could legitimately pass
+            if let Some(timeout) = timeout {
+                let expected_max = timeout + TIME_GAP_THRESHOLD;
+
+                if elapsed > expected_max {
+                    let gap = elapsed - expected_max;
+
+                    warn!(
+                        "reactor: detected scheduling gap of {:?}, delaying timers to compensate",
+                        gap
+                    );
+
+                    timer.start += gap;
+                }
+            }
+        }
+
+        self.advance_time(current_time);
+    }
+
     fn advance_time(&self, current_time: Instant) {
         let timer = &mut *self.inner.timer.borrow_mut();
 
@@ -812,48 +927,53 @@ impl Reactor {
             }
         }
 
-        let timer = &mut *self.inner.timer.borrow_mut();
+        // tasks woken by timer expirations are processed ahead of other
+        // woken tasks, so that timeouts are handled promptly even when the
+        // executor is busy
+        executor::with_high_priority_wakes(|| {
+            let timer = &mut *self.inner.timer.borrow_mut();
 
-        let mut expire_count = 0;
+            let mut expire_count = 0;
 
-        while let Some((_, key)) = timer.wheel.take_expired() {
-            let mut registrations = self.inner.registrations.borrow_mut();
+            while let Some((_, key)) = timer.wheel.take_expired() {
+                let mut registrations = self.inner.registrations.borrow_mut();
 
-            if let Some(event_reg) = registrations.get_mut(key) {
-                event_reg.readiness = Some(mio::Interest::READABLE);
-                event_reg.timer_key = None;
+                if let Some(event_reg) = registrations.get_mut(key) {
+                    event_reg.readiness = Some(mio::Interest::READABLE);
+                    event_reg.timer_key = None;
 
-                if let Some(wi) = event_reg.waker.take() {
-                    let persistent = event_reg.waker_persistent;
-                    drop(registrations);
+                    if let Some(wi) = event_reg.waker.take() {
+                        let persistent = event_reg.waker_persistent;
+                        drop(registrations);
 
-                    let wi_remaining = if persistent {
-                        wi.wake_by_ref(mio::Interest::READABLE);
+                        let wi_remaining = if persistent {
+                            wi.wake_by_ref(mio::Interest::READABLE);
 
-                        Some(wi)
-                    } else {
-                        wi.wake(mio::Interest::READABLE)
-                    };
+                            Some(wi)
+                        } else {
+                            wi.wake(mio::Interest::READABLE)
+                        };
 
-                    if let Some(wi_remaining) = wi_remaining {
-                        let mut registrations = self.inner.registrations.borrow_mut();
+                        if let Some(wi_remaining) = wi_remaining {
+                            let mut registrations = self.inner.registrations.borrow_mut();
 
-                        if let Some(event_reg) = registrations.get_mut(key) {
-                            match event_reg.waker.take() {
-                                Some(wi) => event_reg.waker = Some(wi.merge(wi_remaining)),
-                                None => event_reg.waker = Some(wi_remaining),
+                            if let Some(event_reg) = registrations.get_mut(key) {
+                                match event_reg.waker.take() {
+                                    Some(wi) => event_reg.waker = Some(wi.merge(wi_remaining)),
+                                    None => event_reg.waker = Some(wi_remaining),
+                                }
                             }
                         }
                     }
                 }
-            }
 
-            expire_count += 1;
+                expire_count += 1;
 
-            if expire_count >= EXPIRE_MAX {
-                break;
+                if expire_count >= EXPIRE_MAX {
+                    break;
+                }
             }
-        }
+        });
     }
 }
 
@@ -893,6 +1013,12 @@ impl<S: mio::event::Source> IoEvented<S> {
         &self.inner.as_ref().unwrap().io
     }
 
+    pub fn set_interest(&mut self, interest: mio::Interest) -> Result<(), io::Error> {
+        let inner = self.inner.as_mut().unwrap();
+
+        inner.registration.reregister_io(&mut inner.io, interest)
+    }
+
     // return registration and io object, without deregistering it
     pub fn into_parts(mut self) -> (Registration, S) {
         let inner = self.inner.take().unwrap();
@@ -918,29 +1044,37 @@ impl<S: mio::event::Source> Drop for IoEvented<S> {
 
 pub struct FdEvented {
     registration: Registration,
-    fd: RawFd,
+    watch: platform::SocketWatch,
 }
 
 impl FdEvented {
-    pub fn new(fd: RawFd, interest: mio::Interest, reactor: &Reactor) -> Result<Self, io::Error> {
-        let registration = reactor.register_io(&mut mio::unix::SourceFd(&fd), interest)?;
+    pub fn new(
+        fd: platform::RawSocket,
+        interest: mio::Interest,
+        reactor: &Reactor,
+    ) -> Result<Self, io::Error> {
+        let mut watch = platform::SocketWatch::new(fd)?;
+        let registration = reactor.register_socket(&mut watch, interest)?;
 
-        Ok(Self { registration, fd })
+        Ok(Self {
+            registration,
+            watch,
+        })
     }
 
     pub fn registration(&self) -> &Registration {
         &self.registration
     }
 
-    pub fn fd(&self) -> &RawFd {
-        &self.fd
+    pub fn fd(&self) -> platform::RawSocket {
+        self.watch.socket()
     }
 }
 
 impl Drop for FdEvented {
     fn drop(&mut self) {
-        self.registration()
-            .deregister_io(&mut mio::unix::SourceFd(&self.fd))
+        self.registration
+            .deregister_socket(&mut self.watch)
             .unwrap();
     }
 }
@@ -1012,10 +1146,10 @@ impl TimerEvented {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::platform::AsRawSocket;
     use crate::waker;
     use std::cell::Cell;
     use std::mem;
-    use std::os::unix::io::AsRawFd;
     use std::rc::Rc;
     use std::thread;
 
@@ -1083,7 +1217,7 @@ mod tests {
         let listener = std::net::TcpListener::bind(addr).unwrap();
 
         let evented =
-            FdEvented::new(listener.as_raw_fd(), mio::Interest::READABLE, &reactor).unwrap();
+            FdEvented::new(listener.as_raw_socket(), mio::Interest::READABLE, &reactor).unwrap();
 
         let addr = listener.local_addr().unwrap();
 
@@ -1175,6 +1309,85 @@ mod tests {
         assert_eq!(reactor.now(), now + Duration::from_millis(100));
     }
 
+    #[test]
+    fn test_reactor_time_gap() {
+        let now = Instant::now();
+
+        let reactor = Reactor::new_with_time(1, now);
+
+        let evented = TimerEvented::new(now + Duration::from_secs(10), &reactor).unwrap();
+
+        let waker = Rc::new(TestWaker::new());
+
+        evented
+            .registration()
+            .set_waker(&waker.clone().into_std(), mio::Interest::READABLE);
+
+        // within what the timeout allows
+        reactor.advance_time_compensated(now, now + Duration::from_secs(3), Some(Duration::ZERO));
+        assert_eq!(reactor.now(), now + Duration::from_secs(3));
+
+        // a long pause is detected. the clock still advances, but pending
+        // timers are delayed by the excess
+        reactor.advance_time_compensated(
+            now + Duration::from_secs(3),
+            now + Duration::from_secs(40),
+            Some(Duration::from_secs(1)),
+        );
+        assert_eq!(reactor.now(), now + Duration::from_secs(40));
+
+        reactor.process_events();
+        assert_eq!(waker.was_waked(), false);
+
+        // without a timeout, any amount of time is accepted
+        reactor.advance_time_compensated(
+            now + Duration::from_secs(40),
+            now + Duration::from_secs(45),
+            None,
+        );
+        assert_eq!(reactor.now(), now + Duration::from_secs(45));
+
+        reactor.process_events();
+        assert_eq!(waker.was_waked(), true);
+    }
+
+    #[test]
+    fn test_reactor_slow_task() {
+        let now = Instant::now();
+
+        let reactor = Reactor::new_with_time(1, now);
+
+        let evented = TimerEvented::new(now + Duration::from_secs(10), &reactor).unwrap();
+
+        let waker = Rc::new(TestWaker::new());
+
+        evented
+            .registration()
+            .set_waker(&waker.clone().into_std(), mio::Interest::READABLE);
+
+        reactor.advance_time_compensated(
+            now,
+            now + Duration::from_secs(1),
+            Some(Duration::from_secs(10)),
+        );
+
+        reactor.process_events();
+        assert_eq!(waker.was_waked(), false);
+
+        // a task runs for 8 seconds before the next poll, which is longer
+        // than the gap threshold. the poll itself takes no longer than its
+        // timeout, so the timer isn't delayed
+        reactor.advance_time_compensated(
+            now + Duration::from_secs(9),
+            now + Duration::from_secs(10),
+            Some(Duration::from_secs(1)),
+        );
+        assert_eq!(reactor.now(), now + Duration::from_secs(10));
+
+        reactor.process_events();
+        assert_eq!(waker.was_waked(), true);
+    }
+
     #[test]
     fn test_reactor_current() {
         assert!(Reactor::current().is_none());
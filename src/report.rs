@@ -0,0 +1,328 @@
+/*
+ * Copyright (C) 2024 Fanout, Inc.
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+// periodic stats reports, published on a zmq PUB socket for monitoring.
+// each interval, one message is published per worker with the topic
+// "worker-report", followed by one message with the topic "report"
+// containing the totals across all workers. like announcements, the topic
+// is followed by a space and a tnetstring map prefixed with 'T'. values
+// are cumulative since the instance started, except for connections
+
+use crate::spawn_thread;
+use crate::stats::{KeepAliveCounters, Occupancy, WorkerStats};
+use crate::tnetstring;
+use crate::zhttppacket::Counters;
+use crate::zmq::{SpecInfo, ZmqSocket};
+use log::{debug, error};
+use std::fmt;
+use std::io;
+use std::sync::mpsc;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+pub const TOPIC: &[u8] = b"report ";
+pub const WORKER_TOPIC: &[u8] = b"worker-report ";
+
+const MESSAGE_SIZE_MAX: usize = 1_024;
+
+#[derive(Default)]
+pub struct Report<'a> {
+    pub instance_id: &'a str,
+
+    // set for per-worker reports, unset for the aggregate
+    pub worker: Option<usize>,
+
+    // increases with each reporting interval
+    pub seq: u64,
+
+    pub connections: Occupancy,
+    pub counters: Counters,
+    pub errors: u64,
+    pub keep_alives: KeepAliveCounters,
+}
+
+impl<'a> Report<'a> {
+    // report on each worker, passing each to f, and return the totals
+    pub fn collect<F>(
+        instance_id: &'a str,
+        seq: u64,
+        workers: &[Arc<WorkerStats>],
+        mut f: F,
+    ) -> Self
+    where
+        F: FnMut(&Report),
+    {
+        let mut total = Report {
+            instance_id,
+            seq,
+            ..Default::default()
+        };
+
+        for (i, stats) in workers.iter().enumerate() {
+            let r = Report {
+                instance_id,
+                worker: Some(i),
+                seq,
+                connections: stats.occupancy().connections,
+                counters: stats.counters(),
+                errors: stats.errors(),
+                keep_alives: stats.keep_alives(),
+            };
+
+            f(&r);
+
+            total.add(&r);
+        }
+
+        total
+    }
+
+    fn add(&mut self, other: &Report) {
+        self.connections.used += other.connections.used;
+        self.connections.capacity += other.connections.capacity;
+        self.counters += other.counters;
+        self.errors += other.errors;
+        self.keep_alives += other.keep_alives;
+    }
+
+    pub fn serialize(&self, dest: &mut [u8]) -> Result<usize, io::Error> {
+        let topic = if self.worker.is_some() {
+            WORKER_TOPIC
+        } else {
+            TOPIC
+        };
+
+        if dest.len() < topic.len() + 1 {
+            return Err(io::Error::from(io::ErrorKind::WriteZero));
+        }
+
+        dest[..topic.len()].copy_from_slice(topic);
+        dest[topic.len()] = b'T';
+
+        let start = topic.len() + 1;
+
+        let mut cursor = io::Cursor::new(&mut dest[start..]);
+        let mut w = tnetstring::Writer::new(&mut cursor);
+
+        w.start_map()?;
+
+        w.write_string(b"from")?;
+        w.write_string(self.instance_id.as_bytes())?;
+
+        if let Some(worker) = self.worker {
+            w.write_string(b"worker")?;
+            w.write_int(worker as isize)?;
+        }
+
+        w.write_string(b"seq")?;
+        w.write_int(self.seq as isize)?;
+
+        w.write_string(b"connections")?;
+        w.write_int(self.connections.used as isize)?;
+
+        w.write_string(b"maxconn")?;
+        w.write_int(self.connections.capacity as isize)?;
+
+        w.write_string(b"bytes-in")?;
+        w.write_int(self.counters.bytes_in as isize)?;
+
+        w.write_string(b"bytes-out")?;
+        w.write_int(self.counters.bytes_out as isize)?;
+
+        w.write_string(b"messages-in")?;
+        w.write_int(self.counters.messages_in as isize)?;
+
+        w.write_string(b"messages-out")?;
+        w.write_int(self.counters.messages_out as isize)?;
+
+        w.write_string(b"errors")?;
+        w.write_int(self.errors as isize)?;
+
+        w.write_string(b"keep-alive-batches")?;
+        w.write_int(self.keep_alives.batches as isize)?;
+
+        w.write_string(b"keep-alive-sessions")?;
+        w.write_int(self.keep_alives.sessions as isize)?;
+
+        w.end_map()?;
+
+        w.flush()?;
+
+        Ok(start + (cursor.position() as usize))
+    }
+}
+
+// the same fields as serialized, as space-separated key=value pairs
+impl fmt::Display for Report<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        if let Some(worker) = self.worker {
+            write!(f, "worker={} ", worker)?;
+        }
+
+        write!(
+            f,
+            "connections={} maxconn={} bytes-in={} bytes-out={} messages-in={} messages-out={} errors={} keep-alive-batches={} keep-alive-sessions={}",
+            self.connections.used,
+            self.connections.capacity,
+            self.counters.bytes_in,
+            self.counters.bytes_out,
+            self.counters.messages_in,
+            self.counters.messages_out,
+            self.errors,
+            self.keep_alives.batches,
+            self.keep_alives.sessions,
+        )
+    }
+}
+
+pub struct Reporter {
+    stop: Option<mpsc::Sender<()>>,
+    thread: Option<thread::JoinHandle<()>>,
+}
+
+impl Reporter {
+    pub fn new(
+        zmq_context: &Arc<zmq::Context>,
+        instance_id: &str,
+        spec: SpecInfo,
+        interval: Duration,
+        workers: Vec<Arc<WorkerStats>>,
+    ) -> Result<Self, String> {
+        let sock = ZmqSocket::new(zmq_context, zmq::PUB);
+
+        if let Err(e) = sock.apply_specs(&[spec]) {
+            return Err(e.to_string());
+        }
+
+        let (stop, r_stop) = mpsc::channel();
+
+        let instance_id = instance_id.to_string();
+
+        let thread = spawn_thread("report".to_string(), move || {
+            let mut buf = [0; MESSAGE_SIZE_MAX];
+
+            let send = |r: &Report, buf: &mut [u8]| match r.serialize(buf) {
+                Ok(size) => {
+                    let msg = zmq::Message::from(&buf[..size]);
+
+                    // subscribers that can't keep up miss reports
+                    if let Err(e) = sock.send(msg, zmq::DONTWAIT) {
+                        debug!("report send: {}", e);
+                    }
+                }
+                Err(e) => error!("report serialize: {}", e),
+            };
+
+            for seq in 0.. {
+                let total = Report::collect(&instance_id, seq, &workers, |r| send(r, &mut buf));
+
+                send(&total, &mut buf);
+
+                match r_stop.recv_timeout(interval) {
+                    Err(mpsc::RecvTimeoutError::Timeout) => {}
+                    _ => break,
+                }
+            }
+        })?;
+
+        Ok(Self {
+            stop: Some(stop),
+            thread: Some(thread),
+        })
+    }
+}
+
+impl Drop for Reporter {
+    fn drop(&mut self) {
+        // wake the thread
+        self.stop = None;
+
+        let thread = self.thread.take().unwrap();
+        thread.join().unwrap();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str;
+
+    #[test]
+    fn serialize() {
+        let mut w1 = Report {
+            instance_id: "condure",
+            worker: Some(1),
+            seq: 3,
+            connections: Occupancy::new(2, 100),
+            counters: Counters {
+                bytes_in: 100,
+                bytes_out: 200,
+                messages_in: 1,
+                messages_out: 2,
+            },
+            errors: 1,
+            keep_alives: KeepAliveCounters {
+                batches: 4,
+                sessions: 8,
+            },
+        };
+
+        let mut buf = [0; MESSAGE_SIZE_MAX];
+        let size = w1.serialize(&mut buf).unwrap();
+
+        let expected = concat!(
+            "worker-report T215:4:from,7:condure,6:worker,1:1#3:seq,1:3#11:connections,1:2#",
+            "7:maxconn,3:100#8:bytes-in,3:100#9:bytes-out,3:200#11:messages-in,1:1#",
+            "12:messages-out,1:2#6:errors,1:1#18:keep-alive-batches,1:4#",
+            "19:keep-alive-sessions,1:8#}",
+        );
+
+        assert_eq!(str::from_utf8(&buf[..size]).unwrap(), expected);
+
+        let mut total = Report {
+            instance_id: "condure",
+            seq: 3,
+            ..Default::default()
+        };
+
+        total.add(&w1);
+        w1.worker = Some(0);
+        total.add(&w1);
+
+        let size = total.serialize(&mut buf).unwrap();
+
+        let expected = concat!(
+            "report T203:4:from,7:condure,3:seq,1:3#11:connections,1:4#",
+            "7:maxconn,3:200#8:bytes-in,3:200#9:bytes-out,3:400#11:messages-in,1:2#",
+            "12:messages-out,1:4#6:errors,1:2#18:keep-alive-batches,1:8#",
+            "19:keep-alive-sessions,2:16#}",
+        );
+
+        assert_eq!(str::from_utf8(&buf[..size]).unwrap(), expected);
+
+        assert_eq!(
+            w1.to_string(),
+            concat!(
+                "worker=0 connections=2 maxconn=100 bytes-in=100 bytes-out=200 messages-in=1 ",
+                "messages-out=2 errors=1 keep-alive-batches=4 keep-alive-sessions=8",
+            )
+        );
+
+        let mut buf = [0; 8];
+        assert!(total.serialize(&mut buf).is_err());
+    }
+}
@@ -0,0 +1,535 @@
+/*
+ * Copyright (C) 2024 Fanout, Inc.
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use crate::zhttppacket::Counters;
+use crate::zhttpsocket::{QueueStats, SendStats};
+use std::fmt;
+use std::ops::AddAssign;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+// how much of a fixed-capacity resource is in use
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Occupancy {
+    pub used: usize,
+    pub capacity: usize,
+}
+
+impl Occupancy {
+    pub fn new(used: usize, capacity: usize) -> Self {
+        Self { used, capacity }
+    }
+
+    // percentage of capacity in use, rounded down
+    pub fn percent(&self) -> usize {
+        if self.capacity == 0 {
+            return 0;
+        }
+
+        (self.used * 100) / self.capacity
+    }
+}
+
+impl From<(usize, usize)> for Occupancy {
+    fn from(v: (usize, usize)) -> Self {
+        Self::new(v.0, v.1)
+    }
+}
+
+impl fmt::Display for Occupancy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        write!(f, "{}/{}", self.used, self.capacity)
+    }
+}
+
+// occupancy of a worker's preallocated structures. the values are useful
+// for tuning maxconn, handle_bound, and messages_max
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct WorkerOccupancy {
+    // slab of connection items
+    pub connections: Occupancy,
+
+    // arena of shared stream data
+    pub stream_shared: Occupancy,
+
+    // arenas of parsed handler responses
+    pub req_responses: Occupancy,
+    pub stream_responses: Occupancy,
+
+    // queues of messages waiting to be written to zmq sockets
+    pub zreq_out: Occupancy,
+    pub zstream_out: Occupancy,
+    pub zstream_out_stream: Occupancy,
+
+    // reactor
+    pub registrations: Occupancy,
+    pub timers: Occupancy,
+}
+
+impl WorkerOccupancy {
+    pub fn fields(&self) -> [(&'static str, Occupancy); 9] {
+        [
+            ("connections", self.connections),
+            ("stream_shared", self.stream_shared),
+            ("req_responses", self.req_responses),
+            ("stream_responses", self.stream_responses),
+            ("zreq_out", self.zreq_out),
+            ("zstream_out", self.zstream_out),
+            ("zstream_out_stream", self.zstream_out_stream),
+            ("registrations", self.registrations),
+            ("timers", self.timers),
+        ]
+    }
+}
+
+// a connection that has been waiting a while for a handler response
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct StalledConnection {
+    pub id: String,
+    pub waiting: Duration,
+}
+
+// details useful for diagnosing workers that are slow but not failing
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct WorkerDiagnostics {
+    // messages queued to connections, in total and for the connection with
+    // the most
+    pub conn_queued: usize,
+    pub conn_queued_max: usize,
+
+    // timers that have expired but haven't been processed yet
+    pub timers_backlog: usize,
+
+    // connections waiting longest for handler responses, longest first
+    pub stalled: Vec<StalledConnection>,
+
+    // number of stalled connections, which may be more than listed
+    pub stalled_count: usize,
+}
+
+// write a human readable report of a worker's queues and stalls
+pub fn write_diagnostics<W: fmt::Write>(
+    w: &mut W,
+    worker_id: usize,
+    occupancy: &WorkerOccupancy,
+    diag: &WorkerDiagnostics,
+) -> Result<(), fmt::Error> {
+    writeln!(w, "worker {}:", worker_id)?;
+
+    writeln!(
+        w,
+        "  queues: zreq_out={} zstream_out={} zstream_out_stream={} conn_queued={} conn_queued_max={}",
+        occupancy.zreq_out,
+        occupancy.zstream_out,
+        occupancy.zstream_out_stream,
+        diag.conn_queued,
+        diag.conn_queued_max,
+    )?;
+
+    writeln!(
+        w,
+        "  timers: {} backlog={}",
+        occupancy.timers, diag.timers_backlog
+    )?;
+
+    writeln!(w, "  stalled: {}", diag.stalled_count)?;
+
+    for c in diag.stalled.iter() {
+        writeln!(w, "    {} waiting {:.1}s", c.id, c.waiting.as_secs_f64())?;
+    }
+
+    Ok(())
+}
+
+const QUEUE_ADDRS_SHOWN: usize = 5;
+
+fn write_send_stats<W: fmt::Write>(w: &mut W, s: &SendStats) -> Result<(), fmt::Error> {
+    write!(
+        w,
+        "sent={} hwm_hits={} hwm_wait={:.1}s errors={}",
+        s.sent,
+        s.hwm_hits,
+        s.hwm_wait.as_secs_f64(),
+        s.errors
+    )
+}
+
+// write a human readable report of the messages sent to a set of handlers,
+// including the handler addresses that most often hit the high water mark
+// or failed
+pub fn write_queue_stats<W: fmt::Write>(
+    w: &mut W,
+    name: &str,
+    stats: &QueueStats,
+) -> Result<(), fmt::Error> {
+    writeln!(w, "{}:", name)?;
+
+    for (kind, s) in [
+        ("req", &stats.req),
+        ("stream", &stats.stream),
+        ("stream_to", &stats.stream_to),
+    ] {
+        write!(w, "  {}: ", kind)?;
+        write_send_stats(w, s)?;
+        writeln!(w)?;
+    }
+
+    let slow = stats
+        .stream_to_addrs
+        .iter()
+        .filter(|(_, s)| s.hwm_hits > 0 || s.errors > 0);
+
+    for (addr, s) in slow.take(QUEUE_ADDRS_SHOWN) {
+        write!(w, "    {} ", addr)?;
+        write_send_stats(w, s)?;
+        writeln!(w)?;
+    }
+
+    Ok(())
+}
+
+// requests duplicated to a shadow handler
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct MirrorCounters {
+    pub sent: u64,
+
+    // not sent because the queue to the shadow handler was full
+    pub dropped: u64,
+}
+
+impl AddAssign for MirrorCounters {
+    fn add_assign(&mut self, other: Self) {
+        self.sent += other.sent;
+        self.dropped += other.dropped;
+    }
+}
+
+// keep alive messages sent to handlers. each batch covers many sessions
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct KeepAliveCounters {
+    pub batches: u64,
+    pub sessions: u64,
+}
+
+impl AddAssign for KeepAliveCounters {
+    fn add_assign(&mut self, other: Self) {
+        self.batches += other.batches;
+        self.sessions += other.sessions;
+    }
+}
+
+// latest stats reported by a worker thread. the worker updates the values
+// periodically, and they can be read from any thread
+#[derive(Default)]
+pub struct WorkerStats {
+    occupancy: Mutex<WorkerOccupancy>,
+    diagnostics: Mutex<WorkerDiagnostics>,
+    counters: Mutex<Counters>,
+    errors: Mutex<u64>,
+    keep_alives: Mutex<KeepAliveCounters>,
+    mirror: Mutex<MirrorCounters>,
+    updated: Mutex<Option<Instant>>,
+}
+
+impl WorkerStats {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn occupancy(&self) -> WorkerOccupancy {
+        self.occupancy.lock().unwrap().clone()
+    }
+
+    pub fn set_occupancy(&self, occupancy: WorkerOccupancy) {
+        *self.occupancy.lock().unwrap() = occupancy;
+        *self.updated.lock().unwrap() = Some(Instant::now());
+    }
+
+    // when the worker last reported occupancy, if ever
+    pub fn updated(&self) -> Option<Instant> {
+        *self.updated.lock().unwrap()
+    }
+
+    pub fn diagnostics(&self) -> WorkerDiagnostics {
+        self.diagnostics.lock().unwrap().clone()
+    }
+
+    pub fn set_diagnostics(&self, diagnostics: WorkerDiagnostics) {
+        *self.diagnostics.lock().unwrap() = diagnostics;
+    }
+
+    pub fn counters(&self) -> Counters {
+        *self.counters.lock().unwrap()
+    }
+
+    pub fn set_counters(&self, counters: Counters) {
+        *self.counters.lock().unwrap() = counters;
+    }
+
+    // number of connections that ended with an error
+    pub fn errors(&self) -> u64 {
+        *self.errors.lock().unwrap()
+    }
+
+    pub fn set_errors(&self, errors: u64) {
+        *self.errors.lock().unwrap() = errors;
+    }
+
+    pub fn keep_alives(&self) -> KeepAliveCounters {
+        *self.keep_alives.lock().unwrap()
+    }
+
+    pub fn add_keep_alive_batch(&self, sessions: usize) {
+        *self.keep_alives.lock().unwrap() += KeepAliveCounters {
+            batches: 1,
+            sessions: sessions as u64,
+        };
+    }
+
+    pub fn mirror(&self) -> MirrorCounters {
+        *self.mirror.lock().unwrap()
+    }
+
+    pub fn set_mirror(&self, counters: MirrorCounters) {
+        *self.mirror.lock().unwrap() = counters;
+    }
+}
+
+// checks that workers are still reporting stats, and that handlers are
+// keeping up with the messages sent to them
+pub struct HealthCheck {
+    workers: Vec<Arc<WorkerStats>>,
+    max_age: Duration,
+}
+
+impl HealthCheck {
+    pub fn new(workers: Vec<Arc<WorkerStats>>, max_age: Duration) -> Self {
+        Self { workers, max_age }
+    }
+
+    pub fn check(&self, now: Instant) -> Result<(), String> {
+        for (id, stats) in self.workers.iter().enumerate() {
+            match stats.updated() {
+                Some(t) if now.saturating_duration_since(t) <= self.max_age => {}
+                Some(t) => {
+                    return Err(format!(
+                        "worker {}: no stats for {:.1}s",
+                        id,
+                        now.saturating_duration_since(t).as_secs_f64()
+                    ))
+                }
+                None => return Err(format!("worker {}: not started", id)),
+            }
+
+            let o = stats.occupancy();
+
+            // a full output queue alone may be a burst, but together with
+            // stalled connections it means the handler isn't reading
+            let full = [
+                ("zreq_out", o.zreq_out),
+                ("zstream_out", o.zstream_out),
+                ("zstream_out_stream", o.zstream_out_stream),
+            ]
+            .iter()
+            .copied()
+            .find(|(_, q)| q.capacity > 0 && q.used >= q.capacity);
+
+            if let Some((name, _)) = full {
+                let stalled = stats.diagnostics().stalled_count;
+
+                if stalled > 0 {
+                    return Err(format!(
+                        "worker {}: {} full with {} stalled connections",
+                        id, name, stalled
+                    ));
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn occupancy() {
+        let o = Occupancy::new(25, 200);
+        assert_eq!(o.percent(), 12);
+        assert_eq!(o.to_string(), "25/200");
+
+        let o = Occupancy::from((0, 0));
+        assert_eq!(o.percent(), 0);
+    }
+
+    #[test]
+    fn worker_stats() {
+        let stats = WorkerStats::new();
+        assert_eq!(stats.occupancy(), WorkerOccupancy::default());
+
+        let mut o = WorkerOccupancy::default();
+        o.connections = Occupancy::new(1, 10);
+        stats.set_occupancy(o.clone());
+        assert_eq!(stats.occupancy(), o);
+        assert_eq!(
+            stats.occupancy().fields()[0],
+            ("connections", o.connections)
+        );
+
+        assert_eq!(stats.counters(), Counters::default());
+
+        let c = Counters {
+            bytes_in: 100,
+            bytes_out: 200,
+            messages_in: 1,
+            messages_out: 1,
+        };
+        stats.set_counters(c);
+        assert_eq!(stats.counters(), c);
+
+        assert_eq!(stats.mirror(), MirrorCounters::default());
+
+        let mut m = MirrorCounters {
+            sent: 3,
+            dropped: 1,
+        };
+        stats.set_mirror(m);
+        assert_eq!(stats.mirror(), m);
+
+        m += MirrorCounters {
+            sent: 1,
+            dropped: 0,
+        };
+        assert_eq!(m.sent, 4);
+        assert_eq!(m.dropped, 1);
+    }
+
+    #[test]
+    fn diagnostics() {
+        let stats = WorkerStats::new();
+        assert_eq!(stats.diagnostics(), WorkerDiagnostics::default());
+
+        let mut o = WorkerOccupancy::default();
+        o.zreq_out = Occupancy::new(2, 100);
+        o.timers = Occupancy::new(5, 1000);
+
+        let d = WorkerDiagnostics {
+            conn_queued: 3,
+            conn_queued_max: 2,
+            timers_backlog: 1,
+            stalled: vec![StalledConnection {
+                id: "0-4-1".to_string(),
+                waiting: Duration::from_millis(12_340),
+            }],
+            stalled_count: 1,
+        };
+
+        stats.set_diagnostics(d.clone());
+        assert_eq!(stats.diagnostics(), d);
+
+        let mut out = String::new();
+        write_diagnostics(&mut out, 0, &o, &d).unwrap();
+
+        let expected = concat!(
+            "worker 0:\n",
+            "  queues: zreq_out=2/100 zstream_out=0/0 zstream_out_stream=0/0 conn_queued=3 conn_queued_max=2\n",
+            "  timers: 5/1000 backlog=1\n",
+            "  stalled: 1\n",
+            "    0-4-1 waiting 12.3s\n",
+        );
+
+        assert_eq!(out, expected);
+    }
+
+    #[test]
+    fn queue_stats() {
+        let stats = QueueStats {
+            req: SendStats {
+                sent: 10,
+                hwm_hits: 2,
+                hwm_wait: Duration::from_millis(1_500),
+                errors: 1,
+            },
+            stream_to_addrs: vec![
+                (
+                    "handler-1".to_string(),
+                    SendStats {
+                        sent: 4,
+                        hwm_hits: 1,
+                        hwm_wait: Duration::from_millis(100),
+                        errors: 0,
+                    },
+                ),
+                (
+                    "handler-2".to_string(),
+                    SendStats {
+                        sent: 3,
+                        hwm_hits: 0,
+                        hwm_wait: Duration::from_millis(0),
+                        errors: 0,
+                    },
+                ),
+            ],
+            ..Default::default()
+        };
+
+        let mut out = String::new();
+        write_queue_stats(&mut out, "handlers", &stats).unwrap();
+
+        let expected = concat!(
+            "handlers:\n",
+            "  req: sent=10 hwm_hits=2 hwm_wait=1.5s errors=1\n",
+            "  stream: sent=0 hwm_hits=0 hwm_wait=0.0s errors=0\n",
+            "  stream_to: sent=0 hwm_hits=0 hwm_wait=0.0s errors=0\n",
+            "    handler-1 sent=4 hwm_hits=1 hwm_wait=0.1s errors=0\n",
+        );
+
+        assert_eq!(out, expected);
+    }
+
+    #[test]
+    fn health_check() {
+        let stats = Arc::new(WorkerStats::new());
+        let health = HealthCheck::new(vec![stats.clone()], Duration::from_secs(10));
+
+        let now = Instant::now();
+        assert_eq!(health.check(now), Err("worker 0: not started".to_string()));
+
+        let mut o = WorkerOccupancy::default();
+        o.zreq_out = Occupancy::new(100, 100);
+        stats.set_occupancy(o);
+
+        let now = stats.updated().unwrap();
+        assert_eq!(health.check(now), Ok(()));
+
+        let late = now + Duration::from_secs(11);
+        assert_eq!(
+            health.check(late),
+            Err("worker 0: no stats for 11.0s".to_string())
+        );
+
+        stats.set_diagnostics(WorkerDiagnostics {
+            stalled_count: 2,
+            ..Default::default()
+        });
+        assert_eq!(
+            health.check(now),
+            Err("worker 0: zreq_out full with 2 stalled connections".to_string())
+        );
+    }
+}
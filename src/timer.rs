@@ -122,6 +122,7 @@ fn need_resched_simple(curtime: u64, newtime: u64) -> [u64; WHEEL_NUM] {
 
 enum InList {
     Wheel(usize, usize),
+    Overflow,
     Expired,
 }
 
@@ -137,6 +138,13 @@ pub struct TimerWheel {
     expired: list::List,
     pending: [u64; WHEEL_NUM],
     curtime: u64,
+
+    // timers that expire beyond the range of the wheels are kept here until
+    // they come within range
+    overflow: list::List,
+
+    // earliest time at which an overflow timer comes within range
+    overflow_next: Option<u64>,
 }
 
 impl TimerWheel {
@@ -147,9 +155,28 @@ impl TimerWheel {
             expired: list::List::default(),
             pending: [0; WHEEL_NUM],
             curtime: 0,
+            overflow: list::List::default(),
+            overflow_next: None,
         }
     }
 
+    pub fn len(&self) -> usize {
+        self.nodes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.nodes.is_empty()
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.nodes.capacity()
+    }
+
+    // number of timers that have expired but not been taken yet
+    pub fn expired_count(&self) -> usize {
+        self.expired.iter(&self.nodes).count()
+    }
+
     #[allow(clippy::result_unit_err)]
     pub fn add(&mut self, expires: u64, user_data: usize) -> Result<usize, ()> {
         if self.nodes.len() == self.nodes.capacity() {
@@ -184,6 +211,13 @@ impl TimerWheel {
                     self.pending[wheel] &= !(1 << slot);
                 }
             }
+            Some(InList::Overflow) => {
+                self.overflow.remove(&mut self.nodes, key);
+
+                if self.overflow.is_empty() {
+                    self.overflow_next = None;
+                }
+            }
             Some(InList::Expired) => {
                 self.expired.remove(&mut self.nodes, key);
             }
@@ -229,6 +263,16 @@ impl TimerWheel {
             relmask |= WHEEL_MASK;
         }
 
+        // wake up in time to move overflow timers into the wheels
+        if let Some(next) = self.overflow_next {
+            let t = next - self.curtime;
+
+            timeout = Some(match timeout {
+                Some(best) => cmp::min(best, t),
+                None => t,
+            });
+        }
+
         timeout
     }
 
@@ -256,6 +300,15 @@ impl TimerWheel {
 
         self.curtime = curtime;
 
+        if let Some(next) = self.overflow_next {
+            if next <= curtime {
+                // reschedule all overflow timers. any still out of range
+                //   will be put back
+                l.concat(&mut self.nodes, &mut self.overflow);
+                self.overflow_next = None;
+            }
+        }
+
         while let Some(key) = l.head {
             l.remove(&mut self.nodes, key);
 
@@ -284,10 +337,22 @@ impl TimerWheel {
         let n = &self.nodes[key];
         let expires = n.value.expires;
 
-        if expires > self.curtime {
-            // get relative timeout, capped
-            let t = cmp::min(expires - self.curtime, TIMEOUT_MAX);
-            assert!(t > 0);
+        if expires > self.curtime && expires - self.curtime > TIMEOUT_MAX {
+            self.overflow.push_back(&mut self.nodes, key);
+
+            let n = &mut self.nodes[key];
+            n.value.list = Some(InList::Overflow);
+
+            let next = expires - TIMEOUT_MAX;
+
+            self.overflow_next = Some(match self.overflow_next {
+                Some(cur) => cmp::min(cur, next),
+                None => next,
+            });
+        } else if expires > self.curtime {
+            // get relative timeout
+            let t = expires - self.curtime;
+            assert!(t > 0 && t <= TIMEOUT_MAX);
 
             // wheel is selected by relative time
             // t =    0 = not valid
@@ -534,7 +599,9 @@ mod tests {
 
         w.update(8);
         assert_eq!(w.timeout(), Some(0));
+        assert_eq!(w.expired_count(), 1);
         assert_eq!(w.take_expired(), Some((t2, 2)));
+        assert_eq!(w.expired_count(), 0);
         assert_eq!(w.take_expired(), None);
 
         for i in 0..2 {
@@ -572,4 +639,58 @@ mod tests {
             assert_eq!(w.timeout(), None);
         }
     }
+
+    #[test]
+    fn test_overflow() {
+        let mut w = TimerWheel::new(10);
+
+        w.update(7);
+
+        let far = 7 + (TIMEOUT_MAX * 3);
+
+        // beyond the range of the wheels
+        let t1 = w.add(far, 1).unwrap();
+        let t2 = w.add(far + 100, 2).unwrap();
+        assert_eq!(w.overflow.head, Some(t1));
+        assert_eq!(w.timeout(), Some(TIMEOUT_MAX * 2));
+
+        w.update(7 + TIMEOUT_MAX);
+        assert_eq!(w.take_expired(), None);
+        assert_eq!(w.timeout(), Some(TIMEOUT_MAX));
+
+        // t1 now within range, t2 not yet
+        w.update(7 + (TIMEOUT_MAX * 2));
+        assert_eq!(w.take_expired(), None);
+        assert_eq!(w.overflow.head, Some(t2));
+        assert_eq!(w.timeout(), Some(100));
+
+        w.update(7 + (TIMEOUT_MAX * 2) + 100);
+        assert_eq!(w.take_expired(), None);
+        assert!(w.overflow.is_empty());
+        assert_eq!(w.overflow_next, None);
+        assert!(w.timeout().unwrap() <= TIMEOUT_MAX);
+
+        w.update(far - 1);
+        assert_eq!(w.take_expired(), None);
+
+        w.update(far);
+        assert_eq!(w.take_expired(), Some((t1, 1)));
+        assert_eq!(w.take_expired(), None);
+
+        w.update(far + 100);
+        assert_eq!(w.take_expired(), Some((t2, 2)));
+        assert_eq!(w.timeout(), None);
+
+        // removal
+        let t3 = w.add(far * 2, 3).unwrap();
+        assert!(w.timeout().is_some());
+        w.remove(t3);
+        assert_eq!(w.overflow_next, None);
+        assert_eq!(w.timeout(), None);
+
+        // large jumps in time expire overflow timers directly
+        w.add(far * 4, 4).unwrap();
+        w.update(far * 5);
+        assert_eq!(w.take_expired().map(|(_, v)| v), Some(4));
+    }
 }
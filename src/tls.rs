@@ -15,17 +15,35 @@
  */
 
 use arrayvec::ArrayString;
+use libc::{c_int, c_uchar, c_void};
 use log::debug;
 use openssl::error::ErrorStack;
+use openssl::ex_data::Index;
+use openssl::nid::Nid;
 use openssl::pkey::PKey;
+use openssl::rand::rand_bytes;
 use openssl::ssl::{
-    self, HandshakeError, MidHandshakeSslStream, NameType, SniError, SslAcceptor, SslConnector,
-    SslContext, SslContextBuilder, SslFiletype, SslMethod, SslStream, SslVerifyMode,
+    self, HandshakeError, MidHandshakeSslStream, NameType, SniError, Ssl, SslAcceptor,
+    SslConnector, SslContext, SslContextBuilder, SslFiletype, SslMethod, SslRef, SslStream,
+    SslVerifyMode,
 };
 use openssl::x509::X509;
+use openssl_sys as ffi;
+#[cfg(feature = "rustls")]
+use rustls::pki_types::pem::PemObject;
+#[cfg(feature = "rustls")]
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+#[cfg(feature = "rustls")]
+use rustls::server::{ClientHello, ResolvesServerCert};
+#[cfg(feature = "rustls")]
+use rustls::sign::CertifiedKey;
+#[cfg(feature = "rustls")]
+use rustls::{ServerConfig, ServerConnection};
 use std::any::Any;
 use std::cmp;
-use std::collections::HashMap;
+use std::collections::{HashMap, VecDeque};
+use std::convert::TryFrom;
+use std::ffi::OsStr;
 use std::fmt;
 use std::fs;
 use std::io;
@@ -35,12 +53,30 @@ use std::mem;
 use std::path;
 use std::path::{Path, PathBuf};
 use std::ptr;
+use std::slice;
 use std::str::FromStr;
-use std::sync::{Arc, Mutex, MutexGuard};
-use std::time::SystemTime;
+use std::sync::{Arc, Mutex, MutexGuard, OnceLock};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
 const DOMAIN_LEN_MAX: usize = 253;
 
+// most early data a client may send before completing the handshake
+const EARLY_DATA_MAX: u32 = 16_384;
+
+const EARLY_DATA_READ_SIZE: usize = 4_096;
+
+// from openssl's err.h and sslerr.h
+const ERR_LIB_SSL: i32 = 20;
+const SSL_R_HTTPS_PROXY_REQUEST: i32 = 155;
+const SSL_R_HTTP_REQUEST: i32 = 156;
+
+// from openssl's ssl.h
+const SSL_CTRL_SET_TLSEXT_TICKET_KEY_CB: c_int = 72;
+
+const TICKET_KEY_NAME_LEN: usize = 16;
+const TICKET_KEY_LEN: usize = 32;
+const TICKET_IV_LEN: usize = 16;
+
 enum IdentityError {
     InvalidName,
     CertMetadata(PathBuf, io::Error),
@@ -75,6 +111,11 @@ impl fmt::Display for IdentityError {
 
 struct Identity {
     ssl_context: SslContext,
+
+    // for the rustls backend. None if rustls can't use the cert or key
+    #[cfg(feature = "rustls")]
+    certified_key: Option<Arc<CertifiedKey>>,
+
     cert_fname: PathBuf,
     key_fname: PathBuf,
     modified: Option<SystemTime>,
@@ -135,8 +176,20 @@ impl Identity {
             return Err(IdentityError::CertCheck(e));
         }
 
+        #[cfg(feature = "rustls")]
+        let certified_key = match load_certified_key(&cert_fname, &key_fname) {
+            Ok(key) => Some(Arc::new(key)),
+            Err(e) => {
+                debug!("cert {} not usable with rustls: {}", name, e);
+
+                None
+            }
+        };
+
         Ok(Self {
             ssl_context: ctx.build(),
+            #[cfg(feature = "rustls")]
+            certified_key,
             cert_fname,
             key_fname,
             modified,
@@ -144,6 +197,21 @@ impl Identity {
     }
 }
 
+#[cfg(feature = "rustls")]
+fn load_certified_key(cert_fname: &Path, key_fname: &Path) -> Result<CertifiedKey, String> {
+    let certs = CertificateDer::pem_file_iter(cert_fname)
+        .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+        .map_err(|e| format!("failed to read cert content {:?}: {}", cert_fname, e))?;
+
+    let key = PrivateKeyDer::from_pem_file(key_fname)
+        .map_err(|e| format!("failed to read key content {:?}: {}", key_fname, e))?;
+
+    let key = rustls::crypto::ring::sign::any_supported_type(&key)
+        .map_err(|e| format!("unsupported key {:?}: {}", key_fname, e))?;
+
+    Ok(CertifiedKey::new(certs, key))
+}
+
 fn modified_after(fnames: &[&Path], t: SystemTime) -> Result<bool, io::Error> {
     for fname in fnames {
         match fs::metadata(fname)?.modified() {
@@ -155,6 +223,136 @@ fn modified_after(fnames: &[&Path], t: SystemTime) -> Result<bool, io::Error> {
     Ok(false)
 }
 
+// whether a cert name, possibly a wildcard, covers a domain. wildcards only
+// match a single, complete label
+fn name_matches(pattern: &str, domain: &str) -> bool {
+    match pattern.strip_prefix("*.") {
+        Some(suffix) => match domain.split_once('.') {
+            Some((label, rest)) => !label.is_empty() && rest == suffix,
+            None => false,
+        },
+        None => pattern == domain,
+    }
+}
+
+// read the names a cert is valid for. per rfc 6125, the common name is only
+// considered if there are no dns names
+fn read_cert_names(fname: &Path) -> Vec<String> {
+    let data = match fs::read(fname) {
+        Ok(data) => data,
+        Err(e) => {
+            debug!("failed to read cert {:?}: {}", fname, e);
+            return Vec::new();
+        }
+    };
+
+    // if the file contains a chain, the first cert is the server's
+    let cert = match X509::from_pem(&data) {
+        Ok(cert) => cert,
+        Err(e) => {
+            debug!("failed to parse cert {:?}: {}", fname, e);
+            return Vec::new();
+        }
+    };
+
+    let mut names = Vec::new();
+
+    if let Some(alt_names) = cert.subject_alt_names() {
+        for name in alt_names.iter() {
+            if let Some(name) = name.dnsname() {
+                names.push(name.to_lowercase());
+            }
+        }
+    }
+
+    if names.is_empty() {
+        for entry in cert.subject_name().entries_by_nid(Nid::COMMONNAME) {
+            if let Ok(name) = entry.data().as_utf8() {
+                names.push(name.to_lowercase());
+            }
+        }
+    }
+
+    names
+}
+
+struct IndexedCert {
+    modified: Option<SystemTime>,
+    names: Vec<String>,
+}
+
+// the names of the certs in the certs dir, for finding certs that aren't
+// named after the domain, such as those with multiple or wildcard names.
+// the dir is rescanned whenever its modification time changes, and certs are
+// only read again if their own modification time changed
+#[derive(Default)]
+struct CertIndex {
+    scanned: bool,
+    dir_modified: Option<SystemTime>,
+    certs: HashMap<String, IndexedCert>,
+}
+
+impl CertIndex {
+    fn update(&mut self, dir: &Path) {
+        let dir_modified = fs::metadata(dir).and_then(|md| md.modified()).ok();
+
+        if self.scanned && dir_modified.is_some() && dir_modified == self.dir_modified {
+            return;
+        }
+
+        let entries = match fs::read_dir(dir) {
+            Ok(entries) => entries,
+            Err(e) => {
+                debug!("failed to read certs dir {:?}: {}", dir, e);
+                return;
+            }
+        };
+
+        let mut certs = HashMap::new();
+
+        for entry in entries.flatten() {
+            let path = entry.path();
+
+            if path.extension() != Some(OsStr::new("crt")) {
+                continue;
+            }
+
+            let name = match path.file_stem().and_then(|s| s.to_str()) {
+                Some(name) => name,
+                None => continue,
+            };
+
+            let modified = entry.metadata().and_then(|md| md.modified()).ok();
+
+            let names = match self.certs.remove(name) {
+                Some(c) if c.modified.is_some() && c.modified == modified => c.names,
+                _ => read_cert_names(&path),
+            };
+
+            certs.insert(String::from(name), IndexedCert { modified, names });
+        }
+
+        self.scanned = true;
+        self.dir_modified = dir_modified;
+        self.certs = certs;
+    }
+
+    // find the name of a cert covering the domain, either exactly or by
+    // wildcard. if several do, the first by name is chosen
+    fn find(&self, domain: &str, wildcard: bool) -> Option<String> {
+        self.certs
+            .iter()
+            .filter(|(_, cert)| {
+                cert.names
+                    .iter()
+                    .any(|n| n.starts_with("*.") == wildcard && name_matches(n, domain))
+            })
+            .map(|(name, _)| name)
+            .min()
+            .cloned()
+    }
+}
+
 struct IdentityRef<'a> {
     _data: MutexGuard<'a, HashMap<String, Identity>>,
     name: &'a str,
@@ -164,6 +362,7 @@ struct IdentityRef<'a> {
 pub struct IdentityCache {
     dir: PathBuf,
     data: Mutex<HashMap<String, Identity>>,
+    index: Mutex<CertIndex>,
 }
 
 impl IdentityCache {
@@ -171,37 +370,73 @@ impl IdentityCache {
         Self {
             dir: certs_dir.to_path_buf(),
             data: Mutex::new(HashMap::new()),
+            index: Mutex::new(CertIndex::default()),
         }
     }
 
+    // forget the loaded certs so that each is read again when next used,
+    // and rescan the certs dir. returns the number of certs found
+    pub fn reload(&self) -> usize {
+        self.data.lock().unwrap().clear();
+
+        let mut index = self.index.lock().unwrap();
+
+        *index = CertIndex::default();
+        index.update(&self.dir);
+
+        index.certs.len()
+    }
+
     fn get_by_domain<'a>(&'a self, domain: &str) -> Option<IdentityRef<'a>> {
         let name = domain.to_lowercase();
 
-        // try to find a file named after the exact host, then try with a
-        //   wildcard pattern at the same subdomain level. the filename
+        // try to find a file named after the exact host, then a cert
+        //   listing the exact host among its names. then try the same with
+        //   a wildcard pattern at the same subdomain level. the filename
         //   format uses underscores instead of asterisks. so, a domain of
         //   www.example.com will attempt to be matched against a file named
-        //   www.example.com.crt and _.example.com.crt. wildcards at other
-        //   levels are not supported
+        //   www.example.com.crt, a cert for www.example.com, a file named
+        //   _.example.com.crt, and a cert for *.example.com, in that order.
+        //   wildcards at other levels are not supported
 
         if let Some(identity) = self.get_by_name(&name) {
             return Some(identity);
         }
 
+        if let Some(identity) = self.get_by_cert_name(&name, false) {
+            return Some(identity);
+        }
+
         let pos = match name.find('.') {
             Some(pos) => pos,
             None => return None,
         };
 
-        let name = format!("_{}", &name[pos..]);
+        let wildcard_name = format!("_{}", &name[pos..]);
 
-        if let Some(identity) = self.get_by_name(&name) {
+        if let Some(identity) = self.get_by_name(&wildcard_name) {
+            return Some(identity);
+        }
+
+        if let Some(identity) = self.get_by_cert_name(&name, true) {
             return Some(identity);
         }
 
         None
     }
 
+    fn get_by_cert_name<'a>(&'a self, domain: &str, wildcard: bool) -> Option<IdentityRef<'a>> {
+        let name = {
+            let mut index = self.index.lock().unwrap();
+
+            index.update(&self.dir);
+
+            index.find(domain, wildcard)?
+        };
+
+        self.get_by_name(&name)
+    }
+
     fn get_by_name<'a>(&'a self, name: &str) -> Option<IdentityRef<'a>> {
         self.ensure_updated(name);
 
@@ -252,6 +487,260 @@ impl IdentityCache {
     }
 }
 
+// the identity to use for a handshake, based on the server name indicated by
+// the client, if any
+fn find_identity<'a>(
+    cache: &'a IdentityCache,
+    servername: Option<&str>,
+    default_cert: Option<&str>,
+    require_sni: bool,
+) -> Option<IdentityRef<'a>> {
+    match servername {
+        Some(name) => {
+            debug!("tls server name: {}", name);
+
+            match cache.get_by_domain(name) {
+                Some(identity) => Some(identity),
+                None => cache.get_by_name(default_cert?),
+            }
+        }
+        None if require_sni => {
+            debug!("tls server name missing");
+
+            None
+        }
+        None => cache.get_by_name(default_cert?),
+    }
+}
+
+// selects certs for the rustls backend
+#[cfg(feature = "rustls")]
+struct CertResolver {
+    cache: Arc<IdentityCache>,
+    default_cert: Option<String>,
+    require_sni: bool,
+}
+
+#[cfg(feature = "rustls")]
+impl fmt::Debug for CertResolver {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("CertResolver")
+            .field("default_cert", &self.default_cert)
+            .field("require_sni", &self.require_sni)
+            .finish()
+    }
+}
+
+#[cfg(feature = "rustls")]
+impl ResolvesServerCert for CertResolver {
+    fn resolve(&self, client_hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        let identity = find_identity(
+            &self.cache,
+            client_hello.server_name(),
+            self.default_cert.as_deref(),
+            self.require_sni,
+        )?;
+
+        debug!("using cert: {}", identity.name);
+
+        identity.value.certified_key.clone()
+    }
+}
+
+#[derive(Clone)]
+struct TicketKey {
+    name: [u8; TICKET_KEY_NAME_LEN],
+    hmac_key: [u8; TICKET_KEY_LEN],
+    aes_key: [u8; TICKET_KEY_LEN],
+    created: Instant,
+}
+
+impl TicketKey {
+    fn generate(created: Instant) -> Result<Self, ErrorStack> {
+        let mut key = Self {
+            name: [0; TICKET_KEY_NAME_LEN],
+            hmac_key: [0; TICKET_KEY_LEN],
+            aes_key: [0; TICKET_KEY_LEN],
+            created,
+        };
+
+        rand_bytes(&mut key.name)?;
+        rand_bytes(&mut key.hmac_key)?;
+        rand_bytes(&mut key.aes_key)?;
+
+        Ok(key)
+    }
+}
+
+// session ticket keys, shared by the acceptors of all workers so that a
+// session established with one worker can be resumed with any other. a new
+// key is used to issue tickets every rotation interval, and replaced keys
+// can still be used to resume sessions for the overlap window
+pub struct TicketKeys {
+    rotation: Duration,
+    overlap: Duration,
+
+    // newest first
+    keys: Mutex<VecDeque<TicketKey>>,
+}
+
+impl TicketKeys {
+    pub fn new(rotation: Duration, overlap: Duration) -> Self {
+        Self {
+            rotation,
+            overlap,
+            keys: Mutex::new(VecDeque::new()),
+        }
+    }
+
+    // the key to issue tickets with
+    fn current(&self, now: Instant) -> Result<TicketKey, ErrorStack> {
+        let keys = &mut *self.keys.lock().unwrap();
+
+        self.update(keys, now)?;
+
+        Ok(keys[0].clone())
+    }
+
+    // the key a ticket was issued with, if it is still usable, and whether
+    // it is the current key
+    fn find(&self, name: &[u8], now: Instant) -> Result<Option<(TicketKey, bool)>, ErrorStack> {
+        let keys = &mut *self.keys.lock().unwrap();
+
+        self.update(keys, now)?;
+
+        Ok(keys
+            .iter()
+            .position(|k| k.name[..] == *name)
+            .map(|i| (keys[i].clone(), i == 0)))
+    }
+
+    fn update(&self, keys: &mut VecDeque<TicketKey>, now: Instant) -> Result<(), ErrorStack> {
+        let expired = match keys.front() {
+            Some(key) => now.saturating_duration_since(key.created) >= self.rotation,
+            None => true,
+        };
+
+        if expired {
+            keys.push_front(TicketKey::generate(now)?);
+        }
+
+        // each key was replaced when the key before it was created
+        if let Some(pos) = (1..keys.len())
+            .find(|&i| now.saturating_duration_since(keys[i - 1].created) > self.overlap)
+        {
+            keys.truncate(pos);
+        }
+
+        Ok(())
+    }
+}
+
+fn ticket_keys_index() -> Index<Ssl, Arc<TicketKeys>> {
+    static INDEX: OnceLock<Index<Ssl, Arc<TicketKeys>>> = OnceLock::new();
+
+    *INDEX.get_or_init(|| Ssl::new_ex_index().unwrap())
+}
+
+type TicketKeyCallback = extern "C" fn(
+    *mut ffi::SSL,
+    *mut c_uchar,
+    *mut c_uchar,
+    *mut ffi::EVP_CIPHER_CTX,
+    *mut ffi::HMAC_CTX,
+    c_int,
+) -> c_int;
+
+// called by openssl to set up the encryption of a new ticket (enc = 1), or
+// the decryption of a ticket presented by a client (enc = 0). returns 1 on
+// success, 2 if the ticket should be renewed, 0 if the ticket can't be
+// decrypted, and -1 on error
+extern "C" fn ticket_key_callback(
+    ssl: *mut ffi::SSL,
+    key_name: *mut c_uchar,
+    iv: *mut c_uchar,
+    cipher_ctx: *mut ffi::EVP_CIPHER_CTX,
+    hmac_ctx: *mut ffi::HMAC_CTX,
+    enc: c_int,
+) -> c_int {
+    // SAFETY: the acceptor sets the keys on each ssl object before the
+    // handshake, and openssl passes buffers of the sizes it documents for
+    // this callback
+    unsafe {
+        let keys =
+            ffi::SSL_get_ex_data(ssl, ticket_keys_index().as_raw()) as *const Arc<TicketKeys>;
+
+        let keys = match keys.as_ref() {
+            Some(keys) => keys,
+            None => return -1,
+        };
+
+        let key_name = slice::from_raw_parts_mut(key_name, TICKET_KEY_NAME_LEN);
+        let iv = slice::from_raw_parts_mut(iv, TICKET_IV_LEN);
+
+        let now = Instant::now();
+
+        let (key, ret) = if enc == 1 {
+            let key = match keys.current(now) {
+                Ok(key) => key,
+                Err(_) => return -1,
+            };
+
+            if rand_bytes(iv).is_err() {
+                return -1;
+            }
+
+            key_name.copy_from_slice(&key.name);
+
+            if ffi::EVP_EncryptInit_ex(
+                cipher_ctx,
+                ffi::EVP_aes_256_cbc(),
+                ptr::null_mut(),
+                key.aes_key.as_ptr(),
+                iv.as_ptr(),
+            ) != 1
+            {
+                return -1;
+            }
+
+            (key, 1)
+        } else {
+            let (key, current) = match keys.find(key_name, now) {
+                Ok(Some(ret)) => ret,
+                Ok(None) => return 0,
+                Err(_) => return -1,
+            };
+
+            if ffi::EVP_DecryptInit_ex(
+                cipher_ctx,
+                ffi::EVP_aes_256_cbc(),
+                ptr::null_mut(),
+                key.aes_key.as_ptr(),
+                iv.as_ptr(),
+            ) != 1
+            {
+                return -1;
+            }
+
+            // tickets issued with replaced keys are renewed
+            (key, if current { 1 } else { 2 })
+        };
+
+        if ffi::HMAC_Init_ex(
+            hmac_ctx,
+            key.hmac_key.as_ptr() as *const c_void,
+            TICKET_KEY_LEN as c_int,
+            ffi::EVP_sha256(),
+            ptr::null_mut(),
+        ) != 1
+        {
+            return -1;
+        }
+
+        ret
+    }
+}
+
 trait ReadWrite: Read + Write + Any + Send {
     fn as_any(&mut self) -> &mut dyn Any;
     fn into_any(self: Box<Self>) -> Box<dyn Any>;
@@ -270,43 +759,199 @@ impl<T: Read + Write + Any + Send> ReadWrite for T {
 enum Stream<T> {
     Ssl(SslStream<T>),
     MidHandshakeSsl(MidHandshakeSslStream<T>),
+
+    // accepted with early data enabled, and the handshake is not complete
+    EarlyDataSsl(SslStream<T>),
+
+    // accepted with the rustls backend. the connection does no i/o of its
+    // own, and is driven using the plain stream
+    #[cfg(feature = "rustls")]
+    Rustls(Box<RustlsState>),
+
     NoSsl,
 }
 
+#[cfg(feature = "rustls")]
+struct RustlsState {
+    conn: ServerConnection,
+
+    // set once the handshake is complete and its messages have been sent
+    handshake_done: bool,
+
+    // size of plaintext accepted by the last write, whose records have not
+    // yet all been sent. like with openssl, the write is to be retried
+    // until they have been, and then reports the size
+    write_pending: Option<usize>,
+
+    close_notify_sent: bool,
+}
+
+#[cfg(feature = "rustls")]
+impl RustlsState {
+    fn new(conn: ServerConnection) -> Self {
+        Self {
+            conn,
+            handshake_done: false,
+            write_pending: None,
+            close_notify_sent: false,
+        }
+    }
+}
+
+// send the tls data the connection has pending
+#[cfg(feature = "rustls")]
+fn rustls_flush(
+    conn: &mut ServerConnection,
+    mut io: &mut dyn ReadWrite,
+    interests: &mut Option<mio::Interest>,
+) -> Result<(), io::Error> {
+    while conn.wants_write() {
+        if let Err(e) = conn.write_tls(&mut io) {
+            if e.kind() == io::ErrorKind::WouldBlock {
+                *interests = Some(mio::Interest::WRITABLE);
+            }
+
+            return Err(e);
+        }
+    }
+
+    Ok(())
+}
+
+// receive tls data and process it. returns WouldBlock if there is none
+#[cfg(feature = "rustls")]
+fn rustls_receive(
+    conn: &mut ServerConnection,
+    mut io: &mut dyn ReadWrite,
+    interests: &mut Option<mio::Interest>,
+) -> Result<(), TlsStreamError> {
+    if let Err(e) = conn.read_tls(&mut io) {
+        if e.kind() == io::ErrorKind::WouldBlock {
+            *interests = Some(mio::Interest::READABLE);
+        }
+
+        return Err(TlsStreamError::Io(e));
+    }
+
+    if let Err(e) = conn.process_new_packets() {
+        // try to let the peer know
+        let _ = conn.write_tls(&mut io);
+
+        return Err(TlsStreamError::Rustls(e));
+    }
+
+    Ok(())
+}
+
+// whether data was received as tls 1.3 early data, before the handshake
+// completed. early data is stale if the session it resumes was established
+// longer ago than the acceptor's early data window
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum EarlyData {
+    #[default]
+    None,
+    Fresh,
+    Stale,
+}
+
+fn early_data_kind(ssl: &SslRef, window: Duration) -> EarlyData {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+
+    // the time at which the resumed session was established
+    let established = match ssl.session().map(|s| u64::try_from(s.time())) {
+        Some(Ok(t)) => t,
+        _ => return EarlyData::Stale,
+    };
+
+    if now.saturating_sub(established) <= window.as_secs() {
+        EarlyData::Fresh
+    } else {
+        EarlyData::Stale
+    }
+}
+
+// early data of an accepted stream, kept until the handshake completes and
+// the data has been read
+struct EarlyDataState {
+    // received but not yet read
+    buf: Vec<u8>,
+
+    finished: bool,
+    kind: EarlyData,
+    window: Duration,
+}
+
+impl EarlyDataState {
+    fn new(window: Duration) -> Self {
+        Self {
+            buf: Vec::new(),
+            finished: false,
+            kind: EarlyData::None,
+            window,
+        }
+    }
+}
+
+enum AcceptorBackend {
+    OpenSsl(SslAcceptor),
+
+    #[cfg(feature = "rustls")]
+    Rustls(Arc<ServerConfig>),
+}
+
 pub struct TlsAcceptor {
-    acceptor: SslAcceptor,
+    backend: AcceptorBackend,
+
+    // if set, early data is accepted, with the given window
+    early_data: Option<Duration>,
+
+    ticket_keys: Option<Arc<TicketKeys>>,
 }
 
 impl TlsAcceptor {
-    pub fn new(cache: &Arc<IdentityCache>, default_cert: Option<&str>) -> Self {
+    // if require_sni is set, handshakes from clients that don't indicate a
+    // server name fail even if there is a default cert. if early_data is
+    // set, clients resuming sessions may send requests before the handshake
+    // completes. openssl permits each session to be resumed with early data
+    // only once, and early data resuming sessions established longer ago
+    // than the early_data window is reported as stale
+    pub fn new(
+        cache: &Arc<IdentityCache>,
+        default_cert: Option<&str>,
+        require_sni: bool,
+        early_data: Option<Duration>,
+        ticket_keys: &Arc<TicketKeys>,
+    ) -> Self {
         let mut acceptor = SslAcceptor::mozilla_intermediate(SslMethod::tls()).unwrap();
 
+        // SAFETY: the callback has the signature openssl expects for this
+        // command, and the keys it uses are set on each ssl object by accept
+        unsafe {
+            let cb =
+                mem::transmute::<TicketKeyCallback, unsafe extern "C" fn()>(ticket_key_callback);
+
+            ffi::SSL_CTX_callback_ctrl__fixed_rust(
+                acceptor.as_ptr(),
+                SSL_CTRL_SET_TLSEXT_TICKET_KEY_CB,
+                Some(cb),
+            );
+        }
+
         let cache = Arc::clone(cache);
         let default_cert: Option<String> = default_cert.map(|s| s.to_owned());
 
         acceptor.set_servername_callback(move |ssl, _| {
-            let identity = match ssl.servername(NameType::HOST_NAME) {
-                Some(name) => {
-                    debug!("tls server name: {}", name);
-
-                    match cache.get_by_domain(name) {
-                        Some(ctx) => ctx,
-                        None => match &default_cert {
-                            Some(default_cert) => match cache.get_by_name(default_cert) {
-                                Some(ctx) => ctx,
-                                None => return Err(SniError::ALERT_FATAL),
-                            },
-                            None => return Err(SniError::ALERT_FATAL),
-                        },
-                    }
-                }
-                None => match &default_cert {
-                    Some(default_cert) => match cache.get_by_name(default_cert) {
-                        Some(ctx) => ctx,
-                        None => return Err(SniError::ALERT_FATAL),
-                    },
-                    None => return Err(SniError::ALERT_FATAL),
-                },
+            let identity = match find_identity(
+                &cache,
+                ssl.servername(NameType::HOST_NAME),
+                default_cert.as_deref(),
+                require_sni,
+            ) {
+                Some(identity) => identity,
+                None => return Err(SniError::ALERT_FATAL),
             };
 
             debug!("using cert: {}", identity.name);
@@ -319,7 +964,9 @@ impl TlsAcceptor {
         });
 
         Self {
-            acceptor: acceptor.build(),
+            backend: AcceptorBackend::OpenSsl(acceptor.build()),
+            early_data,
+            ticket_keys: Some(Arc::clone(ticket_keys)),
         }
     }
 
@@ -384,16 +1031,79 @@ impl TlsAcceptor {
         acceptor.set_private_key(&key).unwrap();
 
         Self {
-            acceptor: acceptor.build(),
+            backend: AcceptorBackend::OpenSsl(acceptor.build()),
+            early_data: None,
+            ticket_keys: None,
+        }
+    }
+
+    // uses the rustls backend. early data and shared ticket keys are not
+    // supported
+    #[cfg(feature = "rustls")]
+    pub fn new_rustls(
+        cache: &Arc<IdentityCache>,
+        default_cert: Option<&str>,
+        require_sni: bool,
+    ) -> Self {
+        let resolver = CertResolver {
+            cache: Arc::clone(cache),
+            default_cert: default_cert.map(|s| s.to_owned()),
+            require_sni,
+        };
+
+        let provider = Arc::new(rustls::crypto::ring::default_provider());
+
+        let config = ServerConfig::builder_with_provider(provider)
+            .with_safe_default_protocol_versions()
+            .unwrap()
+            .with_no_client_auth()
+            .with_cert_resolver(Arc::new(resolver));
+
+        Self {
+            backend: AcceptorBackend::Rustls(Arc::new(config)),
+            early_data: None,
+            ticket_keys: None,
         }
     }
 
     pub fn accept(
         &self,
         stream: mio::net::TcpStream,
-    ) -> Result<TlsStream<mio::net::TcpStream>, ssl::Error> {
-        let result = TlsStream::new(false, stream, |stream| {
-            let stream = match self.acceptor.accept(stream) {
+    ) -> Result<TlsStream<mio::net::TcpStream>, (mio::net::TcpStream, TlsStreamError)> {
+        match &self.backend {
+            AcceptorBackend::OpenSsl(acceptor) => self.accept_openssl(acceptor, stream),
+            #[cfg(feature = "rustls")]
+            AcceptorBackend::Rustls(config) => TlsStream::new(false, stream, |_| {
+                let conn = ServerConnection::new(Arc::clone(config))?;
+
+                Ok(Stream::Rustls(Box::new(RustlsState::new(conn))))
+            })
+            .map_err(|(stream, e)| (stream, TlsStreamError::Rustls(e))),
+        }
+    }
+
+    fn accept_openssl(
+        &self,
+        acceptor: &SslAcceptor,
+        stream: mio::net::TcpStream,
+    ) -> Result<TlsStream<mio::net::TcpStream>, (mio::net::TcpStream, TlsStreamError)> {
+        let ret = TlsStream::new(false, stream, |stream| {
+            let mut ssl = Ssl::new(acceptor.context())?;
+
+            if let Some(keys) = &self.ticket_keys {
+                ssl.set_ex_data(ticket_keys_index(), Arc::clone(keys));
+            }
+
+            if self.early_data.is_some() {
+                ssl.set_accept_state();
+                ssl.set_max_early_data(EARLY_DATA_MAX)?;
+
+                // the handshake is driven by the stream, while reading
+                // early data
+                return Ok(Stream::EarlyDataSsl(SslStream::new(ssl, stream)?));
+            }
+
+            let stream = match ssl.accept(stream) {
                 Ok(stream) => Stream::Ssl(stream),
                 Err(HandshakeError::SetupFailure(e)) => return Err(e.into()),
                 Err(HandshakeError::Failure(stream)) => return Err(stream.into_error()),
@@ -403,13 +1113,26 @@ impl TlsAcceptor {
             Ok(stream)
         });
 
-        match result {
-            Ok(stream) => Ok(stream),
-            Err((_, e)) => Err(e),
+        match ret {
+            Ok(mut stream) => {
+                stream.early_data = self.early_data.map(|w| Box::new(EarlyDataState::new(w)));
+
+                Ok(stream)
+            }
+            Err((stream, e)) => Err((stream, e.into())),
         }
     }
 }
 
+// whether a handshake failed because the peer sent plain http
+pub fn is_plain_http(e: &ErrorStack) -> bool {
+    e.errors().iter().any(|e| {
+        e.library_code() == ERR_LIB_SSL
+            && (e.reason_code() == SSL_R_HTTP_REQUEST
+                || e.reason_code() == SSL_R_HTTPS_PROXY_REQUEST)
+    })
+}
+
 pub enum VerifyMode {
     Full,
     None,
@@ -419,10 +1142,25 @@ pub enum VerifyMode {
 pub enum TlsStreamError {
     Io(io::Error),
     Ssl(ErrorStack),
+    #[cfg(feature = "rustls")]
+    Rustls(rustls::Error),
     Unusable,
 }
 
 impl TlsStreamError {
+    pub fn is_plain_http(&self) -> bool {
+        match self {
+            TlsStreamError::Ssl(e) => is_plain_http(e),
+            // the first bytes sent weren't a tls record
+            #[cfg(feature = "rustls")]
+            TlsStreamError::Rustls(e) => matches!(
+                e,
+                rustls::Error::InvalidMessage(rustls::InvalidMessage::InvalidContentType)
+            ),
+            _ => false,
+        }
+    }
+
     fn into_io_error(self) -> io::Error {
         match self {
             TlsStreamError::Io(e) => e,
@@ -431,6 +1169,18 @@ impl TlsStreamError {
     }
 }
 
+impl fmt::Display for TlsStreamError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Io(e) => write!(f, "{}", e),
+            Self::Ssl(e) => write!(f, "{}", e),
+            #[cfg(feature = "rustls")]
+            Self::Rustls(e) => write!(f, "{}", e),
+            Self::Unusable => write!(f, "stream unusable"),
+        }
+    }
+}
+
 impl From<ssl::Error> for TlsStreamError {
     fn from(e: ssl::Error) -> Self {
         match e.into_io_error() {
@@ -468,6 +1218,11 @@ pub struct TlsStream<T> {
     plain_stream: Box<Box<dyn ReadWrite>>,
     id: ArrayString<64>,
     client: bool,
+
+    // boxed, as most streams never have any
+    early_data: Option<Box<EarlyDataState>>,
+    early_data_read: EarlyData,
+
     interests_for_handshake: Option<mio::Interest>,
     interests_for_shutdown: Option<mio::Interest>,
     interests_for_read: Option<mio::Interest>,
@@ -508,6 +1263,9 @@ where
         let plain_stream: &'a mut Box<dyn ReadWrite> = match &mut self.stream {
             Stream::Ssl(stream) => stream.get_mut(),
             Stream::MidHandshakeSsl(stream) => stream.get_mut(),
+            Stream::EarlyDataSsl(stream) => stream.get_mut(),
+            #[cfg(feature = "rustls")]
+            Stream::Rustls(_) => Box::as_mut(&mut self.plain_stream),
             Stream::NoSsl => Box::as_mut(&mut self.plain_stream),
         };
 
@@ -526,6 +1284,31 @@ where
         Ok(())
     }
 
+    // server name indicated by the client, once its hello has been read
+    pub fn servername(&self) -> Option<&str> {
+        match &self.stream {
+            Stream::Ssl(stream) => stream.ssl().servername(NameType::HOST_NAME),
+            Stream::MidHandshakeSsl(stream) => stream.ssl().servername(NameType::HOST_NAME),
+            Stream::EarlyDataSsl(stream) => stream.ssl().servername(NameType::HOST_NAME),
+            #[cfg(feature = "rustls")]
+            Stream::Rustls(state) => state.conn.server_name(),
+            Stream::NoSsl => None,
+        }
+    }
+
+    // whether data returned by read since the last call was received as
+    // early data. data buffered before the handshake completed continues to
+    // be reported as early data until it has all been read
+    pub fn take_early_data(&mut self) -> EarlyData {
+        let ret = self.early_data_read;
+
+        if self.early_data.is_none() {
+            self.early_data_read = EarlyData::None;
+        }
+
+        ret
+    }
+
     pub fn interests_for_handshake(&self) -> Option<mio::Interest> {
         self.interests_for_handshake
     }
@@ -569,6 +1352,18 @@ where
                 },
                 _ => unreachable!(),
             },
+            Stream::EarlyDataSsl(_) => match self.finish_early_data_handshake() {
+                // buffered early data may be read before the handshake
+                // completes
+                Err(TlsStreamError::Io(e))
+                    if e.kind() == io::ErrorKind::WouldBlock && self.has_early_data() =>
+                {
+                    Ok(())
+                }
+                ret => ret,
+            },
+            #[cfg(feature = "rustls")]
+            Stream::Rustls(_) => self.rustls_handshake(),
             Stream::NoSsl => Err(TlsStreamError::Unusable),
         }
     }
@@ -578,6 +1373,21 @@ where
 
         let stream = match &mut self.stream {
             Stream::Ssl(stream) => stream,
+            #[cfg(feature = "rustls")]
+            Stream::Rustls(state) if state.handshake_done => {
+                if !state.close_notify_sent {
+                    state.conn.send_close_notify();
+                    state.close_notify_sent = true;
+                }
+
+                let io: &mut dyn ReadWrite = Box::as_mut(Box::as_mut(&mut self.plain_stream));
+
+                rustls_flush(&mut state.conn, io, &mut self.interests_for_shutdown)?;
+
+                debug!("{} {}: tls shutdown sent", self.log_prefix(), self.id);
+
+                return Ok(());
+            }
             _ => return Err(io::Error::from(io::ErrorKind::Other)),
         };
 
@@ -613,11 +1423,11 @@ where
         unsafe { mem::transmute(self) }
     }
 
-    fn new<F>(client: bool, stream: T, init_fn: F) -> Result<Self, (T, ssl::Error)>
+    fn new<F, E>(client: bool, stream: T, init_fn: F) -> Result<Self, (T, E)>
     where
         F: FnOnce(
             &'static mut Box<dyn ReadWrite>,
-        ) -> Result<Stream<&'static mut Box<dyn ReadWrite>>, ssl::Error>,
+        ) -> Result<Stream<&'static mut Box<dyn ReadWrite>>, E>,
     {
         // box the stream, casting to ReadWrite
         let inner_box: Box<dyn ReadWrite> = Box::new(stream);
@@ -654,6 +1464,8 @@ where
             plain_stream: outer_box,
             id: ArrayString::from("<unknown>").unwrap(),
             client,
+            early_data: None,
+            early_data_read: EarlyData::None,
             interests_for_handshake: None,
             interests_for_shutdown: None,
             interests_for_read: None,
@@ -662,6 +1474,13 @@ where
         })
     }
 
+    fn has_early_data(&self) -> bool {
+        match &self.early_data {
+            Some(state) => !state.buf.is_empty(),
+            None => false,
+        }
+    }
+
     fn log_prefix(&self) -> &'static str {
         if self.client {
             "client-conn"
@@ -670,6 +1489,61 @@ where
         }
     }
 
+    // read early data into a buffer until the client indicates there is no
+    // more, then complete the handshake
+    fn finish_early_data_handshake(&mut self) -> Result<(), TlsStreamError> {
+        let stream = match &mut self.stream {
+            Stream::EarlyDataSsl(stream) => stream,
+            _ => unreachable!(),
+        };
+
+        let state = self.early_data.as_mut().unwrap();
+
+        while !state.finished {
+            let len = state.buf.len();
+            state.buf.resize(len + EARLY_DATA_READ_SIZE, 0);
+
+            match stream.read_early_data(&mut state.buf[len..]) {
+                Ok(0) => {
+                    state.buf.truncate(len);
+                    state.finished = true;
+                }
+                Ok(size) => {
+                    state.buf.truncate(len + size);
+
+                    if state.kind == EarlyData::None {
+                        state.kind = early_data_kind(stream.ssl(), state.window);
+                    }
+                }
+                Err(e) => {
+                    state.buf.truncate(len);
+                    apply_wants(&e, &mut self.interests_for_handshake);
+
+                    return Err(e.into());
+                }
+            }
+        }
+
+        if let Err(e) = stream.do_handshake() {
+            apply_wants(&e, &mut self.interests_for_handshake);
+
+            return Err(e.into());
+        }
+
+        match mem::replace(&mut self.stream, Stream::NoSsl) {
+            Stream::EarlyDataSsl(stream) => self.stream = Stream::Ssl(stream),
+            _ => unreachable!(),
+        }
+
+        if !self.has_early_data() {
+            self.early_data = None;
+        }
+
+        debug!("{} {}: tls handshake success", self.log_prefix(), self.id);
+
+        Ok(())
+    }
+
     fn ssl_read(&mut self, buf: &mut [u8]) -> Result<usize, TlsStreamError> {
         self.interests_for_read = None;
 
@@ -684,8 +1558,26 @@ where
             return Err(e);
         }
 
+        if let Some(state) = &mut self.early_data {
+            if !state.buf.is_empty() {
+                let size = cmp::min(buf.len(), state.buf.len());
+
+                buf[..size].copy_from_slice(&state.buf[..size]);
+                state.buf.drain(..size);
+                self.early_data_read = state.kind;
+
+                if state.buf.is_empty() && matches!(self.stream, Stream::Ssl(_)) {
+                    self.early_data = None;
+                }
+
+                return Ok(size);
+            }
+        }
+
         let stream = match &mut self.stream {
             Stream::Ssl(stream) => stream,
+            #[cfg(feature = "rustls")]
+            Stream::Rustls(_) => return self.rustls_read(buf),
             _ => unreachable!(),
         };
 
@@ -716,6 +1608,16 @@ where
 
         let stream = match &mut self.stream {
             Stream::Ssl(stream) => stream,
+            Stream::EarlyDataSsl(_) => {
+                // responses wait for the handshake to complete
+                self.interests_for_write = self.interests_for_handshake;
+
+                return Err(TlsStreamError::Io(io::Error::from(
+                    io::ErrorKind::WouldBlock,
+                )));
+            }
+            #[cfg(feature = "rustls")]
+            Stream::Rustls(_) => return self.rustls_write(buf),
             _ => unreachable!(),
         };
 
@@ -728,6 +1630,108 @@ where
             }
         }
     }
+
+    #[cfg(feature = "rustls")]
+    fn rustls_parts(&mut self) -> (&mut RustlsState, &mut dyn ReadWrite) {
+        let state = match &mut self.stream {
+            Stream::Rustls(state) => state,
+            _ => unreachable!(),
+        };
+
+        (state, Box::as_mut(Box::as_mut(&mut self.plain_stream)))
+    }
+
+    #[cfg(feature = "rustls")]
+    fn rustls_handshake(&mut self) -> Result<(), TlsStreamError> {
+        let mut interests = None;
+
+        let (state, io) = self.rustls_parts();
+
+        let ret = if state.handshake_done {
+            Ok(())
+        } else {
+            loop {
+                if let Err(e) = rustls_flush(&mut state.conn, io, &mut interests) {
+                    break Err(TlsStreamError::Io(e));
+                }
+
+                if !state.conn.is_handshaking() {
+                    state.handshake_done = true;
+
+                    break Ok(());
+                }
+
+                if let Err(e) = rustls_receive(&mut state.conn, io, &mut interests) {
+                    break Err(e);
+                }
+            }
+        };
+
+        self.interests_for_handshake = interests;
+
+        if ret.is_ok() && self.rustls_parts().0.handshake_done {
+            debug!("{} {}: tls handshake success", self.log_prefix(), self.id);
+        }
+
+        ret
+    }
+
+    #[cfg(feature = "rustls")]
+    fn rustls_read(&mut self, buf: &mut [u8]) -> Result<usize, TlsStreamError> {
+        let mut interests = None;
+
+        let (state, io) = self.rustls_parts();
+
+        let ret = loop {
+            match state.conn.reader().read(buf) {
+                Ok(size) => break Ok(size),
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {}
+                Err(e) => break Err(TlsStreamError::Io(e)),
+            }
+
+            if let Err(e) = rustls_receive(&mut state.conn, io, &mut interests) {
+                break Err(e);
+            }
+
+            // send any responses to what was received, such as key updates,
+            // if possible. writes will try again otherwise
+            let _ = rustls_flush(&mut state.conn, io, &mut None);
+        };
+
+        self.interests_for_read = interests;
+
+        ret
+    }
+
+    #[cfg(feature = "rustls")]
+    fn rustls_write(&mut self, buf: &[u8]) -> Result<usize, TlsStreamError> {
+        let mut interests = None;
+
+        let (state, io) = self.rustls_parts();
+
+        let ret = (|| {
+            // finish sending records of earlier writes first
+            rustls_flush(&mut state.conn, io, &mut interests)?;
+
+            if let Some(size) = state.write_pending.take() {
+                return Ok(size);
+            }
+
+            let size = state.conn.writer().write(buf)?;
+
+            if let Err(e) = rustls_flush(&mut state.conn, io, &mut interests) {
+                state.write_pending = Some(size);
+
+                return Err(e);
+            }
+
+            Ok(size)
+        })();
+
+        self.interests_for_write = interests;
+
+        ret.map_err(TlsStreamError::Io)
+    }
 }
 
 impl<T> Read for TlsStream<T>
@@ -761,6 +1765,11 @@ where
 #[cfg(test)]
 mod tests {
     use super::*;
+    use openssl::asn1::Asn1Time;
+    use openssl::ec::{EcGroup, EcKey};
+    use openssl::hash::MessageDigest;
+    use openssl::x509::extension::SubjectAlternativeName;
+    use openssl::x509::{X509Builder, X509NameBuilder};
 
     #[derive(Debug)]
     struct ReadWriteA {
@@ -844,4 +1853,329 @@ mod tests {
         assert_eq!(stream.c, 1);
         assert_eq!(e.into_io_error().unwrap().kind(), io::ErrorKind::Other);
     }
+
+    #[test]
+    fn test_accept_plain_http() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+
+        let mut client = std::net::TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        client
+            .write_all(b"GET / HTTP/1.1\r\nHost: example.com\r\n\r\n")
+            .unwrap();
+
+        let (stream, _) = listener.accept().unwrap();
+        let stream = mio::net::TcpStream::from_std(stream);
+
+        let acceptor = TlsAcceptor::new_self_signed();
+
+        let (_, e) = match acceptor.accept(stream) {
+            Ok(_) => panic!("unexpected success"),
+            Err(ret) => ret,
+        };
+
+        assert!(e.is_plain_http());
+    }
+
+    #[test]
+    fn test_accept_early_data_plain_http() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+
+        let mut client = std::net::TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        client
+            .write_all(b"GET / HTTP/1.1\r\nHost: example.com\r\n\r\n")
+            .unwrap();
+
+        let (stream, _) = listener.accept().unwrap();
+        let stream = mio::net::TcpStream::from_std(stream);
+
+        let mut acceptor = TlsAcceptor::new_self_signed();
+        acceptor.early_data = Some(Duration::from_secs(10));
+
+        // with early data enabled, the handshake starts with the first read
+        let mut stream = match acceptor.accept(stream) {
+            Ok(stream) => stream,
+            Err(_) => panic!("unexpected failure"),
+        };
+
+        let e = stream.ensure_handshake().unwrap_err();
+
+        assert!(e.is_plain_http());
+        assert_eq!(stream.take_early_data(), EarlyData::None);
+    }
+
+    #[cfg(feature = "rustls")]
+    #[test]
+    fn test_rustls_accept_plain_http() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+
+        let mut client = std::net::TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        client
+            .write_all(b"GET / HTTP/1.1\r\nHost: example.com\r\n\r\n")
+            .unwrap();
+
+        let (stream, _) = listener.accept().unwrap();
+        let stream = mio::net::TcpStream::from_std(stream);
+
+        let cache = Arc::new(IdentityCache::new(Path::new("/nonexistent")));
+        let acceptor = TlsAcceptor::new_rustls(&cache, None, false);
+
+        // the handshake starts with the first read
+        let mut stream = match acceptor.accept(stream) {
+            Ok(stream) => stream,
+            Err(_) => panic!("unexpected failure"),
+        };
+
+        let e = stream.ensure_handshake().unwrap_err();
+
+        assert!(e.is_plain_http());
+    }
+
+    #[cfg(feature = "rustls")]
+    #[test]
+    fn test_rustls_accept() {
+        fn retry<T, F>(mut f: F) -> T
+        where
+            F: FnMut() -> Result<T, io::Error>,
+        {
+            loop {
+                match f() {
+                    Ok(ret) => break ret,
+                    Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                        std::thread::sleep(Duration::from_millis(1))
+                    }
+                    Err(e) => panic!("{}", e),
+                }
+            }
+        }
+
+        let dir = std::env::temp_dir().join(format!("condure-rustls-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+
+        write_cert(&dir, "example.com", "example.com", &["example.com"]);
+
+        let cache = Arc::new(IdentityCache::new(&dir));
+        let acceptor = TlsAcceptor::new_rustls(&cache, None, true);
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+
+        let client = std::net::TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        client.set_nonblocking(true).unwrap();
+
+        let (server, _) = listener.accept().unwrap();
+        server.set_nonblocking(true).unwrap();
+
+        let mut client = TlsStream::connect(
+            "example.com",
+            mio::net::TcpStream::from_std(client),
+            VerifyMode::None,
+        )
+        .unwrap();
+
+        let mut server = match acceptor.accept(mio::net::TcpStream::from_std(server)) {
+            Ok(stream) => stream,
+            Err(_) => panic!("unexpected failure"),
+        };
+
+        let mut done = (false, false);
+
+        for _ in 0..1000 {
+            for (stream_done, ret) in [
+                (&mut done.0, client.ensure_handshake()),
+                (&mut done.1, server.ensure_handshake()),
+            ] {
+                match ret {
+                    Ok(()) => *stream_done = true,
+                    Err(TlsStreamError::Io(e)) if e.kind() == io::ErrorKind::WouldBlock => {}
+                    Err(e) => panic!("{}", e),
+                }
+            }
+
+            if done == (true, true) {
+                break;
+            }
+
+            std::thread::sleep(Duration::from_millis(1));
+        }
+
+        assert_eq!(done, (true, true));
+        assert_eq!(server.servername(), Some("example.com"));
+
+        let mut buf = [0; 64];
+
+        retry(|| client.write(b"hello"));
+        let size = retry(|| server.read(&mut buf));
+        assert_eq!(&buf[..size], b"hello");
+
+        retry(|| server.write(b"world"));
+        let size = retry(|| client.read(&mut buf));
+        assert_eq!(&buf[..size], b"world");
+
+        retry(|| server.shutdown());
+        assert_eq!(retry(|| client.read(&mut buf)), 0);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_ticket_keys_rotation() {
+        let keys = TicketKeys::new(Duration::from_secs(10), Duration::from_secs(15));
+
+        let start = Instant::now();
+
+        let k1 = keys.current(start).unwrap();
+        assert_eq!(
+            keys.current(start + Duration::from_secs(9)).unwrap().name,
+            k1.name
+        );
+
+        // rotated. the old key can still decrypt, but is not current
+        let k2 = keys.current(start + Duration::from_secs(10)).unwrap();
+        assert_ne!(k2.name, k1.name);
+
+        let (k, current) = keys
+            .find(&k1.name, start + Duration::from_secs(10))
+            .unwrap()
+            .unwrap();
+        assert_eq!(k.name, k1.name);
+        assert!(!current);
+
+        let (_, current) = keys
+            .find(&k2.name, start + Duration::from_secs(10))
+            .unwrap()
+            .unwrap();
+        assert!(current);
+
+        assert!(keys
+            .find(&[0; TICKET_KEY_NAME_LEN], start + Duration::from_secs(10))
+            .unwrap()
+            .is_none());
+    }
+
+    #[test]
+    fn test_ticket_keys_overlap() {
+        let keys = TicketKeys::new(Duration::from_secs(10), Duration::from_secs(15));
+
+        let start = Instant::now();
+
+        let k1 = keys.current(start).unwrap();
+        let k2 = keys.current(start + Duration::from_secs(10)).unwrap();
+
+        // within the overlap window of k1's replacement
+        assert!(keys
+            .find(&k1.name, start + Duration::from_secs(25))
+            .unwrap()
+            .is_some());
+
+        // past it. k2 is replaced at the same time, but remains usable
+        assert!(keys
+            .find(&k1.name, start + Duration::from_secs(26))
+            .unwrap()
+            .is_none());
+
+        let k3 = keys.current(start + Duration::from_secs(30)).unwrap();
+        assert_ne!(k3.name, k2.name);
+        assert!(keys
+            .find(&k2.name, start + Duration::from_secs(30))
+            .unwrap()
+            .is_some());
+        assert_eq!(keys.keys.lock().unwrap().len(), 2);
+    }
+
+    fn write_cert(dir: &Path, name: &str, common_name: &str, alt_names: &[&str]) {
+        let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1).unwrap();
+        let key = PKey::from_ec_key(EcKey::generate(&group).unwrap()).unwrap();
+
+        let mut subject = X509NameBuilder::new().unwrap();
+        subject
+            .append_entry_by_nid(Nid::COMMONNAME, common_name)
+            .unwrap();
+        let subject = subject.build();
+
+        let mut builder = X509Builder::new().unwrap();
+        builder.set_version(2).unwrap();
+        builder.set_subject_name(&subject).unwrap();
+        builder.set_issuer_name(&subject).unwrap();
+        builder.set_pubkey(&key).unwrap();
+        builder
+            .set_not_before(&Asn1Time::days_from_now(0).unwrap())
+            .unwrap();
+        builder
+            .set_not_after(&Asn1Time::days_from_now(1).unwrap())
+            .unwrap();
+
+        if !alt_names.is_empty() {
+            let mut san = SubjectAlternativeName::new();
+            for name in alt_names {
+                san.dns(name);
+            }
+
+            let san = san.build(&builder.x509v3_context(None, None)).unwrap();
+            builder.append_extension(san).unwrap();
+        }
+
+        builder.sign(&key, MessageDigest::sha256()).unwrap();
+
+        fs::write(
+            dir.join(format!("{}.crt", name)),
+            builder.build().to_pem().unwrap(),
+        )
+        .unwrap();
+        fs::write(
+            dir.join(format!("{}.key", name)),
+            key.private_key_to_pem_pkcs8().unwrap(),
+        )
+        .unwrap();
+    }
+
+    #[test]
+    fn test_name_matches() {
+        assert!(name_matches("www.example.com", "www.example.com"));
+        assert!(!name_matches("www.example.com", "example.com"));
+        assert!(name_matches("*.example.com", "www.example.com"));
+        assert!(!name_matches("*.example.com", "example.com"));
+        assert!(!name_matches("*.example.com", "a.www.example.com"));
+        assert!(!name_matches("*.example.com", ".example.com"));
+    }
+
+    #[test]
+    fn test_identity_cache_cert_names() {
+        let dir = std::env::temp_dir().join(format!("condure-certs-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+
+        write_cert(
+            &dir,
+            "multi",
+            "a.example.com",
+            &["a.example.com", "b.example.org"],
+        );
+        write_cert(
+            &dir,
+            "wild",
+            "example.net",
+            &["*.example.net", "example.net"],
+        );
+        write_cert(&dir, "cn", "c.example.com", &[]);
+        write_cert(&dir, "_.example.org", "x", &["x"]);
+
+        let cache = IdentityCache::new(&dir);
+
+        let name = |domain| cache.get_by_domain(domain).map(|i| String::from(i.name));
+
+        assert_eq!(name("b.example.org").as_deref(), Some("multi"));
+        assert_eq!(name("www.example.net").as_deref(), Some("wild"));
+        assert_eq!(name("example.net").as_deref(), Some("wild"));
+        assert_eq!(name("C.Example.com").as_deref(), Some("cn"));
+
+        // the wildcard file name is tried before wildcard cert names
+        assert_eq!(name("d.example.org").as_deref(), Some("_.example.org"));
+
+        assert_eq!(name("a.www.example.net"), None);
+        assert_eq!(name("other.com"), None);
+
+        assert_eq!(cache.reload(), 4);
+        assert!(cache.data.lock().unwrap().is_empty());
+        assert_eq!(name("b.example.org").as_deref(), Some("multi"));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
 }
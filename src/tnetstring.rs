@@ -23,6 +23,7 @@ use thiserror::Error;
 
 const F64_SIZE_MAX: usize = 64;
 const OPS_MAX: usize = 1_000;
+pub const DEFERRED_MAX: usize = 4;
 
 const TRUE_BYTES: &[u8] = b"true";
 const FALSE_BYTES: &[u8] = b"false";
@@ -266,21 +267,41 @@ fn calc_len(ops: &[Op], lens: &mut [usize]) -> usize {
     count
 }
 
-pub struct Writer<'a, 'b> {
+pub struct Writer<'a, 'b, 'c> {
     ops: [Op<'a>; OPS_MAX],
     len: usize,
-    dest: &'a mut io::Cursor<&'b mut [u8]>,
+    dest: &'b mut io::Cursor<&'c mut [u8]>,
+    defer_min: usize,
+    deferred: [(usize, &'a [u8]); DEFERRED_MAX],
+    deferred_len: usize,
 }
 
-impl<'a, 'b> Writer<'a, 'b> {
-    pub fn new(dest: &'a mut io::Cursor<&'b mut [u8]>) -> Self {
+impl<'a, 'b, 'c> Writer<'a, 'b, 'c> {
+    pub fn new(dest: &'b mut io::Cursor<&'c mut [u8]>) -> Self {
         Self {
             ops: [Op::Invalid; OPS_MAX],
             len: 0,
             dest,
+            defer_min: 0,
+            deferred: [(0, b""); DEFERRED_MAX],
+            deferred_len: 0,
         }
     }
 
+    // leave the contents of strings of at least min bytes out of the
+    // output, up to DEFERRED_MAX of them, so that they can be gathered from
+    // their original location instead of being copied into the output
+    // first. 0 disables
+    pub fn set_defer_min(&mut self, min: usize) {
+        self.defer_min = min;
+    }
+
+    // strings left out so far, with the positions in the destination where
+    // they belong
+    pub fn deferred(&self) -> &[(usize, &'a [u8])] {
+        &self.deferred[..self.deferred_len]
+    }
+
     fn append(&mut self, op: Op<'a>) -> Result<(), io::Error> {
         if self.len >= self.ops.len() {
             return Err(io::Error::from(io::ErrorKind::WriteZero));
@@ -340,7 +361,20 @@ impl<'a, 'b> Writer<'a, 'b> {
             assert!(i + count <= self.len);
 
             for _ in 0..count {
-                self.ops[i].serialize(self.dest, lens[i])?;
+                match self.ops[i] {
+                    Op::String(s)
+                        if self.defer_min > 0
+                            && s.len() >= self.defer_min
+                            && self.deferred_len < DEFERRED_MAX =>
+                    {
+                        write!(self.dest, "{}:", lens[i])?;
+                        self.deferred[self.deferred_len] = (self.dest.position() as usize, s);
+                        self.deferred_len += 1;
+                        write_exact(self.dest, b",")?;
+                    }
+                    op => op.serialize(self.dest, lens[i])?,
+                }
+
                 self.ops[i] = Op::Invalid;
                 i += 1;
             }
@@ -1067,6 +1101,26 @@ mod tests {
         assert!(it.next().is_none());
     }
 
+    #[test]
+    fn test_deferred() {
+        let mut buf = [0; 64];
+
+        let mut cursor = io::Cursor::new(&mut buf[..]);
+        let mut w = Writer::new(&mut cursor);
+        w.set_defer_min(5);
+        w.start_map().unwrap();
+        w.write_string(b"body").unwrap();
+        w.write_string(b"hello").unwrap();
+        w.end_map().unwrap();
+        w.flush().unwrap();
+
+        let deferred = w.deferred();
+        assert_eq!(deferred, &[(12, &b"hello"[..])]);
+
+        let size = cursor.position() as usize;
+        assert_eq!(&buf[..size], b"15:4:body,5:,}");
+    }
+
     #[test]
     fn test_overflow() {
         let mut buf = [0; 256];
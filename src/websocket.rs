@@ -17,7 +17,7 @@
 use crate::buffer::{
     trim_for_display, write_vectored_offset, BaseRingBuffer, LimitBufsMut, RefRead, VECTORED_MAX,
 };
-use crate::http1::HeaderParamsIterator;
+use crate::http1::{self, HeaderParamsIterator};
 use arrayvec::ArrayVec;
 use log::{log_enabled, trace};
 use miniz_oxide::deflate;
@@ -26,10 +26,12 @@ use miniz_oxide::{DataFormat, MZError, MZFlush, MZStatus};
 use std::ascii;
 use std::cell::{Cell, RefCell};
 use std::cmp;
+use std::convert::TryInto;
 use std::fmt;
 use std::io;
 use std::io::Write;
 use std::mem::{self, MaybeUninit};
+use std::str;
 
 pub const WS_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
 
@@ -50,6 +52,10 @@ pub const OPCODE_PONG: u8 = 10;
 
 pub const CONTROL_FRAME_PAYLOAD_MAX: usize = 125;
 
+pub const EXTENSIONS_MAX: usize = 4;
+
+const RSV1: u8 = 0x40;
+
 const DEFAULT_MAX_WINDOW_BITS: u8 = 15;
 const DEFLATE_SUFFIX: [u8; 4] = [0x00, 0x00, 0xff, 0xff];
 const ENC_NEXT_BUF_SIZE: usize = DEFLATE_SUFFIX.len();
@@ -217,8 +223,27 @@ pub fn write_header(
     }
 }
 
+const MASK_CHUNK_SIZE: usize = mem::size_of::<u128>();
+
+// xor a block at a time so the compiler can use wide registers, then finish
+// the tail byte by byte. the chunk size is a multiple of the mask size, so
+// every chunk starts at the same mask position
 pub fn apply_mask(buf: &mut [u8], mask: [u8; 4], offset: usize) {
-    for (i, c) in buf.iter_mut().enumerate() {
+    let mut wide_mask = [0; MASK_CHUNK_SIZE];
+    for (i, c) in wide_mask.iter_mut().enumerate() {
+        *c = mask[(offset + i) % 4];
+    }
+
+    let wide_mask = u128::from_ne_bytes(wide_mask);
+
+    let mut chunks = buf.chunks_exact_mut(MASK_CHUNK_SIZE);
+
+    for chunk in &mut chunks {
+        let v = u128::from_ne_bytes(chunk.try_into().unwrap()) ^ wide_mask;
+        chunk.copy_from_slice(&v.to_ne_bytes());
+    }
+
+    for (i, c) in chunks.into_remainder().iter_mut().enumerate() {
         *c ^= mask[(offset + i) % 4];
     }
 }
@@ -271,6 +296,7 @@ fn parse_bits(s: &str, dest: &mut Option<u8>, default: Option<u8>) -> Result<(),
     Err(io::Error::from(io::ErrorKind::InvalidData))
 }
 
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub struct PerMessageDeflateConfig {
     pub client_no_context_takeover: bool,
     pub server_no_context_takeover: bool,
@@ -374,6 +400,108 @@ impl Default for PerMessageDeflateConfig {
     }
 }
 
+// an extension agreed to during the handshake, with its parameters. to add
+// support for an extension, add a variant here, accept its offers in the
+// upgrade path, and install its frame handling where the connection sets up
+// the protocol
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Extension {
+    PerMessageDeflate(PerMessageDeflateConfig),
+}
+
+impl Extension {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::PerMessageDeflate(_) => "permessage-deflate",
+        }
+    }
+
+    // reserved bits of the frame header used by the extension
+    pub fn rsv_bits(&self) -> u8 {
+        match self {
+            Self::PerMessageDeflate(_) => RSV1,
+        }
+    }
+
+    pub fn serialize<W: Write>(&self, w: &mut W) -> Result<(), io::Error> {
+        write!(w, "{}", self.name())?;
+
+        match self {
+            Self::PerMessageDeflate(config) => config.serialize(w),
+        }
+    }
+}
+
+pub type Extensions = ArrayVec<Extension, EXTENSIONS_MAX>;
+
+// select extensions from the offers in Sec-WebSocket-Extensions header
+// values. accept is called with the name and params of each offer, and
+// returns the extension to use if the offer is acceptable. the client can
+// present multiple offers for the same extension, in order of preference, so
+// only the first accepted offer of each name is used. offers that would use
+// the same reserved bits as an already selected extension are skipped
+pub fn negotiate_extensions<'a, I, F>(values: I, mut accept: F) -> Result<Extensions, io::Error>
+where
+    I: IntoIterator<Item = &'a [u8]>,
+    F: FnMut(&str, HeaderParamsIterator) -> Option<Extension>,
+{
+    let mut exts = Extensions::new();
+
+    for value in values {
+        for part in http1::parse_header_value(value) {
+            let (name, params) = part?;
+
+            if exts.iter().any(|e| e.name() == name) {
+                continue;
+            }
+
+            let ext = match accept(name, params) {
+                Some(ext) => ext,
+                None => continue,
+            };
+
+            if exts.is_full() || exts.iter().any(|e| e.rsv_bits() & ext.rsv_bits() != 0) {
+                continue;
+            }
+
+            exts.push(ext);
+        }
+    }
+
+    Ok(exts)
+}
+
+// whether a protocol selected by a server is one of those offered in
+// Sec-WebSocket-Protocol header values. only a single protocol may be
+// selected
+pub fn protocol_offered<'a, I>(values: I, protocol: &[u8]) -> bool
+where
+    I: IntoIterator<Item = &'a [u8]>,
+{
+    let protocol = match str::from_utf8(protocol) {
+        Ok(s) if !s.is_empty() && !s.contains(',') => s,
+        _ => return false,
+    };
+
+    values.into_iter().any(|value| match str::from_utf8(value) {
+        Ok(s) => s.split(',').any(|offer| offer.trim() == protocol),
+        Err(_) => false,
+    })
+}
+
+// write a Sec-WebSocket-Extensions header value for the extensions
+pub fn write_extensions<W: Write>(exts: &[Extension], w: &mut W) -> Result<(), io::Error> {
+    for (i, ext) in exts.iter().enumerate() {
+        if i > 0 {
+            write!(w, ", ")?;
+        }
+
+        ext.serialize(w)?;
+    }
+
+    Ok(())
+}
+
 trait ArrayVecExt<T> {
     fn resize(&mut self, new_len: usize, value: T);
     fn shift_left(&mut self, amount: usize);
@@ -895,6 +1023,7 @@ pub enum Error {
     InvalidControlFrame,
     UnexpectedOpcode,
     CompressionError,
+    FrameTooBig,
 }
 
 impl From<io::Error> for Error {
@@ -946,6 +1075,7 @@ pub struct Protocol<T> {
     sending: Sending,
     receiving: RefCell<Receiving>,
     deflate_state: Option<RefCell<DeflateState<T>>>,
+    frame_payload_max: Cell<usize>,
 }
 
 impl<T: AsRef<[u8]> + AsMut<[u8]>> Protocol<T> {
@@ -970,6 +1100,7 @@ impl<T: AsRef<[u8]> + AsMut<[u8]>> Protocol<T> {
                 message: None,
             }),
             deflate_state,
+            frame_payload_max: Cell::new(0),
         }
     }
 
@@ -977,6 +1108,21 @@ impl<T: AsRef<[u8]> + AsMut<[u8]>> Protocol<T> {
         self.state.get()
     }
 
+    // refuse received data frames with larger payloads. 0 means no limit
+    pub fn set_frame_payload_max(&self, max: usize) {
+        self.frame_payload_max.set(max);
+    }
+
+    fn check_frame_payload(&self, fi: &FrameInfo) -> Result<(), Error> {
+        let max = self.frame_payload_max.get();
+
+        if fi.opcode & 0x08 == 0 && max > 0 && fi.payload_size > max {
+            return Err(Error::FrameTooBig);
+        }
+
+        Ok(())
+    }
+
     pub fn send_frame<W: Write>(
         &self,
         writer: &mut W,
@@ -1100,6 +1246,10 @@ impl<T: AsRef<[u8]> + AsMut<[u8]>> Protocol<T> {
                 Err(e) => return Some(Err(e.into())),
             };
 
+            if let Err(e) = self.check_frame_payload(&fi) {
+                return Some(Err(e));
+            }
+
             rbuf.consume(fi.payload_offset);
 
             receiving.frame = Some(fi);
@@ -1312,6 +1462,10 @@ impl<T: AsRef<[u8]> + AsMut<[u8]>> Protocol<T> {
                 Err(e) => return Some(Err(e.into())),
             };
 
+            if let Err(e) = self.check_frame_payload(&fi) {
+                return Some(Err(e));
+            }
+
             rbuf.consume(fi.payload_offset);
 
             receiving.frame = Some(fi);
@@ -1523,6 +1677,30 @@ pub mod testutil {
         }
     }
 
+    pub struct BenchApplyMask {
+        content: Vec<u8>,
+    }
+
+    impl BenchApplyMask {
+        pub fn new(size: usize) -> Self {
+            let mut content = Vec::with_capacity(size);
+            for i in 0..size {
+                content.push((i % 256) as u8);
+            }
+
+            Self { content }
+        }
+
+        pub fn init(&self) -> Vec<u8> {
+            self.content.clone()
+        }
+
+        pub fn run(&self, buf: &mut [u8]) {
+            // unaligned offset, as when resuming within a frame
+            apply_mask(buf, [0x01, 0x02, 0x03, 0x04], 1);
+        }
+    }
+
     pub struct BenchRecvMessageArgs {
         protocol: Protocol<Vec<u8>>,
         rbuf: RingBuffer,
@@ -1755,6 +1933,34 @@ mod tests {
         let mut buf = [b'a', b'b', b'c', b'd', b'e'];
         apply_mask(&mut buf, [0x01, 0x02, 0x03, 0x04], 0);
         assert_eq!(buf, [0x60, 0x60, 0x60, 0x60, 0x64]);
+
+        let mask = [0x01, 0x02, 0x03, 0x04];
+
+        let mut src = Vec::new();
+        for i in 0..100 {
+            src.push((i * 7) as u8);
+        }
+
+        for size in [0, 1, 15, 16, 17, 33, 100] {
+            for offset in 0..8 {
+                let mut buf = src[..size].to_vec();
+                apply_mask(&mut buf, mask, offset);
+
+                let expected: Vec<u8> = src[..size]
+                    .iter()
+                    .enumerate()
+                    .map(|(i, c)| c ^ mask[(offset + i) % 4])
+                    .collect();
+
+                assert_eq!(buf, expected);
+            }
+        }
+    }
+
+    #[test]
+    fn bench_apply_mask() {
+        let t = BenchApplyMask::new(16_384);
+        t.run(&mut t.init());
     }
 
     #[test]
@@ -2171,6 +2377,33 @@ mod tests {
         assert!(r.is_err());
     }
 
+    #[test]
+    fn test_recv_frame_payload_max() {
+        // ping within the limit, then text over it
+        let mut data = b"\x89\x05hello\x81\x05hello".to_vec();
+
+        let mut rbuf = io::Cursor::new(&mut data[..]);
+
+        let p = Protocol::<[u8; 0]>::new(None);
+        p.set_frame_payload_max(4);
+
+        let mut dest = [0; 1024];
+
+        let (opcode, size, end) = p
+            .recv_message_content(&mut rbuf, &mut dest)
+            .unwrap()
+            .unwrap();
+        assert_eq!(opcode, OPCODE_PING);
+        assert_eq!(&dest[..size], b"hello");
+        assert_eq!(end, true);
+
+        let r = p.recv_message_content(&mut rbuf, &mut dest).unwrap();
+        assert!(matches!(r, Err(Error::FrameTooBig)));
+
+        // nothing was consumed
+        assert_eq!(rbuf.len(), 7);
+    }
+
     #[test]
     fn test_send_recv_compressed() {
         let tmp = Rc::new(TmpBuffer::new(1024));
@@ -2381,6 +2614,79 @@ mod tests {
         assert_eq!(&dest[..written], b"Hello");
     }
 
+    #[test]
+    fn test_negotiate_extensions() {
+        let accept = |name: &str, params: HeaderParamsIterator| match name {
+            "permessage-deflate" => {
+                let config = PerMessageDeflateConfig::from_params(params).ok()?;
+
+                Some(Extension::PerMessageDeflate(config.create_response().ok()?))
+            }
+            _ => None,
+        };
+
+        let exts = negotiate_extensions(Vec::<&[u8]>::new(), accept).unwrap();
+        assert!(exts.is_empty());
+
+        // unsupported extensions are skipped
+        let exts = negotiate_extensions([&b"x-unknown; foo=1"[..]], accept).unwrap();
+        assert!(exts.is_empty());
+
+        // first acceptable offer wins, across header values
+        let values: [&[u8]; 2] = [
+            b"x-unknown, permessage-deflate; client_max_window_bits=bogus",
+            b"permessage-deflate; server_no_context_takeover, permessage-deflate",
+        ];
+        let exts = negotiate_extensions(values, accept).unwrap();
+        assert_eq!(exts.len(), 1);
+
+        let mut dest = Vec::new();
+        write_extensions(&exts, &mut dest).unwrap();
+        assert_eq!(
+            str::from_utf8(&dest).unwrap(),
+            "permessage-deflate; server_no_context_takeover"
+        );
+
+        // extensions using the same reserved bits are not combined
+        let exts = negotiate_extensions([&b"x-a, x-b"[..]], |_, _| {
+            Some(Extension::PerMessageDeflate(
+                PerMessageDeflateConfig::default(),
+            ))
+        })
+        .unwrap();
+        assert_eq!(exts.len(), 1);
+
+        let r = negotiate_extensions([&b"permessage-deflate; =1"[..]], accept);
+        assert!(r.is_err());
+    }
+
+    #[test]
+    fn test_write_extensions() {
+        let mut dest = Vec::new();
+        write_extensions(&[], &mut dest).unwrap();
+        assert!(dest.is_empty());
+
+        let exts = [Extension::PerMessageDeflate(
+            PerMessageDeflateConfig::default(),
+        )];
+
+        let mut dest = Vec::new();
+        write_extensions(&exts, &mut dest).unwrap();
+        assert_eq!(str::from_utf8(&dest).unwrap(), "permessage-deflate");
+    }
+
+    #[test]
+    fn test_protocol_offered() {
+        let offers = [&b"mqtt, stomp"[..], &b"wamp"[..]];
+
+        assert!(protocol_offered(offers, b"stomp"));
+        assert!(protocol_offered(offers, b"wamp"));
+        assert!(!protocol_offered(offers, b"soap"));
+        assert!(!protocol_offered(offers, b"mqtt, stomp"));
+        assert!(!protocol_offered(offers, b""));
+        assert!(!protocol_offered([], b"mqtt"));
+    }
+
     #[test]
     fn bench_send_message() {
         let t = BenchSendMessage::new(false);
@@ -20,7 +20,10 @@ use arrayvec::ArrayVec;
 use std::cell::RefCell;
 use std::io;
 use std::mem;
+use std::ops::{AddAssign, Deref, Range};
 use std::str;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
 use thiserror::Error;
 
 pub const IDS_MAX: usize = 128;
@@ -72,6 +75,9 @@ pub enum ParseError {
 
     #[error("no id")]
     NoId,
+
+    #[error("{0} is missing a required field")]
+    MissingField(&'static str),
 }
 
 trait ErrorContext<T> {
@@ -135,7 +141,7 @@ impl ParseScratch<'_> {
 }
 
 trait Serialize<'a> {
-    fn serialize(&self, w: &mut tnetstring::Writer<'a, '_>) -> Result<(), io::Error>;
+    fn serialize(&self, w: &mut tnetstring::Writer<'a, '_, '_>) -> Result<(), io::Error>;
 }
 
 trait Parse<'buf: 'scratch, 'scratch> {
@@ -147,15 +153,86 @@ trait Parse<'buf: 'scratch, 'scratch> {
     ) -> Result<Self::Parsed, ParseError>;
 }
 
+fn write_header_list<'a>(
+    w: &mut tnetstring::Writer<'a, '_, '_>,
+    field: &'static [u8],
+    headers: &[Header<'a>],
+) -> Result<(), io::Error> {
+    w.write_string(field)?;
+    w.start_array()?;
+
+    for h in headers.iter() {
+        w.start_array()?;
+        w.write_string(h.name.as_bytes())?;
+        w.write_string(h.value)?;
+        w.end_array()?;
+    }
+
+    w.end_array()
+}
+
+// append the items of a header list to scratch, and return their position
+fn parse_header_list<'buf>(
+    data: &'buf [u8],
+    field: &'static str,
+    scratch: &mut HeadersScratch<'buf>,
+) -> Result<Range<usize>, ParseError> {
+    let start = scratch.len();
+
+    let headers = tnetstring::parse_array(data).field(field)?;
+
+    for ha in headers {
+        let ha = ha?;
+
+        if scratch.remaining_capacity() == 0 {
+            return Err(ParseError::TooManyHeaders);
+        }
+
+        let mut hi = tnetstring::parse_array(ha.data).field("header item")?;
+
+        let name = match hi.next() {
+            Some(Ok(name)) => name,
+            Some(Err(e)) => {
+                return Err(e.into());
+            }
+            None => {
+                return Err(ParseError::InvalidHeader);
+            }
+        };
+
+        let name = tnetstring::parse_string(name.data).field("header name")?;
+
+        let name = str::from_utf8(name).field("header name")?;
+
+        let value = match hi.next() {
+            Some(Ok(name)) => name,
+            Some(Err(e)) => {
+                return Err(e.into());
+            }
+            None => {
+                return Err(ParseError::InvalidHeader);
+            }
+        };
+
+        let value = tnetstring::parse_string(value.data).field("header value")?;
+
+        scratch.push(Header { name, value });
+    }
+
+    Ok(start..scratch.len())
+}
+
 struct CommonData<'buf, 'ids> {
     from: &'buf [u8],
     ids: &'ids [Id<'buf>],
+    sessions: &'ids [SessionInfo],
     multi: bool,
+    handler_refresh: bool,
     ptype_str: &'buf str,
 }
 
 impl<'buf: 'ids, 'ids> CommonData<'buf, 'ids> {
-    fn serialize(&self, w: &mut tnetstring::Writer<'buf, '_>) -> Result<(), io::Error> {
+    fn serialize(&self, w: &mut tnetstring::Writer<'buf, '_, '_>) -> Result<(), io::Error> {
         if !self.from.is_empty() {
             w.write_string(b"from")?;
             w.write_string(self.from)?;
@@ -170,11 +247,22 @@ impl<'buf: 'ids, 'ids> CommonData<'buf, 'ids> {
                 w.write_string(b"seq")?;
                 w.write_int(seq as isize)?;
             }
+
+            if let Some(session) = self.sessions.first() {
+                session.serialize(w)?;
+            }
         } else if self.ids.len() > 1 {
+            // session info is only included if there is one per id
+            let sessions = if self.sessions.len() == self.ids.len() {
+                self.sessions
+            } else {
+                &[]
+            };
+
             w.write_string(b"id")?;
 
             w.start_array()?;
-            for id in self.ids.iter() {
+            for (i, id) in self.ids.iter().enumerate() {
                 w.start_map()?;
 
                 w.write_string(b"id")?;
@@ -185,17 +273,28 @@ impl<'buf: 'ids, 'ids> CommonData<'buf, 'ids> {
                     w.write_int(seq as isize)?;
                 }
 
+                if let Some(session) = sessions.get(i) {
+                    session.serialize(w)?;
+                }
+
                 w.end_map()?;
             }
             w.end_array()?;
         }
 
-        if self.multi {
+        if self.multi || self.handler_refresh {
             w.write_string(b"ext")?;
             w.start_map()?;
 
-            w.write_string(b"multi")?;
-            w.write_bool(true)?;
+            if self.multi {
+                w.write_string(b"multi")?;
+                w.write_bool(true)?;
+            }
+
+            if self.handler_refresh {
+                w.write_string(b"handler-refresh")?;
+                w.write_bool(true)?;
+            }
 
             w.end_map()?;
         }
@@ -214,6 +313,7 @@ impl<'buf: 'ids, 'ids> CommonData<'buf, 'ids> {
     ) -> Result<Self, ParseError> {
         let mut from = EMPTY_BYTES;
         let mut multi = false;
+        let mut handler_refresh = false;
         let mut ptype_str = "";
 
         for e in root {
@@ -301,10 +401,18 @@ impl<'buf: 'ids, 'ids> CommonData<'buf, 'ids> {
                     for m in ext {
                         let m = m?;
 
-                        if m.key == "multi" {
-                            let b = tnetstring::parse_bool(m.data).field("multi")?;
+                        match m.key {
+                            "multi" => {
+                                let b = tnetstring::parse_bool(m.data).field("multi")?;
 
-                            multi = b;
+                                multi = b;
+                            }
+                            "handler-refresh" => {
+                                let b = tnetstring::parse_bool(m.data).field("handler-refresh")?;
+
+                                handler_refresh = b;
+                            }
+                            _ => {} // skip unknown fields
                         }
                     }
                 }
@@ -315,7 +423,9 @@ impl<'buf: 'ids, 'ids> CommonData<'buf, 'ids> {
         Ok(Self {
             from,
             ids: scratch.as_slice(),
+            sessions: &[],
             multi,
+            handler_refresh,
             ptype_str,
         })
     }
@@ -332,6 +442,10 @@ pub struct RequestData<'buf, 'headers> {
     pub headers: &'headers [Header<'buf>],
     pub content_type: Option<ContentType>, // websocket
     pub body: &'buf [u8],
+
+    // trailing headers, sent with the last part of a chunked body
+    pub trailers: &'headers [Header<'buf>],
+
     pub peer_address: &'buf str,
     pub peer_port: u16,
     pub connect_host: &'buf str,
@@ -340,6 +454,12 @@ pub struct RequestData<'buf, 'headers> {
     pub trust_connect_host: bool,
     pub ignore_tls_errors: bool,
     pub follow_redirects: bool,
+
+    // received as tls early data, and possibly replayed
+    pub early_data: bool,
+
+    // key/value pairs of the listener the request arrived on
+    pub tags: &'headers [Header<'buf>],
 }
 
 #[allow(clippy::new_without_default)]
@@ -355,6 +475,7 @@ impl RequestData<'_, '_> {
             uri: "",
             headers: &EMPTY_HEADERS,
             body: EMPTY_BYTES,
+            trailers: &EMPTY_HEADERS,
             content_type: None,
             peer_address: "",
             peer_port: 0,
@@ -364,12 +485,14 @@ impl RequestData<'_, '_> {
             trust_connect_host: false,
             ignore_tls_errors: false,
             follow_redirects: false,
+            early_data: false,
+            tags: &EMPTY_HEADERS,
         }
     }
 }
 
 impl<'a> Serialize<'a> for RequestData<'a, 'a> {
-    fn serialize(&self, w: &mut tnetstring::Writer<'a, '_>) -> Result<(), io::Error> {
+    fn serialize(&self, w: &mut tnetstring::Writer<'a, '_, '_>) -> Result<(), io::Error> {
         if !self.method.is_empty() {
             w.write_string(b"method")?;
             w.write_string(self.method.as_bytes())?;
@@ -381,17 +504,7 @@ impl<'a> Serialize<'a> for RequestData<'a, 'a> {
         }
 
         if !self.headers.is_empty() {
-            w.write_string(b"headers")?;
-            w.start_array()?;
-
-            for h in self.headers.iter() {
-                w.start_array()?;
-                w.write_string(h.name.as_bytes())?;
-                w.write_string(h.value)?;
-                w.end_array()?;
-            }
-
-            w.end_array()?;
+            write_header_list(w, b"headers", self.headers)?;
         }
 
         if let Some(ctype) = &self.content_type {
@@ -410,6 +523,10 @@ impl<'a> Serialize<'a> for RequestData<'a, 'a> {
             w.write_string(self.body)?;
         }
 
+        if !self.trailers.is_empty() {
+            write_header_list(w, b"trailers", self.trailers)?;
+        }
+
         if self.credits > 0 {
             w.write_string(b"credits")?;
             w.write_int(self.credits as isize)?;
@@ -443,6 +560,23 @@ impl<'a> Serialize<'a> for RequestData<'a, 'a> {
             w.write_int(self.peer_port as isize)?;
         }
 
+        if self.early_data {
+            w.write_string(b"early-data")?;
+            w.write_bool(true)?;
+        }
+
+        if !self.tags.is_empty() {
+            w.write_string(b"tags")?;
+            w.start_map()?;
+
+            for t in self.tags.iter() {
+                w.write_string(t.name.as_bytes())?;
+                w.write_string(t.value)?;
+            }
+
+            w.end_map()?;
+        }
+
         Ok(())
     }
 }
@@ -461,8 +595,10 @@ impl<'buf: 'scratch, 'scratch> Parse<'buf, 'scratch> for RequestData<'buf, 'scra
         let mut timeout = 0;
         let mut method = "";
         let mut uri = "";
+        let mut headers = 0..0;
         let mut content_type = None;
         let mut body = EMPTY_BYTES;
+        let mut trailers = 0..0;
         let mut peer_address = "";
         let mut peer_port = 0;
         let mut connect_host = "";
@@ -471,6 +607,7 @@ impl<'buf: 'scratch, 'scratch> Parse<'buf, 'scratch> for RequestData<'buf, 'scra
         let mut trust_connect_host = false;
         let mut ignore_tls_errors = false;
         let mut follow_redirects = false;
+        let mut early_data = false;
 
         for e in root {
             let e = e?;
@@ -527,47 +664,8 @@ impl<'buf: 'scratch, 'scratch> Parse<'buf, 'scratch> for RequestData<'buf, 'scra
 
                     uri = s;
                 }
-                "headers" => {
-                    let headers = tnetstring::parse_array(e.data).field("headers")?;
-
-                    for ha in headers {
-                        let ha = ha?;
-
-                        if scratch.remaining_capacity() == 0 {
-                            return Err(ParseError::TooManyHeaders);
-                        }
-
-                        let mut hi = tnetstring::parse_array(ha.data).field("header item")?;
-
-                        let name = match hi.next() {
-                            Some(Ok(name)) => name,
-                            Some(Err(e)) => {
-                                return Err(e.into());
-                            }
-                            None => {
-                                return Err(ParseError::InvalidHeader);
-                            }
-                        };
-
-                        let name = tnetstring::parse_string(name.data).field("header name")?;
-
-                        let name = str::from_utf8(name).field("header name")?;
-
-                        let value = match hi.next() {
-                            Some(Ok(name)) => name,
-                            Some(Err(e)) => {
-                                return Err(e.into());
-                            }
-                            None => {
-                                return Err(ParseError::InvalidHeader);
-                            }
-                        };
-
-                        let value = tnetstring::parse_string(value.data).field("header value")?;
-
-                        scratch.push(Header { name, value });
-                    }
-                }
+                "headers" => headers = parse_header_list(e.data, "headers", scratch)?,
+                "trailers" => trailers = parse_header_list(e.data, "trailers", scratch)?,
                 "content-type" => {
                     let s = tnetstring::parse_string(e.data).field("content-type")?;
 
@@ -633,10 +731,17 @@ impl<'buf: 'scratch, 'scratch> Parse<'buf, 'scratch> for RequestData<'buf, 'scra
 
                     follow_redirects = b;
                 }
+                "early-data" => {
+                    let b = tnetstring::parse_bool(e.data).field("early-data")?;
+
+                    early_data = b;
+                }
                 _ => {} // skip unknown fields
             }
         }
 
+        let scratch = scratch.as_slice();
+
         Ok(Self {
             credits,
             more,
@@ -645,9 +750,10 @@ impl<'buf: 'scratch, 'scratch> Parse<'buf, 'scratch> for RequestData<'buf, 'scra
             timeout,
             method,
             uri,
-            headers: scratch.as_slice(),
+            headers: &scratch[headers],
             content_type,
             body,
+            trailers: &scratch[trailers],
             peer_address,
             peer_port,
             connect_host,
@@ -656,6 +762,86 @@ impl<'buf: 'scratch, 'scratch> Parse<'buf, 'scratch> for RequestData<'buf, 'scra
             trust_connect_host,
             ignore_tls_errors,
             follow_redirects,
+            early_data,
+
+            // only sent by the server
+            tags: &[],
+        })
+    }
+}
+
+// a local file to send as the body, instead of passing the content in
+// the message. the length defaults to the rest of the file
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ResponseFile<'a> {
+    pub path: &'a str,
+    pub offset: u64,
+    pub length: Option<u64>,
+}
+
+impl<'a> ResponseFile<'a> {
+    fn serialize(&self, w: &mut tnetstring::Writer<'a, '_, '_>) -> Result<(), io::Error> {
+        w.write_string(b"file")?;
+        w.start_map()?;
+
+        w.write_string(b"path")?;
+        w.write_string(self.path.as_bytes())?;
+
+        if self.offset > 0 {
+            w.write_string(b"offset")?;
+            w.write_int(self.offset as isize)?;
+        }
+
+        if let Some(length) = self.length {
+            w.write_string(b"length")?;
+            w.write_int(length as isize)?;
+        }
+
+        w.end_map()?;
+
+        Ok(())
+    }
+
+    fn parse(src: &'a [u8]) -> Result<Self, ParseError> {
+        let mut path = None;
+        let mut offset = 0;
+        let mut length = None;
+
+        for m in tnetstring::parse_map(src).field("file")? {
+            let m = m?;
+
+            match m.key {
+                "path" => {
+                    let s = tnetstring::parse_string(m.data).field("file")?;
+
+                    path = Some(str::from_utf8(s).field("file")?);
+                }
+                "offset" | "length" => {
+                    let x = tnetstring::parse_int(m.data).field("file")?;
+
+                    if x < 0 {
+                        return Err(ParseError::NegativeInt("file"));
+                    }
+
+                    if m.key == "offset" {
+                        offset = x as u64;
+                    } else {
+                        length = Some(x as u64);
+                    }
+                }
+                _ => {} // skip unknown fields
+            }
+        }
+
+        let path = match path {
+            Some(path) => path,
+            None => return Err(ParseError::MissingField("file")),
+        };
+
+        Ok(Self {
+            path,
+            offset,
+            length,
         })
     }
 }
@@ -668,6 +854,15 @@ pub struct ResponseData<'buf, 'headers> {
     pub headers: &'headers [Header<'buf>],
     pub content_type: Option<ContentType>, // websocket
     pub body: &'buf [u8],
+
+    // trailing headers, sent with the last part of a chunked body
+    pub trailers: &'headers [Header<'buf>],
+
+    pub download_rate: u32, // bytes per second, 0 = unchanged
+    pub sse: bool,          // body is an event stream
+
+    // if set, the body is read from a local file
+    pub file: Option<ResponseFile<'buf>>,
 }
 
 #[allow(clippy::new_without_default)]
@@ -681,12 +876,16 @@ impl ResponseData<'_, '_> {
             headers: &EMPTY_HEADERS,
             content_type: None,
             body: EMPTY_BYTES,
+            trailers: &EMPTY_HEADERS,
+            download_rate: 0,
+            sse: false,
+            file: None,
         }
     }
 }
 
 impl<'a> Serialize<'a> for ResponseData<'a, 'a> {
-    fn serialize(&self, w: &mut tnetstring::Writer<'a, '_>) -> Result<(), io::Error> {
+    fn serialize(&self, w: &mut tnetstring::Writer<'a, '_, '_>) -> Result<(), io::Error> {
         if self.code > 0 {
             w.write_string(b"code")?;
             w.write_int(self.code as isize)?;
@@ -698,17 +897,7 @@ impl<'a> Serialize<'a> for ResponseData<'a, 'a> {
         }
 
         if !self.headers.is_empty() {
-            w.write_string(b"headers")?;
-            w.start_array()?;
-
-            for h in self.headers.iter() {
-                w.start_array()?;
-                w.write_string(h.name.as_bytes())?;
-                w.write_string(h.value)?;
-                w.end_array()?;
-            }
-
-            w.end_array()?;
+            write_header_list(w, b"headers", self.headers)?;
         }
 
         if let Some(ctype) = &self.content_type {
@@ -727,6 +916,10 @@ impl<'a> Serialize<'a> for ResponseData<'a, 'a> {
             w.write_string(self.body)?;
         }
 
+        if !self.trailers.is_empty() {
+            write_header_list(w, b"trailers", self.trailers)?;
+        }
+
         if self.credits > 0 {
             w.write_string(b"credits")?;
             w.write_int(self.credits as isize)?;
@@ -737,6 +930,20 @@ impl<'a> Serialize<'a> for ResponseData<'a, 'a> {
             w.write_bool(true)?;
         }
 
+        if self.download_rate > 0 {
+            w.write_string(b"download-rate")?;
+            w.write_int(self.download_rate as isize)?;
+        }
+
+        if self.sse {
+            w.write_string(b"sse")?;
+            w.write_bool(true)?;
+        }
+
+        if let Some(file) = &self.file {
+            file.serialize(w)?;
+        }
+
         Ok(())
     }
 }
@@ -752,8 +959,13 @@ impl<'buf: 'scratch, 'scratch> Parse<'buf, 'scratch> for ResponseData<'buf, 'scr
         let mut more = false;
         let mut code = 0;
         let mut reason = "";
+        let mut headers = 0..0;
         let mut content_type = None;
         let mut body = EMPTY_BYTES;
+        let mut trailers = 0..0;
+        let mut download_rate = 0;
+        let mut sse = false;
+        let mut file = None;
 
         for e in root {
             let e = e?;
@@ -789,47 +1001,8 @@ impl<'buf: 'scratch, 'scratch> Parse<'buf, 'scratch> for ResponseData<'buf, 'scr
 
                     reason = s;
                 }
-                "headers" => {
-                    let headers = tnetstring::parse_array(e.data).field("headers")?;
-
-                    for ha in headers {
-                        let ha = ha?;
-
-                        if scratch.remaining_capacity() == 0 {
-                            return Err(ParseError::TooManyHeaders);
-                        }
-
-                        let mut hi = tnetstring::parse_array(ha.data).field("header item")?;
-
-                        let name = match hi.next() {
-                            Some(Ok(name)) => name,
-                            Some(Err(e)) => {
-                                return Err(e.into());
-                            }
-                            None => {
-                                return Err(ParseError::InvalidHeader);
-                            }
-                        };
-
-                        let name = tnetstring::parse_string(name.data).field("header name")?;
-
-                        let name = str::from_utf8(name).field("header name")?;
-
-                        let value = match hi.next() {
-                            Some(Ok(name)) => name,
-                            Some(Err(e)) => {
-                                return Err(e.into());
-                            }
-                            None => {
-                                return Err(ParseError::InvalidHeader);
-                            }
-                        };
-
-                        let value = tnetstring::parse_string(value.data).field("header value")?;
-
-                        scratch.push(Header { name, value });
-                    }
-                }
+                "headers" => headers = parse_header_list(e.data, "headers", scratch)?,
+                "trailers" => trailers = parse_header_list(e.data, "trailers", scratch)?,
                 "content-type" => {
                     let s = tnetstring::parse_string(e.data).field("content-type")?;
 
@@ -843,18 +1016,39 @@ impl<'buf: 'scratch, 'scratch> Parse<'buf, 'scratch> for ResponseData<'buf, 'scr
 
                     body = s;
                 }
+                "download-rate" => {
+                    let x = tnetstring::parse_int(e.data).field("download-rate")?;
+
+                    if x < 0 {
+                        return Err(ParseError::NegativeInt("download-rate"));
+                    }
+
+                    download_rate = x as u32;
+                }
+                "sse" => {
+                    let b = tnetstring::parse_bool(e.data).field("sse")?;
+
+                    sse = b;
+                }
+                "file" => file = Some(ResponseFile::parse(e.data)?),
                 _ => {} // skip unknown fields
             }
         }
 
+        let scratch = scratch.as_slice();
+
         Ok(Self {
             credits,
             more,
             code,
             reason,
-            headers: scratch.as_slice(),
+            headers: &scratch[headers],
             content_type,
             body,
+            trailers: &scratch[trailers],
+            download_rate,
+            sse,
+            file,
         })
     }
 }
@@ -864,7 +1058,7 @@ pub struct RequestErrorData<'a> {
 }
 
 impl<'a> Serialize<'a> for RequestErrorData<'a> {
-    fn serialize(&self, w: &mut tnetstring::Writer<'a, '_>) -> Result<(), io::Error> {
+    fn serialize(&self, w: &mut tnetstring::Writer<'a, '_, '_>) -> Result<(), io::Error> {
         w.write_string(b"condition")?;
         w.write_string(self.condition.as_bytes())?;
 
@@ -897,6 +1091,36 @@ impl<'buf: 'scratch, 'scratch> Parse<'buf, 'scratch> for RequestErrorData<'buf>
     }
 }
 
+// the condition is optional and only informational, e.g. to let a handler
+// distinguish an operator closing the connection from other cancels
+pub struct CancelData<'a> {
+    pub condition: &'a str,
+}
+
+impl<'a> Serialize<'a> for CancelData<'a> {
+    fn serialize(&self, w: &mut tnetstring::Writer<'a, '_, '_>) -> Result<(), io::Error> {
+        if !self.condition.is_empty() {
+            w.write_string(b"condition")?;
+            w.write_string(self.condition.as_bytes())?;
+        }
+
+        Ok(())
+    }
+}
+
+impl<'buf: 'scratch, 'scratch> Parse<'buf, 'scratch> for CancelData<'buf> {
+    type Parsed = Self;
+
+    fn parse(
+        root: tnetstring::MapIterator<'buf>,
+        scratch: &'scratch mut HeadersScratch<'buf>,
+    ) -> Result<Self::Parsed, ParseError> {
+        let RequestErrorData { condition } = RequestErrorData::parse(root, scratch)?;
+
+        Ok(Self { condition })
+    }
+}
+
 pub struct RejectedInfo<'buf, 'headers> {
     pub code: u16,
     pub reason: &'buf str,
@@ -910,7 +1134,7 @@ pub struct ResponseErrorData<'buf, 'headers> {
 }
 
 impl<'a> Serialize<'a> for ResponseErrorData<'a, 'a> {
-    fn serialize(&self, w: &mut tnetstring::Writer<'a, '_>) -> Result<(), io::Error> {
+    fn serialize(&self, w: &mut tnetstring::Writer<'a, '_, '_>) -> Result<(), io::Error> {
         w.write_string(b"condition")?;
         w.write_string(self.condition.as_bytes())?;
 
@@ -983,45 +1207,7 @@ impl<'buf: 'scratch, 'scratch> Parse<'buf, 'scratch> for ResponseErrorData<'buf,
                     reason = s;
                 }
                 "headers" => {
-                    let headers = tnetstring::parse_array(e.data).field("headers")?;
-
-                    for ha in headers {
-                        let ha = ha?;
-
-                        if scratch.remaining_capacity() == 0 {
-                            return Err(ParseError::TooManyHeaders);
-                        }
-
-                        let mut hi = tnetstring::parse_array(ha.data).field("header item")?;
-
-                        let name = match hi.next() {
-                            Some(Ok(name)) => name,
-                            Some(Err(e)) => {
-                                return Err(e.into());
-                            }
-                            None => {
-                                return Err(ParseError::InvalidHeader);
-                            }
-                        };
-
-                        let name = tnetstring::parse_string(name.data).field("header name")?;
-
-                        let name = str::from_utf8(name).field("header name")?;
-
-                        let value = match hi.next() {
-                            Some(Ok(name)) => name,
-                            Some(Err(e)) => {
-                                return Err(e.into());
-                            }
-                            None => {
-                                return Err(ParseError::InvalidHeader);
-                            }
-                        };
-
-                        let value = tnetstring::parse_string(value.data).field("header value")?;
-
-                        scratch.push(Header { name, value });
-                    }
+                    parse_header_list(e.data, "headers", scratch)?;
                 }
                 "body" => {
                     let s = tnetstring::parse_string(e.data).field("body")?;
@@ -1090,13 +1276,119 @@ impl<'buf: 'scratch, 'scratch> Parse<'buf, 'scratch> for CreditData {
     }
 }
 
+// traffic totals for a connection, for handler accounting
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct Counters {
+    pub bytes_in: u64,
+    pub bytes_out: u64,
+    pub messages_in: u64,
+    pub messages_out: u64,
+}
+
+impl AddAssign for Counters {
+    fn add_assign(&mut self, other: Self) {
+        self.bytes_in += other.bytes_in;
+        self.bytes_out += other.bytes_out;
+        self.messages_in += other.messages_in;
+        self.messages_out += other.messages_out;
+    }
+}
+
+impl Counters {
+    fn serialize(&self, w: &mut tnetstring::Writer) -> Result<(), io::Error> {
+        w.write_string(b"counters")?;
+        w.start_map()?;
+
+        w.write_string(b"bytes-in")?;
+        w.write_int(self.bytes_in as isize)?;
+
+        w.write_string(b"bytes-out")?;
+        w.write_int(self.bytes_out as isize)?;
+
+        w.write_string(b"messages-in")?;
+        w.write_int(self.messages_in as isize)?;
+
+        w.write_string(b"messages-out")?;
+        w.write_int(self.messages_out as isize)?;
+
+        w.end_map()?;
+
+        Ok(())
+    }
+
+    fn parse(root: tnetstring::MapIterator) -> Result<Option<Self>, ParseError> {
+        for e in root {
+            let e = e?;
+
+            if e.key != "counters" {
+                continue;
+            }
+
+            let mut counters = Self::default();
+
+            for m in tnetstring::parse_map(e.data).field("counters")? {
+                let m = m?;
+
+                let dest = match m.key {
+                    "bytes-in" => &mut counters.bytes_in,
+                    "bytes-out" => &mut counters.bytes_out,
+                    "messages-in" => &mut counters.messages_in,
+                    "messages-out" => &mut counters.messages_out,
+                    _ => continue, // skip unknown fields
+                };
+
+                let x = tnetstring::parse_int(m.data).field("counters")?;
+
+                if x < 0 {
+                    return Err(ParseError::NegativeInt("counters"));
+                }
+
+                *dest = x as u64;
+            }
+
+            return Ok(Some(counters));
+        }
+
+        Ok(None)
+    }
+}
+
+// activity of a session, optionally included with each id of a keep-alive
+// so handlers can expire or audit sessions based on it
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct SessionInfo {
+    pub bytes_in: u64,
+    pub bytes_out: u64,
+    pub idle: Duration,
+}
+
+impl SessionInfo {
+    fn serialize(&self, w: &mut tnetstring::Writer) -> Result<(), io::Error> {
+        w.write_string(b"session")?;
+        w.start_map()?;
+
+        w.write_string(b"bytes-in")?;
+        w.write_int(self.bytes_in as isize)?;
+
+        w.write_string(b"bytes-out")?;
+        w.write_int(self.bytes_out as isize)?;
+
+        w.write_string(b"idle")?;
+        w.write_int(self.idle.as_secs() as isize)?;
+
+        w.end_map()?;
+
+        Ok(())
+    }
+}
+
 pub struct CloseData<'a> {
     // code, reason
     pub status: Option<(u16, &'a str)>,
 }
 
 impl<'a> Serialize<'a> for CloseData<'a> {
-    fn serialize(&self, w: &mut tnetstring::Writer<'a, '_>) -> Result<(), io::Error> {
+    fn serialize(&self, w: &mut tnetstring::Writer<'a, '_, '_>) -> Result<(), io::Error> {
         if let Some(status) = self.status {
             w.write_string(b"code")?;
             w.write_int(status.0 as isize)?;
@@ -1190,7 +1482,7 @@ pub struct PingData<'a> {
 }
 
 impl<'a> Serialize<'a> for PingData<'a> {
-    fn serialize(&self, w: &mut tnetstring::Writer<'a, '_>) -> Result<(), io::Error> {
+    fn serialize(&self, w: &mut tnetstring::Writer<'a, '_, '_>) -> Result<(), io::Error> {
         if !self.body.is_empty() {
             w.write_string(b"body")?;
             w.write_string(self.body)?;
@@ -1218,7 +1510,7 @@ pub struct PongData<'a> {
 }
 
 impl<'a> Serialize<'a> for PongData<'a> {
-    fn serialize(&self, w: &mut tnetstring::Writer<'a, '_>) -> Result<(), io::Error> {
+    fn serialize(&self, w: &mut tnetstring::Writer<'a, '_, '_>) -> Result<(), io::Error> {
         if !self.body.is_empty() {
             w.write_string(b"body")?;
             w.write_string(self.body)?;
@@ -1246,7 +1538,7 @@ pub enum RequestPacket<'buf, 'headers> {
     Error(RequestErrorData<'buf>),
     Credit(CreditData),
     KeepAlive,
-    Cancel,
+    Cancel(CancelData<'buf>),
     HandoffStart,
     HandoffProceed,
     Close(CloseData<'buf>),
@@ -1342,6 +1634,10 @@ pub struct Request<'buf, 'ids, 'headers> {
     pub multi: bool,
     pub ptype: RequestPacket<'buf, 'headers>,
     pub ptype_str: &'buf str,
+    pub counters: Option<Counters>,
+
+    // one per id, or empty
+    pub sessions: &'ids [SessionInfo],
 }
 
 impl<'buf, 'ids, 'headers> Request<'buf, 'ids, 'headers> {
@@ -1370,7 +1666,11 @@ impl<'buf, 'ids, 'headers> Request<'buf, 'ids, 'headers> {
     }
 
     pub fn new_cancel(from: &'buf [u8], ids: &'ids [Id<'buf>]) -> Self {
-        Self::new(from, ids, RequestPacket::Cancel)
+        Self::new(
+            from,
+            ids,
+            RequestPacket::Cancel(CancelData { condition: "" }),
+        )
     }
 
     pub fn new_handoff_start(from: &'buf [u8], ids: &'ids [Id<'buf>]) -> Self {
@@ -1406,6 +1706,19 @@ impl<'buf, 'ids, 'headers> Request<'buf, 'ids, 'headers> {
     }
 
     pub fn serialize(&self, dest: &mut [u8]) -> Result<usize, io::Error> {
+        let (size, _) = self.serialize_deferred(dest, 0)?;
+
+        Ok(size)
+    }
+
+    // like serialize, but the contents of strings of at least defer_min
+    // bytes, such as large bodies, are left out of dest. returns the size
+    // written to dest and the strings left out, for use with gather()
+    pub fn serialize_deferred<'s>(
+        &'s self,
+        dest: &mut [u8],
+        defer_min: usize,
+    ) -> Result<(usize, Deferred<'s>), io::Error> {
         if dest.is_empty() {
             return Err(io::Error::from(io::ErrorKind::WriteZero));
         }
@@ -1414,19 +1727,22 @@ impl<'buf, 'ids, 'headers> Request<'buf, 'ids, 'headers> {
 
         let mut cursor = io::Cursor::new(&mut dest[1..]);
         let mut w = tnetstring::Writer::new(&mut cursor);
+        w.set_defer_min(defer_min);
 
         w.start_map()?;
 
         let common = CommonData {
             from: self.from,
             ids: self.ids,
+            sessions: self.sessions,
             multi: self.multi,
+            handler_refresh: false,
             ptype_str: match &self.ptype {
                 RequestPacket::Data(_) => "",
                 RequestPacket::Error(_) => "error",
                 RequestPacket::Credit(_) => "credit",
                 RequestPacket::KeepAlive => "keep-alive",
-                RequestPacket::Cancel => "cancel",
+                RequestPacket::Cancel(_) => "cancel",
                 RequestPacket::HandoffStart => "handoff-start",
                 RequestPacket::HandoffProceed => "handoff-proceed",
                 RequestPacket::Close(_) => "close",
@@ -1442,17 +1758,25 @@ impl<'buf, 'ids, 'headers> Request<'buf, 'ids, 'headers> {
             RequestPacket::Data(data) => data.serialize(&mut w)?,
             RequestPacket::Error(data) => data.serialize(&mut w)?,
             RequestPacket::Credit(data) => data.serialize(&mut w)?,
+            RequestPacket::Cancel(data) => data.serialize(&mut w)?,
             RequestPacket::Close(data) => data.serialize(&mut w)?,
             RequestPacket::Ping(data) => data.serialize(&mut w)?,
             RequestPacket::Pong(data) => data.serialize(&mut w)?,
             _ => {}
         }
 
+        if let Some(counters) = &self.counters {
+            counters.serialize(&mut w)?;
+        }
+
         w.end_map()?;
 
         w.flush()?;
 
-        Ok((cursor.position() as usize) + 1)
+        // positions are relative to the type byte
+        let deferred = w.deferred().iter().map(|(pos, s)| (pos + 1, *s)).collect();
+
+        Ok(((cursor.position() as usize) + 1, deferred))
     }
 
     fn new(from: &'buf [u8], ids: &'ids [Id<'buf>], ptype: RequestPacket<'buf, 'headers>) -> Self {
@@ -1462,6 +1786,8 @@ impl<'buf, 'ids, 'headers> Request<'buf, 'ids, 'headers> {
             multi: false,
             ptype,
             ptype_str: "",
+            counters: None,
+            sessions: &[],
         }
     }
 }
@@ -1484,6 +1810,7 @@ impl<'buf: 'scratch, 'scratch> PacketParse<'buf, 'scratch> for Request<'buf, 'sc
             ids,
             multi,
             ptype_str,
+            ..
         } = CommonData::parse(root, &mut scratch.ids)?;
 
         let ptype = match ptype_str {
@@ -1492,7 +1819,7 @@ impl<'buf: 'scratch, 'scratch> PacketParse<'buf, 'scratch> for Request<'buf, 'sc
             "error" => RequestPacket::Error(RequestErrorData::parse(root, &mut scratch.headers)?),
             "credit" => RequestPacket::Credit(CreditData::parse(root, &mut scratch.headers)?),
             "keep-alive" => RequestPacket::KeepAlive,
-            "cancel" => RequestPacket::Cancel,
+            "cancel" => RequestPacket::Cancel(CancelData::parse(root, &mut scratch.headers)?),
             "handoff-start" => RequestPacket::HandoffStart,
             "handoff-proceed" => RequestPacket::HandoffProceed,
             "close" => RequestPacket::Close(CloseData::parse(root, &mut scratch.headers)?),
@@ -1501,12 +1828,16 @@ impl<'buf: 'scratch, 'scratch> PacketParse<'buf, 'scratch> for Request<'buf, 'sc
             _ => RequestPacket::Unknown,
         };
 
+        let counters = Counters::parse(root)?;
+
         Ok(Self {
             from,
             ids,
             multi,
             ptype,
             ptype_str,
+            counters,
+            sessions: &[],
         })
     }
 }
@@ -1515,6 +1846,10 @@ pub struct Response<'buf, 'ids, 'headers> {
     pub from: &'buf [u8],
     pub ids: &'ids [Id<'buf>],
     pub multi: bool,
+
+    // the handler refreshes its sessions itself, so keep-alives for them
+    // are not needed
+    pub handler_refresh: bool,
     pub ptype: ResponsePacket<'buf, 'headers>,
     pub ptype_str: &'buf str,
 }
@@ -1591,7 +1926,9 @@ impl<'buf, 'ids, 'headers> Response<'buf, 'ids, 'headers> {
         let common = CommonData {
             from: self.from,
             ids: self.ids,
+            sessions: &[],
             multi: self.multi,
+            handler_refresh: self.handler_refresh,
             ptype_str: match &self.ptype {
                 ResponsePacket::Data(_) => "",
                 ResponsePacket::Error(_) => "error",
@@ -1631,6 +1968,7 @@ impl<'buf, 'ids, 'headers> Response<'buf, 'ids, 'headers> {
             from,
             ids,
             multi: false,
+            handler_refresh: false,
             ptype,
             ptype_str: "",
         }
@@ -1654,7 +1992,9 @@ impl<'buf: 'scratch, 'scratch> PacketParse<'buf, 'scratch> for Response<'buf, 's
             from,
             ids,
             multi,
+            handler_refresh,
             ptype_str,
+            ..
         } = CommonData::parse(root, &mut scratch.ids)?;
 
         let ptype = match ptype_str {
@@ -1676,6 +2016,7 @@ impl<'buf: 'scratch, 'scratch> PacketParse<'buf, 'scratch> for Response<'buf, 's
             from,
             ids,
             multi,
+            handler_refresh,
             ptype,
             ptype_str,
         })
@@ -1754,6 +2095,96 @@ impl OwnedResponse {
     }
 }
 
+// strings left out of a serialized packet, with the positions where they
+// belong
+pub type Deferred<'a> = ArrayVec<(usize, &'a [u8]), { tnetstring::DEFERRED_MAX }>;
+
+// assemble a packet from its serialized part and the strings left out of
+// it, after the given prefix
+pub fn gather(prefix: &[u8], head: &[u8], deferred: &[(usize, &[u8])]) -> Vec<u8> {
+    let size = prefix.len() + head.len() + deferred.iter().map(|(_, s)| s.len()).sum::<usize>();
+
+    let mut v = Vec::with_capacity(size);
+    v.extend_from_slice(prefix);
+
+    let mut pos = 0;
+
+    for (dpos, s) in deferred {
+        v.extend_from_slice(&head[pos..*dpos]);
+        v.extend_from_slice(s);
+        pos = *dpos;
+    }
+
+    v.extend_from_slice(&head[pos..]);
+
+    v
+}
+
+// largest buffer allocated for a packet that doesn't fit in the buffer
+// provided for it
+pub const PACKET_ALLOC_MAX: usize = 16 * 1024 * 1024;
+
+// packets serialized into an allocated buffer because the provided buffer
+// was too small, process-wide
+static OVERSIZED_PACKETS: AtomicU64 = AtomicU64::new(0);
+
+pub fn oversized_packets() -> u64 {
+    OVERSIZED_PACKETS.load(Ordering::Relaxed)
+}
+
+// a serialized packet, either in the provided buffer or in an allocation
+pub enum Serialized<'a> {
+    Borrowed(&'a [u8]),
+    Owned(Vec<u8>),
+}
+
+impl Deref for Serialized<'_> {
+    type Target = [u8];
+
+    fn deref(&self) -> &Self::Target {
+        match self {
+            Self::Borrowed(data) => data,
+            Self::Owned(data) => data,
+        }
+    }
+}
+
+// serialize a packet into buf using f. if buf is too small, the packet is
+// serialized into an allocation instead, doubling its size until the packet
+// fits or the size exceeds PACKET_ALLOC_MAX
+pub fn serialize_or_alloc<F>(buf: &mut [u8], f: F) -> Result<Serialized<'_>, io::Error>
+where
+    F: Fn(&mut [u8]) -> Result<usize, io::Error>,
+{
+    match f(buf) {
+        Ok(size) => return Ok(Serialized::Borrowed(&buf[..size])),
+        Err(e) if e.kind() == io::ErrorKind::WriteZero => {}
+        Err(e) => return Err(e),
+    }
+
+    let mut size = buf.len().max(1024) * 2;
+
+    while size <= PACKET_ALLOC_MAX {
+        let mut v = vec![0; size];
+
+        match f(&mut v) {
+            Ok(size) => {
+                OVERSIZED_PACKETS.fetch_add(1, Ordering::Relaxed);
+
+                v.truncate(size);
+
+                return Ok(Serialized::Owned(v));
+            }
+            Err(e) if e.kind() == io::ErrorKind::WriteZero => {}
+            Err(e) => return Err(e),
+        }
+
+        size *= 2;
+    }
+
+    Err(io::Error::from(io::ErrorKind::WriteZero))
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -1769,6 +2200,19 @@ mod tests {
         }
 
         // data, error, credit, keepalive, cancel, handoffstart/proceed, close, ping, pong
+        const SESSIONS: [SessionInfo; 2] = [
+            SessionInfo {
+                bytes_in: 100,
+                bytes_out: 2000,
+                idle: Duration::from_secs(5),
+            },
+            SessionInfo {
+                bytes_in: 0,
+                bytes_out: 10,
+                idle: Duration::from_millis(500),
+            },
+        ];
+
         let tests = [
             Test {
                 name: "data",
@@ -1793,6 +2237,7 @@ mod tests {
                         }],
                         content_type: None,
                         body: b"hello",
+                        trailers: &[],
                         peer_address: "",
                         peer_port: 0,
                         connect_host: "",
@@ -1801,8 +2246,12 @@ mod tests {
                         trust_connect_host: false,
                         ignore_tls_errors: false,
                         follow_redirects: false,
+                        early_data: false,
+                        tags: &[],
                     }),
                     ptype_str: "",
+                    counters: None,
+                    sessions: &[],
                 },
                 expected: concat!(
                     "T161:4:from,6:client,2:id,1:1,3:seq,1:0#6:method,4:POST,3:uri",
@@ -1823,12 +2272,87 @@ mod tests {
                         condition: "bad-request",
                     }),
                     ptype_str: "",
+                    counters: None,
+                    sessions: &[],
                 },
                 expected: concat!(
                     "T77:4:from,6:client,2:id,1:1,3:seq,1:0#4:type,5:error,9:condi",
                     "tion,11:bad-request,}",
                 ),
             },
+            Test {
+                name: "cancel",
+                req: Request {
+                    from: b"client",
+                    ids: &[Id {
+                        id: b"1",
+                        seq: Some(0),
+                    }],
+                    multi: false,
+                    ptype: RequestPacket::Cancel(CancelData { condition: "" }),
+                    ptype_str: "",
+                    counters: Some(Counters {
+                        bytes_in: 100,
+                        bytes_out: 2000,
+                        messages_in: 1,
+                        messages_out: 0,
+                    }),
+                    sessions: &[],
+                },
+                expected: concat!(
+                    "T141:4:from,6:client,2:id,1:1,3:seq,1:0#4:type,6:cancel,8:cou",
+                    "nters,75:8:bytes-in,3:100#9:bytes-out,4:2000#11:messages-in,1",
+                    ":1#12:messages-out,1:0#}}",
+                ),
+            },
+            Test {
+                name: "keep-alive",
+                req: Request {
+                    from: b"client",
+                    ids: &[
+                        Id {
+                            id: b"1",
+                            seq: Some(0),
+                        },
+                        Id {
+                            id: b"2",
+                            seq: Some(4),
+                        },
+                    ],
+                    multi: true,
+                    ptype: RequestPacket::KeepAlive,
+                    ptype_str: "",
+                    counters: None,
+                    sessions: &SESSIONS,
+                },
+                expected: concat!(
+                    "T236:4:from,6:client,2:id,164:80:2:id,1:1,3:seq,1:0#7:session,",
+                    "47:8:bytes-in,3:100#9:bytes-out,4:2000#4:idle,1:5#}}76:2:id,1:",
+                    "2,3:seq,1:4#7:session,43:8:bytes-in,1:0#9:bytes-out,2:10#4:idl",
+                    "e,1:0#}}]3:ext,15:5:multi,4:true!}4:type,10:keep-alive,}",
+                ),
+            },
+            Test {
+                name: "cancel-condition",
+                req: Request {
+                    from: b"client",
+                    ids: &[Id {
+                        id: b"1",
+                        seq: Some(0),
+                    }],
+                    multi: false,
+                    ptype: RequestPacket::Cancel(CancelData {
+                        condition: "drained",
+                    }),
+                    ptype_str: "",
+                    counters: None,
+                    sessions: &[],
+                },
+                expected: concat!(
+                    "T73:4:from,6:client,2:id,1:1,3:seq,1:0#4:type,6:cancel,9:condit",
+                    "ion,7:drained,}",
+                ),
+            },
         ];
 
         for test in tests.iter() {
@@ -1864,6 +2388,7 @@ mod tests {
                         seq: Some(0),
                     }],
                     multi: false,
+                    handler_refresh: false,
                     ptype: ResponsePacket::Data(ResponseData {
                         credits: 0,
                         more: true,
@@ -1875,6 +2400,10 @@ mod tests {
                         }],
                         content_type: None,
                         body: b"hello",
+                        trailers: &[],
+                        download_rate: 0,
+                        sse: false,
+                        file: None,
                     }),
                     ptype_str: "",
                 },
@@ -1893,6 +2422,7 @@ mod tests {
                         seq: Some(0),
                     }],
                     multi: false,
+                    handler_refresh: false,
                     ptype: ResponsePacket::Error(ResponseErrorData {
                         condition: "bad-request",
                         rejected_info: None,
@@ -1920,6 +2450,108 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_serialize_or_alloc() {
+        let body = [b'a'; 3000];
+
+        let ids = [Id {
+            id: b"1",
+            seq: Some(0),
+        }];
+
+        let req = Request::new_data(b"client", &ids, RequestData::new());
+        let mut req_big = Request::new_data(b"client", &ids, RequestData::new());
+
+        if let RequestPacket::Data(data) = &mut req_big.ptype {
+            data.body = &body;
+        }
+
+        let mut buf = [0; 1024];
+
+        let data = serialize_or_alloc(&mut buf, |dest| req.serialize(dest)).unwrap();
+        assert!(matches!(data, Serialized::Borrowed(_)));
+
+        let before = oversized_packets();
+
+        let data = serialize_or_alloc(&mut buf, |dest| req_big.serialize(dest)).unwrap();
+        assert!(matches!(data, Serialized::Owned(_)));
+        assert!(oversized_packets() > before);
+
+        let mut expected = vec![0; 4096];
+        let size = req_big.serialize(&mut expected).unwrap();
+        assert_eq!(&*data, &expected[..size]);
+
+        let e = serialize_or_alloc(&mut buf, |_| Err(io::Error::from(io::ErrorKind::WriteZero)))
+            .err()
+            .unwrap();
+        assert_eq!(e.kind(), io::ErrorKind::WriteZero);
+    }
+
+    #[test]
+    fn test_req_serialize_deferred() {
+        let body = [b'a'; 100];
+
+        let ids = [Id {
+            id: b"1",
+            seq: Some(0),
+        }];
+
+        let mut data = RequestData::new();
+        data.method = "POST";
+        data.uri = "http://example.com/path";
+        data.body = &body;
+
+        let req = Request::new_data(b"client", &ids, data);
+
+        let mut expected = [0; 1024];
+        let size = req.serialize(&mut expected).unwrap();
+        let expected = &expected[..size];
+
+        let mut buf = [0; 1024];
+        let (size, deferred) = req.serialize_deferred(&mut buf, 100).unwrap();
+        assert_eq!(size, expected.len() - body.len());
+        assert_eq!(deferred.len(), 1);
+        assert_eq!(deferred[0].1, &body[..]);
+
+        let v = gather(b"addr ", &buf[..size], &deferred);
+        assert_eq!(&v[..5], b"addr ");
+        assert_eq!(&v[5..], expected);
+
+        // nothing large enough to defer
+        let (size, deferred) = req.serialize_deferred(&mut buf, 101).unwrap();
+        assert!(deferred.is_empty());
+        assert_eq!(&buf[..size], expected);
+    }
+
+    #[test]
+    fn test_req_tags() {
+        let ids = [Id {
+            id: b"1",
+            seq: Some(0),
+        }];
+
+        let tags = [Header {
+            name: "zone",
+            value: b"internal",
+        }];
+
+        let mut data = RequestData::new();
+        data.method = "GET";
+        data.tags = &tags;
+
+        let req = Request::new_data(b"client", &ids, data);
+
+        let mut buf = [0; 1024];
+        let size = req.serialize(&mut buf).unwrap();
+
+        let expected = concat!(
+            "T79:4:from,6:client,2:id,1:1,3:seq,1:0#6:method,3:GET,4:tag",
+            "s,18:4:zone,8:internal,}}",
+        );
+
+        assert_eq!(str::from_utf8(&buf[..size]).unwrap(), expected);
+    }
+
     #[test]
     fn test_req_parse() {
         let data = concat!(
@@ -1954,6 +2586,48 @@ mod tests {
 
         let ctype = rdata.content_type.unwrap();
         assert_eq!(ctype, ContentType::Binary);
+        assert_eq!(req.counters, None);
+
+        let data = concat!(
+            "T141:4:from,6:client,2:id,1:1,3:seq,1:0#4:type,6:cancel,8:cou",
+            "nters,75:8:bytes-in,3:100#9:bytes-out,4:2000#11:messages-in,1",
+            ":1#12:messages-out,1:0#}}",
+        )
+        .as_bytes();
+
+        let mut scratch = ParseScratch::new();
+        let req = Request::parse(&data, &mut scratch).unwrap();
+
+        assert!(matches!(
+            req.ptype,
+            RequestPacket::Cancel(CancelData { condition: "" })
+        ));
+        assert_eq!(
+            req.counters,
+            Some(Counters {
+                bytes_in: 100,
+                bytes_out: 2000,
+                messages_in: 1,
+                messages_out: 0,
+            })
+        );
+
+        let data = concat!(
+            "T73:4:from,6:client,2:id,1:1,3:seq,1:0#4:type,6:cancel,9:condi",
+            "tion,7:drained,}",
+        )
+        .as_bytes();
+
+        let mut scratch = ParseScratch::new();
+        let req = Request::parse(&data, &mut scratch).unwrap();
+
+        assert!(matches!(
+            req.ptype,
+            RequestPacket::Cancel(CancelData {
+                condition: "drained"
+            })
+        ));
+        assert_eq!(req.counters, None);
     }
 
     #[test]
@@ -1990,6 +2664,184 @@ mod tests {
 
         let ctype = rdata.content_type.unwrap();
         assert_eq!(ctype, ContentType::Binary);
+
+        assert_eq!(rdata.download_rate, 0);
+    }
+
+    #[test]
+    fn test_resp_download_rate() {
+        let resp = Response {
+            from: b"server",
+            ids: &[Id {
+                id: b"1",
+                seq: Some(0),
+            }],
+            multi: false,
+            handler_refresh: false,
+            ptype: ResponsePacket::Data(ResponseData {
+                download_rate: 1000,
+                ..ResponseData::new()
+            }),
+            ptype_str: "",
+        };
+
+        let mut data = [0; 1024];
+        let size = resp.serialize(&mut data).unwrap();
+
+        assert_eq!(
+            str::from_utf8(&data[..size]).unwrap(),
+            "T59:4:from,6:server,2:id,1:1,3:seq,1:0#13:download-rate,4:1000#}"
+        );
+
+        let mut scratch = ParseScratch::new();
+        let resp = Response::parse(&data[..size], &mut scratch).unwrap();
+
+        let rdata = match resp.ptype {
+            ResponsePacket::Data(data) => data,
+            _ => panic!("expected data packet"),
+        };
+
+        assert_eq!(rdata.download_rate, 1000);
+    }
+
+    #[test]
+    fn test_resp_sse() {
+        let resp = Response {
+            from: b"server",
+            ids: &[Id {
+                id: b"1",
+                seq: Some(0),
+            }],
+            multi: false,
+            handler_refresh: false,
+            ptype: ResponsePacket::Data(ResponseData {
+                sse: true,
+                ..ResponseData::new()
+            }),
+            ptype_str: "",
+        };
+
+        let mut data = [0; 1024];
+        let size = resp.serialize(&mut data).unwrap();
+
+        assert_eq!(
+            str::from_utf8(&data[..size]).unwrap(),
+            "T48:4:from,6:server,2:id,1:1,3:seq,1:0#3:sse,4:true!}"
+        );
+
+        let mut scratch = ParseScratch::new();
+        let resp = Response::parse(&data[..size], &mut scratch).unwrap();
+
+        let rdata = match resp.ptype {
+            ResponsePacket::Data(data) => data,
+            _ => panic!("expected data packet"),
+        };
+
+        assert_eq!(rdata.sse, true);
+    }
+
+    #[test]
+    fn test_resp_file() {
+        let resp = Response {
+            from: b"server",
+            ids: &[Id {
+                id: b"1",
+                seq: Some(0),
+            }],
+            multi: false,
+            handler_refresh: false,
+            ptype: ResponsePacket::Data(ResponseData {
+                file: Some(ResponseFile {
+                    path: "/tmp/a",
+                    offset: 10,
+                    length: Some(20),
+                }),
+                ..ResponseData::new()
+            }),
+            ptype_str: "",
+        };
+
+        let mut data = [0; 1024];
+        let size = resp.serialize(&mut data).unwrap();
+
+        assert_eq!(
+            str::from_utf8(&data[..size]).unwrap(),
+            concat!(
+                "T90:4:from,6:server,2:id,1:1,3:seq,1:0#4:file,44:4:path,6:/tmp/a,",
+                "6:offset,2:10#6:length,2:20#}}",
+            )
+        );
+
+        let mut scratch = ParseScratch::new();
+        let resp = Response::parse(&data[..size], &mut scratch).unwrap();
+
+        let rdata = match resp.ptype {
+            ResponsePacket::Data(data) => data,
+            _ => panic!("expected data packet"),
+        };
+
+        assert_eq!(
+            rdata.file,
+            Some(ResponseFile {
+                path: "/tmp/a",
+                offset: 10,
+                length: Some(20),
+            })
+        );
+
+        let mut scratch = ParseScratch::new();
+        let ret = Response::parse(
+            b"T45:4:from,6:server,2:id,1:1,3:seq,1:0#4:file,0:}}",
+            &mut scratch,
+        );
+        assert!(matches!(ret, Err(ParseError::MissingField("file"))));
+    }
+
+    #[test]
+    fn test_trailers() {
+        let trailers = [Header {
+            name: "Grpc-Status",
+            value: b"0",
+        }];
+
+        let mut data = RequestData::new();
+        data.body = b"hello";
+        data.trailers = &trailers;
+
+        let req = Request::new_data(b"client", &[], data);
+
+        let mut buf = [0; 1024];
+        let size = req.serialize(&mut buf).unwrap();
+
+        let expected = concat!(
+            "T69:4:from,6:client,4:body,5:hello,8:trailers,23:19:11:Grpc-Sta",
+            "tus,1:0,]]}",
+        );
+
+        assert_eq!(str::from_utf8(&buf[..size]).unwrap(), expected);
+
+        // headers and trailers share the scratch space, in any order
+        let data = concat!(
+            "T122:4:from,7:handler,2:id,1:1,3:seq,1:0#8:trailers,23:19:11:G",
+            "rpc-Status,1:0,]]7:headers,34:30:12:Content-Type,10:text/plain",
+            ",]]}",
+        )
+        .as_bytes();
+
+        let mut scratch = ParseScratch::new();
+        let resp = Response::parse(&data, &mut scratch).unwrap();
+
+        let rdata = match resp.ptype {
+            ResponsePacket::Data(data) => data,
+            _ => panic!("expected data packet"),
+        };
+
+        assert_eq!(rdata.headers.len(), 1);
+        assert_eq!(rdata.headers[0].name, "Content-Type");
+        assert_eq!(rdata.headers[0].value, b"text/plain");
+        assert_eq!(rdata.trailers.len(), 1);
+        assert_eq!(rdata.trailers[0].name, "Grpc-Status");
+        assert_eq!(rdata.trailers[0].value, b"0");
     }
 
     #[test]
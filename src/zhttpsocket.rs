@@ -20,16 +20,16 @@ use crate::channel;
 use crate::event;
 use crate::executor::Executor;
 use crate::future::{
-    select_10, select_9, select_option, select_slice, AsyncReceiver, AsyncSender, AsyncZmqSocket,
-    RecvFuture, Select10, Select9, WaitWritableFuture, ZmqSendFuture, ZmqSendToFuture,
+    select_10, select_option, select_slice, AsyncReceiver, AsyncSender, AsyncZmqSocket, RecvFuture,
+    Select10, Timeout, WaitWritableFuture, ZmqSendFuture, ZmqSendToFuture,
     REGISTRATIONS_PER_CHANNEL, REGISTRATIONS_PER_ZMQSOCKET,
 };
 use crate::list;
-use crate::pin;
 use crate::reactor::Reactor;
 use crate::tnetstring;
 use crate::zhttppacket::{parse_ids, Id, ParseScratch};
 use crate::zmq::{MultipartHeader, SpecInfo, ZmqSocket};
+use crate::{pin, spawn_thread};
 use arrayvec::{ArrayString, ArrayVec};
 use log::{debug, error, log_enabled, trace, warn};
 use slab::Slab;
@@ -46,7 +46,7 @@ use std::str::FromStr;
 use std::sync::{mpsc, Arc, Mutex};
 use std::task::{Context, Poll};
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
 pub const FROM_MAX: usize = 64;
 pub const REQ_ID_MAX: usize = 64;
@@ -56,6 +56,8 @@ const STREAM_OUT_STREAM_DELAY: Duration = Duration::from_millis(50);
 const LOG_METADATA_MAX: usize = 1_000;
 const LOG_CONTENT_MAX: usize = 1_000;
 const EXECUTOR_TASKS_MAX: usize = 1;
+const QUEUE_ADDRS_MAX: usize = 100;
+const QUEUE_WARN_INTERVAL: Duration = Duration::from_secs(10);
 
 struct Packet<'a> {
     map_frame: tnetstring::Frame<'a>,
@@ -398,6 +400,7 @@ struct AsyncStreamPipeEnd {
 
 enum ControlRequest {
     Stop,
+    Drain(Duration),
     SetClientReq(Vec<SpecInfo>),
     SetClientStream(Vec<SpecInfo>, Vec<SpecInfo>, Vec<SpecInfo>),
     AddClientReqHandle(ReqPipeEnd, ArrayString<8>),
@@ -453,6 +456,34 @@ struct StreamPipe {
     pe: AsyncStreamPipeEnd,
     filter: ArrayString<8>,
     valid: Cell<bool>,
+
+    // the pipe has two outbound queues that are closed independently. it is
+    // only invalidated once both are closed, so that neither gets discarded
+    // while it still has messages
+    any_open: Cell<bool>,
+    addr_open: Cell<bool>,
+}
+
+impl StreamPipe {
+    fn close_any(&self) -> bool {
+        self.any_open.set(false);
+
+        if !self.addr_open.get() {
+            self.valid.set(false);
+        }
+
+        !self.valid.get()
+    }
+
+    fn close_addr(&self) -> bool {
+        self.addr_open.set(false);
+
+        if !self.any_open.get() {
+            self.valid.set(false);
+        }
+
+        !self.valid.get()
+    }
 }
 
 struct ServerReqPipe {
@@ -569,8 +600,10 @@ impl ReqHandles {
         self.list.push_back(&mut self.nodes, key);
     }
 
+    // returns None if a handle disconnected, so that the caller can clean
+    // it up
     #[allow(clippy::await_holding_refcell_ref)]
-    async fn recv(&self) -> zmq::Message {
+    async fn recv(&self) -> Option<zmq::Message> {
         let mut scratch = self.recv_scratch.borrow_mut();
 
         let (mut tasks, slice_scratch) = scratch.get();
@@ -593,17 +626,15 @@ impl ReqHandles {
             next = n.next;
         }
 
-        loop {
-            match select_slice(&mut tasks, slice_scratch).await {
-                (_, (_, Ok(msg))) => return msg,
-                (pos, (nkey, Err(mpsc::RecvError))) => {
-                    tasks.remove(pos);
+        match select_slice(&mut tasks, slice_scratch).await {
+            (_, (_, Ok(msg))) => Some(msg),
+            (_, (nkey, Err(mpsc::RecvError))) => {
+                let p = &self.nodes[nkey].value;
+                p.valid.set(false);
 
-                    let p = &self.nodes[nkey].value;
-                    p.valid.set(false);
+                self.need_cleanup.set(true);
 
-                    self.need_cleanup.set(true);
-                }
+                None
             }
         }
     }
@@ -699,13 +730,17 @@ impl StreamHandles {
             pe,
             filter,
             valid: Cell::new(true),
+            any_open: Cell::new(true),
+            addr_open: Cell::new(true),
         }));
 
         self.list.push_back(&mut self.nodes, key);
     }
 
+    // returns None if a handle closed its queue, so that the caller can
+    // clean it up
     #[allow(clippy::await_holding_refcell_ref)]
-    async fn recv_any(&self) -> zmq::Message {
+    async fn recv_any(&self) -> Option<zmq::Message> {
         let mut scratch = self.recv_any_scratch.borrow_mut();
 
         let (mut tasks, slice_scratch) = scratch.get();
@@ -716,7 +751,7 @@ impl StreamHandles {
             let n = &self.nodes[nkey];
             let p = &n.value;
 
-            if p.valid.get() {
+            if p.valid.get() && p.any_open.get() {
                 assert!(tasks.len() < tasks.capacity());
 
                 tasks.push(RecvWrapperFuture {
@@ -728,23 +763,23 @@ impl StreamHandles {
             next = n.next;
         }
 
-        loop {
-            match select_slice(&mut tasks, slice_scratch).await {
-                (_, (_, Ok(msg))) => return msg,
-                (pos, (nkey, Err(mpsc::RecvError))) => {
-                    tasks.remove(pos);
-
-                    let p = &self.nodes[nkey].value;
-                    p.valid.set(false);
+        match select_slice(&mut tasks, slice_scratch).await {
+            (_, (_, Ok(msg))) => Some(msg),
+            (_, (nkey, Err(mpsc::RecvError))) => {
+                let p = &self.nodes[nkey].value;
 
+                if p.close_any() {
                     self.need_cleanup.set(true);
                 }
+
+                None
             }
         }
     }
 
+    // returns None if a handle closed its queue, like recv_any
     #[allow(clippy::await_holding_refcell_ref)]
-    async fn recv_addr(&self) -> (ArrayVec<u8, 64>, zmq::Message) {
+    async fn recv_addr(&self) -> Option<(ArrayVec<u8, 64>, zmq::Message)> {
         let mut scratch = self.recv_addr_scratch.borrow_mut();
 
         let (mut tasks, slice_scratch) = scratch.get();
@@ -755,7 +790,7 @@ impl StreamHandles {
             let n = &self.nodes[nkey];
             let p = &n.value;
 
-            if p.valid.get() {
+            if p.valid.get() && p.addr_open.get() {
                 assert!(tasks.len() < tasks.capacity());
 
                 tasks.push(RecvWrapperFuture {
@@ -767,17 +802,16 @@ impl StreamHandles {
             next = n.next;
         }
 
-        loop {
-            match select_slice(&mut tasks, slice_scratch).await {
-                (_, (_, Ok(ret))) => return ret,
-                (pos, (nkey, Err(mpsc::RecvError))) => {
-                    tasks.remove(pos);
-
-                    let p = &self.nodes[nkey].value;
-                    p.valid.set(false);
+        match select_slice(&mut tasks, slice_scratch).await {
+            (_, (_, Ok(ret))) => Some(ret),
+            (_, (nkey, Err(mpsc::RecvError))) => {
+                let p = &self.nodes[nkey].value;
 
+                if p.close_addr() {
                     self.need_cleanup.set(true);
                 }
+
+                None
             }
         }
     }
@@ -961,9 +995,9 @@ impl ServerReqHandles {
     }
 
     // non-blocking send. caller should use check_send() first
-    fn send(&self, header: MultipartHeader, msg: &arena::Arc<zmq::Message>) {
+    fn send(&self, header: MultipartHeader, msg: &arena::Arc<zmq::Message>) -> Delivery {
         if self.nodes.is_empty() {
-            return;
+            return Delivery::Dropped;
         }
 
         let mut skip = self.send_index.get();
@@ -988,14 +1022,20 @@ impl ServerReqHandles {
             let p = &n.value;
 
             match p.pe.sender.try_send((header, arena::Arc::clone(msg))) {
-                Ok(_) => {}
-                Err(mpsc::TrySendError::Full(_)) => error!("req sender is full"),
+                Ok(_) => return Delivery::Delivered,
+                Err(mpsc::TrySendError::Full(_)) => {
+                    error!("req sender is full");
+
+                    return Delivery::Full;
+                }
                 Err(mpsc::TrySendError::Disconnected(_)) => {
                     p.valid.set(false);
                     self.need_cleanup.set(true);
                 }
             }
         }
+
+        Delivery::Dropped
     }
 
     fn need_cleanup(&self) -> bool {
@@ -1121,9 +1161,9 @@ impl ServerStreamHandles {
     }
 
     // non-blocking send. caller should use check_send_any() first
-    fn send_any(&self, msg: &arena::Arc<zmq::Message>, from: &[u8], ids: &[Id<'_>]) {
+    fn send_any(&self, msg: &arena::Arc<zmq::Message>, from: &[u8], ids: &[Id<'_>]) -> Delivery {
         if self.nodes.is_empty() || ids.is_empty() {
-            return;
+            return Delivery::Dropped;
         }
 
         let mut skip = self.send_index.get();
@@ -1149,20 +1189,24 @@ impl ServerStreamHandles {
 
             let from = match ArrayVec::try_from(from) {
                 Ok(v) => v,
-                Err(_) => return,
+                Err(_) => return Delivery::Dropped,
             };
 
             let id = match ArrayVec::try_from(ids[0].id) {
                 Ok(v) => v,
-                Err(_) => return,
+                Err(_) => return Delivery::Dropped,
             };
 
             let key = (from, id);
 
             if let Ok(session) = self.sessions.add(key, nkey) {
                 match p.pe.sender_any.try_send((arena::Arc::clone(msg), session)) {
-                    Ok(_) => {}
-                    Err(mpsc::TrySendError::Full(_)) => error!("stream sender_any is full"),
+                    Ok(_) => return Delivery::Delivered,
+                    Err(mpsc::TrySendError::Full(_)) => {
+                        error!("stream sender_any is full");
+
+                        return Delivery::Full;
+                    }
                     Err(mpsc::TrySendError::Disconnected(_)) => {
                         p.valid.set(false);
 
@@ -1171,17 +1215,24 @@ impl ServerStreamHandles {
                 }
             }
         }
+
+        Delivery::Dropped
     }
 
     #[allow(clippy::await_holding_refcell_ref)]
-    async fn send_direct(&self, msg: &arena::Arc<zmq::Message>, from: &[u8], ids: &[Id<'_>]) {
+    async fn send_direct(
+        &self,
+        msg: &arena::Arc<zmq::Message>,
+        from: &[u8],
+        ids: &[Id<'_>],
+    ) -> Delivery {
         if self.nodes.is_empty() {
-            return;
+            return Delivery::Dropped;
         }
 
         let from = match ArrayVec::try_from(from) {
             Ok(v) => v,
-            Err(_) => return,
+            Err(_) => return Delivery::Dropped,
         };
 
         let indexes = &mut *self.send_direct_scratch.borrow_mut();
@@ -1194,7 +1245,7 @@ impl ServerStreamHandles {
         for id in ids {
             let id = match ArrayVec::try_from(id.id) {
                 Ok(v) => v,
-                Err(_) => return,
+                Err(_) => return Delivery::Dropped,
             };
 
             let key = (from.clone(), id);
@@ -1204,6 +1255,8 @@ impl ServerStreamHandles {
             }
         }
 
+        let mut delivery = Delivery::Dropped;
+
         for (nkey, &do_send) in indexes.iter().enumerate() {
             let n = match self.nodes.get(nkey) {
                 Some(n) => n,
@@ -1216,7 +1269,7 @@ impl ServerStreamHandles {
                 // blocking send. handle is expected to read as fast as possible
                 //   without downstream backpressure
                 match p.pe.sender_direct.send(arena::Arc::clone(msg)).await {
-                    Ok(_) => {}
+                    Ok(_) => delivery = Delivery::Delivered,
                     Err(_) => {
                         p.valid.set(false);
 
@@ -1225,6 +1278,8 @@ impl ServerStreamHandles {
                 }
             }
         }
+
+        delivery
     }
 
     fn need_cleanup(&self) -> bool {
@@ -1255,8 +1310,238 @@ impl ServerStreamHandles {
     }
 }
 
+// activity of an outbound zmq socket, or of the messages sent to one
+// handler address. a message that had to wait before it could be written
+// counts as a high water mark hit. a message that failed to be written
+// counts as an error rather than as sent
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct SendStats {
+    pub sent: u64,
+    pub hwm_hits: u64,
+    pub hwm_wait: Duration,
+    pub errors: u64,
+}
+
+impl SendStats {
+    fn add(&mut self, wait: Option<Duration>) {
+        self.sent += 1;
+
+        if let Some(wait) = wait {
+            self.hwm_hits += 1;
+            self.hwm_wait += wait;
+        }
+    }
+}
+
+// outbound queue activity of a client socket manager. handlers that are
+// slow to read cause high water mark hits before sessions time out
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct QueueStats {
+    pub req: SendStats,
+    pub stream: SendStats,
+    pub stream_to: SendStats,
+
+    // addressed stream messages per handler address, most hwm hits first.
+    // only a limited number of addresses are tracked
+    pub stream_to_addrs: Vec<(String, SendStats)>,
+}
+
+#[derive(Clone, Copy)]
+enum SendKind {
+    Req,
+    Stream,
+    StreamTo,
+}
+
+impl SendKind {
+    fn name(&self) -> &'static str {
+        match self {
+            SendKind::Req => "req",
+            SendKind::Stream => "stream",
+            SendKind::StreamTo => "stream to",
+        }
+    }
+}
+
+#[derive(Default)]
+struct QueueData {
+    req: SendStats,
+    stream: SendStats,
+    stream_to: SendStats,
+    stream_to_addrs: HashMap<Vec<u8>, SendStats>,
+
+    // start of the send in progress of each kind, if any
+    pending: [Option<Instant>; 3],
+}
+
+impl QueueData {
+    fn is_reachable(&self, now: Instant, max_wait: Duration) -> bool {
+        self.pending.iter().all(|since| match since {
+            Some(since) => now.saturating_duration_since(*since) < max_wait,
+            None => true,
+        })
+    }
+
+    fn stats(&self) -> QueueStats {
+        let mut addrs: Vec<(String, SendStats)> = self
+            .stream_to_addrs
+            .iter()
+            .map(|(addr, s)| (String::from_utf8_lossy(addr).into_owned(), *s))
+            .collect();
+
+        addrs.sort_by(|a, b| b.1.hwm_hits.cmp(&a.1.hwm_hits).then(a.0.cmp(&b.0)));
+
+        QueueStats {
+            req: self.req,
+            stream: self.stream,
+            stream_to: self.stream_to,
+            stream_to_addrs: addrs,
+        }
+    }
+}
+
+// records sends into shared queue data, and warns about high water mark
+// hits at most once per interval per kind of send
+struct QueueMonitor {
+    data: Arc<Mutex<QueueData>>,
+    last_warn: [Option<Instant>; 3],
+    hits_since_warn: [u64; 3],
+}
+
+impl QueueMonitor {
+    fn new(data: Arc<Mutex<QueueData>>) -> Self {
+        Self {
+            data,
+            last_warn: [None; 3],
+            hits_since_warn: [0; 3],
+        }
+    }
+
+    fn update<F>(&self, kind: SendKind, addr: Option<&[u8]>, f: F)
+    where
+        F: Fn(&mut SendStats),
+    {
+        let data = &mut *self.data.lock().unwrap();
+
+        data.pending[kind as usize] = None;
+
+        let stats = match kind {
+            SendKind::Req => &mut data.req,
+            SendKind::Stream => &mut data.stream,
+            SendKind::StreamTo => &mut data.stream_to,
+        };
+
+        f(stats);
+
+        if let Some(addr) = addr {
+            if let Some(stats) = data.stream_to_addrs.get_mut(addr) {
+                f(stats);
+            } else if data.stream_to_addrs.len() < QUEUE_ADDRS_MAX {
+                let mut stats = SendStats::default();
+                f(&mut stats);
+
+                data.stream_to_addrs.insert(addr.to_vec(), stats);
+            }
+        }
+    }
+
+    // note that a send has begun, so that one stuck waiting can be noticed
+    // before it finishes
+    fn start(&mut self, kind: SendKind, now: Instant) {
+        self.data.lock().unwrap().pending[kind as usize] = Some(now);
+    }
+
+    fn record_error(&mut self, kind: SendKind, addr: Option<&[u8]>) {
+        self.update(kind, addr, |stats| stats.errors += 1);
+    }
+
+    fn record(
+        &mut self,
+        kind: SendKind,
+        addr: Option<&[u8]>,
+        blocked_since: Option<Instant>,
+        now: Instant,
+    ) {
+        let wait = blocked_since.map(|t| now.saturating_duration_since(t));
+
+        self.update(kind, addr, |stats| stats.add(wait));
+
+        let wait = match wait {
+            Some(wait) => wait,
+            None => return,
+        };
+
+        let i = kind as usize;
+
+        self.hits_since_warn[i] += 1;
+
+        if let Some(last) = self.last_warn[i] {
+            if now < last + QUEUE_WARN_INTERVAL {
+                return;
+            }
+        }
+
+        let to = match addr {
+            Some(addr) => format!(" {}", String::from_utf8_lossy(addr)),
+            None => String::new(),
+        };
+
+        warn!(
+            "zhttp {} send{} waited {:?} at high water mark ({} times since last warning), handler may be slow",
+            kind.name(),
+            to,
+            wait,
+            self.hits_since_warn[i],
+        );
+
+        self.last_warn[i] = Some(now);
+        self.hits_since_warn[i] = 0;
+    }
+}
+
+// outcome of passing a received message to a handle
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Delivery {
+    Delivered,
+
+    // the queue of the selected handle was full
+    Full,
+
+    // no handle could take the message
+    Dropped,
+}
+
+// messages passed between a server socket manager and one kind of handle.
+// full and dropped messages are lost
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct HandleStats {
+    pub delivered: u64,
+    pub full: u64,
+    pub dropped: u64,
+
+    // messages from handles that failed to be written to zmq
+    pub send_errors: u64,
+}
+
+impl HandleStats {
+    fn record(&mut self, d: Delivery) {
+        match d {
+            Delivery::Delivered => self.delivered += 1,
+            Delivery::Full => self.full += 1,
+            Delivery::Dropped => self.dropped += 1,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ServerHandleStats {
+    pub req: HandleStats,
+    pub stream: HandleStats,
+}
+
 pub struct ClientSocketManager {
     handle_bound: usize,
+    queue_data: Arc<Mutex<QueueData>>,
     thread: Option<thread::JoinHandle<()>>,
     control_pipe: Mutex<(
         channel::Sender<ControlRequest>,
@@ -1279,52 +1564,54 @@ impl ClientSocketManager {
         init_hwm: usize,
         other_hwm: usize,
         handle_bound: usize,
-    ) -> Self {
+    ) -> Result<Self, String> {
         let (s1, r1) = channel::channel(1);
         let (s2, r2) = channel::channel(1);
 
         let instance_id = String::from(instance_id);
 
-        let thread = thread::Builder::new()
-            .name("zhttpsocket".to_string())
-            .spawn(move || {
-                debug!("manager thread start");
+        let queue_data = Arc::new(Mutex::new(QueueData::default()));
+        let monitor = QueueMonitor::new(Arc::clone(&queue_data));
 
-                // 2 control channels, 3 channels per handle, 4 zmq sockets
-                let channels = 2 + (HANDLES_MAX * 3);
-                let zmqsockets = 4;
+        let thread = spawn_thread("zhttpsocket".to_string(), move || {
+            debug!("manager thread start");
 
-                let registrations_max = (channels * REGISTRATIONS_PER_CHANNEL)
-                    + (zmqsockets * REGISTRATIONS_PER_ZMQSOCKET);
+            // 2 control channels, 3 channels per handle, 4 zmq sockets
+            let channels = 2 + (HANDLES_MAX * 3);
+            let zmqsockets = 4;
 
-                let reactor = Reactor::new(registrations_max);
+            let registrations_max =
+                (channels * REGISTRATIONS_PER_CHANNEL) + (zmqsockets * REGISTRATIONS_PER_ZMQSOCKET);
 
-                let executor = Executor::new(EXECUTOR_TASKS_MAX);
+            let reactor = Reactor::new(registrations_max);
 
-                executor
-                    .spawn(Self::run(
-                        ctx,
-                        s1,
-                        r2,
-                        instance_id,
-                        retained_max,
-                        init_hwm,
-                        other_hwm,
-                        handle_bound,
-                    ))
-                    .unwrap();
+            let executor = Executor::new(EXECUTOR_TASKS_MAX);
 
-                executor.run(|timeout| reactor.poll(timeout)).unwrap();
+            executor
+                .spawn(Self::run(
+                    ctx,
+                    s1,
+                    r2,
+                    instance_id,
+                    retained_max,
+                    init_hwm,
+                    other_hwm,
+                    handle_bound,
+                    monitor,
+                ))
+                .unwrap();
 
-                debug!("manager thread end");
-            })
-            .unwrap();
+            executor.run(|timeout| reactor.poll(timeout)).unwrap();
 
-        Self {
+            debug!("manager thread end");
+        })?;
+
+        Ok(Self {
             handle_bound,
+            queue_data,
             thread: Some(thread),
             control_pipe: Mutex::new((s2, r1)),
-        }
+        })
     }
 
     pub fn set_client_req_specs(&mut self, specs: &[SpecInfo]) -> Result<(), String> {
@@ -1385,6 +1672,27 @@ impl ClientSocketManager {
         }
     }
 
+    pub fn queue_stats(&self) -> QueueStats {
+        self.queue_data.lock().unwrap().stats()
+    }
+
+    // whether the handlers are taking messages. a send waiting longer than
+    // max_wait means no handler is connected, or none is keeping up
+    pub fn is_reachable(&self, max_wait: Duration) -> bool {
+        self.queue_data
+            .lock()
+            .unwrap()
+            .is_reachable(Instant::now(), max_wait)
+    }
+
+    // wait until every handle has been dropped and the messages they queued
+    // have been written to the zmq sockets. handles should be dropped before
+    // calling this or it will time out. this is useful before shutdown, so
+    // that final messages such as cancels aren't lost
+    pub fn drain(&self, timeout: Duration) -> Result<(), String> {
+        self.control_req(ControlRequest::Drain(timeout))
+    }
+
     fn control_send(&self, req: ControlRequest) {
         let pipe = self.control_pipe.lock().unwrap();
 
@@ -1410,7 +1718,9 @@ impl ClientSocketManager {
         init_hwm: usize,
         other_hwm: usize,
         handle_bound: usize,
+        mut monitor: QueueMonitor,
     ) {
+        let reactor = Reactor::current().unwrap();
         let control_sender = AsyncSender::new(control_sender);
         let control_receiver = AsyncReceiver::new(control_receiver);
 
@@ -1493,6 +1803,9 @@ impl ClientSocketManager {
         let mut req_send: Option<ZmqSendToFuture> = None;
         let mut stream_out_send: Option<ZmqSendFuture> = None;
         let mut stream_out_stream_send: Option<ZmqSendToFuture> = None;
+        let mut stream_out_stream_addr = None;
+
+        let mut drain_timeout: Option<Timeout> = None;
 
         loop {
             let req_handles_recv = if req_send.is_none() {
@@ -1513,7 +1826,7 @@ impl ClientSocketManager {
                 None
             };
 
-            let result = select_9(
+            let result = select_10(
                 control_receiver.recv(),
                 select_option(pin!(req_handles_recv).as_pin_mut()),
                 select_option(req_send.as_mut()),
@@ -1523,14 +1836,20 @@ impl ClientSocketManager {
                 select_option(pin!(stream_handles_recv_addr).as_pin_mut()),
                 select_option(stream_out_stream_send.as_mut()),
                 client_stream.in_.recv(),
+                select_option(drain_timeout.as_ref().map(|t| t.elapsed())),
             )
             .await;
 
             match result {
                 // control_receiver.recv
-                Select9::R1(result) => match result {
+                Select10::R1(result) => match result {
                     Ok(req) => match req {
                         ControlRequest::Stop => break,
+                        ControlRequest::Drain(timeout) => {
+                            debug!("draining handles");
+
+                            drain_timeout = Some(Timeout::new(reactor.now() + timeout));
+                        }
                         ControlRequest::SetClientReq(specs) => {
                             debug!("applying req specs: {:?}", specs);
 
@@ -1594,25 +1913,33 @@ impl ClientSocketManager {
                     Err(e) => error!("control recv: {}", e),
                 },
                 // req_handles_recv
-                Select9::R2(msg) => {
+                Select10::R2(None) => {}
+                Select10::R2(Some(msg)) => {
                     if log_enabled!(log::Level::Trace) {
                         trace!("OUT req {}", packet_to_string(&msg));
                     }
 
                     let h = MultipartHeader::new();
 
+                    monitor.start(SendKind::Req, reactor.now());
+
                     req_send = Some(client_req.sock.send_to(h, msg));
                 }
                 // req_send
-                Select9::R3(result) => {
-                    if let Err(e) = result {
-                        error!("req zmq send: {}", e);
-                    }
+                Select10::R3(result) => {
+                    let blocked_since = req_send.take().unwrap().blocked_since();
 
-                    req_send = None;
+                    match result {
+                        Ok(()) => monitor.record(SendKind::Req, None, blocked_since, reactor.now()),
+                        Err(e) => {
+                            error!("req zmq send: {}", e);
+
+                            monitor.record_error(SendKind::Req, None);
+                        }
+                    }
                 }
                 // client_req.sock.recv_routed
-                Select9::R4(result) => match result {
+                Select10::R4(result) => match result {
                     Ok((_, msg)) => {
                         if log_enabled!(log::Level::Trace) {
                             trace!("IN req {}", packet_to_string(&msg));
@@ -1623,23 +1950,34 @@ impl ClientSocketManager {
                     Err(e) => error!("req zmq recv: {}", e),
                 },
                 // stream_handles_recv_any
-                Select9::R5(msg) => {
+                Select10::R5(None) => {}
+                Select10::R5(Some(msg)) => {
                     if log_enabled!(log::Level::Trace) {
                         trace!("OUT stream {}", packet_to_string(&msg));
                     }
 
+                    monitor.start(SendKind::Stream, reactor.now());
+
                     stream_out_send = Some(client_stream.out.send(msg));
                 }
                 // stream_out_send
-                Select9::R6(result) => {
-                    if let Err(e) = result {
-                        error!("stream zmq send: {}", e);
-                    }
+                Select10::R6(result) => {
+                    let blocked_since = stream_out_send.take().unwrap().blocked_since();
 
-                    stream_out_send = None;
+                    match result {
+                        Ok(()) => {
+                            monitor.record(SendKind::Stream, None, blocked_since, reactor.now())
+                        }
+                        Err(e) => {
+                            error!("stream zmq send: {}", e);
+
+                            monitor.record_error(SendKind::Stream, None);
+                        }
+                    }
                 }
                 // stream_handles_recv_addr
-                Select9::R7((addr, msg)) => {
+                Select10::R7(None) => {}
+                Select10::R7(Some((addr, msg))) => {
                     let mut h = MultipartHeader::new();
                     h.push(zmq::Message::from(addr.as_ref()));
 
@@ -1647,23 +1985,37 @@ impl ClientSocketManager {
                         trace!("OUT stream to {}", packet_to_string(&msg));
                     }
 
+                    monitor.start(SendKind::StreamTo, reactor.now());
+
                     stream_out_stream_send = Some(client_stream.out_stream.send_to(h, msg));
+                    stream_out_stream_addr = Some(addr);
                 }
                 // stream_out_stream_send
-                Select9::R8(result) => {
+                Select10::R8(result) => {
+                    let blocked_since = stream_out_stream_send.take().unwrap().blocked_since();
+                    let addr = stream_out_stream_addr.take().unwrap();
+
                     match result {
-                        Ok(()) => {}
-                        Err(zmq::Error::EHOSTUNREACH) => {
-                            // this can happen if a known peer goes away
-                            debug!("stream zmq send to host unreachable");
+                        Ok(()) => monitor.record(
+                            SendKind::StreamTo,
+                            Some(addr.as_ref()),
+                            blocked_since,
+                            reactor.now(),
+                        ),
+                        Err(e) => {
+                            if e == zmq::Error::EHOSTUNREACH {
+                                // this can happen if a known peer goes away
+                                debug!("stream zmq send to host unreachable");
+                            } else {
+                                error!("stream zmq send to: {}", e);
+                            }
+
+                            monitor.record_error(SendKind::StreamTo, Some(addr.as_ref()));
                         }
-                        Err(e) => error!("stream zmq send to: {}", e),
                     }
-
-                    stream_out_stream_send = None;
                 }
                 // client_stream.in_.recv
-                Select9::R9(result) => match result {
+                Select10::R9(result) => match result {
                     Ok(msg) => {
                         if log_enabled!(log::Level::Trace) {
                             trace!("IN stream {}", packet_to_string(&msg));
@@ -1679,6 +2031,15 @@ impl ClientSocketManager {
                     }
                     Err(e) => error!("stream zmq recv: {}", e),
                 },
+                // drain_timeout
+                Select10::R10(_) => {
+                    drain_timeout = None;
+
+                    control_sender
+                        .send(Err("timed out draining handles".to_string()))
+                        .await
+                        .expect("failed to send control response");
+                }
             }
 
             if req_handles.need_cleanup() {
@@ -1692,6 +2053,25 @@ impl ClientSocketManager {
                     debug!("stream handle disconnected: filter=[{}]", p.filter);
                 });
             }
+
+            // drained once all handles are gone and nothing is mid-send.
+            // handles are only removed after their queues are empty
+            if drain_timeout.is_some()
+                && req_handles.len() == 0
+                && stream_handles.len() == 0
+                && req_send.is_none()
+                && stream_out_send.is_none()
+                && stream_out_stream_send.is_none()
+            {
+                drain_timeout = None;
+
+                debug!("handles drained");
+
+                control_sender
+                    .send(Ok(()))
+                    .await
+                    .expect("failed to send control response");
+            }
         }
     }
 
@@ -1807,6 +2187,7 @@ impl Drop for ClientSocketManager {
 
 pub struct ServerSocketManager {
     handle_bound: usize,
+    handle_stats: Arc<Mutex<ServerHandleStats>>,
     thread: Option<thread::JoinHandle<()>>,
     control_pipe: Mutex<(
         channel::Sender<ServerControlRequest>,
@@ -1830,53 +2211,55 @@ impl ServerSocketManager {
         other_hwm: usize,
         handle_bound: usize,
         stream_maxconn: usize,
-    ) -> Self {
+    ) -> Result<Self, String> {
         let (s1, r1) = channel::channel(1);
         let (s2, r2) = channel::channel(1);
 
         let instance_id = String::from(instance_id);
 
-        let thread = thread::Builder::new()
-            .name("zhttpsocket".to_string())
-            .spawn(move || {
-                debug!("server manager thread start");
+        let handle_stats = Arc::new(Mutex::new(ServerHandleStats::default()));
+        let thread_handle_stats = Arc::clone(&handle_stats);
 
-                // 2 control channels, 3 channels per handle, 4 zmq sockets
-                let channels = 2 + (HANDLES_MAX * 3);
-                let zmqsockets = 4;
+        let thread = spawn_thread("zhttpsocket".to_string(), move || {
+            debug!("server manager thread start");
 
-                let registrations_max = (channels * REGISTRATIONS_PER_CHANNEL)
-                    + (zmqsockets * REGISTRATIONS_PER_ZMQSOCKET);
+            // 2 control channels, 3 channels per handle, 4 zmq sockets
+            let channels = 2 + (HANDLES_MAX * 3);
+            let zmqsockets = 4;
 
-                let reactor = Reactor::new(registrations_max);
+            let registrations_max =
+                (channels * REGISTRATIONS_PER_CHANNEL) + (zmqsockets * REGISTRATIONS_PER_ZMQSOCKET);
 
-                let executor = Executor::new(EXECUTOR_TASKS_MAX);
+            let reactor = Reactor::new(registrations_max);
 
-                executor
-                    .spawn(Self::run(
-                        ctx,
-                        s1,
-                        r2,
-                        instance_id,
-                        retained_max,
-                        init_hwm,
-                        other_hwm,
-                        handle_bound,
-                        stream_maxconn,
-                    ))
-                    .unwrap();
+            let executor = Executor::new(EXECUTOR_TASKS_MAX);
 
-                executor.run(|timeout| reactor.poll(timeout)).unwrap();
+            executor
+                .spawn(Self::run(
+                    ctx,
+                    s1,
+                    r2,
+                    instance_id,
+                    retained_max,
+                    init_hwm,
+                    other_hwm,
+                    handle_bound,
+                    stream_maxconn,
+                    thread_handle_stats,
+                ))
+                .unwrap();
 
-                debug!("server manager thread end");
-            })
-            .unwrap();
+            executor.run(|timeout| reactor.poll(timeout)).unwrap();
 
-        Self {
+            debug!("server manager thread end");
+        })?;
+
+        Ok(Self {
             handle_bound,
+            handle_stats,
             thread: Some(thread),
             control_pipe: Mutex::new((s2, r1)),
-        }
+        })
     }
 
     pub fn set_server_req_specs(&mut self, specs: &[SpecInfo]) -> Result<(), String> {
@@ -1933,6 +2316,10 @@ impl ServerSocketManager {
         }
     }
 
+    pub fn handle_stats(&self) -> ServerHandleStats {
+        *self.handle_stats.lock().unwrap()
+    }
+
     fn control_send(&self, req: ServerControlRequest) {
         let pipe = self.control_pipe.lock().unwrap();
 
@@ -1959,6 +2346,7 @@ impl ServerSocketManager {
         other_hwm: usize,
         handle_bound: usize,
         stream_maxconn: usize,
+        handle_stats: Arc<Mutex<ServerHandleStats>>,
     ) {
         let control_sender = AsyncSender::new(control_sender);
         let control_receiver = AsyncReceiver::new(control_receiver);
@@ -2174,6 +2562,8 @@ impl ServerSocketManager {
                 Select10::R4(result) => {
                     if let Err(e) = result {
                         error!("server req zmq send: {}", e);
+
+                        handle_stats.lock().unwrap().req.send_errors += 1;
                     }
 
                     req_send = None;
@@ -2182,7 +2572,9 @@ impl ServerSocketManager {
                 Select10::R5(()) => {
                     let (header, msg) = req_in_msg.take().unwrap();
 
-                    Self::handle_req_message(header, msg, &messages_memory, &req_handles);
+                    let d = Self::handle_req_message(header, msg, &messages_memory, &req_handles);
+
+                    handle_stats.lock().unwrap().req.record(d);
                 }
                 // stream_in_recv
                 Select10::R6(result) => match result {
@@ -2202,8 +2594,14 @@ impl ServerSocketManager {
                             trace!("IN server stream next {}", packet_to_string(&msg));
                         }
 
-                        Self::handle_stream_message_direct(msg, &messages_memory, &stream_handles)
-                            .await;
+                        let d = Self::handle_stream_message_direct(
+                            msg,
+                            &messages_memory,
+                            &stream_handles,
+                        )
+                        .await;
+
+                        handle_stats.lock().unwrap().stream.record(d);
                     }
                     Err(e) => error!("server stream next zmq recv: {}", e),
                 },
@@ -2219,6 +2617,8 @@ impl ServerSocketManager {
                 Select10::R9(result) => {
                     if let Err(e) = result {
                         error!("server stream zmq send: {}", e);
+
+                        handle_stats.lock().unwrap().stream.send_errors += 1;
                     }
 
                     stream_out_send = None;
@@ -2227,7 +2627,9 @@ impl ServerSocketManager {
                 Select10::R10(()) => {
                     let msg = stream_in_msg.take().unwrap();
 
-                    Self::handle_stream_message_any(msg, &messages_memory, &stream_handles);
+                    let d = Self::handle_stream_message_any(msg, &messages_memory, &stream_handles);
+
+                    handle_stats.lock().unwrap().stream.record(d);
                 }
             }
 
@@ -2275,17 +2677,17 @@ impl ServerSocketManager {
         msg: zmq::Message,
         messages_memory: &Arc<arena::ArcMemory<zmq::Message>>,
         handles: &ServerReqHandles,
-    ) {
+    ) -> Delivery {
         let msg = arena::Arc::new(msg, messages_memory).unwrap();
 
-        handles.send(header, &msg);
+        handles.send(header, &msg)
     }
 
     fn handle_stream_message_any(
         msg: zmq::Message,
         messages_memory: &Arc<arena::ArcMemory<zmq::Message>>,
         handles: &ServerStreamHandles,
-    ) {
+    ) -> Delivery {
         let msg = arena::Arc::new(msg, messages_memory).unwrap();
 
         let mut scratch = ParseScratch::new();
@@ -2294,18 +2696,18 @@ impl ServerSocketManager {
             Ok(ret) => ret,
             Err(e) => {
                 warn!("unable to determine packet id(s): {}", e);
-                return;
+                return Delivery::Dropped;
             }
         };
 
-        handles.send_any(&msg, from, ids);
+        handles.send_any(&msg, from, ids)
     }
 
     async fn handle_stream_message_direct(
         msg: zmq::Message,
         messages_memory: &Arc<arena::ArcMemory<zmq::Message>>,
         handles: &ServerStreamHandles,
-    ) {
+    ) -> Delivery {
         let msg = arena::Arc::new(msg, messages_memory).unwrap();
 
         let mut scratch = ParseScratch::new();
@@ -2314,11 +2716,11 @@ impl ServerSocketManager {
             Ok(ret) => ret,
             Err(e) => {
                 warn!("unable to determine packet id(s): {}", e);
-                return;
+                return Delivery::Dropped;
             }
         };
 
-        handles.send_direct(&msg, from, ids).await;
+        handles.send_direct(&msg, from, ids).await
     }
 }
 
@@ -2653,8 +3055,82 @@ mod tests {
     use crate::zhttppacket::{
         PacketParse, Request, RequestData, RequestPacket, Response, ResponsePacket,
     };
+    use crate::zmq::SpecOpts;
     use test_log::test;
 
+    #[test]
+    fn queue_monitor() {
+        let data = Arc::new(Mutex::new(QueueData::default()));
+        let mut monitor = QueueMonitor::new(data.clone());
+
+        let now = Instant::now();
+
+        monitor.record(SendKind::Req, None, None, now);
+        monitor.record(
+            SendKind::Req,
+            None,
+            Some(now - Duration::from_millis(100)),
+            now,
+        );
+        monitor.record(SendKind::StreamTo, Some(b"a"), None, now);
+        monitor.record(
+            SendKind::StreamTo,
+            Some(b"b"),
+            Some(now - Duration::from_millis(50)),
+            now,
+        );
+
+        let stats = data.lock().unwrap().stats();
+
+        assert_eq!(
+            stats.req,
+            SendStats {
+                sent: 2,
+                hwm_hits: 1,
+                hwm_wait: Duration::from_millis(100),
+                errors: 0,
+            }
+        );
+        assert_eq!(stats.stream, SendStats::default());
+        assert_eq!(stats.stream_to.sent, 2);
+        assert_eq!(stats.stream_to.hwm_hits, 1);
+
+        // most hwm hits first
+        assert_eq!(stats.stream_to_addrs.len(), 2);
+        assert_eq!(stats.stream_to_addrs[0].0, "b");
+        assert_eq!(stats.stream_to_addrs[0].1.hwm_hits, 1);
+        assert_eq!(stats.stream_to_addrs[1].0, "a");
+        assert_eq!(stats.stream_to_addrs[1].1.hwm_hits, 0);
+
+        monitor.record_error(SendKind::StreamTo, Some(b"a"));
+
+        let stats = data.lock().unwrap().stats();
+        assert_eq!(stats.stream_to.sent, 2);
+        assert_eq!(stats.stream_to.errors, 1);
+        assert_eq!(stats.stream_to_addrs[1].0, "a");
+        assert_eq!(stats.stream_to_addrs[1].1.errors, 1);
+
+        // warnings are limited per interval
+        assert_eq!(monitor.hits_since_warn[SendKind::Req as usize], 0);
+        monitor.record(SendKind::Req, None, Some(now), now);
+        assert_eq!(monitor.hits_since_warn[SendKind::Req as usize], 1);
+
+        let max_wait = Duration::from_secs(5);
+
+        monitor.start(SendKind::Stream, now);
+        assert!(data.lock().unwrap().is_reachable(now, max_wait));
+        assert!(!data
+            .lock()
+            .unwrap()
+            .is_reachable(now + Duration::from_secs(5), max_wait));
+
+        monitor.record(SendKind::Stream, None, None, now + Duration::from_secs(5));
+        assert!(data
+            .lock()
+            .unwrap()
+            .is_reachable(now + Duration::from_secs(5), max_wait));
+    }
+
     fn wait_readable(poller: &mut event::Poller, token: mio::Token) {
         loop {
             poller.poll(None).unwrap();
@@ -2679,11 +3155,42 @@ mod tests {
         }
     }
 
+    #[test]
+    fn handle_stats() {
+        let mut stats = HandleStats::default();
+
+        stats.record(Delivery::Delivered);
+        stats.record(Delivery::Delivered);
+        stats.record(Delivery::Full);
+        stats.record(Delivery::Dropped);
+
+        assert_eq!(
+            stats,
+            HandleStats {
+                delivered: 2,
+                full: 1,
+                dropped: 1,
+                send_errors: 0,
+            }
+        );
+
+        let handles = ServerReqHandles::new(1);
+        let memory = Arc::new(arena::ArcMemory::new(1));
+        let msg = arena::Arc::new(zmq::Message::new(), &memory).unwrap();
+
+        // no handles to take the message
+        assert_eq!(
+            handles.send(MultipartHeader::new(), &msg),
+            Delivery::Dropped
+        );
+    }
+
     #[test]
     fn test_client_send_flow() {
         let zmq_context = Arc::new(zmq::Context::new());
 
-        let mut zsockman = ClientSocketManager::new(Arc::clone(&zmq_context), "test", 1, 1, 1, 1);
+        let mut zsockman =
+            ClientSocketManager::new(Arc::clone(&zmq_context), "test", 1, 1, 1, 1).unwrap();
 
         zsockman
             .set_client_stream_specs(
@@ -2691,16 +3198,19 @@ mod tests {
                     spec: String::from("inproc://flow-test-out"),
                     bind: true,
                     ipc_file_mode: 0,
+                    opts: SpecOpts::default(),
                 }],
                 &vec![SpecInfo {
                     spec: String::from("inproc://flow-test-out-stream"),
                     bind: true,
                     ipc_file_mode: 0,
+                    opts: SpecOpts::default(),
                 }],
                 &vec![SpecInfo {
                     spec: String::from("inproc://flow-test-in"),
                     bind: true,
                     ipc_file_mode: 0,
+                    opts: SpecOpts::default(),
                 }],
             )
             .unwrap();
@@ -2795,13 +3305,14 @@ mod tests {
         let zmq_context = Arc::new(zmq::Context::new());
 
         let mut zsockman =
-            ClientSocketManager::new(Arc::clone(&zmq_context), "test", 1, 100, 100, 100);
+            ClientSocketManager::new(Arc::clone(&zmq_context), "test", 1, 100, 100, 100).unwrap();
 
         zsockman
             .set_client_req_specs(&vec![SpecInfo {
                 spec: String::from("inproc://test-req"),
                 bind: true,
                 ipc_file_mode: 0,
+                opts: SpecOpts::default(),
             }])
             .unwrap();
 
@@ -2909,7 +3420,7 @@ mod tests {
         let zmq_context = Arc::new(zmq::Context::new());
 
         let mut zsockman =
-            ClientSocketManager::new(Arc::clone(&zmq_context), "test", 1, 100, 100, 100);
+            ClientSocketManager::new(Arc::clone(&zmq_context), "test", 1, 100, 100, 100).unwrap();
 
         zsockman
             .set_client_stream_specs(
@@ -2917,16 +3428,19 @@ mod tests {
                     spec: String::from("inproc://test-out"),
                     bind: true,
                     ipc_file_mode: 0,
+                    opts: SpecOpts::default(),
                 }],
                 &vec![SpecInfo {
                     spec: String::from("inproc://test-out-stream"),
                     bind: true,
                     ipc_file_mode: 0,
+                    opts: SpecOpts::default(),
                 }],
                 &vec![SpecInfo {
                     spec: String::from("inproc://test-in"),
                     bind: true,
                     ipc_file_mode: 0,
+                    opts: SpecOpts::default(),
                 }],
             )
             .unwrap();
@@ -3101,12 +3615,94 @@ mod tests {
         drop(zsockman);
     }
 
+    #[test]
+    fn test_client_drain() {
+        let zmq_context = Arc::new(zmq::Context::new());
+
+        let mut zsockman =
+            ClientSocketManager::new(Arc::clone(&zmq_context), "test", 1, 100, 100, 100).unwrap();
+
+        zsockman
+            .set_client_stream_specs(
+                &vec![SpecInfo {
+                    spec: String::from("inproc://test-drain-out"),
+                    bind: true,
+                    ipc_file_mode: 0,
+                    opts: SpecOpts::default(),
+                }],
+                &vec![SpecInfo {
+                    spec: String::from("inproc://test-drain-out-stream"),
+                    bind: true,
+                    ipc_file_mode: 0,
+                    opts: SpecOpts::default(),
+                }],
+                &vec![SpecInfo {
+                    spec: String::from("inproc://test-drain-in"),
+                    bind: true,
+                    ipc_file_mode: 0,
+                    opts: SpecOpts::default(),
+                }],
+            )
+            .unwrap();
+
+        let h = zsockman.client_stream_handle(b"a-");
+
+        let in_sock = zmq_context.socket(zmq::PULL).unwrap();
+        in_sock.connect("inproc://test-drain-out").unwrap();
+
+        let in_stream_sock = zmq_context.socket(zmq::ROUTER).unwrap();
+        in_stream_sock
+            .set_identity("test-handler".as_bytes())
+            .unwrap();
+        in_stream_sock
+            .connect("inproc://test-drain-out-stream")
+            .unwrap();
+
+        // ensure the peer is known before queuing a batch
+        h.send_to_addr(
+            "test-handler".as_bytes(),
+            zmq::Message::from("0".as_bytes()),
+        )
+        .unwrap();
+        let parts = in_stream_sock.recv_multipart(0).unwrap();
+        assert_eq!(parts[2], b"0");
+
+        // can't drain while a handle is alive
+        assert!(zsockman.drain(Duration::from_millis(10)).is_err());
+
+        for i in 1..=3 {
+            h.send_to_addr(
+                "test-handler".as_bytes(),
+                zmq::Message::from(format!("{}", i).into_bytes()),
+            )
+            .unwrap();
+        }
+
+        h.send_to_any(zmq::Message::from("any".as_bytes())).unwrap();
+
+        drop(h);
+
+        zsockman.drain(Duration::from_millis(5_000)).unwrap();
+
+        for i in 1..=3 {
+            let parts = in_stream_sock.recv_multipart(0).unwrap();
+            assert_eq!(parts.len(), 3);
+            assert_eq!(parts[2], format!("{}", i).as_bytes());
+        }
+
+        let parts = in_sock.recv_multipart(0).unwrap();
+        assert_eq!(parts[0], b"any");
+
+        drop(zsockman);
+    }
+
     #[test]
     fn test_server_req() {
         let zmq_context = Arc::new(zmq::Context::new());
 
         let mut zsockman =
-            ServerSocketManager::new(Arc::clone(&zmq_context), "test", 1, 100, 100, 100, 0);
+            ServerSocketManager::new(Arc::clone(&zmq_context), "test", 1, 100, 100, 100, 0)
+                .unwrap();
 
         let h1 = zsockman.server_req_handle();
         let h2 = zsockman.server_req_handle();
@@ -3116,6 +3712,7 @@ mod tests {
                 spec: String::from("inproc://test-server-req"),
                 bind: true,
                 ipc_file_mode: 0,
+                opts: SpecOpts::default(),
             }])
             .unwrap();
 
@@ -3196,7 +3793,8 @@ mod tests {
         let zmq_context = Arc::new(zmq::Context::new());
 
         let zsockman =
-            ServerSocketManager::new(Arc::clone(&zmq_context), "test", 1, 100, 100, 100, 2);
+            ServerSocketManager::new(Arc::clone(&zmq_context), "test", 1, 100, 100, 100, 2)
+                .unwrap();
 
         let h1 = zsockman.server_stream_handle();
         let h2 = zsockman.server_stream_handle();
@@ -3207,16 +3805,19 @@ mod tests {
                     spec: String::from("inproc://test-server-in"),
                     bind: true,
                     ipc_file_mode: 0,
+                    opts: SpecOpts::default(),
                 }],
                 &vec![SpecInfo {
                     spec: String::from("inproc://test-server-in-stream"),
                     bind: true,
                     ipc_file_mode: 0,
+                    opts: SpecOpts::default(),
                 }],
                 &vec![SpecInfo {
                     spec: String::from("inproc://test-server-out"),
                     bind: true,
                     ipc_file_mode: 0,
+                    opts: SpecOpts::default(),
                 }],
             )
             .unwrap();
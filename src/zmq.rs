@@ -14,13 +14,15 @@
  * limitations under the License.
  */
 
+use crate::curve::CurveKey;
+use crate::platform;
 use arrayvec::ArrayVec;
 use std::cell::Cell;
 use std::cell::RefCell;
 use std::fmt;
 use std::fs;
 use std::io;
-use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
 
 const MULTIPART_HEADERS_MAX: usize = 8;
 
@@ -32,11 +34,101 @@ fn trim_prefix<'a>(s: &'a str, prefix: &str) -> Result<&'a str, ()> {
     }
 }
 
+// socket options for the connections of a single spec. zmq captures these
+// when a connection is set up, so they can differ between the specs of a
+// socket. unset options keep the socket's values
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CurveOpts {
+    Disabled,
+    Server {
+        secret_key: CurveKey,
+    },
+    Client {
+        public_key: CurveKey,
+        secret_key: CurveKey,
+        server_key: CurveKey,
+    },
+}
+
+impl CurveOpts {
+    fn apply(&self, sock: &zmq::Socket) -> zmq::Result<()> {
+        match self {
+            // also switches the mechanism back to null
+            CurveOpts::Disabled => sock.set_curve_server(false),
+            CurveOpts::Server { secret_key } => {
+                sock.set_curve_server(true)?;
+                sock.set_curve_secretkey(secret_key)
+            }
+            CurveOpts::Client {
+                public_key,
+                secret_key,
+                server_key,
+            } => {
+                sock.set_curve_serverkey(server_key)?;
+                sock.set_curve_publickey(public_key)?;
+                sock.set_curve_secretkey(secret_key)
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct SpecOpts {
+    pub sndhwm: Option<i32>,
+    pub rcvhwm: Option<i32>,
+    pub sndbuf: Option<i32>, // kernel buffer size in bytes
+    pub rcvbuf: Option<i32>, // kernel buffer size in bytes
+    pub linger: Option<i32>, // milliseconds, -1 = wait forever
+    pub curve: Option<CurveOpts>,
+}
+
+impl SpecOpts {
+    // returns the previous values of the options that were set
+    fn apply(&self, sock: &zmq::Socket) -> zmq::Result<SpecOpts> {
+        let mut prev = SpecOpts::default();
+
+        if let Some(x) = self.sndhwm {
+            prev.sndhwm = Some(sock.get_sndhwm()?);
+            sock.set_sndhwm(x)?;
+        }
+
+        if let Some(x) = self.rcvhwm {
+            prev.rcvhwm = Some(sock.get_rcvhwm()?);
+            sock.set_rcvhwm(x)?;
+        }
+
+        if let Some(x) = self.sndbuf {
+            prev.sndbuf = Some(sock.get_sndbuf()?);
+            sock.set_sndbuf(x)?;
+        }
+
+        if let Some(x) = self.rcvbuf {
+            prev.rcvbuf = Some(sock.get_rcvbuf()?);
+            sock.set_rcvbuf(x)?;
+        }
+
+        if let Some(x) = self.linger {
+            prev.linger = Some(sock.get_linger()?);
+            sock.set_linger(x)?;
+        }
+
+        if let Some(x) = &self.curve {
+            // sockets only use curve through spec options, so the previous
+            // value is always disabled
+            prev.curve = Some(CurveOpts::Disabled);
+            x.apply(sock)?;
+        }
+
+        Ok(prev)
+    }
+}
+
 #[derive(Clone)]
 pub struct SpecInfo {
     pub spec: String,
     pub bind: bool,
     pub ipc_file_mode: u32,
+    pub opts: SpecOpts,
 }
 
 impl fmt::Display for SpecInfo {
@@ -60,6 +152,7 @@ pub enum ZmqSocketError {
     Connect(String, zmq::Error),
     Bind(String, zmq::Error),
     SetMode(String, io::Error),
+    SetOption(String, zmq::Error),
 }
 
 impl ToString for ZmqSocketError {
@@ -68,6 +161,7 @@ impl ToString for ZmqSocketError {
             ZmqSocketError::Connect(spec, e) => format!("connect {}: {}", spec, e),
             ZmqSocketError::Bind(spec, e) => format!("bind {}: {}", spec, e),
             ZmqSocketError::SetMode(spec, e) => format!("set mode {}: {}", spec, e),
+            ZmqSocketError::SetOption(spec, e) => format!("set option {}: {}", spec, e),
         }
     }
 }
@@ -84,6 +178,20 @@ fn unbind(sock: &zmq::Socket, endpoint: &str) -> zmq::Result<()> {
 }
 
 fn setup_spec(sock: &zmq::Socket, spec: &SpecInfo) -> Result<String, ZmqSocketError> {
+    let prev_opts = match spec.opts.apply(sock) {
+        Ok(prev) => prev,
+        Err(e) => return Err(ZmqSocketError::SetOption(spec.spec.clone(), e)),
+    };
+
+    let ret = setup_spec_with_opts(sock, spec);
+
+    // restore the socket's values for other specs
+    prev_opts.apply(sock).unwrap();
+
+    ret
+}
+
+fn setup_spec_with_opts(sock: &zmq::Socket, spec: &SpecInfo) -> Result<String, ZmqSocketError> {
     if spec.bind {
         match sock.bind(&spec.spec) {
             Ok(_) => {
@@ -91,8 +199,7 @@ fn setup_spec(sock: &zmq::Socket, spec: &SpecInfo) -> Result<String, ZmqSocketEr
 
                 if let Ok(path) = trim_prefix(&spec.spec, "ipc://") {
                     if spec.ipc_file_mode > 0 {
-                        let perms = fs::Permissions::from_mode(spec.ipc_file_mode);
-                        if let Err(e) = fs::set_permissions(path, perms) {
+                        if let Err(e) = platform::set_mode(Path::new(path), spec.ipc_file_mode) {
                             // if setting perms fails, undo the bind
                             unbind(sock, &endpoint).unwrap();
 
@@ -310,6 +417,7 @@ impl ZmqSocket {
             }
         }
 
+        // changed options only apply once a spec is set up again
         let mut to_add = Vec::new();
         let mut to_update = Vec::new();
         for new in new_specs.iter() {
@@ -361,17 +469,14 @@ impl ZmqSocket {
             if let Ok(path) = trim_prefix(&spec.spec, "ipc://") {
                 if spec.ipc_file_mode > 0 {
                     match fs::metadata(path) {
-                        Ok(meta) => {
-                            let perms = fs::Permissions::from_mode(spec.ipc_file_mode);
-                            match fs::set_permissions(path, perms) {
-                                Ok(_) => {
-                                    prev_perms.push((String::from(path), meta.permissions()));
-                                }
-                                Err(e) => {
-                                    err = Some(ZmqSocketError::SetMode(spec.spec.clone(), e));
-                                }
+                        Ok(meta) => match platform::set_mode(Path::new(path), spec.ipc_file_mode) {
+                            Ok(_) => {
+                                prev_perms.push((String::from(path), meta.permissions()));
                             }
-                        }
+                            Err(e) => {
+                                err = Some(ZmqSocketError::SetMode(spec.spec.clone(), e));
+                            }
+                        },
                         Err(e) => {
                             err = Some(ZmqSocketError::SetMode(spec.spec.clone(), e));
                         }
@@ -457,6 +562,7 @@ mod tests {
             spec: String::from("inproc://send-test"),
             bind: true,
             ipc_file_mode: 0,
+            opts: SpecOpts::default(),
         }])
         .unwrap();
 
@@ -467,6 +573,7 @@ mod tests {
             spec: String::from("inproc://send-test"),
             bind: false,
             ipc_file_mode: 0,
+            opts: SpecOpts::default(),
         }])
         .unwrap();
 